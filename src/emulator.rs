@@ -6,23 +6,44 @@ pub mod dma;
 pub mod lcd;
 pub mod gamepad;
 use lcd::*;
-use dma::DMA_CTX;
-use std::sync::atomic::{AtomicU16, Ordering};
-use cartridge::CARTRIDGE_CTX;
+use dma::{DMA_CTX, HDMA_CTX};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use cartridge::{CARTRIDGE_CTX, CartridgeError};
 pub mod cpu;
 use cpu::CPU_CTX;
 use cpu::interrupts::*;
 pub mod ram;
-use ram::RAM;
 pub mod address_bus;
-use address_bus::*;
 pub mod ppu;
 use ppu::PPU_CTX;
 pub mod timer;
-use timer::TIMER_CTX;
-pub mod ui;
-use std::sync::Arc;
-use std::sync::Mutex;
+use timer::{TIMER_CTX, DIV_ADDR};
+pub mod apu;
+use apu::APU_CTX;
+pub(crate) mod ui;
+pub mod savestate;
+pub mod keymap;
+pub mod png;
+pub mod serial;
+pub mod debugger;
+pub mod boot;
+pub mod cheats;
+pub mod error;
+pub mod memdump;
+pub use memdump::{dump_memory, MemoryRegion};
+pub mod disasm;
+pub mod archive;
+pub mod ramsearch;
+pub mod movie;
+pub mod hash;
+#[cfg(test)]
+pub mod test_util;
+pub use ramsearch::{ram_search_step, SearchPredicate};
+pub use disasm::disassemble;
+use boot::BOOT_CTX;
+use serial::SERIAL_CTX;
+use savestate::*;
+use ram::RAM_CTX;
 
 use crate::emulator::cpu::CPU;
 
@@ -33,24 +54,128 @@ use crate::emulator::cpu::CPU;
 #[allow(dead_code)]
 pub struct Emulator {
     running: bool,
-    paused: bool,
+    // Runs without opening any windows and, unless the speed is
+    // given, without frame limiting
+    headless: bool,
+    // Stops after the given number of frames if non-zero
+    frame_limit: u64,
+    // Stops once the CPU has run for the given number of ticks if non-zero
+    cycle_limit: u64,
+    // Stops once a test ROM reports its result
+    test_rom: bool,
+    test_result: Option<TestResult>,
+    // Runs as a Game Boy Color
+    cgb: bool,
+    // Runs CGB games as a DMG
+    force_dmg: bool,
+    // Emulation speed relative to the original hardware
+    speed: f64,
+    // Whether the speed was given, headless runs are then paced too
+    speed_set: bool,
+    // Prints the FPS to stdout every second
+    verbose: bool,
 }
 
 unsafe impl Send for Emulator {}
 
 pub static mut EMULATOR_CTX: Emulator = Emulator {
     running: false,
-    paused: true,
+    headless: false,
+    frame_limit: 0,
+    cycle_limit: 0,
+    test_rom: false,
+    test_result: None,
+    cgb: false,
+    force_dmg: false,
+    speed: 1.0,
+    speed_set: false,
+    verbose: false,
 };
 
+/**
+ * The result reported by a test ROM
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TestResult {
+    Passed,
+    Failed,
+}
+
+// Mooneye test ROMs execute LD B, B once they are done, with the
+// Fibonacci numbers in B, C, D, E, H and L if the test passed
+const MOONEYE_BREAKPOINT: u8 = 0x40;
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAILED: [u8; 6] = [0x42; 6];
+
+// Range of the clock scale, beyond which the frame limiter is too
+// coarse to keep a steady rate
+const MIN_SPEED: f64 = 0.25;
+const MAX_SPEED: f64 = 8.0;
+// Frequency of the original clock in MHz
+pub const CLOCK_MHZ: f64 = 4.194304;
+
+// Save state requests made by the UI thread, handled by the
+// CPU thread between two instructions
+const STATE_REQUEST_NONE: u8 = 0;
+const STATE_REQUEST_SAVE: u8 = 1;
+const STATE_REQUEST_LOAD: u8 = 2;
+const STATE_REQUEST_RESET: u8 = 3;
+static STATE_REQUEST: AtomicU8 = AtomicU8::new(STATE_REQUEST_NONE);
+// Set by the UI thread while the fast-forward key is held
+static TURBO: AtomicBool = AtomicBool::new(false);
+// Toggled by the UI thread, the CPU thread idles while set
+static PAUSED: AtomicBool = AtomicBool::new(false);
+// Set by the UI thread, the CPU thread dumps the memories once
+// no OAM DMA transfer is in progress
+static DUMP_REQUEST: AtomicBool = AtomicBool::new(false);
+// Set on window close or SIGINT, the CPU thread stops before the
+// next instruction and writes the battery save
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Stops the emulator cleanly on the first SIGINT / SIGTERM,
+ * exits immediately on the second one
+ */
+extern "C" fn on_signal(_signal: libc::c_int) -> () {
+    if SHUTDOWN.swap(true, Ordering::Relaxed) {
+        unsafe { libc::_exit(130); }
+    }
+}
+
 fn cpu_run(debug: bool) -> () {
     log::info!("Emulator is running");
     unsafe {
         EMULATOR_CTX.running = true;
-        EMULATOR_CTX.paused = false;
+        let mut serial_len = 0;
         while EMULATOR_CTX.running {
-            if EMULATOR_CTX.paused {
-                std::thread::sleep(std::time::Duration::from_millis(32));
+            if EMULATOR_CTX.test_rom {
+                EMULATOR_CTX.test_result = Emulator::check_test_result(&mut serial_len);
+                if EMULATOR_CTX.test_result.is_some() {
+                    EMULATOR_CTX.running = false;
+                    break;
+                }
+            }
+            if SHUTDOWN.load(Ordering::Relaxed) {
+                EMULATOR_CTX.running = false;
+                break;
+            }
+            if EMULATOR_CTX.frame_limit > 0 && PPU_CTX.curr_frame >= EMULATOR_CTX.frame_limit {
+                log::info!("Frame limit of {} reached", EMULATOR_CTX.frame_limit);
+                EMULATOR_CTX.running = false;
+                break;
+            }
+            if EMULATOR_CTX.cycle_limit > 0 &&
+                CPU_CTX.ticks.load(Ordering::Relaxed) >= EMULATOR_CTX.cycle_limit {
+                log::info!("Cycle limit of {} reached", EMULATOR_CTX.cycle_limit);
+                EMULATOR_CTX.running = false;
+                break;
+            }
+            // Save states can still be saved and loaded while paused
+            Emulator::handle_state_request();
+            Emulator::handle_dump_request();
+            if Emulator::is_paused() {
+                std::thread::sleep(std::time::Duration::from_millis(16));
+                continue;
             }
             CPU_CTX.step();
             if debug {
@@ -58,6 +183,7 @@ fn cpu_run(debug: bool) -> () {
             }
         }
     }
+    Emulator::shutdown();
 }
 
 
@@ -76,23 +202,843 @@ impl Emulator {
         // Cartridge initialization
         // Loads the ROM file into the cartridge
         unsafe {
-            CARTRIDGE_CTX.load_rom_file(rom_file);
+            Emulator::load_cartridge(rom_file);
             CARTRIDGE_CTX.print_info(true);
+        }
+        Emulator::init_components(trace);
+        log::info!(target: "stdout", "Initialize emulator: SUCCESS");
+    }
+
+    /**
+     * Initializes the emulator from ROM data held in memory. Used
+     * by the library interface, which never opens any windows.
+     */
+    pub fn init_from_data(rom_data: Vec<u8>) -> Result<(), CartridgeError> {
+        unsafe {
+            CARTRIDGE_CTX.load_rom_data(rom_data)?;
+            EMULATOR_CTX.headless = true;
+        }
+        Emulator::init_components(false);
+        return Ok(());
+    }
+
+    /**
+     * Initializes the components after the cartridge has been loaded
+     */
+    fn init_components(trace: bool) -> () {
+        unsafe {
+            let cgb_mode = CARTRIDGE_CTX.cgb_mode();
+            EMULATOR_CTX.cgb = cgb_mode != cartridge::CgbMode::Dmg && !EMULATOR_CTX.force_dmg;
+            if cgb_mode == cartridge::CgbMode::CgbOnly && !EMULATOR_CTX.cgb {
+                log::warn!(target: "stdout", "The ROM only supports the Game Boy Color \
+                    and may not work in DMG mode");
+            }
+            log::info!("Running in {} mode", if EMULATOR_CTX.cgb { "CGB" } else { "DMG" });
+            RAM_CTX.reset();
+            PPU_CTX.init_vram(RAM_CTX.init());
             LCD::init();
             CPU::cpu_init(trace);
         }
-        log::info!(target: "stdout", "Initialize emulator: SUCCESS");
+        Emulator::init_boot_rom();
+    }
+
+    /**
+     * Starts from the power-on state instead of the state left
+     * by the boot ROM if a boot ROM is mapped
+     */
+    fn init_boot_rom() -> () {
+        unsafe {
+            if BOOT_CTX.is_running() {
+                CPU_CTX.reset_for_boot();
+                TIMER_CTX.write(DIV_ADDR, 0);
+                LCD_CTX.write(0xFF40, 0);
+                LCD_CTX.write(0xFF47, 0);
+            }
+        }
+    }
+
+    /**
+     * Resets the machine as if it was power cycled, keeping the
+     * loaded ROM and the content of the cartridge RAM. The battery
+     * save is written first in case the reset loses unsaved data.
+     */
+    pub fn reset() -> () {
+        unsafe {
+            Emulator::flush_battery();
+            CARTRIDGE_CTX.reset_banking();
+            CPU_CTX.reset();
+            PPU_CTX.reset();
+            LCD_CTX.reset();
+            TIMER_CTX.reset();
+            RAM_CTX.reset();
+            PPU_CTX.init_vram(RAM_CTX.init());
+            DMA_CTX.reset();
+            HDMA_CTX.reset();
+            APU_CTX.reset();
+            SERIAL_CTX.reset();
+            gamepad::GAMEPAD_CTX.reset();
+            BOOT_CTX.reset();
+        }
+        Emulator::init_boot_rom();
+        log::info!(target: "stdout", "Reset emulator: SUCCESS");
+    }
+
+    /**
+     * Asks the CPU thread to reset the machine before executing
+     * the next instruction
+     */
+    pub fn request_reset() -> () {
+        STATE_REQUEST.store(STATE_REQUEST_RESET, Ordering::Relaxed);
+    }
+
+    /**
+     * Loads the given ROM file and prints the cartridge information
+     * to stdout without starting the emulator.
+     */
+    pub fn print_rom_info(rom_file: &str) -> () {
+        Emulator::load_cartridge(rom_file);
+        unsafe { CARTRIDGE_CTX.print_info(true); }
+    }
+
+    /**
+     * Loads the given ROM file and prints the disassembly of the
+     * instructions between `start` and `end` to stdout without
+     * starting the emulator
+     */
+    pub fn print_disassembly(rom_file: &str, start: u16, end: u16) -> () {
+        Emulator::load_cartridge(rom_file);
+        for (address, text) in disassemble(start, end) {
+            println!("${:04X}: {}", address, text);
+        }
+    }
+
+    /**
+     * Loads the ROM file into the cartridge. Exits if the
+     * ROM cannot be loaded.
+     */
+    fn load_cartridge(rom_file: &str) -> () {
+        if let Err(err) = unsafe { CARTRIDGE_CTX.load_rom_file(rom_file) } {
+            log::error!(target: "stdout", "{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    /**
+     * Loads the key bindings from the given key map file.
+     * Exits if the file is invalid.
+     */
+    pub fn set_keymap(keymap_file: &str) -> () {
+        match keymap::KeyMap::from_file(keymap_file) {
+            Ok(keymap) => unsafe { keymap::KEYMAP_CTX = keymap },
+            Err(err) => {
+                log::error!(target: "stdout", "{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /**
+     * Starts recording the input into the given movie file from
+     * the current state. The movie is written when the emulator stops.
+     */
+    pub fn record_movie(movie_file: &str) -> () {
+        unsafe { movie::MOVIE_CTX.start_recording(movie_file); }
+    }
+
+    /**
+     * Plays back the input of the given movie file from the state
+     * it was recorded from. Exits if the movie is invalid.
+     */
+    pub fn play_movie(movie_file: &str) -> () {
+        let result = movie::Movie::from_file(movie_file)
+            .and_then(|movie| unsafe { movie::MOVIE_CTX.start_playback(movie) });
+        match result {
+            Ok(()) => log::info!(target: "stdout", "Playing movie {}", movie_file),
+            Err(err) => {
+                log::error!(target: "stdout", "{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /**
+     * Overrides the region of the cartridge. Used for testing
+     * region-locked behavior.
+     */
+    pub fn set_region(japanese: bool) -> () {
+        unsafe { CARTRIDGE_CTX.set_region_override(if japanese { 0x00 } else { 0x01 }); }
+    }
+
+    /**
+     * Overrides the registers left by the boot ROM, given as a
+     * comma-separated list such as `A=11,SP=FFFE`. Must be called
+     * before init(). Exits if the list is invalid.
+     */
+    pub fn set_register_overrides(spec: &str) -> () {
+        match cpu::parse_register_overrides(spec) {
+            Ok(overrides) => unsafe { CPU_CTX.set_register_overrides(overrides) },
+            Err(err) => {
+                log::error!(target: "stdout", "{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /**
+     * Writes the battery files to the given directory instead of
+     * next to the ROM. Must be called before init().
+     */
+    pub fn set_save_dir(dir: &str) -> () {
+        unsafe { CARTRIDGE_CTX.set_save_dir(dir); }
+    }
+
+    /**
+     * Returns the path of the save state slot of the current ROM,
+     * i.e., the ROM file name with the extension replaced by .state
+     */
+    pub fn state_slot_path() -> String {
+        let rom_file = unsafe { CARTRIDGE_CTX.filename() };
+        return std::path::Path::new(rom_file).with_extension("state")
+            .to_string_lossy().into_owned();
+    }
+
+    /**
+     * Asks the CPU thread to save the state to the slot of the ROM
+     * before executing the next instruction
+     */
+    pub fn request_save_state() -> () {
+        STATE_REQUEST.store(STATE_REQUEST_SAVE, Ordering::Relaxed);
+    }
+
+    /**
+     * Asks the CPU thread to load the state from the slot of the ROM
+     * before executing the next instruction
+     */
+    pub fn request_load_state() -> () {
+        STATE_REQUEST.store(STATE_REQUEST_LOAD, Ordering::Relaxed);
+    }
+
+    /**
+     * Asks the CPU thread to dump the VRAM, OAM, WRAM and HRAM
+     * to files next to the ROM
+     */
+    pub fn request_memory_dump() -> () {
+        DUMP_REQUEST.store(true, Ordering::Relaxed);
+    }
+
+    /**
+     * Handles a pending memory dump request. The dump is delayed
+     * until the OAM DMA has finished so that the OAM is not
+     * half-copied.
+     */
+    fn handle_dump_request() -> () {
+        if !DUMP_REQUEST.load(Ordering::Relaxed) || unsafe { DMA_CTX.is_active() } {
+            return;
+        }
+        DUMP_REQUEST.store(false, Ordering::Relaxed);
+        memdump::dump_all();
+    }
+
+    /**
+     * Handles a pending save state request, if any
+     */
+    fn handle_state_request() -> () {
+        let request = STATE_REQUEST.swap(STATE_REQUEST_NONE, Ordering::Relaxed);
+        if request == STATE_REQUEST_NONE {
+            return;
+        }
+        if request == STATE_REQUEST_RESET {
+            Emulator::reset();
+            return;
+        }
+        let path = Emulator::state_slot_path();
+        let result = if request == STATE_REQUEST_SAVE {
+            Emulator::save_state(&path)
+        } else {
+            Emulator::load_state(&path)
+        };
+        let action = if request == STATE_REQUEST_SAVE { "Saving" } else { "Loading" };
+        match result {
+            Ok(()) => log::info!(target: "stdout", "{} state {}: SUCCESS", action, path),
+            Err(err) => log::error!(target: "stdout", "{} state {}: {}", action, path, err),
+        }
+    }
+
+    /**
+     * Serializes the state of all components into a buffer
+     */
+    fn serialize_state() -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        unsafe {
+            CARTRIDGE_CTX.save_state(&mut writer);
+            CPU_CTX.save_state(&mut writer);
+            PPU_CTX.save_state(&mut writer);
+            LCD_CTX.save_state(&mut writer);
+            TIMER_CTX.save_state(&mut writer);
+            RAM_CTX.save_state(&mut writer);
+            DMA_CTX.save_state(&mut writer);
+            HDMA_CTX.save_state(&mut writer);
+            SERIAL_CTX.save_state(&mut writer);
+            BOOT_CTX.save_state(&mut writer);
+        }
+        return writer.into_bytes();
+    }
+
+    /**
+     * Restores the state of all components from a buffer.
+     * The cartridge is read first so that states of other
+     * games are rejected before anything is modified.
+     */
+    fn deserialize_state(data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data)?;
+        unsafe {
+            CARTRIDGE_CTX.load_state(&mut reader)?;
+            CPU_CTX.load_state(&mut reader)?;
+            PPU_CTX.load_state(&mut reader)?;
+            LCD_CTX.load_state(&mut reader)?;
+            TIMER_CTX.load_state(&mut reader)?;
+            RAM_CTX.load_state(&mut reader)?;
+            DMA_CTX.load_state(&mut reader)?;
+            HDMA_CTX.load_state(&mut reader)?;
+            SERIAL_CTX.load_state(&mut reader)?;
+            BOOT_CTX.load_state(&mut reader)?;
+        }
+        return Ok(());
+    }
+
+    /**
+     * Saves the state of the whole machine to the given file.
+     * The APU is not part of the state, its channels are left
+     * as they are when the state is loaded.
+     */
+    pub fn save_state(path: &str) -> Result<(), StateError> {
+        let data = Emulator::serialize_state();
+        std::fs::write(path, data).map_err(StateError::Io)?;
+        return Ok(());
+    }
+
+    /**
+     * Loads the state of the whole machine from the given file.
+     * If the file is invalid the current state is kept.
+     */
+    pub fn load_state(path: &str) -> Result<(), StateError> {
+        let data = std::fs::read(path).map_err(StateError::Io)?;
+        // Keeps a copy of the current state in case the file
+        // turns out to be truncated or corrupted halfway
+        let backup = Emulator::serialize_state();
+        if let Err(err) = Emulator::deserialize_state(&data) {
+            Emulator::deserialize_state(&backup)
+                .expect("Unable to restore the state after a failed load");
+            return Err(err);
+        }
+        return Ok(());
+    }
+
+    /**
+     * Echoes the data sent through the serial port to stdout.
+     * Used by test ROMs to report their results.
+     */
+    pub fn set_serial_stdout(enabled: bool) -> () {
+        dbg::set_serial_stdout(enabled);
+    }
+
+    /**
+     * Connects the serial port to another emulator through TCP.
+     * Waits for the peer if `listen_port` is given, connects to
+     * `connect_address` (host:port) otherwise. Exits on failure.
+     */
+    pub fn set_link(listen_port: Option<u16>, connect_address: Option<&str>) -> () {
+        let transport = match (listen_port, connect_address) {
+            (Some(port), _) => serial::link::TcpTransport::listen(port),
+            (None, Some(address)) => serial::link::TcpTransport::connect(address),
+            (None, None) => return,
+        };
+        match transport {
+            Ok(transport) => unsafe { SERIAL_CTX.connect(Box::new(transport)) },
+            Err(err) => {
+                log::error!(target: "stdout", "Unable to set up link cable: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /**
+     * Enables the interactive debugger on stdin
+     */
+    pub fn set_debugger(enabled: bool) -> () {
+        if enabled {
+            unsafe { debugger::DEBUGGER_CTX.enable(); }
+        }
+    }
+
+    /**
+     * Runs the given boot ROM before the cartridge instead of
+     * starting from the state left by the boot ROM. Must be
+     * called before init(). Exits if the boot ROM is invalid.
+     */
+    pub fn set_boot_rom(path: &str) -> () {
+        if let Err(err) = unsafe { BOOT_CTX.load_file(path) } {
+            log::error!(target: "stdout", "{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    /**
+     * Writes the trace file in the Gameboy Doctor format
+     * instead of the default one
+     */
+    pub fn set_trace_format(doctor: bool) -> () {
+        let format = if doctor { cpu::TraceFormat::Doctor } else { cpu::TraceFormat::Default };
+        unsafe { CPU_CTX.set_trace_format(format); }
+    }
+
+    /**
+     * Refuses ROMs with a corrupted logo or global checksum
+     * instead of warning about them, must be called before init()
+     */
+    pub fn set_strict_header(enabled: bool) -> () {
+        unsafe { CARTRIDGE_CTX.set_strict_header(enabled); }
+    }
+
+    /**
+     * Makes reads from unmapped memory return the last value
+     * on the bus instead of 0xFF
+     */
+    pub fn set_open_bus(enabled: bool) -> () {
+        address_bus::set_open_bus(enabled);
+    }
+
+    /**
+     * Makes reads from 0xFEA0 - 0xFEFF return the CGB pattern
+     * instead of 0xFF
+     */
+    pub fn set_unusable_pattern(enabled: bool) -> () {
+        address_bus::set_unusable_pattern(enabled);
+    }
+
+    /**
+     * Sets the power-on content of the WRAM, HRAM and VRAM:
+     * `zero`, `random` or `garbage`. Random content is generated
+     * from the seed, or from the current time if none is given.
+     */
+    pub fn set_init_ram(mode: &str, seed: Option<u64>) -> () {
+        let init = match mode {
+            "random" => {
+                let seed = seed.unwrap_or_else(|| {
+                    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_nanos() as u64).unwrap_or(0)
+                });
+                log::info!(target: "stdout", "Power-on RAM seed: {}", seed);
+                ram::InitRam::Random(seed)
+            }
+            "garbage" => ram::InitRam::Garbage,
+            _ => ram::InitRam::Zero,
+        };
+        unsafe { RAM_CTX.set_init(init); }
+    }
+
+    /**
+     * Sets whether opposite directions can be held at the same time
+     */
+    pub fn set_socd(mode: &str) -> () {
+        unsafe { gamepad::GAMEPAD_CTX.set_block_socd(mode == "block"); }
+    }
+
+    /**
+     * Draws all the sprites of a line instead of the first 10
+     */
+    pub fn set_no_sprite_limit(enabled: bool) -> () {
+        ppu::set_sprite_limit(!enabled);
+    }
+
+    /**
+     * Blocks the CPU from accessing VRAM and OAM while the PPU
+     * reads them, as on the hardware
+     */
+    pub fn set_ppu_lock(enabled: bool) -> () {
+        address_bus::set_ppu_lock(enabled);
+    }
+
+    /**
+     * Logs the accesses to the I/O registers of the given
+     * comma-separated subsystems, e.g. `timer,lcd`
+     */
+    pub fn set_io_trace(subsystems: &str) -> () {
+        match io::parse_io_trace(subsystems) {
+            Ok(mask) => io::set_io_trace(mask),
+            Err(name) => {
+                let names: Vec<&str> = io::IO_TRACE_NAMES.iter().map(|(n, _)| *n).collect();
+                log::error!(target: "stdout", "Unknown I/O subsystem '{}' (expected one of {})",
+                    name, names.join(", "));
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /**
+     * Activates a Game Genie or GameShark cheat code
+     */
+    pub fn add_cheat(code: &str) -> () {
+        match unsafe { cheats::CHEATS_CTX.add(code) } {
+            Ok(()) => log::info!(target: "stdout", "Cheat code {} enabled", code),
+            Err(err) => {
+                log::error!(target: "stdout", "{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /**
+     * Runs CGB games as a DMG, must be called before init()
+     */
+    pub fn set_force_dmg(enabled: bool) -> () {
+        unsafe { EMULATOR_CTX.force_dmg = enabled; }
+    }
+
+    /**
+     * Selects how the PPU draws the pixels, either the accurate
+     * pixel FIFO or the faster scanline renderer
+     */
+    pub fn set_renderer(renderer: &str) -> () {
+        let renderer = match renderer {
+            "scanline" => ppu::Renderer::Scanline,
+            _ => ppu::Renderer::Fifo,
+        };
+        ppu::set_renderer(renderer);
+    }
+
+    /**
+     * Sets the initial size of the window in multiples of the LCD
+     * size and how the image is scaled when the window is resized:
+     * integer, aspect or stretch
+     */
+    pub fn set_scale(scale: u32, scaling: &str) -> () {
+        let mode = match scaling {
+            "aspect" => ui::ScalingMode::Aspect,
+            "stretch" => ui::ScalingMode::Stretch,
+            _ => ui::ScalingMode::Integer,
+        };
+        ui::set_scale(scale, mode);
+    }
+
+    /**
+     * Enables the LCD filter of the window with the given
+     * ghosting strength between 0 and 1
+     */
+    pub fn set_lcd_filter(ghosting: f32) -> () {
+        if ghosting.is_nan() || !(0.0..=1.0).contains(&ghosting) {
+            log::error!(target: "stdout", "Invalid ghosting strength {}, must be between 0 and 1", ghosting);
+            std::process::exit(1);
+        }
+        ui::set_lcd_filter(ghosting);
+    }
+
+    /**
+     * Stops the emulator after the given number of frames
+     */
+    pub fn set_frame_limit(frames: u64) -> () {
+        unsafe { EMULATOR_CTX.frame_limit = frames; }
+    }
+
+    /**
+     * Stops the emulator once the CPU has run for the given number
+     * of ticks, so that automated runs always terminate
+     */
+    pub fn set_cycle_limit(cycles: u64) -> () {
+        unsafe { EMULATOR_CTX.cycle_limit = cycles; }
+    }
+
+    /**
+     * Runs the ROM as a test ROM, until it reports its result through
+     * the serial port like the Blargg tests or through the registers
+     * like the Mooneye tests. See test_exit_code.
+     */
+    pub fn set_test_rom(enabled: bool) -> () {
+        unsafe { EMULATOR_CTX.test_rom = enabled; }
+    }
+
+    /**
+     * Returns the result of the test ROM once it reported one. The
+     * serial output is only searched when it grew since the last
+     * call, which `serial_len` keeps track of.
+     */
+    fn check_test_result(serial_len: &mut usize) -> Option<TestResult> {
+        unsafe {
+            if CPU_CTX.get_opcode() == MOONEYE_BREAKPOINT {
+                let regs = CPU_CTX.registers();
+                let values = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+                if values == MOONEYE_PASSED {
+                    return Some(TestResult::Passed);
+                }
+                if values == MOONEYE_FAILED {
+                    return Some(TestResult::Failed);
+                }
+            }
+        }
+        if dbg::serial_output_len() == *serial_len {
+            return None;
+        }
+        *serial_len = dbg::serial_output_len();
+        if dbg::serial_output_contains("Passed") {
+            return Some(TestResult::Passed);
+        }
+        if dbg::serial_output_contains("Failed") {
+            return Some(TestResult::Failed);
+        }
+        return None;
+    }
+
+    /**
+     * Returns the exit code of a test ROM run: 0 if it passed, 1 if
+     * it failed or stopped without a result, e.g. because of the
+     * cycle limit. The result and the serial output are printed.
+     */
+    pub fn test_exit_code() -> i32 {
+        let output = dbg::serial_output();
+        if !output.trim().is_empty() {
+            log::info!(target: "stdout", "Serial output:\n{}", output.trim_end());
+        }
+        match unsafe { EMULATOR_CTX.test_result } {
+            Some(TestResult::Passed) => {
+                log::info!(target: "stdout", "Test ROM: PASSED");
+                return 0;
+            },
+            Some(TestResult::Failed) => log::info!(target: "stdout", "Test ROM: FAILED"),
+            None => log::info!(target: "stdout", "Test ROM: FAILED (no result)"),
+        }
+        return 1;
+    }
+
+    /**
+     * Returns the exit code of an automated run: 0 if the serial
+     * output contains the given text, as printed by test ROMs
+     * that passed, 1 otherwise
+     */
+    pub fn serial_exit_code(pass_text: &str) -> i32 {
+        if dbg::serial_output_contains(pass_text) {
+            log::info!(target: "stdout", "Serial output contains \"{}\": PASSED", pass_text);
+            return 0;
+        }
+        log::info!(target: "stdout", "Serial output does not contain \"{}\": FAILED", pass_text);
+        return 1;
+    }
+
+    /**
+     * Sets the shades of the DMG output from a preset name or a
+     * list of four hex colors. Exits if the palette is invalid.
+     */
+    pub fn set_palette(palette: &str) -> () {
+        match lcd::parse_palette(palette) {
+            Ok(colors) => unsafe { LCD_CTX.set_dmg_colors(colors) },
+            Err(err) => {
+                log::error!(target: "stdout", "{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /**
+     * Switches to the next palette preset
+     */
+    pub fn cycle_palette() -> () {
+        let current = unsafe { LCD_CTX.get_dmg_colors() };
+        // Starts from the first preset if a custom palette is used
+        let index = lcd::PALETTE_PRESETS.iter()
+            .position(|(_, colors)| *colors == current)
+            .map_or(0, |i| (i + 1) % lcd::PALETTE_PRESETS.len());
+        let (name, colors) = lcd::PALETTE_PRESETS[index];
+        log::info!(target: "stdout", "Palette: {}", name);
+        unsafe { LCD_CTX.set_dmg_colors(colors); }
+    }
+
+    /**
+     * Scales the emulated clock by the given multiplier. All the
+     * components are driven by the CPU cycles, so only the real-time
+     * rate of the frames changes. Headless runs, which are otherwise
+     * as fast as possible, are paced at that rate as well. The
+     * multiplier is clamped between 0.25 and 8, exits if it is not
+     * positive.
+     */
+    pub fn set_speed(speed: f64) -> () {
+        if speed.is_nan() || speed <= 0.0 {
+            log::error!(target: "stdout", "Invalid speed {}, must be greater than 0", speed);
+            std::process::exit(1);
+        }
+        let clamped = speed.clamp(MIN_SPEED, MAX_SPEED);
+        if clamped != speed {
+            log::warn!(target: "stdout", "Speed {} is out of range, using {} instead", speed, clamped);
+        }
+        unsafe {
+            EMULATOR_CTX.speed = clamped;
+            EMULATOR_CTX.speed_set = true;
+        }
+    }
+
+    /**
+     * Returns the emulation speed relative to the original hardware
+     */
+    pub fn speed() -> f64 {
+        return unsafe { EMULATOR_CTX.speed };
+    }
+
+    /**
+     * Enables or disables the uncapped turbo mode
+     */
+    pub fn set_turbo(enabled: bool) -> () {
+        TURBO.store(enabled, Ordering::Relaxed);
+    }
+
+    /**
+     * Returns true if the frame limiter is disabled by the turbo mode
+     */
+    pub fn is_turbo() -> bool {
+        return TURBO.load(Ordering::Relaxed);
+    }
+
+    /**
+     * Returns true if the frames are paced against the wall clock,
+     * i.e., unless in turbo mode or headless without a given speed
+     */
+    pub fn is_paced() -> bool {
+        return !Emulator::is_turbo() &&
+            (!Emulator::is_headless() || unsafe { EMULATOR_CTX.speed_set });
+    }
+
+    /**
+     * Pauses the CPU thread, or resumes it if it is paused
+     */
+    pub fn toggle_pause() -> () {
+        let paused = !PAUSED.fetch_xor(true, Ordering::Relaxed);
+        log::info!(target: "stdout", "Emulator {}", if paused { "paused" } else { "resumed" });
+    }
+
+    /**
+     * Returns true while the CPU thread is paused
+     */
+    pub fn is_paused() -> bool {
+        return PAUSED.load(Ordering::Relaxed);
+    }
+
+    /**
+     * Returns true if the emulator runs as a Game Boy Color
+     */
+    pub fn is_cgb() -> bool {
+        return unsafe { EMULATOR_CTX.cgb };
+    }
+
+    /**
+     * Returns true if the emulator runs without a UI
+     */
+    pub fn is_headless() -> bool {
+        return unsafe { EMULATOR_CTX.headless };
+    }
+
+    /**
+     * Exits the process when a fatal emulation error occurs
+     * instead of panicking. Used by the binary.
+     */
+    pub fn exit_on_fatal_error() -> () {
+        error::set_fatal_handler(|err| std::process::exit(err.exit_code()));
+    }
+
+    /**
+     * Prints the FPS to stdout every second
+     */
+    pub fn set_verbose(enabled: bool) -> () {
+        unsafe { EMULATOR_CTX.verbose = enabled; }
+    }
+
+    pub fn is_verbose() -> bool {
+        return unsafe { EMULATOR_CTX.verbose };
+    }
+
+    /**
+     * Asks the CPU thread to stop before the next instruction
+     */
+    pub fn request_shutdown() -> () {
+        SHUTDOWN.store(true, Ordering::Relaxed);
+    }
+
+    /**
+     * Returns true once the emulator has been asked to stop
+     * or the CPU thread has stopped
+     */
+    pub fn is_shutting_down() -> bool {
+        return SHUTDOWN.load(Ordering::Relaxed);
+    }
+
+    /**
+     * Writes the battery save if the cartridge RAM was modified
+     * since it was last saved
+     */
+    pub fn flush_battery() -> () {
+        unsafe {
+            if CARTRIDGE_CTX.need_save() {
+                CARTRIDGE_CTX.save_battery();
+            }
+        }
+    }
+
+    /**
+     * Called by the CPU thread once it stops, so that the progress
+     * made since the last periodic battery save is not lost
+     */
+    fn shutdown() -> () {
+        Emulator::flush_battery();
+        unsafe { movie::MOVIE_CTX.stop(); }
+        // Lets the UI thread know that the CPU thread has stopped
+        SHUTDOWN.store(true, Ordering::Relaxed);
+    }
+
+    /**
+     * Stops the emulator cleanly on SIGINT and SIGTERM
+     */
+    fn handle_signals() -> () {
+        unsafe {
+            libc::signal(libc::SIGINT, on_signal as *const () as libc::sighandler_t);
+            libc::signal(libc::SIGTERM, on_signal as *const () as libc::sighandler_t);
+        }
+    }
+
+    /**
+     * Runs the emulator on the current thread without opening any
+     * windows until the frame limit is reached. If a dump file is
+     * given, the last frame is written to it as a PNG.
+     */
+    pub fn run_headless(debug: bool, dump_file: Option<&str>) -> () {
+        unsafe { EMULATOR_CTX.headless = true; }
+        Emulator::handle_signals();
+        cpu_run(debug);
+        if let Some(dump_file) = dump_file {
+            match ppu::dump_framebuffer(dump_file) {
+                Ok(()) => log::info!(target: "stdout", "Dumping frame to {}: SUCCESS", dump_file),
+                Err(err) => log::error!(target: "stdout",
+                    "Unable to dump frame to {}: {}", dump_file, err),
+            }
+        }
     }
 
     /**
      * Starts running the emulator
      */
     pub fn run(debug: bool) -> () {
+        Emulator::handle_signals();
         let cpu_thread = 
             thread::spawn(move || cpu_run(debug));
         ui::init();
         ui::run();
-        cpu_thread.join().unwrap();
+        Emulator::request_shutdown();
+        // The CPU thread does not stop while the debugger waits
+        // for a command, the battery is then saved from here
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+        while !cpu_thread.is_finished() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        if cpu_thread.is_finished() {
+            cpu_thread.join().unwrap();
+        } else {
+            Emulator::flush_battery();
+        }
     }
 
     /**
@@ -101,17 +1047,140 @@ impl Emulator {
      */
     pub fn cycles(cycles: u32) -> () {
 
+        // In double speed mode the CPU, the timer and the DMA run
+        // twice as fast, the PPU and the APU keep their speed
+        let video_ticks = if unsafe { CPU_CTX.is_double_speed() } { 2 } else { 4 };
         for _i in 0..cycles {
-            for _n in 0..4 { 
+            for n in 0..4 { 
                 unsafe {
                     CPU_CTX.ticks.fetch_add(1, Ordering::Relaxed);
                     if TIMER_CTX.tick() {
                         request_interrupt(InterruptType::IT_TIMER);
                     }
-                    PPU_CTX.tick();
+                    if n < video_ticks {
+                        PPU_CTX.tick();
+                        APU_CTX.tick(TIMER_CTX.internal_counter(), CPU_CTX.is_double_speed());
+                    }
                 }
             }
-            unsafe { DMA_CTX.tick(); }
+            unsafe {
+                DMA_CTX.tick();
+                HDMA_CTX.tick();
+                SERIAL_CTX.tick(TIMER_CTX.internal_counter());
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_restarts_at_the_entry_point() {
+        let _lock = test_util::lock();
+        // INC A; INC A; INC A; loop: JR loop
+        test_util::boot(&[0x3C, 0x3C, 0x3C, 0x18, 0xFE]);
+        let initial = unsafe { CPU_CTX.registers() };
+        for _ in 0..10 {
+            unsafe { CPU_CTX.step(); }
         }
+        let registers = unsafe { CPU_CTX.registers() };
+        assert_eq!(registers.pc, test_util::CODE_START + 3);
+        assert_eq!(registers.a, initial.a.wrapping_add(3));
+
+        Emulator::reset();
+        let registers = unsafe { CPU_CTX.registers() };
+        assert_eq!(registers.pc, 0x0100);
+        assert_eq!(registers.a, initial.a);
+        assert_eq!(unsafe { TIMER_CTX.read(DIV_ADDR) }, 0xAB);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn toggling_the_pause_twice_resumes() {
+        let _lock = test_util::lock();
+        assert!(!Emulator::is_paused());
+        Emulator::toggle_pause();
+        assert!(Emulator::is_paused());
+        Emulator::toggle_pause();
+        assert!(!Emulator::is_paused());
+    }
+
+    #[test]
+    fn headless_shutdown_writes_the_battery_save() {
+        let _lock = test_util::lock();
+        // MBC1 with 8 KiB of battery backed RAM
+        test_util::boot_rom(test_util::build_rom(0x03, 0x00, 0x02, &[]));
+        Emulator::set_save_dir(std::env::temp_dir().to_str().unwrap());
+        let path = unsafe { CARTRIDGE_CTX.battery_path() };
+        let _ = std::fs::remove_file(&path);
+        address_bus::bus_write(0x0000, 0x0A);
+        address_bus::bus_write(0xA000, 0x42);
+
+        Emulator::request_shutdown();
+        Emulator::run_headless(false, None);
+        SHUTDOWN.store(false, Ordering::Relaxed);
+        let save = std::fs::read(&path).expect("The battery save was not written");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0], 0x42);
+    }
+
+    #[test]
+    fn cycle_limit_stops_an_endless_loop() {
+        let _lock = test_util::lock();
+        // loop: JR loop
+        test_util::boot(&[0x18, 0xFE]);
+        Emulator::set_cycle_limit(4096);
+        Emulator::run_headless(false, None);
+        Emulator::set_cycle_limit(0);
+        SHUTDOWN.store(false, Ordering::Relaxed);
+        let ticks = unsafe { CPU_CTX.ticks.load(Ordering::Relaxed) };
+        // Stops after the instruction that reached the limit
+        assert!((4096..4096 + 12).contains(&ticks), "stopped after {} ticks", ticks);
+        assert_eq!(Emulator::serial_exit_code("Never printed"), 1);
+    }
+
+    /**
+     * Runs the given code as a test ROM and returns the exit code
+     */
+    fn run_test_rom(code: &[u8]) -> i32 {
+        test_util::boot(code);
+        dbg::clear_serial_output();
+        Emulator::set_test_rom(true);
+        // Stops the tests that never report a result
+        Emulator::set_cycle_limit(1 << 20);
+        Emulator::run_headless(false, None);
+        Emulator::set_cycle_limit(0);
+        Emulator::set_test_rom(false);
+        SHUTDOWN.store(false, Ordering::Relaxed);
+        return Emulator::test_exit_code();
+    }
+
+    #[test]
+    fn test_roms_report_their_result() {
+        let _lock = test_util::lock();
+        // Like Blargg: sends "Passed" through the serial port
+        let mut code = Vec::new();
+        for c in "Passed".bytes() {
+            // LD A, c; LDH (SB), A; LD A, 0x81; LDH (SC), A
+            code.extend_from_slice(&[0x3E, c, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02]);
+        }
+        // loop: JR loop
+        code.extend_from_slice(&[0x18, 0xFE]);
+        assert_eq!(run_test_rom(&code), 0);
+
+        // Like Mooneye: LD B, B with the Fibonacci numbers in B - L
+        let mooneye = |values: [u8; 6]| -> Vec<u8> {
+            let mut code = Vec::new();
+            for (opcode, value) in [0x06, 0x0E, 0x16, 0x1E, 0x26, 0x2E].iter().zip(values) {
+                code.extend_from_slice(&[*opcode, value]);
+            }
+            code.extend_from_slice(&[0x40, 0x18, 0xFE]);
+            code
+        };
+        assert_eq!(run_test_rom(&mooneye(MOONEYE_PASSED)), 0);
+        assert_eq!(run_test_rom(&mooneye(MOONEYE_FAILED)), 1);
+        // No result before the cycle limit
+        assert_eq!(run_test_rom(&[0x18, 0xFE]), 1);
+    }
+}
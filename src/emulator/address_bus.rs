@@ -4,6 +4,11 @@ use crate::emulator::cpu::CPU_CTX;
 use crate::emulator::ppu::PPU_CTX;
 use crate::emulator::dma::DMA_CTX;
 use super::cartridge::CARTRIDGE_CTX;
+use super::debugger::DEBUGGER_CTX;
+use super::boot::BOOT_CTX;
+use super::cheats::CHEATS_CTX;
+use super::lcd::{LCD_CTX, LCD_MODE};
+use crate::emulator::Emulator;
 /**
  * A struct that defines the address bus
  */
@@ -33,14 +38,149 @@ use super::cartridge::CARTRIDGE_CTX;
  * 0xFFFF - 0xFFFF: Interrupts Enable Register (IE)
  */
 
+// A flat 64 KiB memory that replaces all the components when set,
+// used to run the CPU in isolation, e.g. for single-step tests
+static mut FLAT_MEMORY: Option<Box<[u8]>> = None;
+
+// Whether reads from unmapped memory return the last value on the bus
+static mut OPEN_BUS: bool = false;
+// The last byte read or written by the CPU
+static mut LAST_BUS_VALUE: u8 = 0xFF;
+// Whether the CPU is locked out of VRAM and OAM while the PPU uses them
+static mut PPU_LOCK: bool = false;
+// Whether the unusable region returns the CGB read pattern
+static mut UNUSABLE_PATTERN: bool = false;
+
+/**
+ * Makes reads from unmapped memory return the last value driven
+ * on the bus instead of 0xFF
+ */
+pub fn set_open_bus(enabled: bool) -> () {
+    unsafe { OPEN_BUS = enabled; }
+}
+
+/**
+ * Blocks the CPU from accessing VRAM during mode 3 and OAM during
+ * modes 2 and 3 like the hardware does. Off by default since some
+ * homebrew relies on unrestricted access.
+ */
+pub fn set_ppu_lock(enabled: bool) -> () {
+    unsafe { PPU_LOCK = enabled; }
+}
+
+/**
+ * Makes reads from 0xFEA0 - 0xFEFF return the pattern of the CGB
+ * instead of 0xFF. Only the read pattern is covered, the OAM
+ * corruption caused by accessing the region on DMG is not emulated.
+ */
+pub fn set_unusable_pattern(enabled: bool) -> () {
+    unsafe { UNUSABLE_PATTERN = enabled; }
+}
+
+/**
+ * Returns the value read from the unusable region 0xFEA0 - 0xFEFF.
+ * With the CGB pattern, the high nibble of the low address byte is
+ * returned in both nibbles, e.g. 0xFEB4 reads 0xBB. The region
+ * behaves like unmapped memory otherwise.
+ */
+fn unusable_read(address: u16) -> u8 {
+    if unsafe { UNUSABLE_PATTERN } && Emulator::is_cgb() {
+        let nibble = (address as u8) >> 4;
+        return nibble << 4 | nibble;
+    }
+    return unmapped_read();
+}
+
+/**
+ * Returns true if the PPU currently owns the memory at the given
+ * address, reads then return 0xFF and writes are ignored
+ */
+#[inline(always)]
+fn ppu_locked(address: u16) -> bool {
+    if !unsafe { PPU_LOCK } || !(0x8000..0xFEA0).contains(&address) {
+        return false;
+    }
+    let mode = unsafe { LCD_CTX.get_lcds_mode() };
+    if address < 0xA000 {
+        return mode == LCD_MODE::MODE_XFER;
+    }
+    if address >= 0xFE00 {
+        return mode == LCD_MODE::MODE_OAM || mode == LCD_MODE::MODE_XFER;
+    }
+    return false;
+}
+
+/**
+ * Replaces the memory map with a flat, zeroed 64 KiB memory, or
+ * restores the memory map. In flat mode every address is plain RAM.
+ */
+pub fn set_flat_memory(enabled: bool) -> () {
+    unsafe {
+        FLAT_MEMORY = if enabled { Some(vec![0; 0x10000].into_boxed_slice()) } else { None };
+    }
+}
+
+/**
+ * Returns the flat memory if it is enabled
+ */
+#[inline(always)]
+fn flat_memory() -> Option<&'static mut [u8]> {
+    return unsafe { FLAT_MEMORY.as_deref_mut() };
+}
+
 /**
- * Reads a byte from the address bus
+ * Returns the value read from memory that is not mapped to any
+ * component: the last value on the bus in open bus mode, 0xFF otherwise
+ */
+pub fn unmapped_read() -> u8 {
+    unsafe {
+        if OPEN_BUS {
+            return LAST_BUS_VALUE;
+        }
+    }
+    return 0xFF;
+}
+
+/**
+ * Reads a byte from the address bus as seen by the CPU. While an
+ * OAM DMA transfer is running, the CPU can only access the I/O
+ * registers and HRAM. Reading from OAM returns 0xFF, reading from
+ * anywhere else returns the byte that is currently being copied.
+ * With the PPU lock, VRAM and OAM read 0xFF while the PPU uses them.
  */
 pub fn bus_read(address: u16) -> u8 {
+    let value = if address < 0xFF00 && unsafe { DMA_CTX.is_transferring() } {
+        if (0xFE00..0xFEA0).contains(&address) {
+            0xFF
+        } else {
+            unsafe { DMA_CTX.current_byte() }
+        }
+    } else if flat_memory().is_none() && ppu_locked(address) {
+        0xFF
+    } else {
+        bus_peek(address)
+    };
+    unsafe { LAST_BUS_VALUE = value; }
+    return value;
+}
+
+/**
+ * Reads a byte from the address bus without the restrictions that
+ * apply to the CPU during DMA. Used by the DMA itself and by the
+ * debugging tools.
+ */
+pub fn bus_peek(address: u16) -> u8 {
+    if let Some(memory) = flat_memory() {
+        return memory[address as usize];
+    }
     // Given address indicates ROM address
     if address < 0x8000 {
-        // Reads from ROM
-        return unsafe { CARTRIDGE_CTX.read(address) };
+        // The boot ROM covers the first 256 bytes until it is unmapped
+        if unsafe { BOOT_CTX.is_mapped(address) } {
+            return unsafe { BOOT_CTX.read(address) };
+        }
+        // Reads from ROM, with the Game Genie codes applied
+        return unsafe { CHEATS_CTX.patch_rom(address, CARTRIDGE_CTX.read(address)) };
     } else if address < 0xA000 {
         // Reads from BG Map Data 2
         return unsafe { PPU_CTX.vram_read(address) };
@@ -51,38 +191,46 @@ pub fn bus_read(address: u16) -> u8 {
         // Reads from Work RAM (WRAM)
         return unsafe { RAM_CTX.wram_read(address) };
     } else if address < 0xFE00 {
-        // Reads from ECHO RAM
-        return 0;
+        // Reads from ECHO RAM, which mirrors 0xC000 - 0xDDFF
+        return unsafe { RAM_CTX.wram_read(address - 0x2000) };
     } else if address < 0xFEA0 {
         // Reads from Object Attribute Memory (OAM)
-        if unsafe { DMA_CTX.is_transferring() } {
-            return 0xFF;
-        }
         return unsafe { PPU_CTX.oam_read(address) };
     } else if address < 0xFF00 {
         // Reads from reserved memory (UNUSABLE)
-        return 0;
+        return unusable_read(address);
     } else if address < 0xFF80 {
         // Reads from I/O Registers
         return io_read(address);
     } else if address < 0xFFFF {
         // Reads from High RAM (HRAM)
         return unsafe { RAM_CTX.hram_read(address) };
-    } else if address == 0xFFFF {
-        // Reads from Interrupts Enable Register (IE)
-        return unsafe { CPU_CTX.get_ie_register() };
-        // return self.cpu.borrow().get_ie_register();
-    }
-    // Raises an error if the address is out of range
-    log::error!(target: "stdout",
-        "Reading from address 0x{:04X} currently not supported", address);
-    std::process::exit(-5);
+    }
+    // Reads from Interrupts Enable Register (IE) at 0xFFFF
+    return unsafe { CPU_CTX.get_ie_register() };
 }
 
 /**
  * Writes a byte to the address bus
  */
 pub fn bus_write(address: u16, data: u8) -> () {
+    unsafe {
+        DEBUGGER_CTX.check_watchpoint(address, data);
+        LAST_BUS_VALUE = data;
+    }
+    if let Some(memory) = flat_memory() {
+        memory[address as usize] = data;
+        return;
+    }
+    // Writes outside of the I/O registers and HRAM are
+    // ignored during an OAM DMA transfer
+    if address < 0xFF00 && unsafe { DMA_CTX.is_transferring() } {
+        return;
+    }
+    // As are writes to VRAM and OAM while the PPU uses them
+    if ppu_locked(address) {
+        return;
+    }
     // Given address indicates ROM address
     if address < 0x8000 {
         // Writes to ROM
@@ -98,13 +246,11 @@ pub fn bus_write(address: u16, data: u8) -> () {
         unsafe { RAM_CTX.wram_write(address, data) };
         return;
     } else if address < 0xFE00 {
-        // Writes to ECHO RAM
+        // Writes to ECHO RAM, which mirrors 0xC000 - 0xDDFF
+        unsafe { RAM_CTX.wram_write(address - 0x2000, data) };
         return;
     } else if address < 0xFEA0 {
         // Writes to Object Attribute Memory (OAM)
-        if unsafe { DMA_CTX.is_transferring() } {
-            return;
-        }
         unsafe { PPU_CTX.oam_write(address, data) };
     } else if address < 0xFF00 {
         // Writes to reserved memory (UNUSABLE)
@@ -113,14 +259,12 @@ pub fn bus_write(address: u16, data: u8) -> () {
         // Writes to I/O Registers
         io_write(address, data);
         return;
-        // std::process::exit(-5);
     } else if address < 0xFFFF {
         // Writes to High RAM (HRAM)
         unsafe { RAM_CTX.hram_write(address, data) };
         return;
-    } else if address == 0xFFFF {
-        // Writes to Interrupts Enable Register (IE)
-        // self.cpu.borrow_mut().set_ie_register(data);
+    } else {
+        // Writes to Interrupts Enable Register (IE) at 0xFFFF
         unsafe { CPU_CTX.set_ie_register(data) };
         return;
     }
@@ -131,7 +275,7 @@ pub fn bus_write(address: u16, data: u8) -> () {
  */
 pub fn bus_read_16(address: u16) -> u16 {
     let low = bus_read(address);
-    let high = bus_read(address + 1);
+    let high = bus_read(address.wrapping_add(1));
     return (high as u16) << 8 | low as u16;
 }
     
@@ -142,5 +286,134 @@ pub fn bus_write_16(address: u16, data: u16) -> () {
     let low = data as u8;
     let high = (data >> 8) as u8;
     bus_write(address, low);
-    bus_write(address + 1, high);
+    bus_write(address.wrapping_add(1), high);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::serial::SC_ADDR;
+    use crate::emulator::test_util;
+    use crate::emulator::timer::TAC_ADDR;
+
+    #[test]
+    fn bus_access_16_wraps_around() {
+        let _lock = test_util::lock();
+        set_flat_memory(true);
+        bus_write_16(0xFFFF, 0xBEEF);
+        assert_eq!(bus_peek(0xFFFF), 0xEF);
+        assert_eq!(bus_peek(0x0000), 0xBE);
+        assert_eq!(bus_read_16(0xFFFF), 0xBEEF);
+        set_flat_memory(false);
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        bus_write(0xC123, 0x42);
+        assert_eq!(bus_read(0xE123), 0x42);
+        bus_write(0xE456, 0x24);
+        assert_eq!(bus_read(0xC456), 0x24);
+    }
+
+    #[test]
+    fn unusable_region_and_unmapped_io_read_0xff() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        set_open_bus(false);
+        set_unusable_pattern(false);
+        bus_write(0xC000, 0x12);
+        assert_eq!(bus_read(0xFEA0), 0xFF);
+        assert_eq!(bus_read(0xFEFF), 0xFF);
+        assert_eq!(bus_read(0xFF03), 0xFF);
+        // KEY1 is not mapped on DMG
+        assert_eq!(bus_read(0xFF4D), 0xFF);
+        // The unused bits of SC and TAC read as 1
+        assert_eq!(bus_read(SC_ADDR) & 0x7E, 0x7E);
+        assert_eq!(bus_read(TAC_ADDR) & 0xF8, 0xF8);
+    }
+
+    #[test]
+    fn unusable_region_reads_the_cgb_pattern() {
+        let _lock = test_util::lock();
+        set_open_bus(false);
+        set_unusable_pattern(true);
+        // Only the CGB has the pattern
+        test_util::boot(&[]);
+        let dmg = bus_read(0xFEB4);
+        let mut rom = test_util::build_rom(0x00, 0x00, 0x00, &[]);
+        rom[0x143] = 0x80;
+        test_util::fix_checksums(&mut rom);
+        test_util::boot_rom(rom);
+        let cgb: Vec<u8> = [0xFEA0, 0xFEB4, 0xFEC7, 0xFEFF].iter()
+            .map(|address| bus_read(*address))
+            .collect();
+        // Writes are ignored
+        bus_write(0xFEA0, 0x12);
+        let after_write = bus_read(0xFEA0);
+        set_unusable_pattern(false);
+        assert_eq!(dmg, 0xFF);
+        assert_eq!(cgb, vec![0xAA, 0xBB, 0xCC, 0xFF]);
+        assert_eq!(after_write, 0xAA);
+    }
+
+    #[test]
+    fn open_bus_reads_the_last_driven_byte() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        set_open_bus(true);
+        bus_write(0xC000, 0x5A);
+        let after_write = bus_read(0xFF03);
+        bus_write(0xC001, 0x33);
+        bus_write(0xC002, 0x44);
+        // The read drives the bus as well
+        bus_read(0xC001);
+        let after_read = bus_read(0xFEA0);
+        set_open_bus(false);
+        assert_eq!(after_write, 0x5A);
+        assert_eq!(after_read, 0x33);
+    }
+
+    /**
+     * Writes a byte in the given PPU mode and returns the byte then
+     * read back in HBlank and in the given mode
+     */
+    fn access_in_mode(mode: LCD_MODE, address: u16, value: u8) -> (u8, u8) {
+        unsafe {
+            LCD_CTX.set_lcds_mode(mode);
+            bus_write(address, value);
+            let in_mode = bus_read(address);
+            LCD_CTX.set_lcds_mode(LCD_MODE::MODE_HBLANK);
+            return (bus_read(address), in_mode);
+        }
+    }
+
+    #[test]
+    fn ppu_lock_blocks_vram_and_oam_while_the_ppu_uses_them() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        set_ppu_lock(true);
+        unsafe { LCD_CTX.set_lcds_mode(LCD_MODE::MODE_HBLANK); }
+        bus_write(0x8000, 0x11);
+        bus_write(0xFE00, 0x22);
+        let vram_in_xfer = access_in_mode(LCD_MODE::MODE_XFER, 0x8000, 0x33);
+        let oam_in_xfer = access_in_mode(LCD_MODE::MODE_XFER, 0xFE00, 0x44);
+        let vram_in_oam = access_in_mode(LCD_MODE::MODE_OAM, 0x8000, 0x55);
+        let oam_in_oam = access_in_mode(LCD_MODE::MODE_OAM, 0xFE00, 0x66);
+        let vram_in_vblank = access_in_mode(LCD_MODE::MODE_VBLANK, 0x8000, 0x77);
+        set_ppu_lock(false);
+        let vram_unlocked = access_in_mode(LCD_MODE::MODE_XFER, 0x8001, 0x88);
+        let oam_unlocked = access_in_mode(LCD_MODE::MODE_OAM, 0xFE01, 0x99);
+
+        // Reads return 0xFF and writes are dropped
+        assert_eq!(vram_in_xfer, (0x11, 0xFF));
+        assert_eq!(oam_in_xfer, (0x22, 0xFF));
+        assert_eq!(oam_in_oam, (0x22, 0xFF));
+        // The VRAM stays accessible during the OAM scan
+        assert_eq!(vram_in_oam, (0x55, 0x55));
+        assert_eq!(vram_in_vblank, (0x77, 0x77));
+        assert_eq!(vram_unlocked, (0x88, 0x88));
+        assert_eq!(oam_unlocked, (0x99, 0x99));
+    }
 }
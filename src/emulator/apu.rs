@@ -0,0 +1,816 @@
+use crate::emulator::ui;
+
+/**
+ * Audio Processing Unit (APU)
+ * References:
+ * https://gbdev.io/pandocs/Audio.html
+ * https://gbdev.io/pandocs/Audio_Registers.html
+ * https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware
+ */
+
+pub const APU_START_ADDR: u16 = 0xFF10;
+pub const APU_END_ADDR: u16 = 0xFF3F;
+const WAVE_RAM_ADDR: u16 = 0xFF30;
+const NR52_ADDR: u16 = 0xFF26;
+
+// The frequency of the master clock (T-cycles per second)
+const CPU_FREQ: u32 = 4194304;
+pub const SAMPLE_RATE: u32 = 44100;
+// Number of stereo frames buffered before they are queued
+const SINK_FRAMES: usize = 1024;
+
+// Bits that always read back as 1 for each register in 0xFF10 - 0xFF2F
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // ----, NR21 - NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // ----, NR41 - NR44
+    0x00, 0x00, 0x70,             // NR50, NR51, NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+// Waveforms of the different duty cycles of the pulse channels
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+// Divisors of the noise channel indexed by the lower 3 bits of NR43
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+
+/**
+ * Volume envelope shared by the pulse and the noise channels
+ */
+#[derive(Copy, Clone)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    pace: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    const fn new() -> Envelope {
+        Envelope { initial_volume: 0, increase: false, pace: 0, volume: 0, timer: 0 }
+    }
+
+    /**
+     * Loads the envelope settings from a NRx2 register
+     */
+    fn write(&mut self, value: u8) -> () {
+        self.initial_volume = value >> 4;
+        self.increase = (value & 0x08) != 0;
+        self.pace = value & 0x07;
+    }
+
+    fn read(&self) -> u8 {
+        return (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.pace;
+    }
+
+    /**
+     * Returns true if the DAC of the channel is powered on
+     */
+    fn dac_enabled(&self) -> bool {
+        return self.initial_volume != 0 || self.increase;
+    }
+
+    fn trigger(&mut self) -> () {
+        self.volume = self.initial_volume;
+        self.timer = self.pace;
+    }
+
+    /**
+     * Clocked at 64 Hz by the frame sequencer
+     */
+    fn clock(&mut self) -> () {
+        if self.pace == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.pace;
+            if self.increase && self.volume < 0xF {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+
+/**
+ * Pulse channel (CH1 and CH2). Only CH1 has a frequency sweep.
+ */
+#[derive(Copy, Clone)]
+struct PulseChannel {
+    enabled: bool,
+    has_sweep: bool,
+    // Sweep
+    sweep_pace: u8,
+    sweep_decrease: bool,
+    sweep_step: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_freq: u16,
+    // Length and duty
+    duty: u8,
+    duty_pos: u8,
+    length_counter: u16,
+    length_enabled: bool,
+    envelope: Envelope,
+    freq: u16,
+    freq_timer: u32,
+}
+
+impl PulseChannel {
+    const fn new(has_sweep: bool) -> PulseChannel {
+        PulseChannel {
+            enabled: false,
+            has_sweep: has_sweep,
+            sweep_pace: 0,
+            sweep_decrease: false,
+            sweep_step: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_freq: 0,
+            duty: 0,
+            duty_pos: 0,
+            length_counter: 0,
+            length_enabled: false,
+            envelope: Envelope::new(),
+            freq: 0,
+            freq_timer: 0,
+        }
+    }
+
+    /**
+     * Reads one of the 5 registers of the channel given its index
+     */
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => (self.sweep_pace << 4) | ((self.sweep_decrease as u8) << 3) | self.sweep_step,
+            1 => self.duty << 6,
+            2 => self.envelope.read(),
+            4 => (self.length_enabled as u8) << 6,
+            _ => 0,
+        }
+    }
+
+    /**
+     * Writes to one of the 5 registers of the channel given its index
+     */
+    fn write(&mut self, reg: u16, value: u8) -> () {
+        match reg {
+            0 => {
+                self.sweep_pace = (value >> 4) & 0x07;
+                self.sweep_decrease = (value & 0x08) != 0;
+                self.sweep_step = value & 0x07;
+            },
+            1 => {
+                self.duty = value >> 6;
+                self.length_counter = 64 - (value & 0x3F) as u16;
+            },
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => {
+                self.freq = (self.freq & 0x700) | value as u16;
+            },
+            4 => {
+                self.freq = (self.freq & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.length_enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.trigger();
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn trigger(&mut self) -> () {
+        self.enabled = self.envelope.dac_enabled();
+        if self.length_counter == 0 {
+            self.length_counter = 64;
+        }
+        self.freq_timer = (2048 - self.freq as u32) * 4;
+        self.envelope.trigger();
+
+        if self.has_sweep {
+            self.shadow_freq = self.freq;
+            self.sweep_timer = if self.sweep_pace == 0 { 8 } else { self.sweep_pace };
+            self.sweep_enabled = self.sweep_pace != 0 || self.sweep_step != 0;
+            if self.sweep_step != 0 {
+                // Performs the overflow check immediately
+                self.sweep_calc();
+            }
+        }
+    }
+
+    /**
+     * Computes the next sweep frequency and disables the
+     * channel if it overflows
+     */
+    fn sweep_calc(&mut self) -> u16 {
+        let delta = self.shadow_freq >> self.sweep_step;
+        let new_freq = if self.sweep_decrease {
+            self.shadow_freq.wrapping_sub(delta)
+        } else {
+            self.shadow_freq + delta
+        };
+        if new_freq > 2047 {
+            self.enabled = false;
+        }
+        return new_freq;
+    }
+
+    /**
+     * Clocked at 128 Hz by the frame sequencer
+     */
+    fn clock_sweep(&mut self) -> () {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+        self.sweep_timer = if self.sweep_pace == 0 { 8 } else { self.sweep_pace };
+        if self.sweep_enabled && self.sweep_pace != 0 {
+            let new_freq = self.sweep_calc();
+            if new_freq <= 2047 && self.sweep_step != 0 {
+                self.freq = new_freq;
+                self.shadow_freq = new_freq;
+                self.sweep_calc();
+            }
+        }
+    }
+
+    /**
+     * Clocked at 256 Hz by the frame sequencer
+     */
+    fn clock_length(&mut self) -> () {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self) -> () {
+        if self.freq_timer > 0 {
+            self.freq_timer -= 1;
+        }
+        if self.freq_timer == 0 {
+            self.freq_timer = (2048 - self.freq as u32) * 4;
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        }
+    }
+
+    /**
+     * Returns the current digital output of the channel (0 - 15)
+     */
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        return DUTY_TABLE[self.duty as usize][self.duty_pos as usize] * self.envelope.volume;
+    }
+}
+
+
+/**
+ * Wave channel (CH3), plays the samples stored in the wave RAM
+ */
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length_counter: u16,
+    length_enabled: bool,
+    output_level: u8,
+    freq: u16,
+    freq_timer: u32,
+    position: u8,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    const fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length_counter: 0,
+            length_enabled: false,
+            output_level: 0,
+            freq: 0,
+            freq_timer: 0,
+            position: 0,
+            wave_ram: [0; 16],
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => (self.dac_enabled as u8) << 7,
+            2 => self.output_level << 5,
+            4 => (self.length_enabled as u8) << 6,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) -> () {
+        match reg {
+            0 => {
+                self.dac_enabled = (value & 0x80) != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            },
+            1 => { self.length_counter = 256 - value as u16; },
+            2 => { self.output_level = (value >> 5) & 0x03; },
+            3 => { self.freq = (self.freq & 0x700) | value as u16; },
+            4 => {
+                self.freq = (self.freq & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.length_enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.enabled = self.dac_enabled;
+                    if self.length_counter == 0 {
+                        self.length_counter = 256;
+                    }
+                    self.freq_timer = (2048 - self.freq as u32) * 2;
+                    self.position = 0;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn clock_length(&mut self) -> () {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self) -> () {
+        if self.freq_timer > 0 {
+            self.freq_timer -= 1;
+        }
+        if self.freq_timer == 0 {
+            self.freq_timer = (2048 - self.freq as u32) * 2;
+            self.position = (self.position + 1) & 0x1F;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.wave_ram[(self.position / 2) as usize];
+        // The upper nibble is played first
+        let sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        match self.output_level {
+            0 => 0,
+            1 => sample,
+            2 => sample >> 1,
+            _ => sample >> 2,
+        }
+    }
+}
+
+
+/**
+ * Noise channel (CH4), driven by a linear-feedback shift register
+ */
+struct NoiseChannel {
+    enabled: bool,
+    length_counter: u16,
+    length_enabled: bool,
+    envelope: Envelope,
+    clock_shift: u8,
+    short_mode: bool,
+    divisor_code: u8,
+    freq_timer: u32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    const fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            length_counter: 0,
+            length_enabled: false,
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            freq_timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            2 => self.envelope.read(),
+            3 => (self.clock_shift << 4) | ((self.short_mode as u8) << 3) | self.divisor_code,
+            4 => (self.length_enabled as u8) << 6,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) -> () {
+        match reg {
+            1 => { self.length_counter = 64 - (value & 0x3F) as u16; },
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => {
+                self.clock_shift = value >> 4;
+                self.short_mode = (value & 0x08) != 0;
+                self.divisor_code = value & 0x07;
+            },
+            4 => {
+                self.length_enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    if self.length_counter == 0 {
+                        self.length_counter = 64;
+                    }
+                    self.freq_timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    #[inline(always)]
+    fn period(&self) -> u32 {
+        return NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift;
+    }
+
+    fn clock_length(&mut self) -> () {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self) -> () {
+        if self.freq_timer > 0 {
+            self.freq_timer -= 1;
+        }
+        if self.freq_timer == 0 {
+            self.freq_timer = self.period();
+            let xor = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.short_mode {
+                // 7-bit mode, the result is also copied to bit 6
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || (self.lfsr & 1) != 0 {
+            return 0;
+        }
+        return self.envelope.volume;
+    }
+}
+
+
+/**
+ * Collects the mixed stereo samples and hands them over
+ * to the audio device in batches
+ */
+pub struct AudioSink {
+    // Interleaved stereo samples (left, right, left, right...)
+    buffer: Vec<f32>,
+}
+
+impl AudioSink {
+    const fn new() -> AudioSink {
+        AudioSink { buffer: Vec::new() }
+    }
+
+    /**
+     * Pushes a single stereo frame to the sink
+     */
+    fn push(&mut self, left: f32, right: f32) -> () {
+        self.buffer.push(left);
+        self.buffer.push(right);
+        if self.buffer.len() >= SINK_FRAMES * 2 {
+            ui::queue_audio(&self.buffer);
+            self.buffer.clear();
+        }
+    }
+}
+
+
+pub struct APU {
+    enabled: bool,
+    ch1: PulseChannel,
+    ch2: PulseChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+    // NR50 - Master volume & VIN panning
+    nr50: u8,
+    // NR51 - Sound panning
+    nr51: u8,
+    // Frame sequencer
+    frame_step: u8,
+    prev_div_bit: bool,
+    // Accumulator used to downsample to SAMPLE_RATE
+    sample_counter: u32,
+    sink: AudioSink,
+}
+
+pub static mut APU_CTX: APU = APU::new();
+
+
+impl APU {
+    const fn new() -> APU {
+        return APU {
+            enabled: true,
+            ch1: PulseChannel::new(true),
+            ch2: PulseChannel::new(false),
+            ch3: WaveChannel::new(),
+            ch4: NoiseChannel::new(),
+            nr50: 0x77,
+            nr51: 0xF3,
+            frame_step: 0,
+            prev_div_bit: false,
+            sample_counter: 0,
+            sink: AudioSink::new(),
+        };
+    }
+
+    /**
+     * Resets the channels and registers, the audio sink is kept
+     */
+    pub fn reset(&mut self) -> () {
+        self.enabled = true;
+        self.ch1 = PulseChannel::new(true);
+        self.ch2 = PulseChannel::new(false);
+        self.ch3 = WaveChannel::new();
+        self.ch4 = NoiseChannel::new();
+        self.nr50 = 0x77;
+        self.nr51 = 0xF3;
+        self.frame_step = 0;
+        self.prev_div_bit = false;
+        self.sample_counter = 0;
+    }
+
+    /**
+     * Reads from one of the sound registers or the wave RAM
+     */
+    pub fn read(&self, address: u16) -> u8 {
+        if address >= WAVE_RAM_ADDR {
+            return self.ch3.wave_ram[(address - WAVE_RAM_ADDR) as usize];
+        }
+        let offset = address - APU_START_ADDR;
+        let value = match address {
+            0xFF10..=0xFF14 => self.ch1.read(offset),
+            0xFF16..=0xFF19 => self.ch2.read(offset - 5),
+            0xFF1A..=0xFF1E => self.ch3.read(offset - 10),
+            0xFF20..=0xFF23 => self.ch4.read(offset - 15),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            NR52_ADDR => {
+                ((self.enabled as u8) << 7) |
+                ((self.ch4.enabled as u8) << 3) |
+                ((self.ch3.enabled as u8) << 2) |
+                ((self.ch2.enabled as u8) << 1) |
+                (self.ch1.enabled as u8)
+            },
+            _ => 0,
+        };
+        return value | READ_MASKS[offset as usize];
+    }
+
+    /**
+     * Writes to one of the sound registers or the wave RAM
+     */
+    pub fn write(&mut self, address: u16, value: u8) -> () {
+        if address >= WAVE_RAM_ADDR {
+            self.ch3.wave_ram[(address - WAVE_RAM_ADDR) as usize] = value;
+            return;
+        }
+        if address == NR52_ADDR {
+            let enabled = (value & 0x80) != 0;
+            if self.enabled && !enabled {
+                self.power_off();
+            } else if !self.enabled && enabled {
+                self.frame_step = 0;
+            }
+            self.enabled = enabled;
+            return;
+        }
+        if !self.enabled {
+            // All registers except NR52 are read-only while the APU is off
+            return;
+        }
+        let offset = address - APU_START_ADDR;
+        match address {
+            0xFF10..=0xFF14 => self.ch1.write(offset, value),
+            0xFF16..=0xFF19 => self.ch2.write(offset - 5, value),
+            0xFF1A..=0xFF1E => self.ch3.write(offset - 10, value),
+            0xFF20..=0xFF23 => self.ch4.write(offset - 15, value),
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            _ => (),
+        }
+    }
+
+    /**
+     * Turning the APU off clears all registers except the wave RAM
+     */
+    fn power_off(&mut self) -> () {
+        let wave_ram = self.ch3.wave_ram;
+        self.ch1 = PulseChannel::new(true);
+        self.ch2 = PulseChannel::new(false);
+        self.ch3 = WaveChannel::new();
+        self.ch3.wave_ram = wave_ram;
+        self.ch4 = NoiseChannel::new();
+        self.nr50 = 0;
+        self.nr51 = 0;
+    }
+
+    /**
+     * Steps the frame sequencer, which runs at 512 Hz
+     * Step   Length Ctr  Vol Env     Sweep
+     * 0      Clock       -           -
+     * 2      Clock       -           Clock
+     * 4      Clock       -           -
+     * 6      Clock       -           Clock
+     * 7      -           Clock       -
+     */
+    fn step_frame_sequencer(&mut self) -> () {
+        if self.frame_step % 2 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    /**
+     * Mixes the output of all channels into a stereo frame.
+     * NR51 selects which channels are sent to which side, and
+     * NR50 sets the volume of each side.
+     */
+    fn mix(&self) -> (f32, f32) {
+        let outputs = [
+            self.ch1.output(),
+            self.ch2.output(),
+            self.ch3.output(),
+            self.ch4.output(),
+        ];
+        let mut left: f32 = 0.0;
+        let mut right: f32 = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            let sample = *output as f32 / 15.0;
+            if (self.nr51 & (0x10 << i)) != 0 {
+                left += sample;
+            }
+            if (self.nr51 & (0x01 << i)) != 0 {
+                right += sample;
+            }
+        }
+        let left_volume = (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+        let right_volume = ((self.nr50 & 0x07) + 1) as f32 / 8.0;
+        // Divides by the number of channels to keep the output in range
+        return (left / 4.0 * left_volume, right / 4.0 * right_volume);
+    }
+
+    /**
+     * Returns whether each channel is sent to the left and
+     * to the right output, as selected by NR51
+     */
+    pub fn panning(&self) -> [(bool, bool); 4] {
+        let mut panning = [(false, false); 4];
+        for (i, side) in panning.iter_mut().enumerate() {
+            *side = ((self.nr51 & (0x10 << i)) != 0, (self.nr51 & (0x01 << i)) != 0);
+        }
+        return panning;
+    }
+
+    /**
+     * Performs a single APU tick (one T-cycle). `counter` is the
+     * internal counter of the timer, whose bit 12 (bit 13 in double
+     * speed mode) drives the frame sequencer at 512 Hz.
+     */
+    pub fn tick(&mut self, counter: u16, double_speed: bool) -> () {
+        // The frame sequencer is stepped on the falling edge of the bit
+        let mask = if double_speed { 1 << 13 } else { 1 << 12 };
+        let div_bit = (counter & mask) != 0;
+        if self.prev_div_bit && !div_bit && self.enabled {
+            self.step_frame_sequencer();
+        }
+        self.prev_div_bit = div_bit;
+
+        if self.enabled {
+            self.ch1.tick();
+            self.ch2.tick();
+            self.ch3.tick();
+            self.ch4.tick();
+        }
+
+        // Downsamples the output to the sample rate of the audio device
+        self.sample_counter += SAMPLE_RATE;
+        if self.sample_counter >= CPU_FREQ {
+            self.sample_counter -= CPU_FREQ;
+            let (left, right) = if self.enabled { self.mix() } else { (0.0, 0.0) };
+            self.sink.push(left, right);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nr51_routes_channel_1_to_the_left_only() {
+        let mut apu = APU::new();
+        // CH1 and CH2 at full volume with a 75% duty cycle
+        apu.write(0xFF11, 0xC0);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF14, 0x87);
+        apu.write(0xFF16, 0xC0);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0x87);
+        // CH1 to the left, CH2 to the right
+        apu.write(0xFF25, 0x12);
+        assert_eq!(apu.panning(), [(true, false), (false, true), (false, false), (false, false)]);
+
+        let mut ch1_heard = false;
+        for _ in 0..4096 {
+            apu.ch1.tick();
+            apu.ch2.tick();
+            let (left, right) = apu.mix();
+            // The volume of each side is at its maximum, each channel
+            // contributes output / 15 / 4 to the sides it is sent to
+            assert_eq!((left * 60.0).round() as u8, apu.ch1.output());
+            assert_eq!((right * 60.0).round() as u8, apu.ch2.output());
+            ch1_heard |= apu.ch1.output() != 0;
+        }
+        assert!(ch1_heard);
+    }
+
+    /**
+     * Ticks the APU for 1/8 of a second, the internal counter of the
+     * timer advancing by the given step per tick, and returns the
+     * number of times the frame sequencer was stepped
+     */
+    fn frame_sequencer_steps(counter_step: u16, double_speed: bool) -> u32 {
+        let mut apu = APU::new();
+        let mut counter: u16 = 0;
+        let mut steps = 0;
+        for _ in 0..=CPU_FREQ / 8 {
+            let frame_step = apu.frame_step;
+            apu.tick(counter, double_speed);
+            if apu.frame_step != frame_step {
+                steps += 1;
+            }
+            counter = counter.wrapping_add(counter_step);
+        }
+        return steps;
+    }
+
+    #[test]
+    fn frame_sequencer_runs_at_512_hz() {
+        assert_eq!(frame_sequencer_steps(1, false), 64);
+        // The timer runs twice as fast in double speed mode, bit 13
+        // keeps the frame sequencer at the same rate
+        assert_eq!(frame_sequencer_steps(2, true), 64);
+        assert_eq!(frame_sequencer_steps(2, false), 128);
+    }
+}
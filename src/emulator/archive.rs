@@ -0,0 +1,434 @@
+use crate::emulator::png::crc32;
+
+/**
+ * Decompression of ROMs shipped as .gz or .zip files. Only the
+ * deflate method is supported, which is what gzip and virtually
+ * all zip tools produce.
+ * https://www.rfc-editor.org/rfc/rfc1951 (deflate)
+ * https://www.rfc-editor.org/rfc/rfc1952 (gzip)
+ * https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT (zip)
+ */
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_END_OF_CENTRAL_DIR: u32 = 0x06054B50;
+const ZIP_CENTRAL_DIR_HEADER: u32 = 0x02014B50;
+const ZIP_LOCAL_HEADER: u32 = 0x04034B50;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+// File extensions of the ROMs picked from a zip file
+const ROM_EXTENSIONS: [&str; 2] = ["gb", "gbc"];
+
+/**
+ * Errors that can occur while decompressing a ROM
+ */
+#[derive(Debug)]
+pub enum ArchiveError {
+    // The file is not a valid gzip or zip file
+    InvalidFormat(&'static str),
+    // The compressed data is corrupted
+    InvalidData(&'static str),
+    // The checksum of the decompressed data does not match
+    ChecksumMismatch,
+    // The zip entry uses a compression method other than deflate
+    UnsupportedMethod(u16),
+    // The zip file contains no .gb or .gbc file
+    NoRom,
+    // The zip file contains more than one .gb or .gbc file
+    MultipleRoms(Vec<String>),
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ArchiveError::InvalidFormat(what) => write!(f, "Invalid archive: {}", what),
+            ArchiveError::InvalidData(what) => write!(f, "Corrupted compressed data: {}", what),
+            ArchiveError::ChecksumMismatch => write!(f, "Checksum of the decompressed ROM does not match"),
+            ArchiveError::UnsupportedMethod(method) =>
+                write!(f, "Unsupported compression method {}", method),
+            ArchiveError::NoRom => write!(f, "The zip file contains no .gb or .gbc file"),
+            ArchiveError::MultipleRoms(names) =>
+                write!(f, "The zip file contains more than one ROM: {}", names.join(", ")),
+        }
+    }
+}
+
+
+/**
+ * Reads the bits of a deflate stream, starting with the least
+ * significant bit of each byte
+ */
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> Result<u32, ArchiveError> {
+        let byte = *self.data.get(self.pos).ok_or(ArchiveError::InvalidData("unexpected end"))?;
+        let value = (byte >> self.bit) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+        return Ok(value as u32);
+    }
+
+    fn read_bits(&mut self, count: u8) -> Result<u32, ArchiveError> {
+        let mut value = 0;
+        for i in 0..count {
+            value |= self.read_bit()? << i;
+        }
+        return Ok(value);
+    }
+
+    /**
+     * Skips to the next byte boundary
+     */
+    fn align(&mut self) -> () {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+
+/**
+ * A canonical Huffman code, given by the number of codes of each
+ * length and the symbols ordered by code
+ */
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /**
+     * Builds the code from the code length of each symbol
+     */
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols = Vec::new();
+        for length in 1..16 {
+            for (symbol, _) in lengths.iter().enumerate().filter(|(_, l)| **l == length) {
+                symbols.push(symbol as u16);
+            }
+        }
+        return Huffman { counts: counts, symbols: symbols };
+    }
+
+    /**
+     * Reads the next symbol, one bit at a time
+     */
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, ArchiveError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1..16 {
+            code |= reader.read_bit()? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        return Err(ArchiveError::InvalidData("invalid Huffman code"));
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13
+];
+// Order in which the code lengths of the code length code are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/**
+ * Returns the fixed literal / length and distance codes
+ */
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    return (Huffman::new(&lengths), Huffman::new(&[5; 30]));
+}
+
+/**
+ * Reads the literal / length and distance codes of a dynamic block
+ */
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), ArchiveError> {
+    let literals = reader.read_bits(5)? as usize + 257;
+    let distances = reader.read_bits(5)? as usize + 1;
+    let code_lengths = reader.read_bits(4)? as usize + 4;
+
+    let mut lengths = [0u8; 19];
+    for i in 0..code_lengths {
+        lengths[CODE_LENGTH_ORDER[i]] = reader.read_bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths);
+
+    // The lengths of both codes are stored as a single sequence
+    let mut lengths: Vec<u8> = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let symbol = code_length_code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let prev = *lengths.last().ok_or(ArchiveError::InvalidData("repeat without length"))?;
+                (prev, 3 + reader.read_bits(2)?)
+            },
+            17 => (0, 3 + reader.read_bits(3)?),
+            _ => (0, 11 + reader.read_bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() != literals + distances {
+        return Err(ArchiveError::InvalidData("too many code lengths"));
+    }
+    return Ok((Huffman::new(&lengths[..literals]), Huffman::new(&lengths[literals..])));
+}
+
+/**
+ * Decompresses a raw deflate stream
+ */
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    let mut reader = BitReader { data: data, pos: 0, bit: 0 };
+    let mut output: Vec<u8> = Vec::new();
+    loop {
+        let last_block = reader.read_bit()? == 1;
+        match reader.read_bits(2)? {
+            0 => {
+                // Stored block
+                reader.align();
+                let header = data.get(reader.pos..reader.pos + 4)
+                    .ok_or(ArchiveError::InvalidData("unexpected end"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let nlen = u16::from_le_bytes([header[2], header[3]]) as usize;
+                if len != !nlen & 0xFFFF {
+                    return Err(ArchiveError::InvalidData("invalid stored block length"));
+                }
+                let start = reader.pos + 4;
+                let block = data.get(start..start + len)
+                    .ok_or(ArchiveError::InvalidData("unexpected end"))?;
+                output.extend_from_slice(block);
+                reader.pos = start + len;
+            },
+            block_type @ (1 | 2) => {
+                let (literal_code, dist_code) = if block_type == 1 {
+                    fixed_codes()
+                } else {
+                    dynamic_codes(&mut reader)?
+                };
+                loop {
+                    let symbol = literal_code.decode(&mut reader)? as usize;
+                    if symbol < 256 {
+                        output.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+                    let index = symbol - 257;
+                    if index >= LENGTH_BASE.len() {
+                        return Err(ArchiveError::InvalidData("invalid length"));
+                    }
+                    let length = LENGTH_BASE[index] as usize + reader.read_bits(LENGTH_EXTRA[index])? as usize;
+                    let index = dist_code.decode(&mut reader)? as usize;
+                    if index >= DIST_BASE.len() {
+                        return Err(ArchiveError::InvalidData("invalid distance"));
+                    }
+                    let dist = DIST_BASE[index] as usize + reader.read_bits(DIST_EXTRA[index])? as usize;
+                    if dist > output.len() {
+                        return Err(ArchiveError::InvalidData("distance too far back"));
+                    }
+                    // The copy may overlap with the bytes it produces
+                    let start = output.len() - dist;
+                    for i in 0..length {
+                        output.push(output[start + i]);
+                    }
+                }
+            },
+            _ => return Err(ArchiveError::InvalidData("invalid block type")),
+        }
+        if last_block {
+            return Ok(output);
+        }
+    }
+}
+
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ArchiveError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ArchiveError::InvalidFormat("truncated"))?;
+    return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ArchiveError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ArchiveError::InvalidFormat("truncated"))?;
+    return Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+}
+
+/**
+ * Decompresses a gzip file and verifies its CRC-32
+ */
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    if data.len() < 18 || data[..2] != GZIP_MAGIC || data[2] != METHOD_DEFLATE as u8 {
+        return Err(ArchiveError::InvalidFormat("not a gzip file"));
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    // FEXTRA
+    if flags & 0x04 != 0 {
+        pos += 2 + read_u16(data, pos)? as usize;
+    }
+    // FNAME and FCOMMENT are zero-terminated
+    for flag in [0x08, 0x10] {
+        if flags & flag != 0 {
+            let len = data.get(pos..).and_then(|rest| rest.iter().position(|b| *b == 0))
+                .ok_or(ArchiveError::InvalidFormat("truncated"))?;
+            pos += len + 1;
+        }
+    }
+    // FHCRC
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    let compressed = data.get(pos..data.len() - 8).ok_or(ArchiveError::InvalidFormat("truncated"))?;
+    let output = inflate(compressed)?;
+    if crc32(&output) != read_u32(data, data.len() - 8)? {
+        return Err(ArchiveError::ChecksumMismatch);
+    }
+    return Ok(output);
+}
+
+/**
+ * Returns the single .gb or .gbc file of a zip file, decompressed
+ */
+pub fn unzip_rom(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    // The end of central directory record is at the end of the file,
+    // followed by a comment of up to 64 KiB
+    let search_start = data.len().saturating_sub(22 + 0xFFFF);
+    let eocd = (search_start..data.len().saturating_sub(21)).rev()
+        .find(|pos| read_u32(data, *pos).ok() == Some(ZIP_END_OF_CENTRAL_DIR))
+        .ok_or(ArchiveError::InvalidFormat("not a zip file"))?;
+    let entries = read_u16(data, eocd + 10)?;
+    let mut pos = read_u32(data, eocd + 16)? as usize;
+
+    // (name, method, compressed size, CRC-32, local header offset)
+    let mut roms: Vec<(String, u16, usize, u32, usize)> = Vec::new();
+    for _ in 0..entries {
+        if read_u32(data, pos)? != ZIP_CENTRAL_DIR_HEADER {
+            return Err(ArchiveError::InvalidFormat("corrupted central directory"));
+        }
+        let method = read_u16(data, pos + 10)?;
+        let crc = read_u32(data, pos + 16)?;
+        let compressed_size = read_u32(data, pos + 20)? as usize;
+        let name_len = read_u16(data, pos + 28)? as usize;
+        let extra_len = read_u16(data, pos + 30)? as usize;
+        let comment_len = read_u16(data, pos + 32)? as usize;
+        let offset = read_u32(data, pos + 42)? as usize;
+        let name = data.get(pos + 46..pos + 46 + name_len)
+            .ok_or(ArchiveError::InvalidFormat("truncated"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        let extension = std::path::Path::new(&name).extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        if extension.is_some_and(|ext| ROM_EXTENSIONS.contains(&ext.as_str())) {
+            roms.push((name, method, compressed_size, crc, offset));
+        }
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+
+    if roms.len() > 1 {
+        return Err(ArchiveError::MultipleRoms(roms.into_iter().map(|rom| rom.0).collect()));
+    }
+    let (name, method, compressed_size, crc, offset) = roms.pop().ok_or(ArchiveError::NoRom)?;
+    if read_u32(data, offset)? != ZIP_LOCAL_HEADER {
+        return Err(ArchiveError::InvalidFormat("corrupted local header"));
+    }
+    // The local header may have a different extra field than the central one
+    let start = offset + 30 + read_u16(data, offset + 26)? as usize + read_u16(data, offset + 28)? as usize;
+    let compressed = data.get(start..start + compressed_size)
+        .ok_or(ArchiveError::InvalidFormat("truncated"))?;
+    let output = match method {
+        METHOD_STORED => compressed.to_vec(),
+        METHOD_DEFLATE => inflate(compressed)?,
+        _ => return Err(ArchiveError::UnsupportedMethod(method)),
+    };
+    if crc32(&output) != crc {
+        return Err(ArchiveError::ChecksumMismatch);
+    }
+    log::info!("Extracted {} from the zip file", name);
+    return Ok(output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "Hello, Game Boy!" 3 times and a new line, compressed by gzip
+    // with fixed Huffman codes
+    const FIXED_GZIP: [u8; 41] = [
+        0x1F, 0x8B, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xF3, 0x48,
+        0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0x70, 0x4F, 0xCC, 0x4D, 0x55, 0x70, 0xCA,
+        0xAF, 0x54, 0x54, 0xF0, 0x20, 0x24, 0xC0, 0x05, 0x00, 0x48, 0xA2, 0x67,
+        0x3F, 0x33, 0x00, 0x00, 0x00,
+    ];
+
+    const DYNAMIC_TEXT: &[u8] = b"Press START to begin. The Game Boy has a Sharp SM83 CPU \
+        running at 4.19 MHz, 8 KiB of work RAM, 8 KiB of video RAM and a 160x144 LCD \
+        with four shades of gray.\n";
+    // DYNAMIC_TEXT compressed with dynamic Huffman codes
+    const DYNAMIC_GZIP: [u8; 153] = [
+        0x1F, 0x8B, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4D, 0xCC,
+        0xD1, 0x0A, 0xC2, 0x20, 0x18, 0x86, 0xE1, 0xF3, 0xAE, 0xE2, 0xBB, 0x80,
+        0x90, 0x24, 0x89, 0x75, 0xB8, 0x2D, 0x28, 0x28, 0x61, 0xCC, 0x75, 0x01,
+        0x7F, 0xCC, 0xA9, 0x44, 0x1A, 0xEA, 0x5A, 0xEB, 0xEA, 0x6B, 0x67, 0x9D,
+        0x3E, 0xBC, 0xBC, 0x4D, 0xD4, 0x29, 0x41, 0x75, 0x65, 0xDB, 0x21, 0x07,
+        0xDC, 0xB4, 0x71, 0x9E, 0xA1, 0xB3, 0x1A, 0x47, 0x7A, 0x68, 0x54, 0x61,
+        0x86, 0xA5, 0x04, 0x82, 0xB2, 0x14, 0x9F, 0x50, 0xB2, 0xD8, 0xA2, 0x6E,
+        0xAE, 0x88, 0xA3, 0xF7, 0xCE, 0x1B, 0x50, 0x86, 0x60, 0x7C, 0x0F, 0x79,
+        0xFA, 0xAC, 0x51, 0xE0, 0xEC, 0x2A, 0x84, 0x01, 0x53, 0x88, 0x77, 0xB4,
+        0xA5, 0xFC, 0xA3, 0x97, 0xEB, 0x75, 0x58, 0x0C, 0xE4, 0xFB, 0xDF, 0x8F,
+        0xEF, 0x36, 0x6F, 0x2E, 0x04, 0x2E, 0xF5, 0x01, 0x93, 0xCB, 0x16, 0x43,
+        0x18, 0x23, 0x92, 0xA5, 0x5E, 0xA7, 0xA5, 0x37, 0x91, 0x66, 0xB6, 0xFA,
+        0x02, 0x5A, 0xB4, 0x3E, 0x6B, 0x9F, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn gunzip_decodes_fixed_and_dynamic_huffman_blocks() {
+        assert_eq!(gunzip(&FIXED_GZIP).unwrap(), b"Hello, Game Boy! Hello, Game Boy! Hello, Game Boy!\n");
+        assert_eq!(gunzip(&DYNAMIC_GZIP).unwrap(), DYNAMIC_TEXT);
+    }
+
+    #[test]
+    fn gunzip_rejects_corrupted_files() {
+        let mut corrupted = FIXED_GZIP;
+        // The CRC-32 of the content
+        corrupted[33] ^= 0x01;
+        assert!(matches!(gunzip(&corrupted), Err(ArchiveError::ChecksumMismatch)));
+        assert!(matches!(gunzip(&FIXED_GZIP[1..]), Err(ArchiveError::InvalidFormat(_))));
+    }
+}
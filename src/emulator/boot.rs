@@ -0,0 +1,151 @@
+use crate::emulator::savestate::*;
+
+/**
+ * DMG boot ROM, which scrolls the Nintendo logo and plays the
+ * chime before jumping to the cartridge at 0x0100. While it is
+ * mapped, it replaces the first 256 bytes of the cartridge. The
+ * boot ROM unmaps itself by writing to 0xFF50.
+ * https://gbdev.io/pandocs/Power_Up_Sequence.html
+ */
+
+pub const BOOT_OFF_ADDR: u16 = 0xFF50;
+const BOOT_ROM_SIZE: usize = 0x100;
+
+/**
+ * Errors that can occur while loading a boot ROM
+ */
+#[derive(Debug)]
+pub enum BootRomError {
+    // The boot ROM file could not be read
+    Io(std::io::Error),
+    // The file is not 256 bytes long
+    InvalidSize(usize),
+}
+
+impl std::fmt::Display for BootRomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BootRomError::Io(err) => write!(f, "Unable to read boot ROM: {}", err),
+            BootRomError::InvalidSize(size) =>
+                write!(f, "Invalid boot ROM size {} bytes, expected {}", size, BOOT_ROM_SIZE),
+        }
+    }
+}
+
+
+pub struct BootRom {
+    data: Vec<u8>,
+    mapped: bool,
+}
+
+pub static mut BOOT_CTX: BootRom = BootRom {
+    data: Vec::new(),
+    mapped: false,
+};
+
+impl BootRom {
+    /**
+     * Loads the boot ROM from the given file and maps it
+     */
+    pub fn load_file(&mut self, path: &str) -> Result<(), BootRomError> {
+        let data = std::fs::read(path).map_err(BootRomError::Io)?;
+        if data.len() != BOOT_ROM_SIZE {
+            return Err(BootRomError::InvalidSize(data.len()));
+        }
+        self.data = data;
+        self.mapped = true;
+        return Ok(());
+    }
+
+    /**
+     * Maps the boot ROM again if one was loaded
+     */
+    pub fn reset(&mut self) -> () {
+        self.mapped = !self.data.is_empty();
+    }
+
+    /**
+     * Returns true if the given address is read from the boot ROM
+     */
+    #[inline(always)]
+    pub fn is_mapped(&self, address: u16) -> bool {
+        return self.mapped && (address as usize) < BOOT_ROM_SIZE;
+    }
+
+    /**
+     * Returns true while the boot ROM has not been unmapped
+     */
+    pub fn is_running(&self) -> bool {
+        return self.mapped;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        return self.data[address as usize];
+    }
+
+    /**
+     * Handles a write to 0xFF50. Any non-zero value unmaps the
+     * boot ROM until the next power cycle.
+     */
+    pub fn write(&mut self, data: u8) -> () {
+        if data != 0 && self.mapped {
+            self.mapped = false;
+            log::info!("Boot ROM unmapped");
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_bool(self.mapped);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let mapped = reader.read_bool()?;
+        if mapped && self.data.is_empty() {
+            return Err(StateError::Corrupted("boot ROM is not loaded"));
+        }
+        self.mapped = mapped;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Loads a boot ROM of the given size from a temporary file
+     */
+    fn load(size: usize) -> (BootRom, Result<(), BootRomError>) {
+        let path = std::env::temp_dir().join(format!("gb-emu-boot-{}-{}.bin", std::process::id(), size));
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        let mut boot = BootRom { data: Vec::new(), mapped: false };
+        let result = boot.load_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        return (boot, result);
+    }
+
+    #[test]
+    fn boot_rom_is_mapped_until_0xff50_is_written() {
+        let (mut boot, result) = load(BOOT_ROM_SIZE);
+        assert!(result.is_ok());
+        assert!(boot.is_mapped(0x0000) && boot.is_mapped(0x00FF));
+        assert!(!boot.is_mapped(0x0100));
+        assert_eq!(boot.read(0x00FF), 0xFF);
+        // Writing 0 leaves it mapped
+        boot.write(0);
+        assert!(boot.is_running());
+        boot.write(1);
+        assert!(!boot.is_running() && !boot.is_mapped(0x0000));
+        // A reset maps it again
+        boot.reset();
+        assert!(boot.is_mapped(0x0000));
+    }
+
+    #[test]
+    fn boot_rom_of_the_wrong_size_is_rejected() {
+        let (boot, result) = load(100);
+        assert!(matches!(result, Err(BootRomError::InvalidSize(100))));
+        assert!(!boot.is_running());
+    }
+}
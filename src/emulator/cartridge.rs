@@ -1,6 +1,8 @@
 use phf::{phf_map, Map};
-use std::io::{BufWriter, Write, BufReader, Read};
-extern crate libc;
+use std::io::{BufWriter, Write};
+use crate::emulator::savestate::*;
+use crate::emulator::archive::{self, ArchiveError};
+use crate::emulator::{hash, png};
 
 // https://gbdev.io/pandocs/The_Cartridge_Header.html
 // A struct that defines the cartridge header
@@ -23,6 +25,121 @@ struct RomHeader {
     global_checksum: u16,
 }
 
+/**
+ * The memory bank controllers supported by the emulator
+ * https://gbdev.io/pandocs/MBCs.html
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MbcKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+impl MbcKind {
+    /**
+     * Derives the kind of MBC from the cartridge type in the header.
+     * Unknown controllers are treated as ROM only, as is MBC3 until
+     * its banking is implemented.
+     */
+    fn from_cartridge_type(cartridge_type: u8) -> MbcKind {
+        match cartridge_type {
+            0x01..=0x03 => MbcKind::Mbc1,
+            0x05..=0x06 => MbcKind::Mbc2,
+            0x0F..=0x13 => MbcKind::Mbc3,
+            0x19..=0x1E => MbcKind::Mbc5,
+            _ => MbcKind::RomOnly,
+        }
+    }
+}
+
+/**
+ * How the ROM uses the Game Boy Color, given by the CGB flag
+ * (0x0143, the last byte of the title)
+ * https://gbdev.io/pandocs/The_Cartridge_Header.html#0143--cgb-flag
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CgbMode {
+    // A DMG game
+    Dmg,
+    // Uses the CGB features but also runs on a DMG (0x80)
+    Enhanced,
+    // Only runs on a CGB (0xC0)
+    CgbOnly,
+}
+
+impl CgbMode {
+    fn from_flag(flag: u8) -> CgbMode {
+        match flag {
+            0xC0 => CgbMode::CgbOnly,
+            // Bit 6 is ignored by the CGB if bit 7 is set
+            f if f & 0x80 != 0 => CgbMode::Enhanced,
+            _ => CgbMode::Dmg,
+        }
+    }
+}
+
+// MBC2 has 512 x 4 bits of built-in RAM
+const MBC2_RAM_SIZE: usize = 0x200;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// The ROM must at least contain the whole cartridge header (0x0100 - 0x014F)
+const ROM_HEADER_END: usize = 0x150;
+const ROM_BANK_SIZE: usize = 0x4000;
+
+// The logo at 0x0104 - 0x0133 that the boot ROM compares against
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/**
+ * Errors that can occur while loading a cartridge
+ */
+#[derive(Debug)]
+pub enum CartridgeError {
+    // The ROM file could not be read
+    Io(std::io::Error),
+    // The compressed ROM file could not be decompressed
+    Archive(ArchiveError),
+    // The ROM is too small to contain the cartridge header
+    TooSmall(usize),
+    // The header checksum does not match
+    HeaderChecksum,
+    // The RAM size code in the header is not valid
+    InvalidRamSize(u8),
+    // The ROM is not made of a power of two number of banks
+    InvalidRomSize(usize),
+    // The Nintendo logo in the header is corrupted (strict mode only)
+    InvalidLogo,
+    // The global checksum does not match (strict mode only)
+    GlobalChecksum,
+}
+
+impl std::fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f, "Unable to read ROM file: {}", err),
+            CartridgeError::Archive(err) => write!(f, "Unable to decompress ROM file: {}", err),
+            CartridgeError::TooSmall(size) =>
+                write!(f, "ROM is too small ({} bytes), expected at least {} bytes",
+                    size, ROM_HEADER_END),
+            CartridgeError::HeaderChecksum => write!(f, "Verify ROM header checksum: FAILED"),
+            CartridgeError::InvalidLogo => write!(f, "Verify Nintendo logo: FAILED"),
+            CartridgeError::GlobalChecksum => write!(f, "Verify ROM global checksum: FAILED"),
+            CartridgeError::InvalidRomSize(size) =>
+                write!(f, "Invalid ROM size ({} bytes), expected a power of two number \
+                    of {} byte banks, at least {} bytes", size, ROM_BANK_SIZE, ROM_BANK_SIZE * 2),
+            CartridgeError::InvalidRamSize(code) =>
+                write!(f, "Invalid RAM size code 0x{:02X} in the cartridge header", code),
+        }
+    }
+}
+
 // A struct that defines the cartridge
 // and stores the context of the cartridge
 pub struct Cartridge {
@@ -31,45 +148,44 @@ pub struct Cartridge {
     rom_size: usize,
     // Actual ROM data
     rom: Vec<u8>,
+    // Hashes of the ROM data, which identify the ROM
+    crc32: u32,
+    sha1: [u8; 20],
+    // Memory bank controller of the cartridge
+    mbc: MbcKind,
+    cgb_mode: CgbMode,
 
-    // MBC1 related data
+    // MBC related data
     ram_enabled: bool,
     ram_banking: bool,
 
     rom_bank_x: *mut u8,
     banking_mode: u8,
 
-    rom_bank_value: u8,
+    // 9 bits wide for MBC5
+    rom_bank_value: u16,
     ram_bank_value: u8,
 
-    // Current RAM bank
-    ram_bank: *mut u8,
-    // All RAM banks
-    ram_banks: [*mut u8; 16],
+    // Index of the current RAM bank, None if the cartridge has no RAM
+    ram_bank: Option<usize>,
+    // All RAM banks, as many as given by the header
+    ram_banks: Vec<[u8; RAM_BANK_SIZE]>,
 
     // For battery
     // Has battery
     has_battery: bool,
     // Should save battery backup
     need_save: bool,
+
+    // Overrides the destination code in the header if set
+    region_override: Option<u8>,
+    // Refuses ROMs with a corrupted logo or global checksum
+    strict_header: bool,
+    // Directory of the battery files, next to the ROM if not set
+    save_dir: Option<String>,
 }
 
-pub static mut CARTRIDGE_CTX: Cartridge = Cartridge {
-    filename: String::new(),
-    rom_header: std::ptr::null(),
-    rom_size: 0,
-    rom: Vec::new(),
-    ram_enabled: false,
-    ram_banking: false,
-    rom_bank_x: std::ptr::null_mut(),
-    banking_mode: 0,
-    rom_bank_value: 0,
-    ram_bank_value: 0,
-    ram_bank: std::ptr::null_mut(),
-    ram_banks: [std::ptr::null_mut(); 16],
-    has_battery: false,
-    need_save: false,
-};
+pub static mut CARTRIDGE_CTX: Cartridge = Cartridge::new();
 
 // A static lookup table that maps the cartridge type to a string
 static CARTRIDGE_TYPE: Map<u8, &'static str> = phf_map! {
@@ -116,6 +232,12 @@ static RAM_SIZE: Map<u8, &'static str> = phf_map! {
     0x05_u8 => "64 KiB (8 banks of 8 KiB each)",
 };
 
+// A static lookup table that maps the destination code to a string
+// https://gbdev.io/pandocs/The_Cartridge_Header.html#014a--destination-code
+static DESTINATION_CODE: Map<u8, &'static str> = phf_map! {
+    0x00_u8 => "Japan (and possibly overseas)",
+    0x01_u8 => "Overseas only",
+};
 
 // A static lookup table that maps the license code to a string
 // https://gbdev.io/pandocs/The_Cartridge_Header.html#01440145--new-licensee-code
@@ -188,24 +310,93 @@ static LICENSE_CODE: Map<&'static str, &'static str> = phf_map! {
 
 impl Cartridge {
     /**
-     * Parses the ROM header and stores the data in the cartridge
+     * Creates an empty cartridge, a ROM is inserted with load_rom_data
+     */
+    pub(crate) const fn new() -> Cartridge {
+        return Cartridge {
+            filename: String::new(),
+            rom_header: std::ptr::null(),
+            rom_size: 0,
+            rom: Vec::new(),
+            crc32: 0,
+            sha1: [0; 20],
+            mbc: MbcKind::RomOnly,
+            cgb_mode: CgbMode::Dmg,
+            ram_enabled: false,
+            ram_banking: false,
+            rom_bank_x: std::ptr::null_mut(),
+            banking_mode: 0,
+            rom_bank_value: 0,
+            ram_bank_value: 0,
+            ram_bank: None,
+            ram_banks: Vec::new(),
+            has_battery: false,
+            need_save: false,
+            region_override: None,
+            strict_header: false,
+            save_dir: None,
+        };
+    }
+
+    /**
+     * Reads the given ROM file and loads it into the cartridge
      */
-    pub fn load_rom_file(&mut self, rom_file: &str) -> () {
+    pub fn load_rom_file(&mut self, rom_file: &str) -> Result<(), CartridgeError> {
         log::info!("Loading ROM file: {}", rom_file);
         self.filename = rom_file.to_string();
-        let rom_data = std::fs::read(rom_file).expect("Unable to read ROM file");
+        let file_data = std::fs::read(rom_file).map_err(CartridgeError::Io)?;
+        // Compressed ROMs are detected by their extension
+        let extension = std::path::Path::new(rom_file).extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        let rom_data = match extension.as_deref() {
+            Some("gz") => archive::gunzip(&file_data).map_err(CartridgeError::Archive)?,
+            Some("zip") => archive::unzip_rom(&file_data).map_err(CartridgeError::Archive)?,
+            _ => file_data,
+        };
+        self.load_rom_data(rom_data)
+    }
+
+    /**
+     * Parses the ROM header and stores the data in the cartridge
+     */
+    pub fn load_rom_data(&mut self, rom_data: Vec<u8>) -> Result<(), CartridgeError> {
+        // Makes sure that the header can be parsed without
+        // reading past the end of the ROM
+        if rom_data.len() < ROM_HEADER_END {
+            return Err(CartridgeError::TooSmall(rom_data.len()));
+        }
+        // The banking assumes that the ROM is made of at least two banks
+        let num_banks = rom_data.len() / ROM_BANK_SIZE;
+        if rom_data.len() % ROM_BANK_SIZE != 0 || num_banks < 2 || !num_banks.is_power_of_two() {
+            return Err(CartridgeError::InvalidRomSize(rom_data.len()));
+        }
         self.rom_size = rom_data.len();
         self.rom = rom_data;
+        self.crc32 = png::crc32(&self.rom);
+        self.sha1 = hash::sha1(&self.rom);
+        log::info!("ROM CRC32: {:08X}, SHA1: {}", self.crc32, hash::to_hex(&self.sha1));
         // Parses the ROM header by transmuting the memory starting at 0x100
         // and stores the data in the cartridge
         self.rom_header = unsafe {
             std::mem::transmute::<*const u8, *const RomHeader>(&self.rom[0x100])
         };
+        // The header gives the size as 32 KiB << code
+        let size_code = unsafe { (*self.rom_header).rom_size };
+        if size_code > 8 || (ROM_BANK_SIZE * 2) << size_code != self.rom_size {
+            log::warn!(target: "stdout", "ROM size code 0x{:02X} in the header does not \
+                match the size of the ROM ({} bytes)", size_code, self.rom_size);
+        }
         
+        self.mbc = unsafe { MbcKind::from_cartridge_type((*self.rom_header).cartridge_type) };
+        if self.mbc == MbcKind::Mbc3 {
+            log::warn!(target: "stdout", "MBC3 is not supported yet, only the first \
+                32 KiB of the ROM are accessible");
+        }
+        self.cgb_mode = unsafe { CgbMode::from_flag((*self.rom_header).title[15]) };
         self.has_battery = self.has_battery();
         self.need_save = false;
         // Initializes the memory banks
-        self.setup_banking();
+        self.setup_banking()?;
 
         if self.has_battery {
             self.load_battery();
@@ -213,78 +404,245 @@ impl Cartridge {
 
         // Verifies the ROM header checksum
         if !self.verify_checksum() {
-            log::error!(target: "stdout", "Verify ROM header checksum: FAILED");
-            std::process::exit(1);
+            return Err(CartridgeError::HeaderChecksum);
+        }
+        // Many homebrew ROMs have a wrong global checksum,
+        // so these are only fatal in strict mode
+        if !self.verify_logo() {
+            if self.strict_header {
+                return Err(CartridgeError::InvalidLogo);
+            }
+            log::warn!(target: "stdout", "The Nintendo logo in the header is corrupted");
+        }
+        if !self.verify_global_checksum() {
+            if self.strict_header {
+                return Err(CartridgeError::GlobalChecksum);
+            }
+            log::warn!(target: "stdout", "The global checksum of the ROM does not match");
         }
 
         log::info!(target: "stdout", "Loading ROM file: SUCCESS");
+        Ok(())
+    }
+
+    /**
+     * Returns the title of the game from the ROM header
+     */
+    pub fn title(&self) -> String {
+        if self.rom_header.is_null() {
+            return String::new();
+        }
+        // Removes the trailing NULL characters
+        let title = unsafe { &(*self.rom_header).title };
+        return String::from_utf8_lossy(title).trim_end_matches(char::from(0)).to_string();
+    }
+
+    /**
+     * Returns the header and global checksums from the ROM header,
+     * which identify the ROM in save states and movies
+     */
+    pub fn checksums(&self) -> (u8, u16) {
+        if self.rom_header.is_null() {
+            return (0, 0);
+        }
+        return unsafe { ((*self.rom_header).header_checksum, (*self.rom_header).global_checksum) };
+    }
+
+    /**
+     * Returns the CRC-32 of the ROM data
+     */
+    pub fn crc32(&self) -> u32 {
+        return self.crc32;
+    }
+
+    /**
+     * Returns the SHA-1 digest of the ROM data
+     */
+    pub fn sha1(&self) -> &[u8; 20] {
+        return &self.sha1;
+    }
+
+    /**
+     * Writes the battery files to the given directory instead
+     * of the directory of the ROM
+     */
+    pub fn set_save_dir(&mut self, dir: &str) -> () {
+        self.save_dir = Some(dir.to_string());
+    }
+
+    /**
+     * Returns the path of the battery file. Saves are keyed by the
+     * CRC-32 of the ROM, so that different ROMs never share a save,
+     * even with the same file name or title. The title comes first
+     * to keep the name readable, with the characters that are not
+     * allowed in file names replaced by `_`. The file is written next
+     * to the ROM, or to the save directory if one is given.
+     */
+    pub fn battery_path(&self) -> std::path::PathBuf {
+        let title: String = self.title().chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == ' ' || c == '-' { c } else { '_' })
+            .collect();
+        let title = title.trim();
+        let title = if title.is_empty() { "untitled" } else { title };
+        let name = format!("{}-{:08X}.sav", title, self.crc32);
+        return self.save_dir_path().join(name);
+    }
+
+    /**
+     * Returns the path of the battery file used before saves were
+     * keyed by the CRC-32, i.e., the path of the ROM with the extension
+     * replaced by .sav, if the ROM was loaded from a file
+     */
+    fn legacy_battery_path(&self) -> Option<std::path::PathBuf> {
+        if self.filename.is_empty() {
+            return None;
+        }
+        let stem = std::path::Path::new(&self.filename).file_stem()?;
+        return Some(self.save_dir_path().join(stem).with_extension("sav"));
+    }
+
+    /**
+     * Returns the directory the battery file is written to
+     */
+    fn save_dir_path(&self) -> std::path::PathBuf {
+        return match &self.save_dir {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None if !self.filename.is_empty() => std::path::Path::new(&self.filename)
+                .parent().map(|p| p.to_path_buf()).unwrap_or_default(),
+            None => std::path::PathBuf::new(),
+        };
     }
 
     /**
      * Loads the battery file
      */
     pub fn load_battery(&mut self) -> () {
-        unsafe {
-            // Casts the title from a u8 array to a string
-            let title = std::str::from_utf8_unchecked(&(*self.rom_header).title);
-            // Removes the trailing NULL characters
-            let title = title.trim_end_matches(char::from(0));
-            let filename = format!("{}.sav", title);
-            log::info!("Loading battery file: {}", filename);
-            // Opens the battery file if it exists
-            if !std::path::Path::new(&filename).exists() {
-                log::warn!("Battery file not found: {}", filename);
-                return;
+        let mut path = self.battery_path();
+        // Falls back to the save of an older version, which is
+        // replaced by the new file at the next save
+        if !path.exists() {
+            if let Some(legacy) = self.legacy_battery_path().filter(|legacy| legacy.exists()) {
+                path = legacy;
             }
-            let file = std::fs::File::open(filename).expect("Unable to open battery file");
-            let mut reader = BufReader::new(&file);
-            // Reads the first 0x2000 bytes of the RAM from the battery file
-            reader.read_exact(std::slice::from_raw_parts_mut(self.ram_banks[0], 0x2000))
-                .expect("Unable to read from battery file");
-            log::info!("Loading battery file {}: SUCCESS", title);
         }
+        let filename = path.to_string_lossy().into_owned();
+        log::info!("Loading battery file: {}", filename);
+        // Opens the battery file if it exists
+        if !path.exists() {
+            log::warn!("Battery file not found: {}", filename);
+            return;
+        }
+        if self.ram_banks.is_empty() {
+            log::warn!("The cartridge has no RAM to load the battery file into");
+            return;
+        }
+        let data = std::fs::read(&path).expect("Unable to read from battery file");
+        // The banks are stored one after the other, a file that is too
+        // short only fills the first banks and the rest stays cleared
+        let size = self.ram_bank_size();
+        if data.len() < size * self.ram_banks.len() {
+            log::warn!("Battery file {} is too short: {} bytes instead of {}",
+                filename, data.len(), size * self.ram_banks.len());
+        }
+        for (bank, chunk) in self.ram_banks.iter_mut().zip(data.chunks(size)) {
+            bank[..chunk.len()].copy_from_slice(chunk);
+        }
+        log::info!("Loading battery file {}: SUCCESS", filename);
     }
 
     /**
      * Saves the battery file of the current game
      */
     pub fn save_battery(&self) -> () {
-        unsafe {
-            // Casts the title from a u8 array to a string
-            let title = std::str::from_utf8_unchecked(&(*self.rom_header).title);
-            // Removes the trailing NULL characters
-            let title = title.trim_end_matches(char::from(0));
-
-            let filename = format!("{}.sav", title);
-            log::info!("Saving battery file: {}", filename);
-            
-            let file = std::fs::File::create(filename).expect("Unable to create battery file");
-            let mut writer = BufWriter::new(&file);
-            // Writes the first 0x2000 bytes of the RAM to the battery file
-            writer.write_all(std::slice::from_raw_parts(self.ram_banks[0], 0x2000))
+        let path = self.battery_path();
+        let filename = path.to_string_lossy().into_owned();
+        log::info!("Saving battery file: {}", filename);
+        if self.ram_banks.is_empty() {
+            return;
+        }
+        
+        let file = std::fs::File::create(&path).expect("Unable to create battery file");
+        let mut writer = BufWriter::new(&file);
+        // Writes every bank of the RAM to the battery file
+        for bank in self.ram_banks.iter() {
+            writer.write_all(&bank[..self.ram_bank_size()])
                 .expect("Unable to write to battery file");
-            log::info!("Saving battery file {}: SUCCESS", title);
         }
+        log::info!("Saving battery file {}: SUCCESS", filename);
     }
     
 
     /**
-     * Initializes the memory banks when the cartridge is loaded
+     * Returns the size of a single RAM bank in bytes, which is also
+     * the number of bytes persisted in the battery file
      */
-    fn setup_banking(&mut self) -> () {
-        for i in 0..16 {
-            unsafe {
-                if ((*self.rom_header).ram_size == 0x02 && i == 0) ||
-                    ((*self.rom_header).ram_size == 0x03 && i < 4) || 
-                    ((*self.rom_header).ram_size == 0x04 && i < 16) ||
-                    ((*self.rom_header).ram_size == 0x05 && i < 8) {
-                    self.ram_banks[i] = libc::malloc(0x2000) as *mut u8;
-                    libc::memset(self.ram_banks[i] as *mut libc::c_void, 0, 0x2000);
-                }
-            }
+    fn ram_bank_size(&self) -> usize {
+        if self.mbc == MbcKind::Mbc2 {
+            // Only the 512 nibbles of the built-in RAM are saved
+            return MBC2_RAM_SIZE;
+        }
+        return RAM_BANK_SIZE;
+    }
+
+    /**
+     * Returns the number of 8 KiB RAM banks given by the RAM size
+     * code of the header
+     * https://gbdev.io/pandocs/The_Cartridge_Header.html#0149--ram-size
+     */
+    fn ram_bank_count(ram_size: u8) -> Result<usize, CartridgeError> {
+        match ram_size {
+            0x00 => Ok(0),
+            0x02 => Ok(1),
+            0x03 => Ok(4),
+            0x04 => Ok(16),
+            0x05 => Ok(8),
+            _ => Err(CartridgeError::InvalidRamSize(ram_size)),
+        }
+    }
+
+    /**
+     * Initializes the memory banks when the cartridge is loaded.
+     * The banks of a previously loaded cartridge are dropped.
+     */
+    fn setup_banking(&mut self) -> Result<(), CartridgeError> {
+        let ram_size = unsafe { (*self.rom_header).ram_size };
+        let mut num_banks = Cartridge::ram_bank_count(ram_size)?;
+        if self.mbc == MbcKind::Mbc2 {
+            // The header reports no RAM for MBC2 since it is built into the chip.
+            // Only the first 512 bytes of the bank are used.
+            num_banks = 1;
         }
-        self.ram_bank = self.ram_banks[0];
+        self.ram_banks = vec![[0; RAM_BANK_SIZE]; num_banks];
+        self.select_ram_bank(0);
         // Sets the ROM bank to the address of the ROM data starting at 0x4000
+        self.rom_bank_value = 1;
+        self.rom_bank_x = &mut self.rom[0x4000];
+        return Ok(());
+    }
+
+    /**
+     * Maps the given RAM bank to 0xA000 - 0xBFFF. Bank numbers
+     * beyond the number of banks wrap around.
+     */
+    fn select_ram_bank(&mut self, bank: u8) -> () {
+        self.ram_bank = if self.ram_banks.is_empty() {
+            None
+        } else {
+            Some(bank as usize % self.ram_banks.len())
+        };
+    }
+
+    /**
+     * Resets the MBC registers to their power-on values. The
+     * ROM and the content of the external RAM are kept.
+     */
+    pub fn reset_banking(&mut self) -> () {
+        self.ram_enabled = false;
+        self.ram_banking = false;
+        self.banking_mode = 0;
+        self.ram_bank_value = 0;
+        self.select_ram_bank(0);
+        self.rom_bank_value = 1;
         self.rom_bank_x = &mut self.rom[0x4000];
     }
 
@@ -309,10 +667,78 @@ impl Cartridge {
     }
 
     /**
-     * Reads a byte from the ROM
+     * Verifies the Nintendo logo, which a real Game Boy
+     * refuses to boot without
+     */
+    pub fn verify_logo(&self) -> bool {
+        return unsafe { (*self.rom_header).nintendo_logo } == NINTENDO_LOGO;
+    }
+
+    /**
+     * Verifies the global checksum, the sum of all bytes of the ROM
+     * except the checksum itself, stored in big-endian at 0x014E
+     * https://gbdev.io/pandocs/The_Cartridge_Header.html#014e-014f--global-checksum
+     */
+    pub fn verify_global_checksum(&self) -> bool {
+        let mut checksum: u16 = 0;
+        for (i, byte) in self.rom.iter().enumerate() {
+            if i != 0x14E && i != 0x14F {
+                checksum = checksum.wrapping_add(*byte as u16);
+            }
+        }
+        return checksum == u16::from_be_bytes([self.rom[0x14E], self.rom[0x14F]]);
+    }
+
+    /**
+     * Refuses to load ROMs with a corrupted logo or
+     * global checksum if enabled
+     */
+    pub fn set_strict_header(&mut self, enabled: bool) -> () {
+        self.strict_header = enabled;
+    }
+
+    /**
+     * Reads a byte from the ROM or the external RAM
      */
     pub fn read(&self, address: u16) -> u8 {
-        if !self.mbc1() || address < 0x4000 {
+        match self.mbc {
+            MbcKind::Mbc1 | MbcKind::Mbc5 => self.banked_read(address),
+            MbcKind::Mbc2 => self.mbc2_read(address),
+            _ => self.rom_only_read(address),
+        }
+    }
+
+    /**
+     * Writes a byte to the cartridge, i.e., either to one of the
+     * MBC registers or to the external RAM
+     */
+    pub fn write(&mut self, address: u16, data: u8) -> () {
+        match self.mbc {
+            MbcKind::Mbc1 => self.mbc1_write(address, data),
+            MbcKind::Mbc2 => self.mbc2_write(address, data),
+            MbcKind::Mbc5 => self.mbc5_write(address, data),
+            _ => self.rom_only_write(address, data),
+        }
+    }
+
+    /**
+     * Reads from a cartridge without a (supported) MBC. Only the first
+     * 32 KiB of the ROM are accessible.
+     */
+    fn rom_only_read(&self, address: u16) -> u8 {
+        return self.rom[address as usize];
+    }
+
+    fn rom_only_write(&mut self, address: u16, _data: u8) -> () {
+        log::error!("Writing to address 0x{:04X} not supported", address);
+    }
+
+    /**
+     * Reads from a cartridge that maps a switchable ROM bank to
+     * 0x4000 - 0x7FFF and a switchable RAM bank to 0xA000 - 0xBFFF
+     */
+    fn banked_read(&self, address: u16) -> u8 {
+        if address < 0x4000 {
             return self.rom[address as usize];
         }
 
@@ -323,13 +749,12 @@ impl Cartridge {
                 return 0xFF;
             }
 
-            if self.ram_bank == std::ptr::null_mut() {
-                log::warn!("RAM bank is not set");
-                return 0xFF;
-            }
-
-            return unsafe {
-                *self.ram_bank.offset((address - 0xA000) as isize)
+            return match self.ram_bank {
+                Some(bank) => self.ram_banks[bank][(address - 0xA000) as usize],
+                None => {
+                    log::warn!("RAM bank is not set");
+                    0xFF
+                }
             };
         }
         return unsafe {
@@ -338,15 +763,10 @@ impl Cartridge {
     }
 
     /**
-     * Writes a byte to the ROM. Returns true if the write was successful,
-     * false otherwise.
+     * Handles a write to a MBC1 cartridge
+     * https://gbdev.io/pandocs/MBC1.html
      */
-    pub fn write(&mut self, address: u16, mut data: u8) -> () {
-        if !self.mbc1() {
-            log::error!("Writing to address 0x{:04X} not supported", address);
-            return;
-        }
-
+    fn mbc1_write(&mut self, address: u16, mut data: u8) -> () {
         if address < 0x2000 {
             self.ram_enabled = (data & 0x0F) == 0x0A;
             return;
@@ -359,8 +779,8 @@ impl Cartridge {
             }
             
             data &= 0b11111;
-            self.rom_bank_value = data;
-            self.rom_bank_x = &mut self.rom[(data as usize) * 0x4000];
+            self.rom_bank_value = data as u16;
+            self.switch_rom_bank();
         }
 
         if (address & 0xE000) == 0x4000 {
@@ -371,10 +791,8 @@ impl Cartridge {
                 if self.need_save() {
                     self.save_battery();
                 }
-                self.ram_bank = self.ram_banks[self.ram_bank_value as usize];
-            } else {
-                self.ram_bank = self.ram_banks[(self.ram_bank_value & 0b11) as usize];
             }
+            self.select_ram_bank(self.ram_bank_value);
         }
 
         if (address & 0xE000) == 0x6000 {
@@ -383,7 +801,7 @@ impl Cartridge {
             self.ram_banking = self.banking_mode > 0;
 
             if self.ram_banking {
-                self.ram_bank = self.ram_banks[self.ram_bank_value as usize];
+                self.select_ram_bank(self.ram_bank_value);
             }
         }
 
@@ -393,13 +811,12 @@ impl Cartridge {
                 return;
             }
 
-            if self.ram_bank == std::ptr::null_mut() {
-                log::warn!("RAM bank is not set");
-                return;
-            }
-
-            unsafe {
-                *self.ram_bank.offset((address - 0xA000) as isize) = data;
+            match self.ram_bank {
+                Some(bank) => self.ram_banks[bank][(address - 0xA000) as usize] = data,
+                None => {
+                    log::warn!("RAM bank is not set");
+                    return;
+                }
             }
         }
 
@@ -409,6 +826,104 @@ impl Cartridge {
         }
     }
 
+    /**
+     * Reads from a MBC2 cartridge. Only the lower 4 bits of the
+     * built-in RAM are used, and the RAM is mirrored every 512 bytes.
+     * https://gbdev.io/pandocs/MBC2.html
+     */
+    fn mbc2_read(&self, address: u16) -> u8 {
+        if (address & 0xE000) == 0xA000 {
+            if !self.ram_enabled {
+                return 0xFF;
+            }
+            let offset = (address as usize) & (MBC2_RAM_SIZE - 1);
+            return self.ram_banks[0][offset] & 0x0F;
+        }
+        return self.banked_read(address);
+    }
+
+    /**
+     * Handles a write to a MBC2 cartridge
+     */
+    fn mbc2_write(&mut self, address: u16, data: u8) -> () {
+        match address {
+            0x0000..=0x3FFF => {
+                // Bit 8 of the address decides the meaning of the register
+                if (address & 0x0100) == 0 {
+                    self.ram_enabled = (data & 0x0F) == 0x0A;
+                } else {
+                    let bank = data & 0x0F;
+                    self.rom_bank_value = if bank == 0 { 1 } else { bank as u16 };
+                    self.switch_rom_bank();
+                }
+            },
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+                let offset = (address as usize) & (MBC2_RAM_SIZE - 1);
+                self.ram_banks[0][offset] = data & 0x0F;
+                if self.has_battery {
+                    self.need_save = true;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /**
+     * Handles a write to a MBC5 cartridge
+     * https://gbdev.io/pandocs/MBC5.html
+     */
+    fn mbc5_write(&mut self, address: u16, data: u8) -> () {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = (data & 0x0F) == 0x0A;
+            },
+            0x2000..=0x2FFF => {
+                // Lower 8 bits of the ROM bank number. Unlike MBC1,
+                // bank 0 can be mapped to 0x4000 - 0x7FFF.
+                self.rom_bank_value = (self.rom_bank_value & 0x100) | data as u16;
+                self.switch_rom_bank();
+            },
+            0x3000..=0x3FFF => {
+                // 9th bit of the ROM bank number
+                self.rom_bank_value = (self.rom_bank_value & 0xFF) | (((data & 1) as u16) << 8);
+                self.switch_rom_bank();
+            },
+            0x4000..=0x5FFF => {
+                // On rumble cartridges bit 3 controls the motor
+                // and does not select the RAM bank
+                self.ram_bank_value = if self.has_rumble() { data & 0x07 } else { data & 0x0F };
+                if self.need_save() {
+                    self.save_battery();
+                }
+                self.select_ram_bank(self.ram_bank_value);
+            },
+            0xA000..=0xBFFF => {
+                let bank = match self.ram_bank {
+                    Some(bank) if self.ram_enabled => bank,
+                    _ => return,
+                };
+                self.ram_banks[bank][(address - 0xA000) as usize] = data;
+                if self.has_battery {
+                    self.need_save = true;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /**
+     * Maps the ROM bank selected by rom_bank_value to 0x4000 - 0x7FFF.
+     * Bank numbers beyond the size of the ROM wrap around.
+     */
+    fn switch_rom_bank(&mut self) -> () {
+        let num_banks = std::cmp::max(self.rom_size / 0x4000, 1);
+        let bank = (self.rom_bank_value as usize) % num_banks;
+        self.rom_bank_x = &mut self.rom[bank * 0x4000];
+    }
+
     /**
      * Returns whether the cartridge needs to be saved or not.
      */
@@ -418,14 +933,133 @@ impl Cartridge {
 
 
     /**
-     * Returns whether the cartridge has a memory bank controller or not.
+     * Writes the bank selection and the contents of the RAM banks
+     * to a save state. The title and the checksums of the ROM are
+     * stored so that states of other games are rejected.
      */
-    pub fn mbc1(&self) -> bool {
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
         unsafe {
-            return (*self.rom_header).cartridge_type == 0x01 ||
-                (*self.rom_header).cartridge_type == 0x02 ||
-                (*self.rom_header).cartridge_type == 0x03;
+            writer.write_bytes(&(*self.rom_header).title);
+            writer.write_u8((*self.rom_header).header_checksum);
+            writer.write_u16((*self.rom_header).global_checksum);
+        }
+        writer.write_bool(self.ram_enabled);
+        writer.write_bool(self.ram_banking);
+        writer.write_u8(self.banking_mode);
+        writer.write_u16(self.rom_bank_value);
+        writer.write_u8(self.ram_bank_value);
+        let rom_bank_offset = unsafe { self.rom_bank_x.offset_from(self.rom.as_ptr()) };
+        writer.write_u32(rom_bank_offset as u32);
+        writer.write_u8(self.ram_bank.map_or(0xFF, |i| i as u8));
+        writer.write_u8(self.ram_banks.len() as u8);
+        for bank in self.ram_banks.iter() {
+            writer.write_bytes(&bank[..self.ram_bank_size()]);
+        }
+    }
+
+    /**
+     * Restores the bank selection and the RAM banks from a save state
+     */
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let mut title = [0u8; 16];
+        reader.read_bytes(&mut title)?;
+        let header_checksum = reader.read_u8()?;
+        let global_checksum = reader.read_u16()?;
+        unsafe {
+            if title != (*self.rom_header).title ||
+                header_checksum != (*self.rom_header).header_checksum ||
+                global_checksum != (*self.rom_header).global_checksum {
+                return Err(StateError::RomMismatch);
+            }
+        }
+        self.ram_enabled = reader.read_bool()?;
+        self.ram_banking = reader.read_bool()?;
+        self.banking_mode = reader.read_u8()?;
+        self.rom_bank_value = reader.read_u16()?;
+        self.ram_bank_value = reader.read_u8()?;
+        let rom_bank_offset = reader.read_u32()? as usize;
+        if rom_bank_offset >= self.rom.len() {
+            return Err(StateError::Corrupted("ROM bank offset"));
+        }
+        self.rom_bank_x = &mut self.rom[rom_bank_offset];
+        let ram_bank_index = reader.read_u8()?;
+        if reader.read_u8()? as usize != self.ram_banks.len() {
+            return Err(StateError::Corrupted("RAM bank layout"));
+        }
+        let size = self.ram_bank_size();
+        for bank in self.ram_banks.iter_mut() {
+            reader.read_bytes(&mut bank[..size])?;
+        }
+        self.ram_bank = match ram_bank_index {
+            0xFF => None,
+            i if (i as usize) < self.ram_banks.len() => Some(i as usize),
+            _ => return Err(StateError::Corrupted("RAM bank index")),
+        };
+        if self.has_battery {
+            self.need_save = true;
+        }
+        return Ok(());
+    }
+
+    /**
+     * Returns whether the ROM supports the Game Boy Color features,
+     * i.e., bit 7 of the CGB flag (0x0143, the last byte of the title)
+     * is set.
+     */
+    pub fn supports_cgb(&self) -> bool {
+        return self.cgb_mode != CgbMode::Dmg;
+    }
+
+    /**
+     * Returns how the ROM uses the Game Boy Color
+     */
+    pub fn cgb_mode(&self) -> CgbMode {
+        return self.cgb_mode;
+    }
+
+    /**
+     * Returns the path of the ROM file the cartridge was loaded from.
+     */
+    pub fn filename(&self) -> &str {
+        return &self.filename;
+    }
+
+    /**
+     * Returns the memory bank controller of the cartridge.
+     */
+    pub fn mbc(&self) -> MbcKind {
+        return self.mbc;
+    }
+
+    /**
+     * Returns whether the cartridge has a rumble motor or not.
+     */
+    pub fn has_rumble(&self) -> bool {
+        unsafe {
+            return (0x1C..=0x1E).contains(&(*self.rom_header).cartridge_type);
+        }
+    }
+
+    /**
+     * Overrides the destination code reported by the cartridge.
+     * 0x00 = Japanese, 0x01 = Non-Japanese.
+     * Note that no emulator behavior is currently gated on the region,
+     * the override only affects what the emulator reports.
+     */
+    pub fn set_region_override(&mut self, destination_code: u8) -> () {
+        self.region_override = Some(destination_code);
+    }
+
+    /**
+     * Returns the destination code of the cartridge, i.e.,
+     * 0x00 if the game is sold in Japan, 0x01 otherwise.
+     * If a region override is set, it takes precedence over the header.
+     */
+    pub fn destination_code(&self) -> u8 {
+        if let Some(code) = self.region_override {
+            return code;
         }
+        return unsafe { (*self.rom_header).destination_code };
     }
 
     /**
@@ -458,6 +1092,8 @@ impl Cartridge {
         log::info!(target: print_target, "======= Cartridge information =======");
         log::info!(target: print_target, "  Filename: {}", self.filename);
         log::info!(target: print_target, "  ROM size: {} bytes", self.rom_size);
+        log::info!(target: print_target, "  CRC32: {:08X}", self.crc32);
+        log::info!(target: print_target, "  SHA1: {}", hash::to_hex(&self.sha1));
         unsafe {
             // Casts the title from a u8 array to a string
             let title = std::str::from_utf8_unchecked(&(*self.rom_header).title);
@@ -468,6 +1104,8 @@ impl Cartridge {
             let cartridge_type = CARTRIDGE_TYPE[&(*self.rom_header).cartridge_type];
             log::info!(target: print_target, "  Cartridge type: {} ({})",
                 (*self.rom_header).cartridge_type, cartridge_type);
+            log::info!(target: print_target, "  Memory bank controller: {:?}", self.mbc());
+            log::info!(target: print_target, "  CGB mode: {:?}", self.cgb_mode());
             // Prints the RAM size
             let ram_size = RAM_SIZE[&(*self.rom_header).ram_size];
             log::info!(target: print_target, "  RAM size: {} ({})", 
@@ -485,9 +1123,359 @@ impl Cartridge {
             }
             log::info!(target: print_target, "  License code: {} ({})",
                 license_code, license_code_str);
+            // Prints the destination code
+            let destination_code = self.destination_code();
+            let destination_str = DESTINATION_CODE.get(&destination_code)
+                .copied().unwrap_or("UNKNOWN");
+            log::info!(target: print_target, "  Destination code: {} ({}){}",
+                destination_code, destination_str,
+                if self.region_override.is_some() { " [overridden]" } else { "" });
         }
         log::info!(target: print_target, "=====================================");
     }    
 }
 
-unsafe impl Send for Cartridge {}
\ No newline at end of file
+unsafe impl Send for Cartridge {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::emulator::cpu::CPU_CTX;
+    use crate::emulator::test_util;
+
+    /**
+     * Loads the given ROM into a new cartridge
+     */
+    fn load(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let mut cartridge = Cartridge::new();
+        cartridge.load_rom_data(rom)?;
+        return Ok(cartridge);
+    }
+
+    #[test]
+    fn destination_code_is_parsed_from_the_header() {
+        let mut rom = test_util::build_rom(0x00, 0x00, 0x00, &[]);
+        rom[0x14A] = 0x00;
+        test_util::fix_checksums(&mut rom);
+        let cartridge = load(rom).unwrap();
+        assert_eq!(cartridge.destination_code(), 0x00);
+        assert_eq!(DESTINATION_CODE[&cartridge.destination_code()], "Japan (and possibly overseas)");
+
+        let cartridge = load(test_util::build_rom(0x00, 0x00, 0x00, &[])).unwrap();
+        assert_eq!(cartridge.destination_code(), 0x01);
+        assert_eq!(DESTINATION_CODE[&cartridge.destination_code()], "Overseas only");
+    }
+
+    #[test]
+    fn region_override_takes_precedence_over_the_header() {
+        let mut cartridge = load(test_util::build_rom(0x00, 0x00, 0x00, &[])).unwrap();
+        cartridge.set_region_override(0x00);
+        assert_eq!(cartridge.destination_code(), 0x00);
+    }
+
+    #[test]
+    fn rom_smaller_than_the_header_is_rejected() {
+        match load(vec![0; 0x100]) {
+            Err(CartridgeError::TooSmall(size)) => assert_eq!(size, 0x100),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("A ROM without a header was loaded"),
+        }
+    }
+
+    /**
+     * Builds a ROM of the given size code where the first two bytes
+     * of each bank hold the number of the bank
+     */
+    fn numbered_rom(cartridge_type: u8, rom_size: u8) -> Vec<u8> {
+        let mut rom = test_util::build_rom(cartridge_type, rom_size, 0x00, &[]);
+        for bank in 1..rom.len() / ROM_BANK_SIZE {
+            rom[bank * ROM_BANK_SIZE..bank * ROM_BANK_SIZE + 2].copy_from_slice(&(bank as u16).to_le_bytes());
+        }
+        test_util::fix_checksums(&mut rom);
+        return rom;
+    }
+
+    fn mapped_bank(cartridge: &Cartridge) -> u16 {
+        return u16::from_le_bytes([cartridge.read(0x4000), cartridge.read(0x4001)]);
+    }
+
+    #[test]
+    fn mbc5_selects_bank_0x1ff() {
+        // 8 MiB, the only size where bank 0x1FF exists
+        let mut cartridge = load(numbered_rom(0x19, 0x08)).unwrap();
+        cartridge.write(0x2000, 0xFF);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(mapped_bank(&cartridge), 0x1FF);
+        assert_eq!(cartridge.read(0x4002), cartridge.rom[0x1FF * ROM_BANK_SIZE + 2]);
+
+        // Bank 0 can be mapped to 0x4000 - 0x7FFF
+        cartridge.write(0x2000, 0x00);
+        cartridge.write(0x3000, 0x00);
+        assert_eq!(cartridge.read(0x4100), cartridge.rom[0x100]);
+    }
+
+    #[test]
+    fn mbc5_bank_beyond_the_rom_wraps_around() {
+        // 4 MiB, 256 banks
+        let mut cartridge = load(numbered_rom(0x19, 0x07)).unwrap();
+        cartridge.write(0x2000, 0xFF);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(mapped_bank(&cartridge), 0xFF);
+    }
+
+    #[test]
+    fn mbc1_bank_beyond_the_rom_wraps_around() {
+        // 64 KiB, 4 banks
+        let mut cartridge = load(numbered_rom(0x01, 0x01)).unwrap();
+        cartridge.write(0x2000, 0x03);
+        assert_eq!(mapped_bank(&cartridge), 3);
+        cartridge.write(0x2000, 0x05);
+        assert_eq!(mapped_bank(&cartridge), 1);
+        // Bank 0 is mapped to bank 1
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(mapped_bank(&cartridge), 1);
+    }
+
+    #[test]
+    fn mbc1_rom_boots_through_the_dispatch() {
+        let _lock = test_util::lock();
+        // LD A, 2; LD ($2000), A; LD A, ($4000)
+        let code = [0x3E, 0x02, 0xEA, 0x00, 0x20, 0xFA, 0x00, 0x40];
+        let mut rom = test_util::build_rom(0x01, 0x01, 0x00, &code);
+        rom[2 * ROM_BANK_SIZE] = 0x42;
+        test_util::fix_checksums(&mut rom);
+        test_util::boot_rom(rom);
+        unsafe {
+            assert_eq!(CARTRIDGE_CTX.mbc(), MbcKind::Mbc1);
+            for _ in 0..3 {
+                CPU_CTX.step();
+            }
+            assert_eq!(CPU_CTX.registers().a, 0x42);
+        }
+    }
+
+    #[test]
+    fn mbc2_ram_only_keeps_the_lower_nibble() {
+        let mut cartridge = load(test_util::build_rom(0x05, 0x01, 0x00, &[])).unwrap();
+        // Enables the RAM, bit 8 of the address is clear
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0x0F);
+        assert_eq!(cartridge.read(0xA000), 0x0F);
+        cartridge.write(0xA000, 0xFF);
+        assert_eq!(cartridge.read(0xA000), 0x0F);
+        cartridge.write(0xA000, 0xF0);
+        assert_eq!(cartridge.read(0xA000), 0x00);
+        // The 512 cells are mirrored up to 0xBFFF
+        cartridge.write(0xA001, 0x05);
+        assert_eq!(cartridge.read(0xA201), 0x05);
+        assert_eq!(cartridge.read(0xBE01), 0x05);
+    }
+
+    #[test]
+    fn roms_with_the_same_title_get_distinct_battery_files() {
+        let rom = test_util::build_rom(0x03, 0x00, 0x02, &[]);
+        let mut first = load(rom.clone()).unwrap();
+        let mut second = load(rom).unwrap();
+        first.filename = "games/a/tetris.gb".to_string();
+        second.filename = "games/b/tetris.gb".to_string();
+        let name = first.battery_path().file_name().unwrap().to_os_string();
+        assert_eq!(first.battery_path(), std::path::Path::new("games/a").join(&name));
+        assert_eq!(second.battery_path(), std::path::Path::new("games/b").join(&name));
+        second.set_save_dir("saves");
+        assert_eq!(second.battery_path(), std::path::Path::new("saves").join(&name));
+
+        // The name is the sanitized title and the CRC-32 of the ROM
+        let mut rom = test_util::build_rom(0x03, 0x00, 0x02, &[0x00]);
+        rom[0x134..0x138].copy_from_slice(b"A/B:");
+        test_util::fix_checksums(&mut rom);
+        let mut first = load(rom.clone()).unwrap();
+        rom[0x150] = 0x01;
+        test_util::fix_checksums(&mut rom);
+        let mut second = load(rom).unwrap();
+        let name = first.battery_path().to_string_lossy().into_owned();
+        assert_eq!(name, format!("A_B_-{:08X}.sav", first.crc32));
+        assert_ne!(first.battery_path(), second.battery_path());
+        // Even when both are loaded from files with the same name
+        first.filename = "games/tetris.gb".to_string();
+        second.filename = "games/tetris.gb".to_string();
+        assert_ne!(first.battery_path(), second.battery_path());
+    }
+
+    #[test]
+    fn battery_file_named_after_the_rom_is_still_loaded() {
+        let dir = std::env::temp_dir().join(format!("gb-emu-legacy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut cartridge = load(test_util::build_rom(0x03, 0x00, 0x02, &[])).unwrap();
+        cartridge.filename = dir.join("tetris.gb").to_string_lossy().into_owned();
+        std::fs::write(dir.join("tetris.sav"), vec![0x42; RAM_BANK_SIZE]).unwrap();
+        cartridge.load_battery();
+        assert_eq!(cartridge.ram_banks[0][0], 0x42);
+        // The save is then written under the new name
+        cartridge.save_battery();
+        let saved = cartridge.battery_path().exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(saved);
+    }
+
+    #[test]
+    fn battery_file_holds_every_ram_bank() {
+        let dir = std::env::temp_dir().join(format!("gb-emu-battery-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // MBC5 + RAM + battery with 4 banks
+        let rom = test_util::build_rom(0x1B, 0x00, 0x03, &[]);
+        let mut cartridge = load(rom.clone()).unwrap();
+        cartridge.set_save_dir(dir.to_str().unwrap());
+        for (i, bank) in cartridge.ram_banks.iter_mut().enumerate() {
+            bank[0] = i as u8 + 1;
+            bank[RAM_BANK_SIZE - 1] = 0x80 | i as u8;
+        }
+        cartridge.save_battery();
+        let path = cartridge.battery_path();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * RAM_BANK_SIZE as u64);
+        let mut loaded = load(rom.clone()).unwrap();
+        loaded.set_save_dir(dir.to_str().unwrap());
+        loaded.load_battery();
+        assert!(loaded.ram_banks == cartridge.ram_banks);
+
+        // A short file only fills the first banks
+        std::fs::write(&path, vec![0x42; RAM_BANK_SIZE + 1]).unwrap();
+        let mut loaded = load(rom).unwrap();
+        loaded.set_save_dir(dir.to_str().unwrap());
+        loaded.load_battery();
+        assert!(loaded.ram_banks[0].iter().all(|b| *b == 0x42));
+        assert_eq!(loaded.ram_banks[1][0], 0x42);
+        assert_eq!(loaded.ram_banks[1][1], 0x00);
+        assert_eq!(loaded.ram_banks[3][0], 0x00);
+
+        // MBC2 only saves its 512 cells
+        let mut mbc2 = load(test_util::build_rom(0x06, 0x00, 0x00, &[])).unwrap();
+        mbc2.set_save_dir(dir.to_str().unwrap());
+        mbc2.save_battery();
+        let size = std::fs::metadata(mbc2.battery_path()).unwrap().len();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(size, MBC2_RAM_SIZE as u64);
+    }
+
+    /**
+     * Wraps the data into a gzip file made of stored deflate blocks
+     */
+    fn gzip_stored(data: &[u8]) -> Vec<u8> {
+        let mut gzip = vec![0x1F, 0x8B, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF];
+        let mut blocks = data.chunks(0xFFFF).peekable();
+        while let Some(block) = blocks.next() {
+            // BFINAL on the last block, BTYPE 0
+            gzip.push(if blocks.peek().is_none() { 0x01 } else { 0x00 });
+            gzip.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            gzip.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            gzip.extend_from_slice(block);
+        }
+        gzip.extend_from_slice(&png::crc32(data).to_le_bytes());
+        gzip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        return gzip;
+    }
+
+    #[test]
+    fn gzipped_rom_parses_like_the_raw_one() {
+        // 128 KiB, i.e. more than one stored block
+        let mut rom = test_util::build_rom(0x01, 0x02, 0x00, &[0x3C, 0x18, 0xFE]);
+        rom[0x1FFFF] = 0x42;
+        test_util::fix_checksums(&mut rom);
+        let base = std::env::temp_dir().join(format!("gb-emu-gzip-{}", std::process::id()));
+        let raw_path = base.with_extension("gb");
+        let gzip_path = base.with_extension("gb.gz");
+        std::fs::write(&raw_path, &rom).unwrap();
+        std::fs::write(&gzip_path, gzip_stored(&rom)).unwrap();
+
+        let mut raw = Cartridge::new();
+        let mut gzip = Cartridge::new();
+        let raw_result = raw.load_rom_file(raw_path.to_str().unwrap());
+        let gzip_result = gzip.load_rom_file(gzip_path.to_str().unwrap());
+        std::fs::remove_file(&raw_path).unwrap();
+        std::fs::remove_file(&gzip_path).unwrap();
+        assert!(raw_result.is_ok() && gzip_result.is_ok());
+        assert_eq!(gzip.rom, raw.rom);
+        assert_eq!(gzip.title(), raw.title());
+        assert_eq!(gzip.checksums(), raw.checksums());
+        assert_eq!(gzip.crc32(), raw.crc32());
+        assert_eq!(gzip.cgb_mode(), raw.cgb_mode());
+        // The save is named after the ROM without the .gz
+        assert_eq!(gzip.battery_path(), raw.battery_path());
+    }
+
+    #[test]
+    fn cgb_flag_selects_the_mode() {
+        let _lock = test_util::lock();
+        for (flag, mode) in [(0x00, CgbMode::Dmg), (0x80, CgbMode::Enhanced), (0xC0, CgbMode::CgbOnly)] {
+            let mut rom = test_util::build_rom(0x00, 0x00, 0x00, &[]);
+            rom[0x143] = flag;
+            test_util::fix_checksums(&mut rom);
+            assert_eq!(load(rom.clone()).unwrap().cgb_mode(), mode);
+            test_util::boot_rom(rom);
+            assert_eq!(Emulator::is_cgb(), mode != CgbMode::Dmg);
+        }
+    }
+
+    #[test]
+    fn ram_size_code_gives_the_bank_count() {
+        let mut cartridge = Cartridge::new();
+        for (code, banks) in [(0x00, 0), (0x02, 1), (0x03, 4), (0x04, 16), (0x05, 8)] {
+            // MBC5 + RAM, reloading replaces the banks
+            cartridge.load_rom_data(test_util::build_rom(0x1A, 0x00, code, &[])).unwrap();
+            assert_eq!(cartridge.ram_banks.len(), banks, "RAM size code {:#04X}", code);
+        }
+        for code in [0x01, 0x06] {
+            match load(test_util::build_rom(0x1A, 0x00, code, &[])) {
+                Err(CartridgeError::InvalidRamSize(size)) => assert_eq!(size, code),
+                Err(err) => panic!("Unexpected error: {}", err),
+                Ok(_) => panic!("RAM size code {:#04X} was accepted", code),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_rom_file_fails_gracefully() {
+        let path = std::env::temp_dir().join(format!("gb-emu-truncated-{}.gb", std::process::id()));
+        std::fs::write(&path, [0u8; 100]).unwrap();
+        let mut cartridge = Cartridge::new();
+        let result = cartridge.load_rom_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(CartridgeError::TooSmall(100))));
+
+        // Three banks are not a power of two
+        let mut rom = test_util::build_rom(0x00, 0x00, 0x00, &[]);
+        rom.resize(3 * ROM_BANK_SIZE, 0);
+        assert!(matches!(load(rom), Err(CartridgeError::InvalidRomSize(size)) if size == 3 * ROM_BANK_SIZE));
+    }
+
+    /**
+     * Loads the given ROM with or without the strict header checks
+     */
+    fn load_strict(rom: Vec<u8>, strict: bool) -> Result<Cartridge, CartridgeError> {
+        let mut cartridge = Cartridge::new();
+        cartridge.set_strict_header(strict);
+        cartridge.load_rom_data(rom)?;
+        return Ok(cartridge);
+    }
+
+    #[test]
+    fn logo_and_global_checksum_are_only_enforced_in_strict_mode() {
+        let good = test_util::build_rom(0x00, 0x00, 0x00, &[]);
+        for strict in [false, true] {
+            let cartridge = load_strict(good.clone(), strict).unwrap();
+            assert!(cartridge.verify_logo() && cartridge.verify_global_checksum());
+        }
+
+        // The logo is not covered by the header checksum
+        let mut bad_logo = good.clone();
+        bad_logo[0x104] ^= 0xFF;
+        let cartridge = load_strict(bad_logo.clone(), false).unwrap();
+        assert!(!cartridge.verify_logo());
+        assert!(matches!(load_strict(bad_logo, true), Err(CartridgeError::InvalidLogo)));
+
+        let mut bad_checksum = good.clone();
+        bad_checksum[0x14F] ^= 0xFF;
+        let cartridge = load_strict(bad_checksum.clone(), false).unwrap();
+        assert!(cartridge.verify_logo() && !cartridge.verify_global_checksum());
+        assert!(matches!(load_strict(bad_checksum, true), Err(CartridgeError::GlobalChecksum)));
+    }
+}
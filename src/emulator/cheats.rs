@@ -0,0 +1,209 @@
+use crate::emulator::address_bus::bus_write;
+
+/**
+ * Cheat codes. Game Genie codes patch the bytes read from the
+ * cartridge ROM, GameShark codes write a value to RAM once per frame.
+ * https://gbdev.gg8.se/wiki/articles/Game_Genie
+ * https://gbdev.gg8.se/wiki/articles/GameShark
+ */
+
+/**
+ * Errors that can occur while parsing a cheat code
+ */
+#[derive(Debug)]
+pub enum CheatError {
+    // The code is neither a Game Genie nor a GameShark code
+    InvalidFormat(String),
+    // The code contains a character that is not a hex digit
+    InvalidDigit(String),
+}
+
+impl std::fmt::Display for CheatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheatError::InvalidFormat(code) =>
+                write!(f, "Invalid cheat code '{}', expected ABC-DEF, ABC-DEF-GHI \
+                    (Game Genie) or 01VVLLHH (GameShark)", code),
+            CheatError::InvalidDigit(code) =>
+                write!(f, "Invalid cheat code '{}': not a hex digit", code),
+        }
+    }
+}
+
+
+/**
+ * A Game Genie code, which replaces the byte read at the given ROM
+ * address. With a compare value, the byte is only replaced if the
+ * ROM contains that value, so that other banks are not affected.
+ */
+#[derive(Debug, PartialEq)]
+pub struct RomPatch {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+/**
+ * A GameShark code, which writes the given value every frame
+ */
+#[derive(Debug, PartialEq)]
+pub struct RamWrite {
+    pub address: u16,
+    pub value: u8,
+}
+
+/**
+ * A parsed cheat code
+ */
+#[derive(Debug, PartialEq)]
+pub enum Cheat {
+    GameGenie(RomPatch),
+    GameShark(RamWrite),
+}
+
+/**
+ * Converts a string of hex digits into their values
+ */
+fn hex_digits(code: &str, digits: &str) -> Result<Vec<u8>, CheatError> {
+    return digits.chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(CheatError::InvalidDigit(code.to_string()));
+}
+
+impl Cheat {
+    /**
+     * Parses a Game Genie code (ABC-DEF or ABC-DEF-GHI) or a
+     * GameShark code (TTVVLLHH)
+     */
+    pub fn parse(code: &str) -> Result<Cheat, CheatError> {
+        let trimmed = code.trim();
+        if trimmed.contains('-') {
+            let digits: String = trimmed.split('-').collect();
+            let groups: Vec<usize> = trimmed.split('-').map(|g| g.len()).collect();
+            if groups != [3, 3] && groups != [3, 3, 3] {
+                return Err(CheatError::InvalidFormat(code.to_string()));
+            }
+            return Ok(Cheat::GameGenie(Cheat::parse_game_genie(&hex_digits(code, &digits)?)));
+        }
+        if trimmed.len() == 8 {
+            let n = hex_digits(code, trimmed)?;
+            // The code type (n[0..2]) selects the WRAM bank on the CGB,
+            // the value and the little-endian address follow
+            return Ok(Cheat::GameShark(RamWrite {
+                value: (n[2] << 4) | n[3],
+                address: ((n[6] as u16) << 12) | ((n[7] as u16) << 8) |
+                    ((n[4] as u16) << 4) | n[5] as u16,
+            }));
+        }
+        return Err(CheatError::InvalidFormat(code.to_string()));
+    }
+
+    /**
+     * Decodes the 6 or 9 digits of a Game Genie code. The first two
+     * digits are the new value, the next four the address, with the
+     * top nibble inverted and last, and the optional last three the
+     * compare value, rotated and XORed with 0xBA (the 8th digit is
+     * not used).
+     */
+    fn parse_game_genie(n: &[u8]) -> RomPatch {
+        let value = (n[0] << 4) | n[1];
+        let address = (((n[5] ^ 0xF) as u16) << 12) | ((n[2] as u16) << 8) |
+            ((n[3] as u16) << 4) | n[4] as u16;
+        let compare = if n.len() == 9 {
+            Some(((n[6] << 4) | n[8]).rotate_right(2) ^ 0xBA)
+        } else {
+            None
+        };
+        return RomPatch { address: address, value: value, compare: compare };
+    }
+}
+
+
+/**
+ * The active cheat codes
+ */
+pub struct Cheats {
+    rom_patches: Vec<RomPatch>,
+    ram_writes: Vec<RamWrite>,
+}
+
+pub static mut CHEATS_CTX: Cheats = Cheats {
+    rom_patches: Vec::new(),
+    ram_writes: Vec::new(),
+};
+
+impl Cheats {
+    /**
+     * Parses and activates the given code
+     */
+    pub fn add(&mut self, code: &str) -> Result<(), CheatError> {
+        match Cheat::parse(code)? {
+            Cheat::GameGenie(patch) => self.rom_patches.push(patch),
+            Cheat::GameShark(write) => self.ram_writes.push(write),
+        }
+        return Ok(());
+    }
+
+    /**
+     * Returns the value read from the ROM with the Game Genie
+     * codes applied
+     */
+    #[inline(always)]
+    pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+        if self.rom_patches.is_empty() {
+            return value;
+        }
+        for patch in self.rom_patches.iter() {
+            if patch.address == address && patch.compare.is_none_or(|c| c == value) {
+                return patch.value;
+            }
+        }
+        return value;
+    }
+
+    /**
+     * Performs the writes of the GameShark codes, called once per frame
+     */
+    pub fn apply_ram_writes(&self) -> () {
+        for write in self.ram_writes.iter() {
+            bus_write(write.address, write.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::address_bus::bus_read;
+    use crate::emulator::test_util;
+
+    #[test]
+    fn game_genie_and_gameshark_codes_are_parsed() {
+        assert_eq!(Cheat::parse("3E1-50F").unwrap(),
+            Cheat::GameGenie(RomPatch { address: 0x0150, value: 0x3E, compare: None }));
+        assert_eq!(Cheat::parse(" 3e1-50f-e6a ").unwrap(),
+            Cheat::GameGenie(RomPatch { address: 0x0150, value: 0x3E, compare: Some(0x00) }));
+        assert_eq!(Cheat::parse("014234C1").unwrap(),
+            Cheat::GameShark(RamWrite { address: 0xC134, value: 0x42 }));
+        assert!(matches!(Cheat::parse("3E1-50"), Err(CheatError::InvalidFormat(_))));
+        assert!(matches!(Cheat::parse("3G1-50F"), Err(CheatError::InvalidDigit(_))));
+        assert!(matches!(Cheat::parse("0142"), Err(CheatError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn game_genie_code_alters_a_rom_read() {
+        let _lock = test_util::lock();
+        test_util::boot(&[0x00, 0x00]);
+        let patched = unsafe {
+            // The second code does not match the content of the ROM
+            CHEATS_CTX.add("3E1-50F-E6A").unwrap();
+            CHEATS_CTX.add("3E1-51F-E6E").unwrap();
+            let patched = (bus_read(0x0150), bus_read(0x0151));
+            CHEATS_CTX.rom_patches.clear();
+            patched
+        };
+        assert_eq!(patched, (0x3E, 0x00));
+        assert_eq!(bus_read(0x0150), 0x00);
+    }
+}
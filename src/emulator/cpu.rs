@@ -6,9 +6,16 @@ use instruction::*;
 use crate::emulator::Emulator;
 use crate::emulator::address_bus::*;
 use crate::emulator::dbg::*;
+use crate::emulator::debugger::DEBUGGER_CTX;
+use crate::emulator::gamepad::GAMEPAD_CTX;
+use crate::emulator::timer::{TIMER_CTX, DIV_ADDR};
+use crate::emulator::savestate::*;
+use crate::emulator::error::{fatal, EmuError};
 use self::interrupts::handle_interrupts;
 
 pub mod interrupts;
+#[cfg(test)]
+mod single_step;
 
 
 const Z_FLAG: u8 = 0x80;
@@ -17,23 +24,202 @@ const H_FLAG: u8 = 0x20;
 const C_FLAG: u8 = 0x10;
 
 pub const INT_FLAGS_ADDR: u16 = 0xFF0F;
+// Only the 5 lower bits of IF and IE are connected to interrupts
+pub const INT_MASK: u8 = 0x1F;
 
-struct Registers {
+/**
+ * Format of the lines written to the trace file
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TraceFormat {
+    // Cycle count, disassembly, opcode bytes and registers
+    Default,
+    // The format of Gameboy Doctor, with the registers before each
+    // instruction, e.g. A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D
+    // SP:FFFE PC:0100 PCMEM:00,C3,13,02
+    // https://github.com/robert/gameboy-doctor
+    Doctor,
+}
+
+/**
+ * The register file of the CPU
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Registers {
     /* 8-bit Registers */
-    a: u8,
-    f: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-    h: u8,
-    l: u8,
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
     /* Program counter */
-    pc: u16,
+    pub pc: u16,
     /* Stack pointer */
-    sp: u16,
+    pub sp: u16,
+}
+
+impl Registers {
+    // Values left by the DMG boot ROM
+    pub const DMG_POST_BOOT: Registers = Registers {
+        a: 0x01, f: 0xB0, b: 0x00, c: 0x13,
+        d: 0x00, e: 0xD8, h: 0x01, l: 0x4D,
+        pc: 0x100, sp: 0xFFFE
+    };
+    // Values left by the CGB boot ROM for a CGB game
+    pub const CGB_POST_BOOT: Registers = Registers {
+        a: 0x11, f: 0x80, b: 0x00, c: 0x00,
+        d: 0xFF, e: 0x56, h: 0x00, l: 0x0D,
+        pc: 0x100, sp: 0xFFFE
+    };
+
+    /**
+     * Sets a register by its name (A, F, B, C, D, E, H, L, AF, BC,
+     * DE, HL, SP or PC), ignoring the case
+     */
+    pub fn set_by_name(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let name = name.to_ascii_uppercase();
+        let is_16_bit = name.len() == 2;
+        if !is_16_bit && value > 0xFF {
+            return Err(format!("Value 0x{:X} does not fit into register {}", value, name));
+        }
+        let (hi, lo) = ((value >> 8) as u8, value as u8);
+        match name.as_str() {
+            "A" => self.a = lo,
+            "F" => self.f = lo,
+            "B" => self.b = lo,
+            "C" => self.c = lo,
+            "D" => self.d = lo,
+            "E" => self.e = lo,
+            "H" => self.h = lo,
+            "L" => self.l = lo,
+            "AF" => { self.a = hi; self.f = lo; },
+            "BC" => { self.b = hi; self.c = lo; },
+            "DE" => { self.d = hi; self.e = lo; },
+            "HL" => { self.h = hi; self.l = lo; },
+            "SP" => self.sp = value,
+            "PC" => self.pc = value,
+            _ => return Err(format!("Unknown register '{}'", name)),
+        }
+        return Ok(());
+    }
+}
+
+/**
+ * Parses a comma-separated list of register values, e.g.
+ * `A=11,F=80,SP=FFFE`. Values are hexadecimal, optionally
+ * prefixed with `$` or `0x`.
+ */
+pub fn parse_register_overrides(spec: &str) -> Result<Vec<(String, u16)>, String> {
+    let mut overrides = Vec::new();
+    let mut registers = Registers::DMG_POST_BOOT;
+    for item in spec.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+        let (name, value) = item.split_once('=')
+            .ok_or(format!("Expected <register>=<value>, got '{}'", item))?;
+        let digits = value.trim().trim_start_matches('$').trim_start_matches("0x");
+        let value = u16::from_str_radix(digits, 16)
+            .map_err(|_| format!("Invalid value '{}' for register {}", value, name))?;
+        // Rejects unknown registers and values that are too large
+        registers.set_by_name(name.trim(), value)?;
+        overrides.push((name.trim().to_string(), value));
+    }
+    return Ok(overrides);
 }
 
+/**
+ * Executes the instruction in `CPU::instr`
+ */
+type Executor = fn(&mut CPU) -> ();
+
+/**
+ * Executes a CB-prefixed instruction on the given register,
+ * bit number and current register value
+ */
+type CbExecutor = fn(&mut CPU, &RegType, u8, u8) -> ();
+
+/**
+ * The executor of each instruction type, indexed by `InstrType`.
+ * The CB-prefixed types are dispatched by exec_cb instead.
+ */
+static EXECUTORS: [Option<Executor>; INSTR_TYPE_COUNT] = {
+    let mut table: [Option<Executor>; INSTR_TYPE_COUNT] = [None; INSTR_TYPE_COUNT];
+    table[InstrType::IN_NOP as usize] = Some(CPU::exec_none);
+    // Load instructions
+    table[InstrType::IN_LD as usize] = Some(CPU::exec_ld);
+    table[InstrType::IN_LDH as usize] = Some(CPU::exec_ldh);
+
+    // Arithmetic instructions
+    table[InstrType::IN_INC as usize] = Some(CPU::exec_inc);
+    table[InstrType::IN_DEC as usize] = Some(CPU::exec_dec);
+    table[InstrType::IN_ADD as usize] = Some(CPU::exec_add);
+    table[InstrType::IN_ADC as usize] = Some(CPU::exec_adc);
+    table[InstrType::IN_SUB as usize] = Some(CPU::exec_sub);
+    table[InstrType::IN_SBC as usize] = Some(CPU::exec_sbc);
+
+    // Bitwise instructions
+    table[InstrType::IN_XOR as usize] = Some(CPU::exec_xor);
+    table[InstrType::IN_AND as usize] = Some(CPU::exec_and);
+    table[InstrType::IN_OR as usize] = Some(CPU::exec_or);
+    table[InstrType::IN_CP as usize] = Some(CPU::exec_cp);
+
+    // Jump instructions
+    table[InstrType::IN_JP as usize] = Some(CPU::exec_jp);
+    table[InstrType::IN_JR as usize] = Some(CPU::exec_jr);
+    table[InstrType::IN_CALL as usize] = Some(CPU::exec_call);
+    table[InstrType::IN_RET as usize] = Some(CPU::exec_ret);
+    table[InstrType::IN_RETI as usize] = Some(CPU::exec_reti);
+    table[InstrType::IN_RST as usize] = Some(CPU::exec_rst);
+
+    // Misc instructions
+    table[InstrType::IN_DI as usize] = Some(CPU::exec_di);
+    table[InstrType::IN_CB as usize] = Some(CPU::exec_cb);
+    table[InstrType::IN_RLCA as usize] = Some(CPU::exec_rlca);
+    table[InstrType::IN_RLA as usize] = Some(CPU::exec_rla);
+    table[InstrType::IN_RRCA as usize] = Some(CPU::exec_rrca);
+    table[InstrType::IN_RRA as usize] = Some(CPU::exec_rra);
+    table[InstrType::IN_CPL as usize] = Some(CPU::exec_cpl);
+    table[InstrType::IN_CCF as usize] = Some(CPU::exec_ccf);
+    table[InstrType::IN_SCF as usize] = Some(CPU::exec_scf);
+    table[InstrType::IN_DAA as usize] = Some(CPU::exec_daa);
+    table[InstrType::IN_HALT as usize] = Some(CPU::exec_halt);
+    table[InstrType::IN_STOP as usize] = Some(CPU::exec_stop);
+    table[InstrType::IN_EI as usize] = Some(CPU::exec_ei);
+    table[InstrType::IN_ERR as usize] = Some(CPU::exec_err);
+
+    // Stack-related instructions
+    table[InstrType::IN_PUSH as usize] = Some(CPU::exec_push);
+    table[InstrType::IN_POP as usize] = Some(CPU::exec_pop);
+    table
+};
+
+/**
+ * The executor of each CB-prefixed operation, indexed by bits 3-7
+ * of the opcode: 8 rotates and shifts, then 8 BIT, 8 RES and 8 SET
+ * for each bit number.
+ */
+static CB_EXECUTORS: [CbExecutor; 32] = {
+    let mut table: [CbExecutor; 32] = [CPU::cb_rlc; 32];
+    table[1] = CPU::cb_rrc;
+    table[2] = CPU::cb_rl;
+    table[3] = CPU::cb_rr;
+    table[4] = CPU::cb_sla;
+    table[5] = CPU::cb_sra;
+    table[6] = CPU::cb_swap;
+    table[7] = CPU::cb_srl;
+    let mut i = 8;
+    while i < 32 {
+        table[i] = match i >> 3 {
+            1 => CPU::cb_bit,
+            2 => CPU::cb_res,
+            _ => CPU::cb_set,
+        };
+        i += 1;
+    }
+    table
+};
+
 /**
  * A struct that defines the CPU context
  * https://www.youtube.com/watch?v=17cdj-HYpb0&list=PLVxiWMqQvhg_yk4qy2cSC3457wZJga_e5&index=3
@@ -43,7 +229,18 @@ pub struct CPU {
     pub ticks: AtomicU64,
     // In trace mode
     trace: bool,
+    trace_format: TraceFormat,
     halted: bool,
+    // Set when HALT is executed with IME disabled and an interrupt
+    // pending, the next opcode byte is then read twice
+    halt_bug: bool,
+    // Set by STOP, the CPU stops until a button is pressed
+    stopped: bool,
+    // Set when an illegal opcode is executed, the CPU hangs forever
+    locked: bool,
+    // CGB only, KEY1 - Prepare speed switch
+    double_speed: bool,
+    speed_switch_armed: bool,
     // In stepping mode
     stepping: bool,
     // Interrupt
@@ -58,35 +255,90 @@ pub struct CPU {
     dest_is_mem: bool,
     // Current instruction
     instr: *const Instruction,
+    // Address of the current instruction
+    instr_pc: u16,
     /* Interrupt enable register */
     ie_register: u8,
-    registers: Registers
+    registers: Registers,
+    // Applied over the post-boot values on init and reset
+    register_overrides: Vec<(String, u16)>,
 }
 
-pub static mut CPU_CTX: CPU = CPU {
-    ticks: AtomicU64::new(0),
-    trace: false,
-    halted: false,
-    stepping: false,
-    interrupt_master_enabled: false,
-    enabling_ime: false,
-    int_flags: 0,
-    opcode: 0,
-    fetched_data: 0,
-    mem_dest: 0,
-    dest_is_mem: false,
-    instr: ptr::null::<Instruction>(),
-    ie_register: 0,
-    registers: Registers {
-        a: 0x01, f: 0xB0, b: 0x00, c: 0x13,
-        d: 0x00, e: 0xD8, h: 0x01, l: 0x4D,
-        pc: 0x100, sp: 0xFFFE
-    },
-};
-
+pub static mut CPU_CTX: CPU = CPU::new();
 
 
 impl CPU {
+    /**
+     * Returns a CPU in the state left by the boot ROM
+     */
+    const fn new() -> CPU {
+        CPU {
+        ticks: AtomicU64::new(0),
+        trace: false,
+        trace_format: TraceFormat::Default,
+        halted: false,
+        halt_bug: false,
+        stopped: false,
+        locked: false,
+        double_speed: false,
+        speed_switch_armed: false,
+        stepping: false,
+        interrupt_master_enabled: false,
+        enabling_ime: false,
+        int_flags: 0,
+        opcode: 0,
+        fetched_data: 0,
+        mem_dest: 0,
+        dest_is_mem: false,
+        instr: ptr::null::<Instruction>(),
+        instr_pc: 0,
+        ie_register: 0,
+        registers: Registers::DMG_POST_BOOT,
+        register_overrides: Vec::new(),
+        }
+    }
+
+    /**
+     * Resets the CPU to the state left by the boot ROM,
+     * keeping the trace settings
+     */
+    pub fn reset(&mut self) -> () {
+        let trace = self.trace;
+        let trace_format = self.trace_format;
+        let register_overrides = std::mem::take(&mut self.register_overrides);
+        *self = CPU::new();
+        self.trace = trace;
+        self.trace_format = trace_format;
+        self.register_overrides = register_overrides;
+        self.registers = self.initial_registers();
+    }
+
+    /**
+     * Sets registers that differ from the post-boot values,
+     * e.g. as returned by `parse_register_overrides`
+     */
+    pub fn set_register_overrides(&mut self, overrides: Vec<(String, u16)>) -> () {
+        self.register_overrides = overrides;
+    }
+
+    /**
+     * Returns the registers after the boot ROM of the emulated
+     * model, with the overrides applied
+     */
+    fn initial_registers(&self) -> Registers {
+        let mut registers = if Emulator::is_cgb() {
+            Registers::CGB_POST_BOOT
+        } else {
+            Registers::DMG_POST_BOOT
+        };
+        for (name, value) in self.register_overrides.iter() {
+            // Validated by parse_register_overrides
+            let _ = registers.set_by_name(name, *value);
+        }
+        registers.f &= 0xF0;
+        return registers;
+    }
+
     /**
      * Creates a new CPU instance
      */
@@ -94,10 +346,81 @@ impl CPU {
        
         log::info!("Initializing CPU...");
 
-        unsafe { CPU_CTX.trace = trace };
+        unsafe {
+            CPU_CTX.trace = trace;
+            CPU_CTX.registers = CPU_CTX.initial_registers();
+        }
         log::info!(target: "stdout", "Initializing CPU: SUCCESS");
     }
 
+    /**
+     * Sets the format of the trace file
+     */
+    pub fn set_trace_format(&mut self, format: TraceFormat) -> () {
+        self.trace_format = format;
+    }
+
+    /**
+     * Writes the state before the next instruction to the trace
+     * file in the Gameboy Doctor format
+     */
+    fn trace_doctor(&self) -> () {
+        log::trace!(target: "trace_file", "{}", self.doctor_line());
+    }
+
+    /**
+     * Returns the state before the next instruction in the
+     * Gameboy Doctor format
+     */
+    fn doctor_line(&self) -> String {
+        let regs = &self.registers;
+        let pc = regs.pc;
+        return format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} \
+            SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l,
+            regs.sp, pc,
+            bus_read(pc), bus_read(pc.wrapping_add(1)),
+            bus_read(pc.wrapping_add(2)), bus_read(pc.wrapping_add(3)));
+    }
+
+    /**
+     * Returns the line written to the trace file in the default
+     * format for the instruction at the given address, starting
+     * with the cycle count
+     */
+    fn trace_line(&self, pc: u16, instr_str: &str) -> String {
+        let pc_1 = bus_read(pc.wrapping_add(1));
+        let pc_2 = bus_read(pc.wrapping_add(2));
+        // println!("{:08X} - 0x{:04X}: {:<12} ({:02X} {:02X} {:02X}) A:{:02X} F: {}{}{}{} BC: {:02X}{:02X} DE:{:02X}{:02X} HL: {:02X}{:02X}",
+        return format!("{:08X} - 0x{:04X}: {:<12} ({:02X} {:02X} {:02X}) A: {:02X} F: {}{}{}{} BC: {:02X}{:02X} DE: {:02X}{:02X} HL: {:02X}{:02X} SP: {:04X}",
+            self.ticks.load(std::sync::atomic::Ordering::Relaxed),
+            pc, instr_str,
+            self.opcode, pc_1, pc_2,
+            self.registers.a,
+            if self.get_flag(Z_FLAG) { 'Z' } else { '-' },
+            if self.get_flag(N_FLAG) { 'N' } else { '-' },
+            if self.get_flag(H_FLAG) { 'H' } else { '-' },
+            if self.get_flag(C_FLAG) { 'C' } else { '-' },
+            self.registers.b, self.registers.c,
+            self.registers.d, self.registers.e,
+            self.registers.h, self.registers.l,
+            self.registers.sp
+        );
+    }
+
+    /**
+     * Clears the registers to their power-on values, so that the
+     * CPU starts executing the boot ROM at 0x0000
+     */
+    pub fn reset_for_boot(&mut self) -> () {
+        self.registers = Registers {
+            a: 0, f: 0, b: 0, c: 0,
+            d: 0, e: 0, h: 0, l: 0,
+            pc: 0x0000, sp: 0x0000
+        };
+    }
+
     /*****************************************
      * Functions that process instructions
      *****************************************/
@@ -133,23 +456,23 @@ impl CPU {
                 assert! ((*self.instr).reg1 == RegType::RT_HL && 
                          (*self.instr).reg2 == RegType::RT_SP);
             }
-            // Half Carry Flag (H) is set if there is a carry from bit 3
-            // to bit 4
-            let h_flag = ((self.read_reg(&RegType::RT_SP) & 0x0F) +
-                (self.fetched_data & 0x0F)) >= 0x10;
-            // Carry Flag (C) is set if there is a carry from bit 7
-            // to bit 8
-            let c_flag = ((self.read_reg(&RegType::RT_SP) & 0xFF) +
-                (self.fetched_data & 0xFF)) >= 0x100;
-            
+            let (h_flag, c_flag) =
+                CPU::sp_offset_flags(self.read_reg(&RegType::RT_SP), self.fetched_data as u8);
             self.set_flags(0, 0, h_flag as i8, c_flag as i8);
             let res: u16 = 
                 self.read_reg(&RegType::RT_SP).wrapping_add_signed((self.fetched_data as i8) as i16);
             
             self.set_register(&RegType::RT_HL, res);
+            // The addition takes an extra cycle
+            Emulator::cycles(1);
             return;
         }
 
+        if unsafe { (*self.instr).reg1 == RegType::RT_SP && (*self.instr).reg2 == RegType::RT_HL } {
+            // LD SP, HL takes an extra cycle to copy the 16-bit value
+            Emulator::cycles(1);
+        }
+
         // The most common case: setting the value of a register
         // to the fetched data
         unsafe {
@@ -195,6 +518,11 @@ impl CPU {
      * Executes the JP instruction. A wrapper function for goto_addr
      */
     fn exec_jp(&mut self) -> () {
+        if unsafe { (*self.instr).addr_mode == AddrMode::AM_R } {
+            // JP HL only loads PC from HL, without an extra cycle
+            self.set_register(&RegType::RT_PC, self.fetched_data);
+            return;
+        }
         self.goto_addr(self.fetched_data, false);
     }
     
@@ -257,6 +585,7 @@ impl CPU {
      */
     fn exec_di(&mut self) -> () {
         self.interrupt_master_enabled = false;
+        self.enabling_ime = false;
     }
 
     /**
@@ -376,7 +705,9 @@ impl CPU {
         }
 
         if unsafe { (*self.instr).reg1 == RegType::RT_SP } {
-            // Dealing with the special case of ADD SP, r8
+            // Dealing with the special case of ADD SP, r8, which
+            // takes 2 internal cycles instead of 1
+            Emulator::cycles(1);
             // Converts `fetched_data` to signed 8-bit integer
             let rel: i8 = self.fetched_data as i8;
             val = self.read_reg(&RegType::RT_SP).wrapping_add_signed(rel as i16) as u32;
@@ -405,11 +736,11 @@ impl CPU {
             }
 
             if (*self.instr).reg1 == RegType::RT_SP {
+                let (h, c) = CPU::sp_offset_flags(self.read_reg(&RegType::RT_SP),
+                    self.fetched_data as u8);
                 z_flag = 0;
-                h_flag = (((self.read_reg(&RegType::RT_SP) & 0x0F) +
-                    (self.fetched_data & 0x0F)) >= 0x10) as i8;
-                c_flag = (((self.read_reg(&RegType::RT_SP) & 0xFF) +
-                    (self.fetched_data & 0xFF)) >= 0x100) as i8;
+                h_flag = h as i8;
+                c_flag = c as i8;
             }
 
             self.set_register(&(*self.instr).reg1, (val & 0xFFFF) as u16);
@@ -418,6 +749,20 @@ impl CPU {
     }
 
 
+    /**
+     * Returns the H and C flags of ADD SP, r8 and LD HL, SP + r8.
+     * The offset is added to SP as a signed value, but the flags come
+     * from the unsigned addition of the raw byte to the low byte of
+     * SP: H on a carry from bit 3, C on a carry from bit 7. E.g.
+     * SP = 0x0001 with r8 = 0xFF (-1) gives 0x0000 with H and C set,
+     * SP = 0x0000 with r8 = 0xFF gives 0xFFFF with H and C clear.
+     */
+    fn sp_offset_flags(sp: u16, offset: u8) -> (bool, bool) {
+        let h_flag = (sp & 0x0F) + (offset as u16 & 0x0F) >= 0x10;
+        let c_flag = (sp & 0xFF) + offset as u16 >= 0x100;
+        return (h_flag, c_flag);
+    }
+
     /**
      * Executes the ADC instruction, i.e., Add with Carry
      */
@@ -455,17 +800,17 @@ impl CPU {
      * Subtract with Carry
      */
     fn exec_sbc(&mut self) -> () {
-        let c_val = self.get_flag(C_FLAG) as u8;
-        let op1 = unsafe { self.read_reg(&(*self.instr).reg1) };
-        let val = self.fetched_data + (c_val as u16);
-        
-        let z_flag = ((op1.wrapping_sub(val) as u8) == 0) as i8;
-        let h_flag = (((op1 as i32 & 0x0F).wrapping_sub(self.fetched_data as i32 & 0x0F) -
-                (c_val as i32)) < 0) as i8;
-        let c_flag = (((op1 as i32).wrapping_sub(self.fetched_data as i32) -
-                (c_val as i32)) < 0) as i8;
-        
-        unsafe { self.set_register(&(*self.instr).reg1, op1.wrapping_sub(val)) };
+        let c_val = self.get_flag(C_FLAG) as i32;
+        let op1 = unsafe { self.read_reg(&(*self.instr).reg1) } as i32;
+        let op2 = (self.fetched_data & 0xFF) as i32;
+        // result = A - operand - carry
+        let val = op1 - op2 - c_val;
+
+        let z_flag = ((val & 0xFF) == 0) as i8;
+        let h_flag = (((op1 & 0x0F) - (op2 & 0x0F) - c_val) < 0) as i8;
+        let c_flag = (val < 0) as i8;
+
+        unsafe { self.set_register(&(*self.instr).reg1, (val & 0xFF) as u16) };
         self.set_flags(z_flag, 1, h_flag, c_flag);
     }
 
@@ -507,124 +852,136 @@ impl CPU {
         Emulator::cycles(1);
     }
 
+    /**
+     * Executes a CB-prefixed instruction. Bits 3-7 of the opcode select
+     * the operation in CB_EXECUTORS, bits 0-2 select the register.
+     * All intermediate values are u8, so results are always truncated
+     * to 8 bits before the Z flag is computed.
+     */
     fn exec_cb(&mut self) -> () {
         let cb_opcode = self.fetched_data as u8;
         // On which register to perform the operation
         let reg = cb_decode_reg(cb_opcode & 0b111);
         // On which bit to perform the operation
         let bit = (cb_opcode >> 3) & 0b111;
-        // The operation to perform
-        let bit_op = (cb_opcode >> 6) & 0b11;
         let reg_val = self.read_cb_reg(reg);
 
-        Emulator::cycles(1);
-
+        // The prefix and the CB opcode have been fetched, (HL) takes
+        // a cycle to read and, except for BIT, one to write back
         if *reg == RegType::RT_HL {
-            Emulator::cycles(2);
+            Emulator::cycles(cb_cycles(cb_opcode) as u32 - 2);
         }
 
-        match bit_op {
-            1 => {
-                // BIT
-                // Copies the complement of the specified bit to the Z flag
-                let z_flag = ((reg_val & (1 << bit)) == 0) as i8;
-                self.set_flags(z_flag, 0, 1, -1);
-                return;
-            },
-            2 => {
-                // RES
-                // Resets the specified bit
-                let new_val = reg_val & !(1 << bit);
-                self.set_cb_reg(reg, new_val);
-                return;
-            },
-            3 => {
-                // SET
-                let new_val = reg_val | (1 << bit);
-                self.set_cb_reg(reg, new_val);
-                return;
-            },
-            _ => {
-                // Handle all other cases
-                let c_flag = self.get_flag(C_FLAG) as u8;
-                match bit {
-                    0 => {
-                        // RLC
-                        // Rotates the register left
-                        let mut set_c = false;
-                        let mut new_val = (reg_val << 1) & 0xFF;
-                        // If bit 7 is not set
-                        if (reg_val & (1 << 7)) != 0 {
-                            new_val |= 1;
-                            set_c = true;
-                        }
-                        self.set_cb_reg(reg, new_val);
-                        self.set_flags((new_val == 0) as i8, 0, 0, set_c as i8);
-                    },
-                    1 => {
-                        // RRC
-                        // Rotates the register right
-                        let mut new_val = reg_val >> 1;
-                        new_val |= reg_val << 7;
-                        self.set_cb_reg(reg, new_val);
-                        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 1) as i8);
-                    },
-                    2 => {
-                        // RL
-                        // Rotates the register left through the carry flag
-                        let mut new_val = reg_val << 1;
-                        new_val |= c_flag;
-
-                        self.set_cb_reg(reg, new_val);
-                        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 0x80 != 0) as i8);
-                    },
-                    3 => {
-                        // RR
-                        // Rotates the register right through the carry flag
-                        let mut new_val = reg_val >> 1;
-                        new_val |= c_flag << 7;
-
-                        self.set_cb_reg(reg, new_val);
-                        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 1) as i8);
-                    },
-                    4 => {
-                        // SLA
-                        // Shifts the register left into the carry flag
-                        let new_val = reg_val << 1;
-
-                        self.set_cb_reg(reg, new_val);
-                        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 0x80 != 0) as i8);
-                    },
-                    5 => {
-                        // SRA
-                        // Shifts the register right into the carry flag
-                        let new_val = (reg_val as i8 >> 1) as u8;
-                        self.set_cb_reg(reg, new_val);
-                        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 1) as i8);
-                    },
-                    6 => {
-                        // SWAP
-                        // Swaps the upper and lower nibbles of the register
-                        let new_val = ((reg_val & 0x0F) << 4) | ((reg_val & 0xF0) >> 4);
-                        self.set_cb_reg(reg, new_val);
-                        self.set_flags((new_val == 0) as i8, 0, 0, 0);
-                    },
-                    7 => {
-                        // SRL
-                        // Shifts the register right into the carry flag
-                        let new_val = reg_val >> 1;
-                        self.set_cb_reg(reg, new_val);
-                        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 1) as i8);
-                    },
-                    _ => {
-                        log::error!(target: "stdout",
-                            "Invalid CB instruction: {:02X}", cb_opcode);
-                        std::process::exit(-1);
-                    }
-                }
-            }
-        }
+        CB_EXECUTORS[(cb_opcode >> 3) as usize](self, reg, bit, reg_val);
+    }
+
+    /**
+     * Rotates the register left, bit 7 goes to both bit 0 and C
+     * Flags: Z 0 0 C
+     */
+    fn cb_rlc(&mut self, reg: &RegType, _bit: u8, reg_val: u8) -> () {
+        let new_val: u8 = reg_val.rotate_left(1);
+        self.set_cb_reg(reg, new_val);
+        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val >> 7) as i8);
+    }
+
+    /**
+     * Rotates the register right, bit 0 goes to both bit 7 and C
+     * Flags: Z 0 0 C
+     */
+    fn cb_rrc(&mut self, reg: &RegType, _bit: u8, reg_val: u8) -> () {
+        let new_val: u8 = reg_val.rotate_right(1);
+        self.set_cb_reg(reg, new_val);
+        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 1) as i8);
+    }
 
+    /**
+     * Rotates the register left through the carry flag,
+     * the old C goes to bit 0 and bit 7 goes to C
+     * Flags: Z 0 0 C
+     */
+    fn cb_rl(&mut self, reg: &RegType, _bit: u8, reg_val: u8) -> () {
+        let c_flag = self.get_flag(C_FLAG) as u8;
+        let new_val: u8 = (reg_val << 1) | c_flag;
+        self.set_cb_reg(reg, new_val);
+        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val >> 7) as i8);
+    }
+
+    /**
+     * Rotates the register right through the carry flag,
+     * the old C goes to bit 7 and bit 0 goes to C
+     * Flags: Z 0 0 C
+     */
+    fn cb_rr(&mut self, reg: &RegType, _bit: u8, reg_val: u8) -> () {
+        let c_flag = self.get_flag(C_FLAG) as u8;
+        let new_val: u8 = (reg_val >> 1) | (c_flag << 7);
+        self.set_cb_reg(reg, new_val);
+        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 1) as i8);
+    }
+
+    /**
+     * Shifts the register left into the carry flag, bit 0 is cleared
+     * Flags: Z 0 0 C
+     */
+    fn cb_sla(&mut self, reg: &RegType, _bit: u8, reg_val: u8) -> () {
+        let new_val: u8 = reg_val << 1;
+        self.set_cb_reg(reg, new_val);
+        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val >> 7) as i8);
+    }
+
+    /**
+     * Shifts the register right into the carry flag, keeping bit 7
+     * Flags: Z 0 0 C
+     */
+    fn cb_sra(&mut self, reg: &RegType, _bit: u8, reg_val: u8) -> () {
+        let new_val: u8 = (reg_val >> 1) | (reg_val & 0x80);
+        self.set_cb_reg(reg, new_val);
+        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 1) as i8);
+    }
+
+    /**
+     * Swaps the upper and lower nibbles of the register
+     * Flags: Z 0 0 0
+     */
+    fn cb_swap(&mut self, reg: &RegType, _bit: u8, reg_val: u8) -> () {
+        let new_val: u8 = reg_val.rotate_left(4);
+        self.set_cb_reg(reg, new_val);
+        self.set_flags((new_val == 0) as i8, 0, 0, 0);
+    }
+
+    /**
+     * Shifts the register right into the carry flag, bit 7 is cleared
+     * Flags: Z 0 0 C
+     */
+    fn cb_srl(&mut self, reg: &RegType, _bit: u8, reg_val: u8) -> () {
+        let new_val: u8 = reg_val >> 1;
+        self.set_cb_reg(reg, new_val);
+        self.set_flags((new_val == 0) as i8, 0, 0, (reg_val & 1) as i8);
+    }
+
+    /**
+     * Copies the complement of the specified bit to the Z flag
+     * Flags: Z 0 1 -
+     */
+    fn cb_bit(&mut self, _reg: &RegType, bit: u8, reg_val: u8) -> () {
+        let z_flag = ((reg_val & (1 << bit)) == 0) as i8;
+        self.set_flags(z_flag, 0, 1, -1);
+    }
+
+    /**
+     * Resets the specified bit
+     * Flags: - - - -
+     */
+    fn cb_res(&mut self, reg: &RegType, bit: u8, reg_val: u8) -> () {
+        self.set_cb_reg(reg, reg_val & !(1 << bit));
+    }
+
+    /**
+     * Sets the specified bit
+     * Flags: - - - -
+     */
+    fn cb_set(&mut self, reg: &RegType, bit: u8, reg_val: u8) -> () {
+        self.set_cb_reg(reg, reg_val | (1 << bit));
     }
 
     /**
@@ -740,18 +1097,48 @@ impl CPU {
      * Executes the HALT instruction.
      */
     fn exec_halt(&mut self) -> () {
+        if !self.interrupt_master_enabled &&
+            (self.ie_register & self.int_flags & INT_MASK) != 0 {
+            // HALT bug: the CPU does not halt and fails to
+            // increment PC after fetching the next opcode
+            log::trace!("HALT bug triggered at 0x{:04X}", self.read_reg(&RegType::RT_PC));
+            self.halt_bug = true;
+            return;
+        }
         self.halted = true;
     }
 
     /**
-     * Executes the STOP instruction.
+     * Executes the STOP instruction. The CPU is stopped until a
+     * selected joypad line goes low. The byte following the
+     * opcode has already been consumed by fetch_data.
      */
     fn exec_stop(&mut self) -> () {
         log::info!("STOP instruction executed");
-        std::process::exit(0);
+        // STOP resets the divider register
+        unsafe { TIMER_CTX.write(DIV_ADDR, 0); }
+        if self.speed_switch_armed {
+            // On CGB, STOP performs the speed switch prepared
+            // through KEY1 instead of stopping the CPU
+            self.speed_switch_armed = false;
+            self.double_speed = !self.double_speed;
+            log::info!("Switched to {} speed", if self.double_speed { "double" } else { "normal" });
+            return;
+        }
+        self.stopped = true;
     }
     
 
+    /**
+     * Executes an illegal opcode. On real hardware,
+     * the CPU locks up until it is powered off.
+     */
+    fn exec_err(&mut self) -> () {
+        log::error!(target: "stdout", "Illegal opcode 0x{:02X} at 0x{:04X}, CPU locked up",
+            self.opcode, self.read_reg(&RegType::RT_PC).wrapping_sub(1));
+        self.locked = true;
+    }
+
     /**
      * Executes the EI instruction.
      */
@@ -796,19 +1183,10 @@ impl CPU {
     fn stack_pop(&mut self) -> u8 {
         let sp_val = self.read_reg(&RegType::RT_SP);
         let data = bus_read(sp_val);
-        self.set_register(&RegType::RT_SP, sp_val + 1);
+        self.set_register(&RegType::RT_SP, sp_val.wrapping_add(1));
         return data;
     }
 
-    /**
-     * Pops a 16-bit value from the stack and returns it.
-     */
-    fn stack_pop16(&mut self) -> u16 {
-        let lo = self.stack_pop() as u16;
-        let hi = self.stack_pop() as u16;
-        return (hi << 8) | lo;
-    }
-
     /*****************************************
      * End of stack operations
      *****************************************/
@@ -819,7 +1197,7 @@ impl CPU {
      */
     #[inline(always)]
     fn increment_pc(&mut self) -> () {
-        self.registers.pc += 1;
+        self.registers.pc = self.registers.pc.wrapping_add(1);
     }
 
     /**
@@ -861,8 +1239,7 @@ impl CPU {
                 return ((hi as u16) << 8) | (lo as u16);
             }
             _ => {
-                log::error!(target: "stdout", "Register {:?} not implemented", reg);
-                std::process::exit(-1);
+                fatal(EmuError::InvalidRegister(format!("{:?}", reg)));
             }
         }
 
@@ -900,8 +1277,7 @@ impl CPU {
                 self.registers.l = (value & 0x00FF) as u8;
             },
             _ => {
-                log::error!(target: "stdout", "Register {:?} not implemented", reg);
-                std::process::exit(-1);
+                fatal(EmuError::InvalidRegister(format!("{:?}", reg)));
             }
         };
     }
@@ -953,9 +1329,32 @@ impl CPU {
         return self.ie_register;
     }
 
+    /**
+     * Returns the address of the next instruction
+     */
+    #[inline(always)]
+    pub fn get_pc(&self) -> u16 {
+        return self.registers.pc;
+    }
+
+    /**
+     * Returns the opcode of the last instruction fetched
+     */
+    pub fn get_opcode(&self) -> u8 {
+        return self.opcode;
+    }
+
+    /**
+     * Returns the address of the instruction being executed
+     */
+    pub fn get_instr_pc(&self) -> u16 {
+        return self.instr_pc;
+    }
+
     /**
      * A private function that sets the value of the interrupt
-     * enable register
+     * enable register. All 8 bits are stored and read back, only
+     * the lower 5 bits enable interrupts.
      */
     #[inline(always)]
     pub fn set_ie_register(&mut self, value: u8) -> () {
@@ -964,20 +1363,124 @@ impl CPU {
 
     /**
      * A private function that retrieves the value of the interrupt
-     * flags register
+     * flags register. Bits 5 - 7 are unused and read as 1.
      */
     #[inline(always)]
     pub fn get_int_flags(&self) -> u8 {
-        return self.int_flags;
+        return (self.int_flags & INT_MASK) | !INT_MASK;
     }
 
     /**
      * A private function that sets the value of the interrupt
-     * flags register
+     * flags register, only the lower 5 bits are stored
      */
     #[inline(always)]
     pub fn set_int_flags(&mut self, value: u8) -> () {
-        self.int_flags = value;
+        self.int_flags = value & INT_MASK;
+    }
+
+    /**
+     * Returns whether the CPU runs in CGB double speed mode
+     */
+    #[inline(always)]
+    pub fn is_double_speed(&self) -> bool {
+        return self.double_speed;
+    }
+
+    /**
+     * Reads the KEY1 register (CGB only).
+     * Bit 7: current speed, bit 0: speed switch armed
+     */
+    pub fn get_key1(&self) -> u8 {
+        return 0x7E | ((self.double_speed as u8) << 7) | (self.speed_switch_armed as u8);
+    }
+
+    /**
+     * Writes the KEY1 register (CGB only), only bit 0 is writable
+     */
+    pub fn set_key1(&mut self, value: u8) -> () {
+        self.speed_switch_armed = (value & 1) != 0;
+    }
+
+    /**
+     * Returns a copy of the register file
+     */
+    pub fn registers(&self) -> Registers {
+        return self.registers;
+    }
+
+    /**
+     * Replaces the register file, e.g. to set up the initial state
+     * of a single-step test. The lower 4 bits of F are always 0.
+     */
+    pub fn set_registers(&mut self, registers: Registers) -> () {
+        self.registers = registers;
+        self.registers.f &= 0xF0;
+    }
+
+    /**
+     * Sets whether interrupts are enabled (IME)
+     */
+    pub fn set_ime(&mut self, enabled: bool) -> () {
+        self.interrupt_master_enabled = enabled;
+        self.enabling_ime = false;
+    }
+
+    pub fn is_ime(&self) -> bool {
+        return self.interrupt_master_enabled;
+    }
+
+    /**
+     * Writes the registers and the interrupt state to a save state.
+     * Only called between two instructions, so the state of the
+     * current fetch does not need to be saved.
+     */
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_u64(self.ticks.load(std::sync::atomic::Ordering::Relaxed));
+        writer.write_bool(self.halted);
+        writer.write_bool(self.halt_bug);
+        writer.write_bool(self.stopped);
+        writer.write_bool(self.locked);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+        writer.write_bool(self.interrupt_master_enabled);
+        writer.write_bool(self.enabling_ime);
+        writer.write_u8(self.int_flags);
+        writer.write_u8(self.ie_register);
+        let regs = &self.registers;
+        writer.write_bytes(&[regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l]);
+        writer.write_u16(regs.pc);
+        writer.write_u16(regs.sp);
+    }
+
+    /**
+     * Restores the registers and the interrupt state from a save state
+     */
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ticks.store(reader.read_u64()?, std::sync::atomic::Ordering::Relaxed);
+        self.halted = reader.read_bool()?;
+        self.halt_bug = reader.read_bool()?;
+        self.stopped = reader.read_bool()?;
+        self.locked = reader.read_bool()?;
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_armed = reader.read_bool()?;
+        self.interrupt_master_enabled = reader.read_bool()?;
+        self.enabling_ime = reader.read_bool()?;
+        self.int_flags = reader.read_u8()? & INT_MASK;
+        self.ie_register = reader.read_u8()?;
+        let mut regs = [0u8; 8];
+        reader.read_bytes(&mut regs)?;
+        self.registers.a = regs[0];
+        self.registers.f = regs[1];
+        self.registers.b = regs[2];
+        self.registers.c = regs[3];
+        self.registers.d = regs[4];
+        self.registers.e = regs[5];
+        self.registers.h = regs[6];
+        self.registers.l = regs[7];
+        self.registers.pc = reader.read_u16()?;
+        self.registers.sp = reader.read_u16()?;
+        return Ok(());
     }
 
     /**
@@ -1049,6 +1552,11 @@ impl CPU {
         let pc = self.read_reg(&RegType::RT_PC);
         self.opcode = bus_read(pc);
         self.instr = Instruction::get_instruction(self.opcode);
+        if self.halt_bug {
+            // The byte after HALT is read twice
+            self.halt_bug = false;
+            return;
+        }
         self.increment_pc();
     }
 
@@ -1128,7 +1636,7 @@ impl CPU {
                     Emulator::cycles(1);
                     
                     // Sets the value of HL to HL + 1
-                    self.set_register(&RegType::RT_HL, hl_val + 1);
+                    self.set_register(&RegType::RT_HL, hl_val.wrapping_add(1));
                     return;
                 },
                 AddrMode::AM_R_HLD => {
@@ -1140,7 +1648,7 @@ impl CPU {
                     Emulator::cycles(1);
                     
                     // Sets the value of HL to HL - 1
-                    self.set_register(&RegType::RT_HL, hl_val - 1);
+                    self.set_register(&RegType::RT_HL, hl_val.wrapping_sub(1));
                     return;
                 },
                 AddrMode::AM_HLI_R => {
@@ -1165,7 +1673,7 @@ impl CPU {
                     self.mem_dest = hl_val;
                     self.dest_is_mem = true;
                     // Sets the value of HL to HL - 1
-                    self.set_register(&RegType::RT_HL, hl_val - 1);
+                    self.set_register(&RegType::RT_HL, hl_val.wrapping_sub(1));
                 },
                 AddrMode::AM_R_A8 => {
                     // Load value from memory location specified by 8-bit
@@ -1270,98 +1778,62 @@ impl CPU {
      * Executes the current instruction
      */
     fn execute(&mut self) -> () {
-        unsafe {
-            // FIXME There is no better way to do it in Rust?
-            match (*self.instr).instr_type {
-                InstrType::IN_NOP   => { self.exec_none(); },
-                // Load instructions
-                InstrType::IN_LD    => { self.exec_ld(); },
-                InstrType::IN_LDH   => { self.exec_ldh(); },
-
-                // Arithmetic instructions
-                InstrType::IN_INC   => { self.exec_inc(); },
-                InstrType::IN_DEC   => { self.exec_dec(); },
-                InstrType::IN_ADD   => { self.exec_add(); },
-                InstrType::IN_ADC   => { self.exec_adc(); },
-                InstrType::IN_SUB   => { self.exec_sub(); },
-                InstrType::IN_SBC   => { self.exec_sbc(); },
-
-                // Bitwise instructions
-                InstrType::IN_XOR   => { self.exec_xor(); },
-                InstrType::IN_AND   => { self.exec_and(); },
-                InstrType::IN_OR    => { self.exec_or(); },
-                InstrType::IN_CP    => { self.exec_cp(); },
-
-                // Jump instructions
-                InstrType::IN_JP    => { self.exec_jp(); },
-                InstrType::IN_JR    => { self.exec_jr(); },
-                InstrType::IN_CALL  => { self.exec_call(); },
-                InstrType::IN_RET   => { self.exec_ret(); },
-                InstrType::IN_RETI  => { self.exec_reti(); },
-                InstrType::IN_RST   => { self.exec_rst(); },
-
-                // Misc instructions
-                InstrType::IN_DI    => { self.exec_di(); },
-                InstrType::IN_CB    => { self.exec_cb(); }
-                InstrType::IN_RLCA  => { self.exec_rlca(); },
-                InstrType::IN_RLA   => { self.exec_rla(); },
-                InstrType::IN_RRCA  => { self.exec_rrca(); },
-                InstrType::IN_RRA   => { self.exec_rra(); },
-                InstrType::IN_CPL   => { self.exec_cpl(); },
-                InstrType::IN_CCF   => { self.exec_ccf(); },
-                InstrType::IN_SCF   => { self.exec_scf(); },
-                InstrType::IN_DAA   => { self.exec_daa(); },
-                InstrType::IN_HALT  => { self.exec_halt(); },
-                InstrType::IN_STOP  => { self.exec_stop(); },
-                InstrType::IN_EI    => { self.exec_ei(); },
-
-                // Stack-related instructions
-                InstrType::IN_PUSH  => { self.exec_push(); },
-                InstrType::IN_POP   => { self.exec_pop(); },
-                _ => {
-                    log::error!(target: "stdout", "Instruction {:?} not implemented",
-                        (*self.instr).instr_type);
-                    std::process::exit(-1);
-                }
-            }
-            
+        let instr_type = unsafe { (*self.instr).instr_type };
+        match EXECUTORS[instr_type as usize] {
+            Some(executor) => executor(self),
+            None => fatal(EmuError::UnimplementedInstruction(format!("{:?}", instr_type))),
         }
     }
-    
+
     /*****************************************
      * Executes a single instruction
      *****************************************/
     pub fn step(&mut self) -> bool {
+        if self.locked {
+            // The CPU no longer executes instructions
+            // but the other components keep running
+            Emulator::cycles(1);
+            return true;
+        }
+
+        // Whether EI was executed by the previous instruction
+        let ime_pending = self.enabling_ime;
+
+        if self.stopped {
+            // Only a button press can bring the CPU back, time keeps
+            // passing for the other components meanwhile
+            if unsafe { GAMEPAD_CTX.get_output() } & 0x0F == 0x0F {
+                Emulator::cycles(1);
+                return true;
+            }
+            log::info!("Resuming from STOP");
+            self.stopped = false;
+        }
+
         if !self.halted {
             let pc = self.read_reg(&RegType::RT_PC);
+            self.instr_pc = pc;
+            unsafe { DEBUGGER_CTX.check_breakpoint(self); }
+            let trace_default = self.trace && self.trace_format == TraceFormat::Default;
+            if self.trace && self.trace_format == TraceFormat::Doctor {
+                self.trace_doctor();
+            }
 
             // Fetch and Decode
             self.fetch_instruction();
+            // The operands are disassembled before they are fetched
+            let instr_str = if trace_default {
+                unsafe { (*self.instr).disass(self) }
+            } else {
+                String::new()
+            };
             Emulator::cycles(1);
             // Execute
             self.fetch_data();
-            if self.trace {
-                let instr_str = unsafe { (*self.instr).disass(self) };
-                let pc_1 = bus_read(pc + 1);
-                let pc_2 = bus_read(pc + 2);
-                // log::trace!(target: "trace_file", "{:08X} - 0x{:04X}: {:<12} ({:02X} {:02X} {:02X}) A:{:02X} F: {}{}{}{} BC: {:02X}{:02X} DE:{:02X}{:02X} HL: {:02X}{:02X}",
-                // println!("{:08X} - 0x{:04X}: {:<12} ({:02X} {:02X} {:02X}) A:{:02X} F: {}{}{}{} BC: {:02X}{:02X} DE:{:02X}{:02X} HL: {:02X}{:02X}",
-                log::trace!(target: "trace_file", "0x{:04X}: {:<12} ({:02X} {:02X} {:02X}) A: {:02X} F: {}{}{}{} BC: {:02X}{:02X} DE: {:02X}{:02X} HL: {:02X}{:02X}",
-                            // self.ticks.load(Ordering::SeqCst),
-                            pc, instr_str,
-                            self.opcode, pc_1, pc_2,
-                            self.registers.a,
-                            if self.get_flag(Z_FLAG) { 'Z' } else { '-' },
-                            if self.get_flag(N_FLAG) { 'N' } else { '-' },
-                            if self.get_flag(H_FLAG) { 'H' } else { '-' },
-                            if self.get_flag(C_FLAG) { 'C' } else { '-' },
-                            self.registers.b, self.registers.c,
-                            self.registers.d, self.registers.e,
-                            self.registers.h, self.registers.l
-                        );
+            if trace_default {
+                log::trace!(target: "trace_file", "{}", self.trace_line(pc, &instr_str));
             }
 
-            dbg_update();
             dbg_print();
 
             self.execute();
@@ -1373,13 +1845,15 @@ impl CPU {
             }
         }
 
-        if self.interrupt_master_enabled {
-             handle_interrupts();
-             self.enabling_ime = false;
+        // EI only takes effect after the instruction following it
+        // has completed. A DI in between cancels the pending EI.
+        if ime_pending && self.enabling_ime {
+            self.interrupt_master_enabled = true;
+            self.enabling_ime = false;
         }
 
-        if self.enabling_ime {
-            self.interrupt_master_enabled = true;
+        if self.interrupt_master_enabled {
+             handle_interrupts();
         }
 
         return true;
@@ -1389,6 +1863,13 @@ impl CPU {
      * Dumps the CPU state
      */
     pub fn print_state(&self, logger: &str) -> () {
+        log::debug!(target: logger, "{}", self.state_str());
+    }
+
+    /**
+     * Returns the registers and flags of the CPU as a string
+     */
+    pub fn state_str(&self) -> String {
         let mut state = String::new();
         state.push_str(&format!("======= CPU state =======\n"));
         state.push_str(&format!("A : 0x{:02X}\t", self.registers.a));
@@ -1396,19 +1877,437 @@ impl CPU {
         state.push_str(&format!("DE: 0x{:02X}{:02X}\n", self.registers.d, self.registers.e));
         state.push_str(&format!("HL: 0x{:02X}{:02X}\t", self.registers.h, self.registers.l));
         state.push_str(&format!("PC: 0x{:04X}\t", self.registers.pc));
-        state.push_str(&format!("SP: 0x{:04X}", self.registers.sp));
-        log::debug!(target: logger, "{}", state);
-        self.print_flags(logger);
+        state.push_str(&format!("SP: 0x{:04X}\n", self.registers.sp));
+        state.push_str(&self.flags_str());
+        return state;
     }
 
     /**
-     * Prints all the flags in register f.
+     * Returns all the flags in register f as a string
      */
-    pub fn print_flags(&self, logger: &str) -> () {
-        log::debug!(target: logger, "Flags: {}{}{}{}",
+    pub fn flags_str(&self) -> String {
+        return format!("Flags: {}{}{}{}",
             if self.get_flag(Z_FLAG) { 'Z' } else { '-' },
             if self.get_flag(N_FLAG) { 'N' } else { '-' },
             if self.get_flag(H_FLAG) { 'H' } else { '-' },
             if self.get_flag(C_FLAG) { 'C' } else { '-' });
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::ppu::PPU_CTX;
+    use crate::emulator::test_util;
+
+    /**
+     * Runs the given number of instructions of the code on the flat
+     * memory, after setting up the registers, and returns the registers
+     */
+    fn run(code: &[u8], steps: usize, setup: impl FnOnce(&mut Registers)) -> Registers {
+        test_util::flat_cpu(code);
+        return resume(steps, setup);
+    }
+
+    /**
+     * Runs the given number of instructions from the current state,
+     * after setting up the registers, and returns the registers
+     */
+    fn resume(steps: usize, setup: impl FnOnce(&mut Registers)) -> Registers {
+        unsafe {
+            let mut registers = CPU_CTX.registers();
+            setup(&mut registers);
+            CPU_CTX.set_registers(registers);
+            for _ in 0..steps {
+                CPU_CTX.step();
+            }
+            return CPU_CTX.registers();
+        }
+    }
+
+    /**
+     * Runs a single instruction operating on A and B
+     */
+    fn run_a_b(code: &[u8], a: u8, b: u8, f: u8) -> (u8, u8) {
+        let registers = run(code, 1, |r| { r.a = a; r.b = b; r.f = f; });
+        return (registers.a, registers.f);
+    }
+
+    #[test]
+    fn add_a_b_sets_the_result_and_flags() {
+        let _lock = test_util::lock();
+        // ADD A, B
+        assert_eq!(run_a_b(&[0x80], 0x12, 0x34, Z_FLAG | N_FLAG | H_FLAG | C_FLAG), (0x46, 0));
+        assert_eq!(run_a_b(&[0x80], 0x0F, 0x01, 0), (0x10, H_FLAG));
+        assert_eq!(run_a_b(&[0x80], 0xF0, 0x20, 0), (0x10, C_FLAG));
+        assert_eq!(run_a_b(&[0x80], 0x3A, 0xC6, 0), (0x00, Z_FLAG | H_FLAG | C_FLAG));
+        // The carry is not added
+        assert_eq!(run_a_b(&[0x80], 0x01, 0x01, C_FLAG), (0x02, 0));
+    }
+
+    #[test]
+    fn sub_a_b_sets_the_result_and_flags() {
+        let _lock = test_util::lock();
+        // SUB A, B
+        assert_eq!(run_a_b(&[0x90], 0x3E, 0x3E, 0), (0x00, Z_FLAG | N_FLAG));
+        assert_eq!(run_a_b(&[0x90], 0x3E, 0x0F, 0), (0x2F, N_FLAG | H_FLAG));
+        assert_eq!(run_a_b(&[0x90], 0x3E, 0x40, 0), (0xFE, N_FLAG | C_FLAG));
+        assert_eq!(run_a_b(&[0x90], 0x00, 0x01, 0), (0xFF, N_FLAG | H_FLAG | C_FLAG));
+        // The carry is not subtracted
+        assert_eq!(run_a_b(&[0x90], 0x05, 0x01, C_FLAG), (0x04, N_FLAG));
+    }
+
+    #[test]
+    fn sp_plus_negative_offset_uses_the_unsigned_low_byte_for_the_flags() {
+        let _lock = test_util::lock();
+        let all_flags = Z_FLAG | N_FLAG | H_FLAG | C_FLAG;
+        // ADD SP, -1
+        let registers = run(&[0xE8, 0xFF], 1, |r| { r.sp = 0xFFF8; r.f = all_flags; });
+        assert_eq!((registers.sp, registers.f), (0xFFF7, H_FLAG | C_FLAG));
+        let registers = run(&[0xE8, 0xFF], 1, |r| { r.sp = 0x0000; r.f = all_flags; });
+        assert_eq!((registers.sp, registers.f), (0xFFFF, 0));
+        // LD HL, SP - 1, Z stays clear for a zero result
+        let registers = run(&[0xF8, 0xFF], 1, |r| { r.sp = 0x0001; r.f = all_flags; });
+        assert_eq!((registers.h, registers.l, registers.sp, registers.f),
+            (0x00, 0x00, 0x0001, H_FLAG | C_FLAG));
+        let registers = run(&[0xF8, 0xFF], 1, |r| { r.sp = 0xD000; r.f = 0; });
+        assert_eq!((registers.h, registers.l, registers.f), (0xCF, 0xFF, 0));
+    }
+
+    #[test]
+    fn register_overrides_apply_over_the_post_boot_values() {
+        let _lock = test_util::lock();
+        unsafe {
+            CPU_CTX.set_register_overrides(parse_register_overrides("A=11, sp=$D000").unwrap());
+            test_util::boot(&[]);
+            assert_eq!(CPU_CTX.read_reg(&RegType::RT_A), 0x11);
+            assert_eq!(CPU_CTX.read_reg(&RegType::RT_SP), 0xD000);
+            assert_eq!(CPU_CTX.read_reg(&RegType::RT_BC), 0x0013);
+            CPU_CTX.set_register_overrides(Vec::new());
+
+            // A CGB game starts with the values of the CGB boot ROM
+            let mut rom = test_util::build_rom(0x00, 0x00, 0x00, &[]);
+            rom[0x143] = 0x80;
+            test_util::fix_checksums(&mut rom);
+            test_util::boot_rom(rom);
+            let registers = CPU_CTX.registers();
+            assert_eq!((registers.a, registers.f, registers.d, registers.e), (0x11, 0x80, 0xFF, 0x56));
+        }
+        assert!(parse_register_overrides("A=100").is_err());
+        assert!(parse_register_overrides("IX=0").is_err());
+        assert!(parse_register_overrides("A").is_err());
+    }
+
+    /**
+     * Runs the instruction at the start of the code with the given
+     * flags and returns the number of M-cycles it took
+     */
+    fn m_cycles(code: &[u8], f: u8) -> u64 {
+        test_util::flat_cpu(code);
+        unsafe {
+            let start_ticks = CPU_CTX.ticks.load(std::sync::atomic::Ordering::Relaxed);
+            resume(1, |r| { r.f = f; r.h = 0xC0; r.l = 0x00; r.sp = 0xD000; });
+            return (CPU_CTX.ticks.load(std::sync::atomic::Ordering::Relaxed) - start_ticks) / 4;
+        }
+    }
+
+    #[test]
+    fn instructions_take_the_cycles_of_the_opcode_table() {
+        let _lock = test_util::lock();
+        // Code, flags and whether the branch is taken
+        let cases: [(&[u8], u8, bool); 22] = [
+            (&[0x00], 0, false),                // NOP
+            (&[0x06, 0x12], 0, false),          // LD B, d8
+            (&[0x36, 0x12], 0, false),          // LD (HL), d8
+            (&[0xFA, 0x00, 0xC0], 0, false),    // LD A, (a16)
+            (&[0x08, 0x00, 0xC0], 0, false),    // LD (a16), SP
+            (&[0xC5], 0, false),                // PUSH BC
+            (&[0xC1], 0, false),                // POP BC
+            (&[0xE8, 0xFF], 0, false),          // ADD SP, r8
+            (&[0xE9], 0, false),                // JP (HL)
+            (&[0xCD, 0x00, 0xC0], 0, false),    // CALL a16
+            (&[0xC9], 0, false),                // RET
+            (&[0x20, 0x05], 0, true),           // JR NZ, r8
+            (&[0x20, 0x05], Z_FLAG, false),
+            (&[0xCA, 0x00, 0xC0], Z_FLAG, true), // JP Z, a16
+            (&[0xCA, 0x00, 0xC0], 0, false),
+            (&[0xDC, 0x00, 0xC0], C_FLAG, true), // CALL C, a16
+            (&[0xDC, 0x00, 0xC0], 0, false),
+            (&[0xD0], 0, true),                 // RET NC
+            (&[0xD0], C_FLAG, false),
+            (&[0xCB, 0x11], 0, false),          // RL C
+            (&[0xCB, 0x46], 0, false),          // BIT 0, (HL)
+            (&[0xCB, 0x86], 0, false),          // RES 0, (HL)
+        ];
+        for (code, f, taken) in cases {
+            let expected = if code[0] == 0xCB {
+                cb_cycles(code[1])
+            } else {
+                opcode_cycles(code[0], taken)
+            };
+            assert_eq!(m_cycles(code, f), expected as u64, "opcode {:02X?} with F = {:02X}", code, f);
+        }
+    }
+
+    #[test]
+    fn halt_bug_reads_the_next_byte_twice() {
+        let _lock = test_util::lock();
+        // HALT; INC A; NOP with a pending interrupt and IME disabled
+        run(&[0x76, 0x3C, 0x00], 0, |r| r.a = 0);
+        unsafe {
+            CPU_CTX.set_ime(false);
+            CPU_CTX.set_ie_register(0x01);
+            CPU_CTX.set_int_flags(0x01);
+            CPU_CTX.step();
+            assert!(!CPU_CTX.halted);
+            CPU_CTX.step();
+            CPU_CTX.step();
+            // INC A is executed twice and PC ends up after it
+            assert_eq!(CPU_CTX.registers().a, 2);
+            assert_eq!(CPU_CTX.get_pc(), test_util::CODE_START + 2);
+        }
+    }
+
+    #[test]
+    fn halt_without_pending_interrupt_halts() {
+        let _lock = test_util::lock();
+        run(&[0x76, 0x3C], 0, |_| ());
+        unsafe {
+            CPU_CTX.set_ime(false);
+            CPU_CTX.set_ie_register(0x01);
+            CPU_CTX.step();
+            assert!(CPU_CTX.halted);
+            assert!(!CPU_CTX.halt_bug);
+        }
+    }
+
+    #[test]
+    fn ei_enables_interrupts_after_the_next_instruction() {
+        let _lock = test_util::lock();
+        // EI; NOP; NOP
+        run(&[0xFB, 0x00, 0x00], 0, |_| ());
+        unsafe {
+            CPU_CTX.set_ime(false);
+            CPU_CTX.step();
+            assert!(!CPU_CTX.is_ime());
+            CPU_CTX.step();
+            assert!(CPU_CTX.is_ime());
+        }
+    }
+
+    #[test]
+    fn di_right_after_ei_keeps_interrupts_disabled() {
+        let _lock = test_util::lock();
+        // EI; DI; NOP
+        run(&[0xFB, 0xF3, 0x00], 0, |_| ());
+        unsafe {
+            CPU_CTX.set_ime(false);
+            for _ in 0..3 {
+                CPU_CTX.step();
+                assert!(!CPU_CTX.is_ime());
+            }
+        }
+    }
+
+    #[test]
+    fn sbc_a_b_borrows_from_the_carry() {
+        let _lock = test_util::lock();
+        // SBC A, B
+        assert_eq!(run_a_b(&[0x98], 0x00, 0x00, C_FLAG), (0xFF, N_FLAG | H_FLAG | C_FLAG));
+        assert_eq!(run_a_b(&[0x98], 0x00, 0x00, 0), (0x00, Z_FLAG | N_FLAG));
+        assert_eq!(run_a_b(&[0x98], 0x10, 0x0F, C_FLAG), (0x00, Z_FLAG | N_FLAG | H_FLAG));
+        assert_eq!(run_a_b(&[0x98], 0xFF, 0xFF, C_FLAG), (0xFF, N_FLAG | H_FLAG | C_FLAG));
+        assert_eq!(run_a_b(&[0x98], 0x3B, 0x2A, C_FLAG), (0x10, N_FLAG));
+        assert_eq!(run_a_b(&[0x98], 0x01, 0x00, C_FLAG), (0x00, Z_FLAG | N_FLAG));
+    }
+
+    #[test]
+    fn stack_wraps_around_the_address_space() {
+        let _lock = test_util::lock();
+        // POP BC from 0xFFFF reads the high byte from 0x0000
+        test_util::flat_cpu(&[0xC1]);
+        bus_write(0xFFFF, 0x34);
+        bus_write(0x0000, 0x12);
+        let registers = resume(1, |r| r.sp = 0xFFFF);
+        assert_eq!((registers.b, registers.c), (0x12, 0x34));
+        assert_eq!(registers.sp, 0x0001);
+
+        // PUSH DE from 0x0001 writes the low byte to 0xFFFF
+        let registers = run(&[0xD5], 1, |r| { r.sp = 0x0001; r.d = 0xAB; r.e = 0xCD; });
+        assert_eq!(registers.sp, 0xFFFF);
+        assert_eq!(bus_peek(0x0000), 0xAB);
+        assert_eq!(bus_peek(0xFFFF), 0xCD);
+    }
+
+    /**
+     * Returns the number of instructions executed during a whole frame
+     */
+    fn steps_per_frame() -> u32 {
+        unsafe {
+            let start_frame = PPU_CTX.curr_frame;
+            while PPU_CTX.curr_frame == start_frame {
+                CPU_CTX.step();
+            }
+            let mut steps = 0;
+            while PPU_CTX.curr_frame == start_frame + 1 {
+                CPU_CTX.step();
+                steps += 1;
+            }
+            return steps;
+        }
+    }
+
+    #[test]
+    fn double_speed_runs_twice_as_many_steps_per_frame() {
+        let _lock = test_util::lock();
+        // LD A, 1; LDH (KEY1), A; STOP; loop: JR loop
+        let mut rom = test_util::build_rom(0x00, 0x00, 0x00,
+            &[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+        rom[0x143] = 0x80;
+        test_util::fix_checksums(&mut rom);
+        test_util::boot_rom(rom);
+        assert!(Emulator::is_cgb());
+        unsafe {
+            let mut registers = CPU_CTX.registers();
+            registers.pc = test_util::CODE_START + 6;
+            CPU_CTX.set_registers(registers);
+            let normal_steps = steps_per_frame();
+
+            // Performs the speed switch
+            registers.pc = test_util::CODE_START;
+            CPU_CTX.set_registers(registers);
+            for _ in 0..3 {
+                CPU_CTX.step();
+            }
+            assert!(CPU_CTX.is_double_speed());
+            let double_steps = steps_per_frame();
+            assert!(double_steps.abs_diff(normal_steps * 2) <= 1,
+                "{} steps per frame in normal speed, {} in double speed", normal_steps, double_steps);
+        }
+    }
+
+    #[test]
+    fn trace_lines_include_sp_and_the_cycle_count() {
+        let _lock = test_util::lock();
+        // LD A, 0x12; NOP; NOP
+        run(&[0x3E, 0x12, 0x00, 0x00], 1, |r| {
+            *r = Registers { a: 0x01, f: 0xB0, b: 0x00, c: 0x13, d: 0x00, e: 0xD8,
+                h: 0x01, l: 0x4D, pc: test_util::CODE_START, sp: 0xDFF0 };
+        });
+        unsafe {
+            assert_eq!(CPU_CTX.doctor_line(),
+                "A:12 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:DFF0 PC:0152 PCMEM:00,00,00,00");
+            let ticks = CPU_CTX.ticks.load(std::sync::atomic::Ordering::Relaxed);
+            let line = CPU_CTX.trace_line(0x0152, "NOP");
+            assert!(line.starts_with(&format!("{:08X} - 0x0152: NOP", ticks)), "{}", line);
+            assert!(line.ends_with("A: 12 F: Z-HC BC: 0013 DE: 00D8 HL: 014D SP: DFF0"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn every_instruction_has_an_executor() {
+        // 0xD3 is an illegal opcode, which maps to IN_ERR
+        let instructions = INSTRUCTIONS.values().chain(std::iter::once(Instruction::get_instruction(0xD3)));
+        let mut missing: Vec<InstrType> = Vec::new();
+        for instr in instructions {
+            if EXECUTORS[instr.instr_type as usize].is_none() && !missing.contains(&instr.instr_type) {
+                missing.push(instr.instr_type);
+            }
+        }
+        assert!(missing.is_empty(), "Instructions without an executor: {:?}", missing);
+    }
+
+    /**
+     * Runs the CB-prefixed instruction on B and on (HL), checks that
+     * both give the same result and returns the value and the flags
+     */
+    fn run_cb(op: u8, value: u8, f: u8) -> (u8, u8) {
+        let registers = run(&[0xCB, op], 1, |r| { r.b = value; r.f = f; });
+        let on_reg = (registers.b, registers.f);
+
+        test_util::flat_cpu(&[0xCB, op | 0x06]);
+        bus_write(0xC000, value);
+        let registers = resume(1, |r| { r.h = 0xC0; r.l = 0x00; r.f = f; });
+        let on_mem = (bus_read(0xC000), registers.f);
+        assert_eq!(on_reg, on_mem, "CB {:02X} on B and CB {:02X} on (HL)", op, op | 0x06);
+        return on_reg;
+    }
+
+    #[test]
+    fn cb_rotates_and_shifts_set_the_result_and_flags() {
+        let _lock = test_util::lock();
+        // RLC B, bit 7 goes to bit 0 and C
+        assert_eq!(run_cb(0x00, 0x85, 0), (0x0B, C_FLAG));
+        assert_eq!(run_cb(0x00, 0x00, C_FLAG | N_FLAG | H_FLAG), (0x00, Z_FLAG));
+        // RRC B, bit 0 goes to bit 7 and C
+        assert_eq!(run_cb(0x08, 0x01, 0), (0x80, C_FLAG));
+        assert_eq!(run_cb(0x08, 0x02, C_FLAG), (0x01, 0));
+        // RL B, the old carry goes to bit 0
+        assert_eq!(run_cb(0x10, 0x01, C_FLAG), (0x03, 0));
+        assert_eq!(run_cb(0x10, 0x85, 0), (0x0A, C_FLAG));
+        // RR B, the old carry goes to bit 7
+        assert_eq!(run_cb(0x18, 0x02, C_FLAG), (0x81, 0));
+        assert_eq!(run_cb(0x18, 0x85, 0), (0x42, C_FLAG));
+        // SLA B, bit 0 is cleared whatever the carry
+        assert_eq!(run_cb(0x20, 0x41, C_FLAG), (0x82, 0));
+        assert_eq!(run_cb(0x20, 0xC0, 0), (0x80, C_FLAG));
+        // SRA B, bit 7 is kept
+        assert_eq!(run_cb(0x28, 0x81, 0), (0xC0, C_FLAG));
+        assert_eq!(run_cb(0x28, 0x42, C_FLAG), (0x21, 0));
+        // SWAP B, C is cleared
+        assert_eq!(run_cb(0x30, 0xF1, C_FLAG | N_FLAG | H_FLAG), (0x1F, 0));
+        assert_eq!(run_cb(0x30, 0x00, 0), (0x00, Z_FLAG));
+        // SRL B, bit 7 is cleared
+        assert_eq!(run_cb(0x38, 0x81, 0), (0x40, C_FLAG));
+        assert_eq!(run_cb(0x38, 0x42, C_FLAG), (0x21, 0));
+    }
+
+    #[test]
+    fn rlc_and_rrc_ignore_the_carry_unlike_rl_and_rr() {
+        let _lock = test_util::lock();
+        // With C set, only RL / RR shift it in
+        assert_eq!(run_cb(0x00, 0x40, C_FLAG), (0x80, 0));
+        assert_eq!(run_cb(0x10, 0x40, C_FLAG), (0x81, 0));
+        assert_eq!(run_cb(0x08, 0x02, C_FLAG), (0x01, 0));
+        assert_eq!(run_cb(0x18, 0x02, C_FLAG), (0x81, 0));
+        // With C clear, bit 7 / 0 only comes back with RLC / RRC
+        assert_eq!(run_cb(0x00, 0x80, 0), (0x01, C_FLAG));
+        assert_eq!(run_cb(0x10, 0x80, 0), (0x00, Z_FLAG | C_FLAG));
+        assert_eq!(run_cb(0x08, 0x01, 0), (0x80, C_FLAG));
+        assert_eq!(run_cb(0x18, 0x01, 0), (0x00, Z_FLAG | C_FLAG));
+    }
+
+    #[test]
+    fn cb_bit_res_and_set() {
+        let _lock = test_util::lock();
+        // BIT 7, B sets H, clears N and leaves C
+        assert_eq!(run_cb(0x78, 0x80, C_FLAG | N_FLAG), (0x80, H_FLAG | C_FLAG));
+        // BIT 0, B
+        assert_eq!(run_cb(0x40, 0x80, 0), (0x80, Z_FLAG | H_FLAG));
+        // BIT 3, B
+        assert_eq!(run_cb(0x58, 0xF7, 0), (0xF7, Z_FLAG | H_FLAG));
+        // RES 7, B and SET 0, B leave the flags alone
+        assert_eq!(run_cb(0xB8, 0xFF, Z_FLAG | C_FLAG), (0x7F, Z_FLAG | C_FLAG));
+        assert_eq!(run_cb(0x80, 0x00, N_FLAG), (0x00, N_FLAG));
+        assert_eq!(run_cb(0xC0, 0x00, 0), (0x01, 0));
+        assert_eq!(run_cb(0xE8, 0x01, H_FLAG), (0x21, H_FLAG));
+    }
+
+    #[test]
+    fn cb_shifts_at_the_boundaries_stay_within_8_bits() {
+        let _lock = test_util::lock();
+        // (opcode, result and flags for 0x80, result and flags for 0x01), C clear
+        let cases = [
+            (0x00, (0x01, C_FLAG), (0x02, 0)),           // RLC
+            (0x08, (0x40, 0), (0x80, C_FLAG)),           // RRC
+            (0x10, (0x00, Z_FLAG | C_FLAG), (0x02, 0)),  // RL
+            (0x18, (0x40, 0), (0x00, Z_FLAG | C_FLAG)),  // RR
+            (0x20, (0x00, Z_FLAG | C_FLAG), (0x02, 0)),  // SLA
+            (0x28, (0xC0, 0), (0x00, Z_FLAG | C_FLAG)),  // SRA
+            (0x38, (0x40, 0), (0x00, Z_FLAG | C_FLAG)),  // SRL
+        ];
+        for (op, high, low) in cases {
+            assert_eq!(run_cb(op, 0x80, 0), high, "CB {:02X} on 0x80", op);
+            assert_eq!(run_cb(op, 0x01, 0), low, "CB {:02X} on 0x01", op);
+        }
+    }
+}
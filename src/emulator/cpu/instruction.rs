@@ -1,5 +1,6 @@
 use phf::{phf_map, Map};
 use crate::emulator::cpu::CPU;
+use crate::emulator::address_bus::bus_peek;
 
 /* Addressing mode */
 #[derive(Debug, PartialEq, Eq)]
@@ -28,6 +29,22 @@ pub enum AddrMode {
     AM_R_A16,
 }
 
+impl AddrMode {
+    /**
+     * Returns the number of bytes of an instruction with this mode,
+     * i.e., the opcode and its immediate operands
+     */
+    pub const fn instruction_length(&self) -> u16 {
+        match self {
+            AddrMode::AM_R_D16 | AddrMode::AM_D16 | AddrMode::AM_D16_R |
+            AddrMode::AM_A16_R | AddrMode::AM_R_A16 => 3,
+            AddrMode::AM_R_D8 | AddrMode::AM_R_A8 | AddrMode::AM_A8_R |
+            AddrMode::AM_HL_SPR | AddrMode::AM_D8 | AddrMode::AM_MR_D8 => 2,
+            _ => 1,
+        }
+    }
+}
+
 
 /* Register type */
 #[derive(strum_macros::Display, Debug, PartialEq, Eq, PartialOrd)]
@@ -78,8 +95,23 @@ pub enum CondType {
     CT_C
 }
 
+impl CondType {
+    /**
+     * Returns a string representation of the condition type.
+     */
+    fn str(&self) -> &'static str {
+        match self {
+            CondType::CT_NONE => "",
+            CondType::CT_NZ => "NZ",
+            CondType::CT_Z => "Z",
+            CondType::CT_NC => "NC",
+            CondType::CT_C => "C",
+        }
+    }
+}
+
 /* Instruction type */
-#[derive(strum_macros::Display, Eq, PartialEq, Hash, Debug)]
+#[derive(strum_macros::Display, Eq, PartialEq, Hash, Debug, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum InstrType {
     IN_NONE,
@@ -133,6 +165,9 @@ pub enum InstrType {
     IN_SET
 }
 
+// Number of instruction types, IN_SET must remain the last variant
+pub const INSTR_TYPE_COUNT: usize = InstrType::IN_SET as usize + 1;
+
 
 /**
  * A lookup table that maps a given 8-bit integer
@@ -260,76 +295,271 @@ impl Instruction {
 
 
     /**
-     * Disassembles the instruction and returns a string representation
+     * Disassembles the instruction and returns a string representation,
+     * e.g. `JP NZ, $C350` or `LD A, ($FF44)`. Must be called right after
+     * the opcode has been fetched since the immediate operands are read
+     * from memory at PC.
      */
-    pub fn disass(&self, cpu: &mut CPU) -> String {
-        let mut result = String::new();
-        result.push_str(&format!("{} ", self.instr_type.str()));
+    pub fn disass(&self, cpu: &CPU) -> String {
+        return self.disass_at(cpu.read_reg(&RegType::RT_PC));
+    }
+
+    /**
+     * Disassembles the instruction whose operands start at the given
+     * address, i.e., the address right after the opcode
+     */
+    pub fn disass_at(&self, pc: u16) -> String {
+        let d8 = bus_peek(pc);
+        let d16 = (bus_peek(pc.wrapping_add(1)) as u16) << 8 | d8 as u16;
+        let r1 = self.reg1.str();
+        let r2 = self.reg2.str();
+
+        let mut operands: Vec<String> = Vec::new();
+        if self.cond_type != CondType::CT_NONE {
+            operands.push(self.cond_type.str().to_string());
+        }
         match self.addr_mode {
-            AddrMode::AM_IMP => { return result; },
-            AddrMode::AM_R_D16 | AddrMode::AM_R_A16 => {
-                result.push_str(&format!("{},${:04X}", self.reg1.str(), cpu.fetched_data));
+            AddrMode::AM_IMP => {
+                if self.instr_type == InstrType::IN_RST {
+                    operands.push(format!("${:02X}", self.param));
+                }
+            },
+            AddrMode::AM_R_D16 => {
+                operands.push(r1);
+                operands.push(format!("${:04X}", d16));
             },
             AddrMode::AM_R_R => {
-                result.push_str(&format!("{},{}", self.reg1.str(), self.reg2.str()));
+                operands.push(r1);
+                operands.push(r2);
             },
             AddrMode::AM_MR_R => {
-                result.push_str(&format!("({}),{}", self.reg1.str(), self.reg2.str()));
+                // LD (C), A writes to $FF00 + C
+                if self.reg1 == RegType::RT_C {
+                    operands.push("($FF00+C)".to_string());
+                } else {
+                    operands.push(format!("({})", r1));
+                }
+                operands.push(r2);
             },
             AddrMode::AM_R => {
-                result.push_str(&format!("{}", self.reg1.str()));
+                operands.push(r1);
             },
-            AddrMode::AM_R_D8 |AddrMode::AM_R_A8 => {
-                result.push_str(&format!("{},${:02X}", self.reg1.str(), cpu.fetched_data & 0xFF));
+            AddrMode::AM_R_D8 => {
+                operands.push(r1);
+                // ADD SP, r8 takes a signed operand
+                if self.reg1 == RegType::RT_SP {
+                    operands.push(signed_str(d8));
+                } else {
+                    operands.push(format!("${:02X}", d8));
+                }
             },
             AddrMode::AM_R_MR => {
-                result.push_str(&format!("{},({})", self.reg1.str(), self.reg2.str()));
+                operands.push(r1);
+                // LD A, (C) reads from $FF00 + C
+                if self.reg2 == RegType::RT_C {
+                    operands.push("($FF00+C)".to_string());
+                } else {
+                    operands.push(format!("({})", r2));
+                }
             },
             AddrMode::AM_R_HLI => {
-                result.push_str(&format!("{},(HL+)", self.reg1.str()));
+                operands.push(r1);
+                operands.push("(HL+)".to_string());
             },
             AddrMode::AM_R_HLD => {
-                result.push_str(&format!("{},(HL-)", self.reg1.str()));
+                operands.push(r1);
+                operands.push("(HL-)".to_string());
             },
             AddrMode::AM_HLI_R => {
-                result.push_str(&format!("(HL+),{}", self.reg2.str()));
+                operands.push("(HL+)".to_string());
+                operands.push(r2);
             },
             AddrMode::AM_HLD_R => {
-                result.push_str(&format!("(HL-),{}", self.reg2.str()));
+                operands.push("(HL-)".to_string());
+                operands.push(r2);
+            },
+            AddrMode::AM_R_A8 => {
+                operands.push(r1);
+                operands.push(format!("(${:04X})", 0xFF00 | d8 as u16));
             },
             AddrMode::AM_A8_R => {
-                result.push_str(&format!("${:02X},{}", (cpu.mem_dest & 0xFF) as u8, self.reg2.str()));
+                operands.push(format!("(${:04X})", 0xFF00 | d8 as u16));
+                operands.push(r2);
             },
             AddrMode::AM_HL_SPR => {
-                result.push_str(&format!("(HL),SP+{}", cpu.fetched_data & 0xFF));
+                operands.push(r1);
+                operands.push(format!("SP{}", signed_str(d8)));
             },
             AddrMode::AM_D16 => {
-                result.push_str(&format!("${:04X}", cpu.fetched_data));
+                operands.push(format!("${:04X}", d16));
             },
             AddrMode::AM_D8 => {
-                result.push_str(&format!("${:02X}", cpu.fetched_data));
-            }
+                match self.instr_type {
+                    // The target of a relative jump is shown instead of the offset
+                    InstrType::IN_JR => {
+                        let target = pc.wrapping_add(1).wrapping_add(d8 as i8 as u16);
+                        operands.push(format!("${:04X}", target));
+                    },
+                    InstrType::IN_CB => { return disass_cb(d8); },
+                    _ => operands.push(format!("${:02X}", d8)),
+                }
+            },
             AddrMode::AM_D16_R | AddrMode::AM_A16_R => {
-                result.push_str(&format!("(${:04X}),{}", cpu.fetched_data, self.reg2.str()));
+                operands.push(format!("(${:04X})", d16));
+                operands.push(r2);
+            },
+            AddrMode::AM_R_A16 => {
+                operands.push(r1);
+                operands.push(format!("(${:04X})", d16));
             },
             AddrMode::AM_MR_D8 => {
-                result.push_str(&format!("({}),${:02X}", self.reg1.str(), cpu.fetched_data));
+                operands.push(format!("({})", r1));
+                operands.push(format!("${:02X}", d8));
             },
             AddrMode::AM_MR => {
-                result.push_str(&format!("({})", self.reg1.str()));
+                operands.push(format!("({})", r1));
             }
         }
-        return result;
+
+        if operands.is_empty() {
+            return self.instr_type.str();
+        }
+        return format!("{} {}", self.instr_type.str(), operands.join(", "));
     }
 
+    /**
+     * Returns the number of bytes of the instruction. CB-prefixed
+     * instructions are 2 bytes, the prefix and the CB opcode.
+     */
+    pub fn length(&self) -> u16 {
+        if self.instr_type == InstrType::IN_CB {
+            return 2;
+        }
+        return self.addr_mode.instruction_length();
+    }
+
+    /**
+     * Returns the instruction that corresponds to the given opcode.
+     * Illegal opcodes are mapped to an IN_ERR instruction.
+     */
     pub fn get_instruction(opcode: u8) -> &'static Instruction {
         if INSTRUCTIONS.contains_key(&opcode) {
             return &INSTRUCTIONS[&opcode];
-        } else {
-            log::error!(target: "stdout", "Opcode: 0x{:02X} not implemented", opcode);
-            std::process::exit(-1);
         }
+        return &ILLEGAL_INSTRUCTION;
+    }
+}
+
+/**
+ * Number of M-cycles taken by each opcode, with conditional jumps,
+ * calls and returns not taken. Illegal opcodes are 0, CB-prefixed
+ * instructions are given by `cb_cycles`.
+ * https://gbdev.io/gb-opcodes/optables/
+ *
+ * `step` does not consume the cycles from this table. Each M-cycle
+ * is ticked by fetch_data or the executor where its memory access
+ * happens, so that the PPU, the timer and the DMA see the accesses
+ * at the right time. The table is what these cycles are checked
+ * against.
+ */
+const OPCODE_CYCLES: [u8; 256] = [
+    // 0x00 - 0x3F
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    // 0x40 - 0x7F, LD r, r
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+    // 0x80 - 0xBF, arithmetic on A
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    // 0xC0 - 0xFF
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 2, 3, 6, 2, 4,
+    2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+    3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+    3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+];
+
+/**
+ * Returns the number of M-cycles taken by the given opcode. For the
+ * conditional JR, JP, CALL and RET, `branch_taken` selects whether
+ * the condition was met, it is ignored for the other opcodes.
+ */
+pub fn opcode_cycles(opcode: u8, branch_taken: bool) -> u8 {
+    let extra = match opcode {
+        // JR cc
+        0x20 | 0x28 | 0x30 | 0x38 => 1,
+        // JP cc
+        0xC2 | 0xCA | 0xD2 | 0xDA => 1,
+        // CALL cc
+        0xC4 | 0xCC | 0xD4 | 0xDC => 3,
+        // RET cc
+        0xC0 | 0xC8 | 0xD0 | 0xD8 => 3,
+        _ => 0,
+    };
+    return OPCODE_CYCLES[opcode as usize] + if branch_taken { extra } else { 0 };
+}
+
+/**
+ * Returns the number of M-cycles taken by a CB-prefixed instruction,
+ * including the prefix. Operations on (HL) read and write memory,
+ * except BIT which only reads it.
+ */
+pub fn cb_cycles(cb_opcode: u8) -> u8 {
+    if cb_opcode & 0b111 != 6 {
+        return 2;
+    }
+    return if (0x40..0x80).contains(&cb_opcode) { 3 } else { 4 };
+}
+
+/**
+ * The instruction returned for the illegal opcodes
+ * 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD
+ */
+static ILLEGAL_INSTRUCTION: Instruction = Instruction::default(InstrType::IN_ERR, AddrMode::AM_IMP);
+
+/**
+ * Formats an 8-bit value as a signed offset, e.g. `+$05` or `-$10`
+ */
+fn signed_str(value: u8) -> String {
+    let offset = value as i8;
+    if offset < 0 {
+        return format!("-${:02X}", (offset as i16).unsigned_abs());
     }
+    return format!("+${:02X}", offset);
+}
+
+/**
+ * Disassembles a CB-prefixed instruction from the byte after the prefix
+ */
+fn disass_cb(cb_opcode: u8) -> String {
+    let reg = cb_decode_reg(cb_opcode & 0b111);
+    let target = if *reg == RegType::RT_HL { "(HL)".to_string() } else { reg.str() };
+    let bit = (cb_opcode >> 3) & 0b111;
+    let instr_type = match cb_opcode >> 6 {
+        1 => InstrType::IN_BIT,
+        2 => InstrType::IN_RES,
+        3 => InstrType::IN_SET,
+        _ => match bit {
+            0 => InstrType::IN_RLC,
+            1 => InstrType::IN_RRC,
+            2 => InstrType::IN_RL,
+            3 => InstrType::IN_RR,
+            4 => InstrType::IN_SLA,
+            5 => InstrType::IN_SRA,
+            6 => InstrType::IN_SWAP,
+            _ => InstrType::IN_SRL,
+        },
+    };
+    if cb_opcode >> 6 == 0 {
+        return format!("{} {}", instr_type.str(), target);
+    }
+    return format!("{} {}, {}", instr_type.str(), bit, target);
 }
 
 /**************************************************
@@ -787,3 +1017,74 @@ pub static INSTRUCTIONS: Map<u8, Instruction> = phf_map! {
 };
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ILLEGAL_OPCODES: [u8; 11] = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+
+    // Number of bytes of each opcode, 0 for the illegal ones
+    // https://gbdev.io/gb-opcodes/optables/
+    const OPCODE_LENGTHS: [u16; 256] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1,
+        1, 1, 3, 0, 3, 1, 2, 1, 1, 1, 3, 0, 3, 0, 2, 1,
+        2, 1, 1, 0, 0, 1, 2, 1, 2, 1, 3, 0, 0, 0, 2, 1,
+        2, 1, 1, 1, 0, 1, 2, 1, 2, 1, 3, 1, 0, 0, 2, 1,
+    ];
+
+    #[test]
+    fn lengths_follow_the_addressing_mode() {
+        // NOP, LD B, d8, JP a16 and the CB prefix
+        assert_eq!(Instruction::get_instruction(0x00).length(), 1);
+        assert_eq!(Instruction::get_instruction(0x06).length(), 2);
+        assert_eq!(Instruction::get_instruction(0xC3).length(), 3);
+        assert_eq!(Instruction::get_instruction(0xCB).length(), 2);
+        assert_eq!(AddrMode::AM_IMP.instruction_length(), 1);
+        assert_eq!(AddrMode::AM_R_D8.instruction_length(), 2);
+        assert_eq!(AddrMode::AM_D16.instruction_length(), 3);
+    }
+
+    #[test]
+    fn every_legal_opcode_is_mapped() {
+        for opcode in 0..=255u8 {
+            let instruction = Instruction::get_instruction(opcode);
+            if ILLEGAL_OPCODES.contains(&opcode) {
+                assert_eq!(instruction.instr_type, InstrType::IN_ERR, "opcode 0x{:02X}", opcode);
+                continue;
+            }
+            assert_ne!(instruction.instr_type, InstrType::IN_ERR, "opcode 0x{:02X}", opcode);
+            assert_eq!(instruction.length(), OPCODE_LENGTHS[opcode as usize],
+                "length of opcode 0x{:02X} ({:?})", opcode, instruction.addr_mode);
+            // The modes that name a register operand must have one
+            let (needs_reg1, needs_reg2) = match instruction.addr_mode {
+                AddrMode::AM_R | AddrMode::AM_R_D8 | AddrMode::AM_R_D16 |
+                AddrMode::AM_R_A8 | AddrMode::AM_R_A16 | AddrMode::AM_MR |
+                AddrMode::AM_MR_D8 => (true, false),
+                AddrMode::AM_R_R | AddrMode::AM_R_MR | AddrMode::AM_MR_R |
+                AddrMode::AM_R_HLI | AddrMode::AM_R_HLD | AddrMode::AM_HLI_R |
+                AddrMode::AM_HLD_R | AddrMode::AM_HL_SPR => (true, true),
+                AddrMode::AM_A8_R | AddrMode::AM_A16_R | AddrMode::AM_D16_R => (false, true),
+                _ => (false, false),
+            };
+            if needs_reg1 {
+                assert_ne!(instruction.reg1, RegType::RT_NONE, "reg1 of opcode 0x{:02X}", opcode);
+            }
+            if needs_reg2 {
+                assert_ne!(instruction.reg2, RegType::RT_NONE, "reg2 of opcode 0x{:02X}", opcode);
+            }
+        }
+    }
+}
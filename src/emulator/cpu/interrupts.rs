@@ -1,7 +1,6 @@
-use crate::emulator::cpu::CPU;
 use crate::emulator::cpu::instruction::RegType;
-use crate::emulator::address_bus::*;
 use crate::emulator::cpu::CPU_CTX;
+use crate::emulator::Emulator;
 
 const VBLANK_ADDR: u16 = 0x40;
 const LCD_STAT_ADDR: u16 = 0x48;
@@ -22,14 +21,22 @@ pub enum InterruptType {
 
 
 /**
- * A helper function that sets the PC to the given address
+ * A helper function that sets the PC to the given address. The
+ * dispatch takes 5 M-cycles: 2 wait cycles, one cycle for each
+ * byte of PC pushed onto the stack and one to set PC.
  */
 fn set_interrupt_addr(address: u16) -> () {
     unsafe {
-        // Pushes PC onto the stack
-        CPU_CTX.stack_push16(CPU_CTX.read_reg(&RegType::RT_PC));
+        Emulator::cycles(2);
+        // Pushes PC onto the stack, high byte first
+        let pc = CPU_CTX.read_reg(&RegType::RT_PC);
+        Emulator::cycles(1);
+        CPU_CTX.stack_push((pc >> 8) as u8);
+        Emulator::cycles(1);
+        CPU_CTX.stack_push((pc & 0xFF) as u8);
         // Sets the PC to the given address
         CPU_CTX.set_register(&RegType::RT_PC, address);
+        Emulator::cycles(1);
     }
 }
 
@@ -66,4 +73,47 @@ pub fn handle_interrupts() -> () {
 
 pub fn request_interrupt(interrupt_type: InterruptType) -> () {
     unsafe { CPU_CTX.int_flags |= interrupt_type as u8; }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use super::*;
+    use crate::emulator::address_bus::{bus_read, bus_write};
+    use crate::emulator::test_util;
+
+    #[test]
+    fn unused_bits_of_if_read_as_1_and_are_ignored() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        bus_write(0xFF0F, 0x00);
+        assert_eq!(bus_read(0xFF0F), 0xE0);
+        unsafe {
+            CPU_CTX.set_ime(true);
+            CPU_CTX.set_ie_register(0xFF);
+            bus_write(0xFF0F, 0xE0);
+            handle_interrupts();
+            assert_eq!(CPU_CTX.get_pc(), test_util::CODE_START);
+            assert!(CPU_CTX.interrupt_master_enabled);
+        }
+    }
+
+    #[test]
+    fn dispatch_takes_5_m_cycles() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        unsafe {
+            CPU_CTX.set_ime(true);
+            CPU_CTX.set_ie_register(0x04);
+            request_interrupt(InterruptType::IT_TIMER);
+            let sp = CPU_CTX.read_reg(&RegType::RT_SP);
+            let start_ticks = CPU_CTX.ticks.load(Ordering::Relaxed);
+            handle_interrupts();
+            assert_eq!(CPU_CTX.ticks.load(Ordering::Relaxed) - start_ticks, 5 * 4);
+            assert_eq!(CPU_CTX.get_pc(), TIMER_ADDR);
+            // The return address is pushed onto the stack
+            assert_eq!(CPU_CTX.read_reg(&RegType::RT_SP), sp - 2);
+            assert_eq!(bus_read(sp - 1), (test_util::CODE_START >> 8) as u8);
+            assert_eq!(bus_read(sp - 2), test_util::CODE_START as u8);
+        }
+    }
+}
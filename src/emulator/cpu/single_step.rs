@@ -0,0 +1,217 @@
+/*
+ * Runs the SM83 single step tests, which give the state of the CPU
+ * and of the memory before and after executing a single instruction,
+ * as well as the number of M-cycles it takes:
+ * https://github.com/SingleStepTests/sm83
+ * The suite has one JSON file per opcode. The files are read from the
+ * directory given by the SM83_TESTS_DIR environment variable, and the
+ * suite is skipped when it is not set. Each test runs on a flat 64 KiB
+ * memory, so that no cartridge or I/O register gets in the way.
+ */
+
+use std::sync::atomic::Ordering;
+use serde_json::Value;
+use super::*;
+use crate::emulator::test_util;
+
+// Written in the format of the suite, checks the harness itself
+const INLINE_TESTS: &str = r#"[
+    {
+        "name": "80 inline",
+        "initial": {
+            "pc": 49152, "sp": 65534, "a": 58, "b": 198, "c": 0, "d": 0,
+            "e": 0, "f": 64, "h": 0, "l": 0, "ime": 0, "ie": 0,
+            "ram": [[49152, 128]]
+        },
+        "final": {
+            "pc": 49153, "sp": 65534, "a": 0, "b": 198, "c": 0, "d": 0,
+            "e": 0, "f": 176, "h": 0, "l": 0, "ime": 0,
+            "ram": [[49152, 128]]
+        },
+        "cycles": [[49152, 128, "r-m"]]
+    },
+    {
+        "name": "ea inline",
+        "initial": {
+            "pc": 49152, "sp": 65534, "a": 66, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0,
+            "ram": [[49152, 234], [49153, 52], [49154, 210], [53812, 0]]
+        },
+        "final": {
+            "pc": 49155, "sp": 65534, "a": 66, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0,
+            "ram": [[49152, 234], [49153, 52], [49154, 210], [53812, 66]]
+        },
+        "cycles": [
+            [49153, 52, "r-m"], [49154, 210, "r-m"],
+            [53812, 66, "-wm"], [49155, 0, "r-m"]
+        ]
+    },
+    {
+        "name": "2a inline, HL wraps around to 0x0000",
+        "initial": {
+            "pc": 49152, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 255, "l": 255, "ime": 0, "ie": 0,
+            "ram": [[49152, 42], [65535, 90]]
+        },
+        "final": {
+            "pc": 49153, "sp": 65534, "a": 90, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0,
+            "ram": [[49152, 42], [65535, 90]]
+        },
+        "cycles": [[65535, 90, "r-m"], [49153, 0, "r-m"]]
+    },
+    {
+        "name": "3a inline, HL wraps around to 0xFFFF",
+        "initial": {
+            "pc": 49152, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0,
+            "ram": [[0, 119], [49152, 58]]
+        },
+        "final": {
+            "pc": 49153, "sp": 65534, "a": 119, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 255, "l": 255, "ime": 0,
+            "ram": [[0, 119], [49152, 58]]
+        },
+        "cycles": [[0, 119, "r-m"], [49153, 0, "r-m"]]
+    },
+    {
+        "name": "32 inline, HL wraps around to 0xFFFF",
+        "initial": {
+            "pc": 49152, "sp": 65534, "a": 33, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0,
+            "ram": [[0, 0], [49152, 50]]
+        },
+        "final": {
+            "pc": 49153, "sp": 65534, "a": 33, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 255, "l": 255, "ime": 0,
+            "ram": [[0, 33], [49152, 50]]
+        },
+        "cycles": [[0, 33, "-wm"], [49153, 0, "r-m"]]
+    },
+    {
+        "name": "00 inline, PC wraps around to 0x0000",
+        "initial": {
+            "pc": 65535, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0,
+            "ram": [[65535, 0]]
+        },
+        "final": {
+            "pc": 0, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0,
+            "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0,
+            "ram": [[65535, 0]]
+        },
+        "cycles": [[0, 0, "r-m"]]
+    }
+]"#;
+
+// STOP depends on the joypad and the speed switch
+const SKIPPED_OPCODES: [&str; 1] = ["10"];
+
+fn field(state: &Value, key: &str) -> u16 {
+    return state[key].as_u64().unwrap_or_else(|| panic!("Missing field {}", key)) as u16;
+}
+
+fn memory(state: &Value) -> Vec<(u16, u8)> {
+    return state["ram"].as_array().expect("Missing field ram").iter()
+        .map(|entry| (entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8))
+        .collect();
+}
+
+/**
+ * Sets up the CPU and the memory as given by the initial state
+ */
+fn load_state(state: &Value) -> () {
+    test_util::flat_cpu(&[]);
+    for (address, value) in memory(state) {
+        bus_write(address, value);
+    }
+    unsafe {
+        CPU_CTX.set_registers(Registers {
+            a: field(state, "a") as u8, f: field(state, "f") as u8,
+            b: field(state, "b") as u8, c: field(state, "c") as u8,
+            d: field(state, "d") as u8, e: field(state, "e") as u8,
+            h: field(state, "h") as u8, l: field(state, "l") as u8,
+            pc: field(state, "pc"), sp: field(state, "sp"),
+        });
+        CPU_CTX.interrupt_master_enabled = field(state, "ime") != 0;
+        // No interrupt is pending, so none is serviced after the instruction
+        CPU_CTX.int_flags = 0;
+    }
+}
+
+/**
+ * Executes the instruction of a test and compares the registers,
+ * the memory and the number of M-cycles with the expected ones
+ */
+fn run_test(test: &Value) -> () {
+    let name = test["name"].as_str().unwrap_or("?");
+    load_state(&test["initial"]);
+    let registers = unsafe {
+        let start_ticks = CPU_CTX.ticks.load(Ordering::Relaxed);
+        CPU_CTX.step();
+        let ticks = CPU_CTX.ticks.load(Ordering::Relaxed) - start_ticks;
+        let cycles = test["cycles"].as_array().expect("Missing field cycles").len();
+        assert_eq!(ticks, cycles as u64 * 4, "{}: M-cycles", name);
+        CPU_CTX.registers()
+    };
+
+    let expected = &test["final"];
+    for (key, value) in [
+        ("a", registers.a as u16), ("f", registers.f as u16),
+        ("b", registers.b as u16), ("c", registers.c as u16),
+        ("d", registers.d as u16), ("e", registers.e as u16),
+        ("h", registers.h as u16), ("l", registers.l as u16),
+        ("pc", registers.pc), ("sp", registers.sp),
+    ] {
+        assert_eq!(value, field(expected, key), "{}: register {}", name, key);
+    }
+    for (address, value) in memory(expected) {
+        assert_eq!(bus_read(address), value, "{}: memory at 0x{:04X}", name, address);
+    }
+}
+
+fn run_tests(json: &str) -> usize {
+    let tests: Value = serde_json::from_str(json).expect("Invalid test file");
+    let tests = tests.as_array().expect("The test file is not an array");
+    for test in tests {
+        run_test(test);
+    }
+    return tests.len();
+}
+
+#[test]
+fn inline_tests_pass() {
+    let _lock = test_util::lock();
+    assert_eq!(run_tests(INLINE_TESTS), 6);
+}
+
+#[test]
+fn sm83_single_step_tests() {
+    let dir = match std::env::var("SM83_TESTS_DIR") {
+        Ok(dir) => dir,
+        Err(_) => {
+            eprintln!("SM83_TESTS_DIR is not set, skipping the SM83 single step tests");
+            return;
+        }
+    };
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("Unable to read {}: {}", dir, err))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    let _lock = test_util::lock();
+    let mut count = 0;
+    for path in paths {
+        let opcode = path.file_stem().unwrap().to_string_lossy().to_lowercase();
+        if SKIPPED_OPCODES.contains(&opcode.as_str()) {
+            continue;
+        }
+        let json = std::fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("Unable to read {}: {}", path.display(), err));
+        count += run_tests(&json);
+    }
+    assert!(count > 0, "No test found in {}", dir);
+}
@@ -1,22 +1,72 @@
-use crate::emulator::address_bus::*;
+use std::io::Write;
 
-static mut dbg_msg: [u8; 1024] = [0x00_u8; 1024];
-static mut msg_size: usize = 0;
+// Characters received through the serial port
+#[allow(non_upper_case_globals)]
+static mut dbg_msg: String = String::new();
+// Number of bytes of dbg_msg already written to stdout
+#[allow(non_upper_case_globals)]
+static mut printed_size: usize = 0;
+// Whether the serial output is echoed to stdout
+#[allow(non_upper_case_globals)]
+static mut serial_stdout: bool = false;
 
-pub fn dbg_update() -> () {
+/**
+ * Enables or disables echoing the serial output to stdout
+ */
+pub fn set_serial_stdout(enabled: bool) -> () {
+    unsafe { serial_stdout = enabled; }
+}
+
+/**
+ * Records a byte sent through the serial port
+ */
+pub fn dbg_update(data: u8) -> () {
+    unsafe { dbg_msg.push(data as char); }
+}
+
+/**
+ * Returns the characters received through the serial port
+ */
+pub fn serial_output() -> String {
+    unsafe { return dbg_msg.clone(); }
+}
+
+/**
+ * Returns the number of characters received through the serial port
+ */
+pub fn serial_output_len() -> usize {
+    unsafe { return dbg_msg.len(); }
+}
+
+/**
+ * Returns true if the serial output contains the given text
+ */
+pub fn serial_output_contains(text: &str) -> bool {
+    unsafe { return dbg_msg.contains(text); }
+}
+
+/**
+ * Forgets the characters received through the serial port
+ */
+#[cfg(test)]
+pub fn clear_serial_output() -> () {
     unsafe {
-        if bus_read(0xFF02) == 0x81 {
-            let c = bus_read(0xFF01);
-            dbg_msg[msg_size] = c;
-            msg_size += 1;
-            bus_write(0xFF02, 0);
-        }
+        dbg_msg.clear();
+        printed_size = 0;
     }
 }
 
 
+/**
+ * Prints the characters received since the last call
+ */
 pub fn dbg_print() -> () {
-    if unsafe { dbg_msg[0] as u8 } > 0 {
-        println!("{}", unsafe { std::str::from_utf8_unchecked(&dbg_msg) });
+    unsafe {
+        if !serial_stdout || printed_size == dbg_msg.len() {
+            return;
+        }
+        print!("{}", &dbg_msg[printed_size..]);
+        std::io::stdout().flush().unwrap_or(());
+        printed_size = dbg_msg.len();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,238 @@
+use std::io::{BufRead, Write};
+use crate::emulator::address_bus::bus_peek;
+use crate::emulator::cpu::CPU;
+use crate::emulator::cpu::instruction::Instruction;
+use crate::emulator::EMULATOR_CTX;
+use crate::emulator::memdump::{self, MemoryRegion};
+use crate::emulator::ramsearch::{RamSearch, SearchPredicate};
+
+/**
+ * An interactive command line debugger. When enabled, the CPU
+ * stops before the first instruction and whenever it reaches a
+ * breakpoint or an instruction wrote to a watched address.
+ * Commands are read from stdin:
+ *   b <addr>         Sets a breakpoint at the given PC
+ *   d <addr>         Deletes the breakpoint at the given PC
+ *   wb <addr>        Stops after a write to the given address
+ *   c                Continues until the next breakpoint
+ *   s                Executes a single instruction
+ *   r                Prints the registers
+ *   x <addr> <len>   Dumps `len` bytes of memory starting at `addr`
+ *   dump <region> [file]
+ *                    Writes vram, oam, wram, hram or all of them to a file
+ *   rs start         Starts a search for an address in WRAM and HRAM
+ *   rs <inc|dec|same|diff|eq <value>>
+ *                    Keeps the addresses whose value changed accordingly
+ *   rs list          Prints the remaining addresses
+ *   q                Quits the emulator
+ * Addresses are hexadecimal, optionally prefixed with `$` or `0x`.
+ * An empty line repeats the previous command.
+ */
+
+const PROMPT: &str = "(gbdb) ";
+const HELP: &str = "Commands: b <addr>, d <addr>, wb <addr>, c, s, r, x <addr> <len>, \
+    dump <vram|oam|wram|hram|all> [file], rs <start|inc|dec|same|diff|eq <value>|list>, q";
+// Number of RAM search candidates printed at most
+const MAX_LISTED_CANDIDATES: usize = 16;
+
+pub struct Debugger {
+    enabled: bool,
+    // Stops before the next instruction
+    stepping: bool,
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<u16>,
+    last_command: String,
+    ram_search: RamSearch,
+}
+
+// A global instance of the debugger context
+pub static mut DEBUGGER_CTX: Debugger = Debugger {
+    enabled: false,
+    stepping: false,
+    breakpoints: Vec::new(),
+    watchpoints: Vec::new(),
+    last_command: String::new(),
+    ram_search: RamSearch::new(),
+};
+
+/**
+ * Parses a hexadecimal address such as `C000`, `$C000` or `0xC000`
+ */
+fn parse_hex(value: &str) -> Option<u16> {
+    let value = value.trim_start_matches('$').trim_start_matches("0x");
+    return u16::from_str_radix(value, 16).ok();
+}
+
+impl Debugger {
+    /**
+     * Enables the debugger, which stops before the first instruction
+     */
+    pub fn enable(&mut self) -> () {
+        self.enabled = true;
+        self.stepping = true;
+    }
+
+    /**
+     * Returns the RAM search of the session
+     */
+    pub fn ram_search(&mut self) -> &mut RamSearch {
+        return &mut self.ram_search;
+    }
+
+    /**
+     * Called by the CPU before fetching an instruction. Drops into
+     * the REPL if the CPU is stepping or hit a breakpoint.
+     */
+    pub fn check_breakpoint(&mut self, cpu: &CPU) -> () {
+        if !self.enabled { return; }
+
+        let pc = cpu.get_pc();
+        if !self.stepping && !self.breakpoints.contains(&pc) {
+            return;
+        }
+        if !self.stepping {
+            println!("Breakpoint hit at ${:04X}", pc);
+        }
+        self.stepping = false;
+        self.repl(cpu);
+    }
+
+    /**
+     * Called on every write to the address bus. Stops before the
+     * next instruction if the address is watched.
+     */
+    #[inline(always)]
+    pub fn check_watchpoint(&mut self, address: u16, value: u8) -> () {
+        if !self.enabled || !self.watchpoints.contains(&address) {
+            return;
+        }
+        println!("Watchpoint hit: ${:02X} written to ${:04X}", value, address);
+        self.stepping = true;
+    }
+
+    /**
+     * Reads and executes commands until the emulation should resume
+     */
+    fn repl(&mut self, cpu: &CPU) -> () {
+        let pc = cpu.get_pc();
+        let instr = Instruction::get_instruction(bus_peek(pc));
+        println!("${:04X}: {}", pc, instr.disass_at(pc.wrapping_add(1)));
+
+        let stdin = std::io::stdin();
+        loop {
+            print!("{}", PROMPT);
+            std::io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                // stdin was closed, keeps running without the debugger
+                self.enabled = false;
+                return;
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            }
+            self.last_command = line.clone();
+
+            let args: Vec<&str> = line.split_whitespace().collect();
+            match args.as_slice() {
+                ["b", addr] => match parse_hex(addr) {
+                    Some(addr) => {
+                        if !self.breakpoints.contains(&addr) {
+                            self.breakpoints.push(addr);
+                        }
+                        println!("Breakpoint set at ${:04X}", addr);
+                    },
+                    None => println!("Invalid address '{}'", addr),
+                },
+                ["d", addr] => match parse_hex(addr) {
+                    Some(addr) => {
+                        self.breakpoints.retain(|bp| *bp != addr);
+                        println!("Breakpoint at ${:04X} deleted", addr);
+                    },
+                    None => println!("Invalid address '{}'", addr),
+                },
+                ["wb", addr] => match parse_hex(addr) {
+                    Some(addr) => {
+                        if !self.watchpoints.contains(&addr) {
+                            self.watchpoints.push(addr);
+                        }
+                        println!("Watchpoint set at ${:04X}", addr);
+                    },
+                    None => println!("Invalid address '{}'", addr),
+                },
+                ["c"] => return,
+                ["s"] => {
+                    self.stepping = true;
+                    return;
+                },
+                ["r"] => println!("{}", cpu.state_str()),
+                ["x", addr, len] => match (parse_hex(addr), len.parse::<u32>()) {
+                    (Some(addr), Ok(len)) => Debugger::dump_memory(addr, len),
+                    _ => println!("Usage: x <addr> <len>"),
+                },
+                ["dump", "all"] => memdump::dump_all(),
+                ["dump", name, rest @ ..] if rest.len() <= 1 => match MemoryRegion::from_name(name) {
+                    Some(region) => {
+                        let path = rest.first().map_or_else(|| memdump::dump_path(region),
+                            |path| path.to_string());
+                        match memdump::dump_memory(region, &path) {
+                            Ok(()) => println!("Dumped {} to {}", name, path),
+                            Err(err) => println!("Unable to write {}: {}", path, err),
+                        }
+                    },
+                    None => println!("Unknown region '{}'", name),
+                },
+                ["rs", "start"] => {
+                    self.ram_search.start();
+                    self.print_candidates();
+                },
+                ["rs", "list"] => self.print_candidates(),
+                ["rs", name, rest @ ..] if rest.len() <= 1 =>
+                    match SearchPredicate::parse(name, rest.first().copied()) {
+                        Some(predicate) => {
+                            self.ram_search.step(predicate);
+                            self.print_candidates();
+                        },
+                        None => println!("Usage: rs <start|inc|dec|same|diff|eq <value>|list>"),
+                    },
+                ["q"] => {
+                    unsafe { EMULATOR_CTX.running = false; }
+                    self.enabled = false;
+                    return;
+                },
+                [] => (),
+                _ => println!("{}", HELP),
+            }
+        }
+    }
+
+    /**
+     * Prints the number of RAM search candidates and the first ones
+     */
+    fn print_candidates(&self) -> () {
+        let candidates = self.ram_search.candidates();
+        println!("{} candidates", candidates.len());
+        for (address, value) in candidates.iter().take(MAX_LISTED_CANDIDATES) {
+            println!("  ${:04X} = ${:02X}", address, value);
+        }
+        if candidates.len() > MAX_LISTED_CANDIDATES {
+            println!("  ...");
+        }
+    }
+
+    /**
+     * Prints `len` bytes starting at the given address, 16 per line
+     */
+    fn dump_memory(start: u16, len: u32) -> () {
+        for row in (0..len).step_by(16) {
+            let addr = start.wrapping_add(row as u16);
+            let mut line = format!("${:04X}:", addr);
+            for i in row..std::cmp::min(row + 16, len) {
+                line.push_str(&format!(" {:02X}", bus_peek(start.wrapping_add(i as u16))));
+            }
+            println!("{}", line);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::emulator::address_bus::bus_peek;
+use crate::emulator::cpu::instruction::{InstrType, Instruction};
+
+/**
+ * Static disassembly of the memory, e.g. of the ROM without running
+ * it. Each instruction is decoded right after the previous one, so
+ * data placed between the code is disassembled as instructions too.
+ */
+
+/**
+ * Disassembles the instructions that start between `start` and `end`
+ * (inclusive). Returns the address and the text of each instruction,
+ * illegal opcodes are shown as `DB $xx`.
+ */
+pub fn disassemble(start: u16, end: u16) -> Vec<(u16, String)> {
+    let mut lines = Vec::new();
+    let mut address = start as u32;
+    while address <= end as u32 {
+        let pc = address as u16;
+        let opcode = bus_peek(pc);
+        let instr = Instruction::get_instruction(opcode);
+        let text = if instr.instr_type == InstrType::IN_ERR {
+            format!("DB ${:02X}", opcode)
+        } else {
+            instr.disass_at(pc.wrapping_add(1))
+        };
+        lines.push((pc, text));
+        address += instr.length() as u32;
+    }
+    return lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::test_util;
+
+    #[test]
+    fn range_is_disassembled_instruction_by_instruction() {
+        let _lock = test_util::lock();
+        // NOP; LD B, $12; JP $1234; RL C; illegal; LDH ($80), A
+        test_util::flat_cpu(&[0x00, 0x06, 0x12, 0xC3, 0x34, 0x12, 0xCB, 0x11, 0xD3, 0xE0, 0x80]);
+        let lines = disassemble(test_util::CODE_START, test_util::CODE_START + 9);
+        let expected = [
+            (0x150, "NOP"), (0x151, "LD B, $12"), (0x153, "JP $1234"),
+            (0x156, "RL C"), (0x158, "DB $D3"), (0x159, "LDH ($FF80), A"),
+        ];
+        assert_eq!(lines.len(), expected.len());
+        for ((address, text), (expected_address, expected_text)) in lines.iter().zip(expected) {
+            assert_eq!((*address, text.as_str()), (expected_address, expected_text));
+        }
+        // The last instruction may extend past the end
+        assert_eq!(disassemble(0x150, 0x151).len(), 2);
+    }
+}
@@ -1,25 +1,41 @@
 use crate::emulator::ppu::PPU_CTX;
 use crate::emulator::address_bus::*;
+use crate::emulator::savestate::*;
 
 pub const DMA_ADDR: u16 = 0xFF46;
 
 pub struct DMA {
     active: bool,
+    // Index of the next byte to copy
     byte: u8,
+    // Upper byte of the source address
     value: u8,
+    // M-cycles between the write to 0xFF46 and the first byte
     start_delay: u8,
+    // The byte copied last, which is what the CPU reads
+    // from a blocked address
+    last_byte: u8,
 }
 
 // A global instance of DMA context
-pub static mut DMA_CTX: DMA = DMA {
-    active: false,
-    byte: 0,
-    value: 0,
-    start_delay: 0,
-};
+pub static mut DMA_CTX: DMA = DMA::new();
 
 
 impl DMA {
+    const fn new() -> DMA {
+        DMA {
+            active: false,
+            byte: 0,
+            value: 0,
+            start_delay: 0,
+            last_byte: 0xFF,
+        }
+    }
+
+    pub fn reset(&mut self) -> () {
+        *self = DMA::new();
+    }
+
     pub fn start(&mut self, start: u8) -> () {
         self.active = true;
         self.byte = 0;
@@ -27,10 +43,30 @@ impl DMA {
         self.start_delay = 2;
     }
 
+    /**
+     * Returns true if the transfer has started copying bytes. The bus
+     * is not blocked during the start delay, so a transfer takes 160
+     * M-cycles from the first byte to the last.
+     */
     pub fn is_transferring(&self) -> bool {
+        return self.active && self.start_delay == 0;
+    }
+
+    /**
+     * Returns true from the write to 0xFF46 until the last byte
+     * has been copied
+     */
+    pub fn is_active(&self) -> bool {
         return self.active;
     }
 
+    /**
+     * Returns the last byte placed on the bus by the transfer
+     */
+    pub fn current_byte(&self) -> u8 {
+        return self.last_byte;
+    }
+
     pub fn tick(&mut self) -> () {
         if !self.active { return; }
         
@@ -39,12 +75,239 @@ impl DMA {
             return;
         }
         let addr = (self.value as u16 * 0x100) + self.byte as u16;
+        self.last_byte = bus_peek(addr);
         unsafe {
-            PPU_CTX.oam_write(self.byte as u16, bus_read(addr));
+            PPU_CTX.oam_write(self.byte as u16, self.last_byte);
         }
 
         // Moves to the next byte
         self.byte += 1;
         self.active = self.byte < 0xA0;
     }
-}
\ No newline at end of file
+
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_bool(self.active);
+        writer.write_bytes(&[self.byte, self.value, self.start_delay, self.last_byte]);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.active = reader.read_bool()?;
+        self.byte = reader.read_u8()?;
+        self.value = reader.read_u8()?;
+        self.start_delay = reader.read_u8()?;
+        self.last_byte = reader.read_u8()?;
+        return Ok(());
+    }
+}
+
+
+// CGB only, HDMA1 - HDMA5 - VRAM DMA source, destination, length / mode / start
+pub const HDMA1_ADDR: u16 = 0xFF51;
+pub const HDMA5_ADDR: u16 = 0xFF55;
+// Number of bytes copied per block
+const HDMA_BLOCK_SIZE: u16 = 0x10;
+const HDMA_MODE_HBLANK: u8 = 0x80;
+
+/**
+ * The VRAM DMA of the CGB. A general purpose transfer copies all
+ * blocks at once, a H-blank transfer copies one block of 16 bytes
+ * each time the PPU enters the H-blank mode.
+ */
+pub struct HDMA {
+    source: u16,
+    dest: u16,
+    // Number of blocks left to transfer minus one
+    blocks: u8,
+    // A H-blank transfer is in progress
+    active: bool,
+    // The PPU has entered H-blank since the last block was copied
+    hblank_pending: bool,
+}
+
+// A global instance of HDMA context
+pub static mut HDMA_CTX: HDMA = HDMA::new();
+
+
+impl HDMA {
+    const fn new() -> HDMA {
+        HDMA {
+            source: 0,
+            dest: 0,
+            blocks: 0x7F,
+            active: false,
+            hblank_pending: false,
+        }
+    }
+
+    pub fn reset(&mut self) -> () {
+        *self = HDMA::new();
+    }
+
+    /**
+     * Reads one of the HDMA registers. Only HDMA5 can be read, it
+     * returns the remaining length with bit 7 cleared while a H-blank
+     * transfer is active, and 0xFF once the transfer has completed.
+     */
+    pub fn read(&self, address: u16) -> u8 {
+        if address != HDMA5_ADDR {
+            return 0xFF;
+        }
+        if self.active {
+            return self.blocks;
+        }
+        return HDMA_MODE_HBLANK | self.blocks;
+    }
+
+    /**
+     * Writes one of the HDMA registers. Writing to HDMA5 starts a
+     * transfer, or stops the active H-blank transfer if bit 7 is 0.
+     */
+    pub fn write(&mut self, address: u16, value: u8) -> () {
+        match address - HDMA1_ADDR {
+            0 => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            1 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            // The destination is always in VRAM
+            2 => self.dest = (self.dest & 0x00FF) | (((value & 0x1F) as u16) << 8),
+            3 => self.dest = (self.dest & 0xFF00) | (value & 0xF0) as u16,
+            4 => self.start(value),
+            _ => (),
+        }
+    }
+
+    fn start(&mut self, value: u8) -> () {
+        if self.active && (value & HDMA_MODE_HBLANK) == 0 {
+            // Cancels the H-blank transfer, the remaining length stays readable
+            self.active = false;
+            return;
+        }
+        self.blocks = value & 0x7F;
+        if (value & HDMA_MODE_HBLANK) != 0 {
+            self.active = true;
+            self.hblank_pending = false;
+            return;
+        }
+        // A general purpose transfer halts the CPU until it is done,
+        // so all blocks are copied at once
+        loop {
+            self.copy_block();
+            if self.blocks == 0 {
+                break;
+            }
+            self.blocks -= 1;
+        }
+        self.blocks = 0x7F;
+    }
+
+    /**
+     * Copies 16 bytes from the source to the destination in VRAM
+     */
+    fn copy_block(&mut self) -> () {
+        for _ in 0..HDMA_BLOCK_SIZE {
+            let value = bus_peek(self.source);
+            unsafe { PPU_CTX.vram_write(0x8000 | (self.dest & 0x1FFF), value); }
+            self.source = self.source.wrapping_add(1);
+            self.dest = (self.dest + 1) & 0x1FFF;
+        }
+    }
+
+    /**
+     * Called by the PPU when it enters the H-blank mode
+     */
+    pub fn hblank(&mut self) -> () {
+        if self.active {
+            self.hblank_pending = true;
+        }
+    }
+
+    /**
+     * Copies the next block of a H-blank transfer
+     */
+    pub fn tick(&mut self) -> () {
+        if !self.hblank_pending { return; }
+
+        self.hblank_pending = false;
+        self.copy_block();
+        if self.blocks == 0 {
+            self.active = false;
+            self.blocks = 0x7F;
+        } else {
+            self.blocks -= 1;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_u16(self.source);
+        writer.write_u16(self.dest);
+        writer.write_u8(self.blocks);
+        writer.write_bool(self.active);
+        writer.write_bool(self.hblank_pending);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.source = reader.read_u16()?;
+        self.dest = reader.read_u16()? & 0x1FFF;
+        self.blocks = reader.read_u8()? & 0x7F;
+        self.active = reader.read_bool()?;
+        self.hblank_pending = reader.read_bool()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::test_util;
+
+    #[test]
+    fn general_purpose_transfer_copies_to_vram() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        for i in 0..0x20u16 {
+            bus_write(0xC000 + i, 0x80 | i as u8);
+        }
+        unsafe {
+            // From 0xC000 to 0x8100, 2 blocks of 16 bytes
+            HDMA_CTX.write(HDMA1_ADDR, 0xC0);
+            HDMA_CTX.write(HDMA1_ADDR + 1, 0x00);
+            HDMA_CTX.write(HDMA1_ADDR + 2, 0x01);
+            HDMA_CTX.write(HDMA1_ADDR + 3, 0x00);
+            HDMA_CTX.write(HDMA5_ADDR, 0x01);
+            for i in 0..0x20u16 {
+                assert_eq!(PPU_CTX.vram_read(0x8100 + i), 0x80 | i as u8);
+            }
+            // The byte after the transfer is left untouched
+            assert_eq!(PPU_CTX.vram_read(0x8120), 0x00);
+            assert_eq!(HDMA_CTX.read(HDMA5_ADDR), 0xFF);
+        }
+    }
+
+    #[test]
+    fn oam_dma_blocks_the_cpu_from_wram() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        for i in 0..0xA0u16 {
+            bus_write(0xC000 + i, i as u8);
+        }
+        bus_write(0xD000, 0x42);
+        bus_write(0xFF80, 0x24);
+        unsafe {
+            DMA_CTX.start(0xC0);
+            // The transfer starts after 2 M-cycles, then copies 5 bytes
+            for _ in 0..7 {
+                DMA_CTX.tick();
+            }
+            assert!(DMA_CTX.is_transferring());
+            // The CPU sees the byte being copied instead of WRAM
+            assert_eq!(bus_read(0xD000), 0x04);
+            assert_eq!(bus_read(0xFE00), 0xFF);
+            // HRAM stays accessible
+            assert_eq!(bus_read(0xFF80), 0x24);
+            for _ in 0..0xA0 - 5 {
+                DMA_CTX.tick();
+            }
+            assert!(!DMA_CTX.is_transferring());
+            assert_eq!(bus_read(0xD000), 0x42);
+            assert_eq!(PPU_CTX.oam_read(0xFE9F), 0x9F);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+/**
+ * Fatal errors of the emulated machine. These indicate a bug in the
+ * emulator rather than in the game, e.g. a component is asked for an
+ * address it does not own. All of them go through `fatal`, which
+ * panics by default so that a library user can catch the error,
+ * while the binary installs a handler that exits the process.
+ */
+
+/**
+ * Errors that stop the emulation
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmuError {
+    // A component was accessed at an address it does not own
+    InvalidAddress(&'static str, u16),
+    // An instruction used a register that it cannot access
+    InvalidRegister(String),
+    // The instruction type has no executor
+    UnimplementedInstruction(String),
+    // A DMG palette number other than 0 - 2 was used
+    InvalidPalette(u8),
+    // The pixel FIFO was pushed to while full or popped while empty
+    PixelFifo(&'static str),
+}
+
+impl std::fmt::Display for EmuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EmuError::InvalidAddress(component, address) =>
+                write!(f, "Invalid {} address 0x{:04X}", component, address),
+            EmuError::InvalidRegister(reg) => write!(f, "Register {} not implemented", reg),
+            EmuError::UnimplementedInstruction(instr) =>
+                write!(f, "Instruction {} not implemented", instr),
+            EmuError::InvalidPalette(palette) => write!(f, "Invalid palette number: {}", palette),
+            EmuError::PixelFifo(what) => write!(f, "Pixel FIFO error: {}", what),
+        }
+    }
+}
+
+impl EmuError {
+    /**
+     * Returns the exit code of the binary for the error
+     */
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            EmuError::InvalidAddress(..) => -5,
+            _ => -1,
+        }
+    }
+}
+
+
+// Called with the error before panicking
+static mut FATAL_HANDLER: Option<fn(&EmuError) -> ()> = None;
+
+/**
+ * Sets the function called when a fatal error occurs. If the
+ * handler returns, the emulator panics with the error.
+ */
+pub fn set_fatal_handler(handler: fn(&EmuError) -> ()) -> () {
+    unsafe { FATAL_HANDLER = Some(handler); }
+}
+
+/**
+ * Reports a fatal error and stops the emulation
+ */
+#[cold]
+pub fn fatal(err: EmuError) -> ! {
+    log::error!(target: "stdout", "{}", err);
+    if let Some(handler) = unsafe { FATAL_HANDLER } {
+        handler(&err);
+    }
+    panic!("{}", err);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_describe_the_faulty_value() {
+        let err = EmuError::InvalidAddress("VRAM", 0x7FFF);
+        assert_eq!(err.to_string(), "Invalid VRAM address 0x7FFF");
+        assert_eq!(err.exit_code(), -5);
+        assert_eq!(EmuError::InvalidPalette(3).to_string(), "Invalid palette number: 3");
+        assert_eq!(EmuError::InvalidPalette(3).exit_code(), -1);
+    }
+
+    #[test]
+    fn fatal_errors_panic_without_a_handler() {
+        let result = std::panic::catch_unwind(|| fatal(EmuError::PixelFifo("empty")));
+        let payload = result.expect_err("fatal returned");
+        assert_eq!(payload.downcast_ref::<String>().map(String::as_str),
+            Some("Pixel FIFO error: empty"));
+    }
+}
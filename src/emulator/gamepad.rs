@@ -1,3 +1,6 @@
+use crate::emulator::cpu::interrupts::*;
+use crate::emulator::keymap::Button;
+
 
 /**
  * Game Pad state
@@ -19,12 +22,19 @@ pub struct GamePadState {
 pub struct GamePad {
     button_select: bool,
     dir_select: bool,
+    // Lower nibble of P1 when the input lines were last checked
+    input_lines: u8,
+    // Pressing a direction releases the opposite one, so that
+    // Left + Right and Up + Down are never seen by the game
+    block_socd: bool,
     pub controller: GamePadState,
 }
 
 pub static mut GAMEPAD_CTX: GamePad = GamePad {
     button_select: false,
     dir_select: false,
+    input_lines: 0x0F,
+    block_socd: false,
     controller: GamePadState {
         start: false,
         select: false,
@@ -38,6 +48,16 @@ pub static mut GAMEPAD_CTX: GamePad = GamePad {
 };
 
 impl GamePad {
+    /**
+     * Resets the P1 selection, the buttons that are held
+     * down stay pressed
+     */
+    pub fn reset(&mut self) -> () {
+        self.button_select = false;
+        self.dir_select = false;
+        self.input_lines = 0x0F;
+    }
+
     /**
      * Returns **FALSE** if the button mode is selected, 
      * i.e., the lower nibble of the input indicates which
@@ -64,13 +84,88 @@ impl GamePad {
     pub fn set_select(&mut self, value: u8) -> () {
         self.button_select = (value & 0x20) != 0;
         self.dir_select = (value & 0x10) != 0;
+        // Selecting a line while a button is held also pulls it low
+        self.update();
+    }
+
+    /**
+     * Checks the selected input lines and requests the joypad
+     * interrupt if any of them went from high to low. Must be
+     * called whenever the state of the controller changes.
+     */
+    pub fn update(&mut self) -> () {
+        let input_lines = self.get_output() & 0x0F;
+        if (self.input_lines & !input_lines) != 0 {
+            request_interrupt(InterruptType::IT_JOYPAD);
+        }
+        self.input_lines = input_lines;
+    }
+
+    /**
+     * Blocks or allows simultaneous opposite directions (SOCD)
+     */
+    pub fn set_block_socd(&mut self, enabled: bool) -> () {
+        self.block_socd = enabled;
+    }
+
+    /**
+     * Presses or releases the given button. When SOCD are blocked,
+     * the last pressed of two opposite directions wins.
+     */
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> () {
+        if pressed && self.block_socd {
+            match button {
+                Button::Up => self.controller.down = false,
+                Button::Down => self.controller.up = false,
+                Button::Left => self.controller.right = false,
+                Button::Right => self.controller.left = false,
+                _ => (),
+            }
+        }
+        match button {
+            Button::A => self.controller.a = pressed,
+            Button::B => self.controller.b = pressed,
+            Button::Start => self.controller.start = pressed,
+            Button::Select => self.controller.select = pressed,
+            Button::Up => self.controller.up = pressed,
+            Button::Down => self.controller.down = pressed,
+            Button::Left => self.controller.left = pressed,
+            Button::Right => self.controller.right = pressed,
+        }
+        self.update();
+    }
+
+    /**
+     * Sets the state of every button from the given mask,
+     * see Button::mask
+     */
+    pub fn set_buttons(&mut self, buttons: u8) -> () {
+        for button in Button::ALL {
+            self.set_button(button, (buttons & button.mask()) != 0);
+        }
+    }
+
+    /**
+     * Returns the buttons that are held down as a mask,
+     * see Button::mask
+     */
+    pub fn buttons(&self) -> u8 {
+        let state = &self.controller;
+        let pressed = [state.a, state.b, state.start, state.select,
+            state.up, state.down, state.left, state.right];
+        return Button::ALL.iter().zip(pressed)
+            .filter(|(_, pressed)| *pressed)
+            .fold(0, |mask, (button, _)| mask | button.mask());
     }
 
     /**
-     * 
+     * Returns the value of the P1 register. Bits 6 - 7 are unused and
+     * read as 1, bits 4 - 5 read back the selected lines and the low
+     * nibble has the pressed buttons of the selected lines pulled low.
      */
     pub fn get_output(&mut self) -> u8 {
-        let mut output = 0xCF;
+        let mut output = 0xC0 | ((self.button_select as u8) << 5) |
+            ((self.dir_select as u8) << 4) | 0x0F;
 
         // If the button mode is selected
         if !self.button_select() {
@@ -120,4 +215,72 @@ impl GamePad {
     pub fn get_state(&self) -> &GamePadState {
         return &self.controller;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::address_bus::{bus_read, bus_write};
+    use crate::emulator::cpu::CPU_CTX;
+    use crate::emulator::test_util;
+
+    /**
+     * Releases all the buttons and selects the given P1 lines
+     * with the interrupt flags cleared
+     */
+    fn setup(select: u8) -> () {
+        unsafe {
+            GAMEPAD_CTX.set_block_socd(false);
+            GAMEPAD_CTX.set_buttons(0);
+            GAMEPAD_CTX.set_select(select);
+            CPU_CTX.set_int_flags(0);
+        }
+    }
+
+    #[test]
+    fn pressing_a_selected_button_requests_the_joypad_interrupt() {
+        let _lock = test_util::lock();
+        // Only the direction line is selected
+        setup(0x20);
+        unsafe {
+            GAMEPAD_CTX.set_button(Button::A, true);
+            assert_eq!(CPU_CTX.get_int_flags() & 0x10, 0);
+            GAMEPAD_CTX.set_button(Button::Right, true);
+            assert_eq!(CPU_CTX.get_int_flags() & 0x10, 0x10);
+        }
+    }
+
+    #[test]
+    fn p1_reads_back_the_selected_lines_and_the_unused_bits() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        setup(0x30);
+        unsafe { GAMEPAD_CTX.set_button(Button::A, true); }
+        // Only the button line is selected
+        bus_write(0xFF00, 0x10);
+        assert_eq!(bus_read(0xFF00), 0xDE);
+        bus_write(0xFF00, 0x20);
+        assert_eq!(bus_read(0xFF00), 0xEF);
+        unsafe { GAMEPAD_CTX.set_button(Button::A, false); }
+    }
+
+    #[test]
+    fn blocked_socd_keep_the_last_pressed_direction() {
+        let _lock = test_util::lock();
+        // Only the direction line is selected
+        setup(0x20);
+        unsafe {
+            GAMEPAD_CTX.set_block_socd(true);
+            GAMEPAD_CTX.set_button(Button::Left, true);
+            GAMEPAD_CTX.set_button(Button::Right, true);
+            assert_eq!(GAMEPAD_CTX.buttons(), Button::Right.mask());
+            // Bit 0 is Right, bit 1 is Left
+            assert_eq!(GAMEPAD_CTX.get_output() & 0x0F, 0x0E);
+
+            // Both are seen when SOCD are allowed
+            GAMEPAD_CTX.set_block_socd(false);
+            GAMEPAD_CTX.set_button(Button::Left, true);
+            assert_eq!(GAMEPAD_CTX.get_output() & 0x0F, 0x0C);
+            GAMEPAD_CTX.set_buttons(0);
+        }
+    }
+}
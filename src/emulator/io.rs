@@ -1,28 +1,113 @@
-use std::ptr;
-
 use crate::emulator::timer::*;
 use crate::emulator::dma::*;
 use crate::emulator::cpu::{CPU_CTX, INT_FLAGS_ADDR};
-use crate::emulator::ppu::PPU_CTX;
+use crate::emulator::ppu::{PPU_CTX, VBK_ADDR};
+use crate::emulator::ram::{RAM_CTX, SVBK_ADDR};
 use crate::emulator::lcd::*;
 use crate::emulator::gamepad::*;
-static mut serial_data: [u8; 2] = [0, 0];
+use crate::emulator::apu::*;
+use crate::emulator::serial::*;
+use crate::emulator::boot::{BOOT_CTX, BOOT_OFF_ADDR};
+use crate::emulator::address_bus::unmapped_read;
+use crate::emulator::Emulator;
+
+// CGB only, KEY1 - Prepare speed switch
+const KEY1_ADDR: u16 = 0xFF4D;
+
+// Subsystems whose register accesses can be traced
+const IO_TRACE_JOYPAD: u8 = 1 << 0;
+const IO_TRACE_SERIAL: u8 = 1 << 1;
+const IO_TRACE_TIMER: u8 = 1 << 2;
+const IO_TRACE_INTERRUPTS: u8 = 1 << 3;
+const IO_TRACE_SOUND: u8 = 1 << 4;
+const IO_TRACE_LCD: u8 = 1 << 5;
+const IO_TRACE_CGB: u8 = 1 << 6;
+
+// The names accepted by --io-trace
+pub const IO_TRACE_NAMES: [(&str, u8); 8] = [
+    ("joypad", IO_TRACE_JOYPAD),
+    ("serial", IO_TRACE_SERIAL),
+    ("timer", IO_TRACE_TIMER),
+    ("interrupts", IO_TRACE_INTERRUPTS),
+    ("sound", IO_TRACE_SOUND),
+    ("lcd", IO_TRACE_LCD),
+    ("cgb", IO_TRACE_CGB),
+    ("all", 0xFF),
+];
+
+// The subsystems whose accesses are logged, nothing by default
+static mut IO_TRACE: u8 = 0;
+
+/**
+ * Parses a comma-separated list of subsystem names, e.g.
+ * `timer,lcd`, into a trace mask. Returns the first unknown
+ * name on failure.
+ */
+pub fn parse_io_trace(list: &str) -> Result<u8, String> {
+    let mut mask: u8 = 0;
+    for name in list.split(',') {
+        let name = name.trim().to_lowercase();
+        match IO_TRACE_NAMES.iter().find(|(n, _)| *n == name) {
+            Some((_, bits)) => mask |= bits,
+            None => return Err(name),
+        }
+    }
+    return Ok(mask);
+}
+
+/**
+ * Logs the accesses to the registers of the subsystems in the mask
+ */
+pub fn set_io_trace(mask: u8) -> () {
+    unsafe { IO_TRACE = mask; }
+}
+
+/**
+ * Returns the trace bit of the subsystem that owns the register
+ */
+fn io_subsystem(address: u16) -> u8 {
+    match address {
+        0xFF00 => IO_TRACE_JOYPAD,
+        SB_ADDR | SC_ADDR => IO_TRACE_SERIAL,
+        DIV_ADDR..=TAC_ADDR => IO_TRACE_TIMER,
+        INT_FLAGS_ADDR => IO_TRACE_INTERRUPTS,
+        APU_START_ADDR..=APU_END_ADDR => IO_TRACE_SOUND,
+        LCD_START_ADDR..=LCD_END_ADDR | BCPS_ADDR..=OPRI_ADDR => IO_TRACE_LCD,
+        _ => IO_TRACE_CGB,
+    }
+}
 
-static mut read_sound_warning: bool = false;
-static mut write_sound_warning: bool = false;
+/**
+ * Logs a register access if tracing is enabled for its subsystem,
+ * along with the address of the instruction that caused it
+ */
+#[inline(always)]
+fn trace_access(kind: char, address: u16, data: u8) -> () {
+    let mask = unsafe { IO_TRACE };
+    if mask != 0 && mask & io_subsystem(address) != 0 {
+        let pc = unsafe { CPU_CTX.get_instr_pc() };
+        log::info!("IO {} {:04X} = {:02X} [PC {:04X}]", kind, address, data, pc);
+    }
+}
 
 /**
- * Reads a byte from the given address from the I/O registers
+ * Reads a byte from the given address from the I/O registers.
+ * Unused bits of a register read as 1. The registers that are not
+ * mapped to any component read as 0xFF, or as the last value on
+ * the bus in open bus mode.
  */
 pub fn io_read(address: u16) -> u8 {
+    let value = read_register(address);
+    trace_access('R', address, value);
+    return value;
+}
+
+fn read_register(address: u16) -> u8 {
     if address == 0xFF00 {
         return unsafe { GAMEPAD_CTX.get_output() };
     }
-    if address == 0xFF01 {
-        return unsafe { serial_data[0] };
-    }
-    if address == 0xFF02 {
-        return unsafe { serial_data[1] };
+    if address == SB_ADDR || address == SC_ADDR {
+        return unsafe { SERIAL_CTX.read(address) };
     }
     if DIV_ADDR <= address && address <= TAC_ADDR {
         return unsafe { TIMER_CTX.read(address) };
@@ -30,16 +115,36 @@ pub fn io_read(address: u16) -> u8 {
     if address == INT_FLAGS_ADDR {
         return unsafe { CPU_CTX.get_int_flags() };
     }
+    if address == KEY1_ADDR && Emulator::is_cgb() {
+        return unsafe { CPU_CTX.get_key1() };
+    }
+    if BCPS_ADDR <= address && address <= OCPD_ADDR && Emulator::is_cgb() {
+        return unsafe { LCD_CTX.cgb_palette_read(address) };
+    }
+    if address == OPRI_ADDR && Emulator::is_cgb() {
+        return unsafe { LCD_CTX.read_opri() };
+    }
+    // KEY0 is locked once the boot ROM is unmapped
+    if address == KEY0_ADDR && Emulator::is_cgb() && unsafe { BOOT_CTX.is_running() } {
+        return unsafe { LCD_CTX.read_key0() };
+    }
+    if address == VBK_ADDR && Emulator::is_cgb() {
+        return unsafe { PPU_CTX.get_vram_bank() };
+    }
+    if address == SVBK_ADDR && Emulator::is_cgb() {
+        return unsafe { RAM_CTX.get_wram_bank() };
+    }
+    if HDMA1_ADDR <= address && address <= HDMA5_ADDR && Emulator::is_cgb() {
+        return unsafe { HDMA_CTX.read(address) };
+    }
     if LCD_START_ADDR <= address && address <= LCD_END_ADDR {
         return unsafe { LCD_CTX.read(address) };
     }
 
-    if 0xFF10 <= address && address <= 0xFF3F && !unsafe { read_sound_warning } {
-        log::warn!("Reading from sound registers not supported");
-        unsafe { read_sound_warning = true };
-        return 0;
+    if APU_START_ADDR <= address && address <= APU_END_ADDR {
+        return unsafe { APU_CTX.read(address) };
     }
-    return 0;
+    return unmapped_read();
 }
 
 
@@ -47,17 +152,18 @@ pub fn io_read(address: u16) -> u8 {
  * Writes a byte to the given address
  */
 pub fn io_write(address: u16, data: u8) -> () {
+    trace_access('W', address, data);
+    write_register(address, data);
+}
+
+fn write_register(address: u16, data: u8) -> () {
     if address == 0xFF00 {
         unsafe { GAMEPAD_CTX.set_select(data) };
         return;
     }
     
-    if address == 0xFF01 {
-        unsafe { serial_data[0] = data };
-        return;
-    }
-    if address == 0xFF02 {
-        unsafe { serial_data[1] = data };
+    if address == SB_ADDR || address == SC_ADDR {
+        unsafe { SERIAL_CTX.write(address, data) };
         return;
     }
     if DIV_ADDR <= address && address <= TAC_ADDR {
@@ -68,13 +174,69 @@ pub fn io_write(address: u16, data: u8) -> () {
         unsafe { CPU_CTX.set_int_flags(data) };
         return;
     }
+    if address == KEY1_ADDR && Emulator::is_cgb() {
+        unsafe { CPU_CTX.set_key1(data) };
+        return;
+    }
+    if BCPS_ADDR <= address && address <= OCPD_ADDR && Emulator::is_cgb() {
+        unsafe { LCD_CTX.cgb_palette_write(address, data) };
+        return;
+    }
+    if address == OPRI_ADDR && Emulator::is_cgb() {
+        unsafe { LCD_CTX.write_opri(data) };
+        return;
+    }
+    if address == KEY0_ADDR && Emulator::is_cgb() {
+        if unsafe { BOOT_CTX.is_running() } {
+            unsafe { LCD_CTX.write_key0(data) };
+        }
+        return;
+    }
+    if address == VBK_ADDR && Emulator::is_cgb() {
+        unsafe { PPU_CTX.set_vram_bank(data) };
+        return;
+    }
+    if address == SVBK_ADDR && Emulator::is_cgb() {
+        unsafe { RAM_CTX.set_wram_bank(data) };
+        return;
+    }
+    if HDMA1_ADDR <= address && address <= HDMA5_ADDR && Emulator::is_cgb() {
+        unsafe { HDMA_CTX.write(address, data) };
+        return;
+    }
+    if address == BOOT_OFF_ADDR {
+        unsafe { BOOT_CTX.write(data) };
+        return;
+    }
     if LCD_START_ADDR <= address && address <= LCD_END_ADDR {
         unsafe { LCD_CTX.write(address, data) };
         return;
     }
-    if 0xFF10 <= address && address <= 0xFF3F && !unsafe { write_sound_warning } {
-        log::warn!("Writing to sound registers not supported");
-        unsafe { write_sound_warning = true };
+    if APU_START_ADDR <= address && address <= APU_END_ADDR {
+        unsafe { APU_CTX.write(address, data) };
         return;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_trace_list_is_parsed_into_a_mask() {
+        assert_eq!(parse_io_trace("timer"), Ok(IO_TRACE_TIMER));
+        assert_eq!(parse_io_trace("Timer, lcd"), Ok(IO_TRACE_TIMER | IO_TRACE_LCD));
+        assert_eq!(parse_io_trace("all"), Ok(0xFF));
+        assert_eq!(parse_io_trace("timer,video"), Err("video".to_string()));
+    }
+
+    #[test]
+    fn registers_are_traced_under_their_subsystem() {
+        assert_eq!(io_subsystem(0xFF00), IO_TRACE_JOYPAD);
+        assert_eq!(io_subsystem(SC_ADDR), IO_TRACE_SERIAL);
+        assert_eq!(io_subsystem(TAC_ADDR), IO_TRACE_TIMER);
+        assert_eq!(io_subsystem(INT_FLAGS_ADDR), IO_TRACE_INTERRUPTS);
+        assert_eq!(io_subsystem(0xFF26), IO_TRACE_SOUND);
+        assert_eq!(io_subsystem(0xFF44), IO_TRACE_LCD);
+        assert_eq!(io_subsystem(KEY1_ADDR), IO_TRACE_CGB);
+    }
+}
@@ -0,0 +1,301 @@
+use phf::{phf_map, Map};
+use sdl2_sys::SDL_KeyCode::*;
+use sdl2_sys::SDL_GameControllerButton::*;
+
+/**
+ * Mapping between keyboard keys and controller buttons and Game
+ * Boy buttons. A key map file contains one `button = keyname` pair
+ * per line, empty lines and lines starting with '#' are ignored.
+ * Controller buttons are named `pad_<name>` and bound in addition
+ * to the key, e.g.
+ *   a = x
+ *   a = pad_b
+ *   b = z
+ *   start = return
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    A,
+    B,
+    Start,
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    // All the buttons, in the order of their bits in a button mask
+    pub const ALL: [Button; 8] = [
+        Button::A, Button::B, Button::Start, Button::Select,
+        Button::Up, Button::Down, Button::Left, Button::Right,
+    ];
+
+    /**
+     * Returns the bit of the button in a button mask: A is bit 0,
+     * then B, Start, Select, Up, Down, Left and Right is bit 7
+     */
+    pub const fn mask(self) -> u8 {
+        return 1 << self as u8;
+    }
+}
+
+// A static lookup table that maps the button names in a key map file
+static BUTTON_NAMES: Map<&'static str, Button> = phf_map! {
+    "a" => Button::A,
+    "b" => Button::B,
+    "start" => Button::Start,
+    "select" => Button::Select,
+    "up" => Button::Up,
+    "down" => Button::Down,
+    "left" => Button::Left,
+    "right" => Button::Right,
+};
+
+// A static lookup table that maps the supported key names to SDL key codes
+static KEY_NAMES: Map<&'static str, i32> = phf_map! {
+    "a" => SDLK_a as i32, "b" => SDLK_b as i32, "c" => SDLK_c as i32,
+    "d" => SDLK_d as i32, "e" => SDLK_e as i32, "f" => SDLK_f as i32,
+    "g" => SDLK_g as i32, "h" => SDLK_h as i32, "i" => SDLK_i as i32,
+    "j" => SDLK_j as i32, "k" => SDLK_k as i32, "l" => SDLK_l as i32,
+    "m" => SDLK_m as i32, "n" => SDLK_n as i32, "o" => SDLK_o as i32,
+    "p" => SDLK_p as i32, "q" => SDLK_q as i32, "r" => SDLK_r as i32,
+    "s" => SDLK_s as i32, "t" => SDLK_t as i32, "u" => SDLK_u as i32,
+    "v" => SDLK_v as i32, "w" => SDLK_w as i32, "x" => SDLK_x as i32,
+    "y" => SDLK_y as i32, "z" => SDLK_z as i32,
+    "0" => SDLK_0 as i32, "1" => SDLK_1 as i32, "2" => SDLK_2 as i32,
+    "3" => SDLK_3 as i32, "4" => SDLK_4 as i32, "5" => SDLK_5 as i32,
+    "6" => SDLK_6 as i32, "7" => SDLK_7 as i32, "8" => SDLK_8 as i32,
+    "9" => SDLK_9 as i32,
+    "up" => SDLK_UP as i32,
+    "down" => SDLK_DOWN as i32,
+    "left" => SDLK_LEFT as i32,
+    "right" => SDLK_RIGHT as i32,
+    "return" => SDLK_RETURN as i32,
+    "enter" => SDLK_RETURN as i32,
+    "tab" => SDLK_TAB as i32,
+    "space" => SDLK_SPACE as i32,
+    "backspace" => SDLK_BACKSPACE as i32,
+    "escape" => SDLK_ESCAPE as i32,
+    "lshift" => SDLK_LSHIFT as i32,
+    "rshift" => SDLK_RSHIFT as i32,
+    "lctrl" => SDLK_LCTRL as i32,
+    "rctrl" => SDLK_RCTRL as i32,
+    "lalt" => SDLK_LALT as i32,
+    "ralt" => SDLK_RALT as i32,
+    "comma" => SDLK_COMMA as i32,
+    "period" => SDLK_PERIOD as i32,
+    "slash" => SDLK_SLASH as i32,
+    "semicolon" => SDLK_SEMICOLON as i32,
+};
+
+// A static lookup table that maps the supported controller button
+// names to SDL game controller buttons. The face buttons are named
+// after their position on an Xbox controller.
+static PAD_BUTTON_NAMES: Map<&'static str, i32> = phf_map! {
+    "pad_a" => SDL_CONTROLLER_BUTTON_A as i32,
+    "pad_b" => SDL_CONTROLLER_BUTTON_B as i32,
+    "pad_x" => SDL_CONTROLLER_BUTTON_X as i32,
+    "pad_y" => SDL_CONTROLLER_BUTTON_Y as i32,
+    "pad_back" => SDL_CONTROLLER_BUTTON_BACK as i32,
+    "pad_guide" => SDL_CONTROLLER_BUTTON_GUIDE as i32,
+    "pad_start" => SDL_CONTROLLER_BUTTON_START as i32,
+    "pad_lstick" => SDL_CONTROLLER_BUTTON_LEFTSTICK as i32,
+    "pad_rstick" => SDL_CONTROLLER_BUTTON_RIGHTSTICK as i32,
+    "pad_lb" => SDL_CONTROLLER_BUTTON_LEFTSHOULDER as i32,
+    "pad_rb" => SDL_CONTROLLER_BUTTON_RIGHTSHOULDER as i32,
+    "pad_up" => SDL_CONTROLLER_BUTTON_DPAD_UP as i32,
+    "pad_down" => SDL_CONTROLLER_BUTTON_DPAD_DOWN as i32,
+    "pad_left" => SDL_CONTROLLER_BUTTON_DPAD_LEFT as i32,
+    "pad_right" => SDL_CONTROLLER_BUTTON_DPAD_RIGHT as i32,
+};
+
+/**
+ * Errors that can occur while loading a key map file
+ */
+#[derive(Debug)]
+pub enum KeyMapError {
+    // The key map file could not be read
+    Io(std::io::Error),
+    // A line is not of the form `button = keyname`
+    Syntax(usize),
+    UnknownButton(usize, String),
+    UnknownKey(usize, String),
+}
+
+impl std::fmt::Display for KeyMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeyMapError::Io(err) => write!(f, "Unable to read key map file: {}", err),
+            KeyMapError::Syntax(line) =>
+                write!(f, "Key map line {}: expected `button = keyname`", line),
+            KeyMapError::UnknownButton(line, name) =>
+                write!(f, "Key map line {}: unknown button '{}' (expected one of \
+                    a, b, start, select, up, down, left, right)", line, name),
+            KeyMapError::UnknownKey(line, name) =>
+                write!(f, "Key map line {}: unknown key '{}'", line, name),
+        }
+    }
+}
+
+
+/**
+ * Key code and controller button bound to each of the buttons
+ */
+pub struct KeyMap {
+    a: i32,
+    b: i32,
+    start: i32,
+    select: i32,
+    up: i32,
+    down: i32,
+    left: i32,
+    right: i32,
+    // Controller button bound to each of the buttons
+    pad: [(i32, Button); 8],
+}
+
+pub static mut KEYMAP_CTX: KeyMap = KeyMap::default_map();
+
+impl KeyMap {
+    /**
+     * Returns the default key bindings:
+     * Z = B, X = A, Enter = Start, Tab = Select, arrows = D-pad.
+     * On a controller, the bottom and right face buttons are B and
+     * A like on the Game Boy, Back = Select, Start = Start and the
+     * D-pad is the D-pad.
+     */
+    pub const fn default_map() -> KeyMap {
+        KeyMap {
+            a: SDLK_x as i32,
+            b: SDLK_z as i32,
+            start: SDLK_RETURN as i32,
+            select: SDLK_TAB as i32,
+            up: SDLK_UP as i32,
+            down: SDLK_DOWN as i32,
+            left: SDLK_LEFT as i32,
+            right: SDLK_RIGHT as i32,
+            pad: [
+                (SDL_CONTROLLER_BUTTON_B as i32, Button::A),
+                (SDL_CONTROLLER_BUTTON_A as i32, Button::B),
+                (SDL_CONTROLLER_BUTTON_START as i32, Button::Start),
+                (SDL_CONTROLLER_BUTTON_BACK as i32, Button::Select),
+                (SDL_CONTROLLER_BUTTON_DPAD_UP as i32, Button::Up),
+                (SDL_CONTROLLER_BUTTON_DPAD_DOWN as i32, Button::Down),
+                (SDL_CONTROLLER_BUTTON_DPAD_LEFT as i32, Button::Left),
+                (SDL_CONTROLLER_BUTTON_DPAD_RIGHT as i32, Button::Right),
+            ],
+        }
+    }
+
+    /**
+     * Reads a key map file. Buttons that are not listed in the
+     * file keep their default binding.
+     */
+    pub fn from_file(path: &str) -> Result<KeyMap, KeyMapError> {
+        let content = std::fs::read_to_string(path).map_err(KeyMapError::Io)?;
+        return KeyMap::parse(&content);
+    }
+
+    /**
+     * Parses the content of a key map file
+     */
+    pub fn parse(content: &str) -> Result<KeyMap, KeyMapError> {
+        let mut keymap = KeyMap::default_map();
+        for (i, line) in content.lines().enumerate() {
+            let line_num = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (button_name, key_name) = match line.split_once('=') {
+                Some((button, key)) => (button.trim().to_lowercase(), key.trim().to_lowercase()),
+                None => return Err(KeyMapError::Syntax(line_num)),
+            };
+            let button = *BUTTON_NAMES.get(button_name.as_str())
+                .ok_or(KeyMapError::UnknownButton(line_num, button_name.clone()))?;
+            if let Some(pad_button) = PAD_BUTTON_NAMES.get(key_name.as_str()) {
+                keymap.bind_pad(button, *pad_button);
+                continue;
+            }
+            let key_code = *KEY_NAMES.get(key_name.as_str())
+                .ok_or(KeyMapError::UnknownKey(line_num, key_name.clone()))?;
+            keymap.bind(button, key_code);
+        }
+        return Ok(keymap);
+    }
+
+    /**
+     * Binds the given key code to a button
+     */
+    fn bind(&mut self, button: Button, key_code: i32) -> () {
+        match button {
+            Button::A => self.a = key_code,
+            Button::B => self.b = key_code,
+            Button::Start => self.start = key_code,
+            Button::Select => self.select = key_code,
+            Button::Up => self.up = key_code,
+            Button::Down => self.down = key_code,
+            Button::Left => self.left = key_code,
+            Button::Right => self.right = key_code,
+        }
+    }
+
+    /**
+     * Binds the given controller button to a button
+     */
+    fn bind_pad(&mut self, button: Button, pad_button: i32) -> () {
+        for binding in self.pad.iter_mut() {
+            if binding.1 == button {
+                binding.0 = pad_button;
+            }
+        }
+    }
+
+    /**
+     * Returns the button bound to the given controller button, if any
+     */
+    pub fn pad_button(&self, pad_button: i32) -> Option<Button> {
+        return self.pad.iter()
+            .find(|(code, _)| *code == pad_button)
+            .map(|(_, button)| *button);
+    }
+
+    /**
+     * Returns the button bound to the given key code, if any
+     */
+    pub fn button(&self, key_code: i32) -> Option<Button> {
+        let bindings = [
+            (self.a, Button::A),
+            (self.b, Button::B),
+            (self.start, Button::Start),
+            (self.select, Button::Select),
+            (self.up, Button::Up),
+            (self.down, Button::Down),
+            (self.left, Button::Left),
+            (self.right, Button::Right),
+        ];
+        return bindings.iter()
+            .find(|(code, _)| *code == key_code)
+            .map(|(_, button)| *button);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controller_buttons_are_bound_next_to_the_keys() {
+        let keymap = KeyMap::parse("# Swaps A and B\na = pad_a\nb = pad_b\na = c\n").unwrap();
+        assert_eq!(keymap.pad_button(SDL_CONTROLLER_BUTTON_A as i32), Some(Button::A));
+        assert_eq!(keymap.pad_button(SDL_CONTROLLER_BUTTON_B as i32), Some(Button::B));
+        assert_eq!(keymap.button(SDLK_c as i32), Some(Button::A));
+        // The other buttons keep their default binding
+        assert_eq!(keymap.pad_button(SDL_CONTROLLER_BUTTON_START as i32), Some(Button::Start));
+        assert_eq!(keymap.pad_button(SDL_CONTROLLER_BUTTON_X as i32), None);
+        assert!(matches!(KeyMap::parse("a = pad_z"), Err(KeyMapError::UnknownKey(1, _))));
+    }
+}
@@ -1,5 +1,7 @@
 use std::convert::TryFrom;
 use crate::emulator::dma::*;
+use crate::emulator::savestate::*;
+use crate::emulator::error::{fatal, EmuError};
 
 pub const LCD_START_ADDR: u16 = 0xFF40;
 pub const LCD_END_ADDR: u16 = 0xFF4B;
@@ -10,6 +12,138 @@ const DEFAULT_COLORS: [u32; 4] = [
     0xFF555555,
     0xFF000000,
 ];
+
+// Named sets of the four DMG shades, from lightest to darkest.
+// F3 cycles through them in this order.
+pub const PALETTE_PRESETS: [(&str, [u32; 4]); 4] = [
+    ("gray", DEFAULT_COLORS),
+    ("dmg-green", [0xFF9BBC0F, 0xFF8BAC0F, 0xFF306230, 0xFF0F380F]),
+    ("pocket", [0xFFC4CFA1, 0xFF8B956D, 0xFF4D533C, 0xFF1F1F1F]),
+    ("bgb", [0xFFE0F8D0, 0xFF88C070, 0xFF346856, 0xFF081820]),
+];
+
+/**
+ * Errors that can occur while parsing a DMG palette
+ */
+#[derive(Debug)]
+pub enum PaletteError {
+    UnknownPreset(String),
+    InvalidColor(String),
+    // The number of colors given instead of four
+    WrongCount(usize),
+}
+
+impl std::fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PaletteError::UnknownPreset(name) =>
+                write!(f, "Unknown palette '{}' (expected one of {} or four hex colors)", name,
+                    PALETTE_PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")),
+            PaletteError::InvalidColor(color) =>
+                write!(f, "Invalid palette color '{}', expected RRGGBB or AARRGGBB", color),
+            PaletteError::WrongCount(count) =>
+                write!(f, "A palette needs exactly 4 colors, {} given", count),
+        }
+    }
+}
+
+/**
+ * Parses a DMG palette given either as the name of a preset or as
+ * four comma-separated hex colors, e.g. `E0F8D0,88C070,346856,081820`.
+ * Colors without an alpha component are opaque.
+ */
+pub fn parse_palette(value: &str) -> Result<[u32; 4], PaletteError> {
+    if let Some((_, colors)) = PALETTE_PRESETS.iter().find(|(name, _)| *name == value) {
+        return Ok(*colors);
+    }
+    if !value.contains(',') {
+        return Err(PaletteError::UnknownPreset(value.to_string()));
+    }
+    let parts: Vec<&str> = value.split(',').map(|part| part.trim()).collect();
+    if parts.len() != 4 {
+        return Err(PaletteError::WrongCount(parts.len()));
+    }
+    let mut colors = [0u32; 4];
+    for (i, part) in parts.iter().enumerate() {
+        let hex = part.trim_start_matches('#').trim_start_matches("0x");
+        let color = u32::from_str_radix(hex, 16)
+            .map_err(|_| PaletteError::InvalidColor(part.to_string()))?;
+        colors[i] = match hex.len() {
+            6 => 0xFF000000 | color,
+            8 => color,
+            _ => return Err(PaletteError::InvalidColor(part.to_string())),
+        };
+    }
+    return Ok(colors);
+}
+// CGB palette registers
+pub const BCPS_ADDR: u16 = 0xFF68;
+pub const BCPD_ADDR: u16 = 0xFF69;
+pub const OCPS_ADDR: u16 = 0xFF6A;
+pub const OCPD_ADDR: u16 = 0xFF6B;
+// CGB only, OPRI - Object priority mode
+pub const OPRI_ADDR: u16 = 0xFF6C;
+// CGB only, KEY0 - CPU mode select, only writable by the boot ROM
+pub const KEY0_ADDR: u16 = 0xFF4C;
+// KEY0 value written by the CGB boot ROM for DMG games
+const KEY0_DMG_COMPAT: u8 = 0x04;
+
+/**
+ * CGB palette memory, holds 8 palettes of 4 RGB555 colors each.
+ * It can only be accessed through an index register (BCPS/OCPS)
+ * and a data register (BCPD/OCPD).
+ * https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+ */
+#[derive(Copy, Clone)]
+pub struct CgbPalette {
+    data: [u8; 64],
+    // Index of the byte accessed through the data register
+    index: u8,
+    // Increments the index after each write to the data register
+    auto_increment: bool,
+}
+
+impl CgbPalette {
+    const fn new() -> CgbPalette {
+        // The palettes are initialized to white on boot
+        CgbPalette { data: [0xFF; 64], index: 0, auto_increment: false }
+    }
+
+    pub fn read_spec(&self) -> u8 {
+        return ((self.auto_increment as u8) << 7) | 0x40 | self.index;
+    }
+
+    pub fn write_spec(&mut self, value: u8) -> () {
+        self.index = value & 0x3F;
+        self.auto_increment = (value & 0x80) != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        return self.data[self.index as usize];
+    }
+
+    pub fn write_data(&mut self, value: u8) -> () {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /**
+     * Returns the given color of the given palette as ARGB8888
+     */
+    pub fn color(&self, palette: u8, color: u8) -> u32 {
+        let offset = ((palette & 0x07) as usize * 8) + (color & 0x03) as usize * 2;
+        let rgb555 = self.data[offset] as u32 | ((self.data[offset + 1] as u32) << 8);
+        // Scales each 5-bit component to 8 bits
+        let r = rgb555 & 0x1F;
+        let g = (rgb555 >> 5) & 0x1F;
+        let b = (rgb555 >> 10) & 0x1F;
+        let scale = |c: u32| (c << 3) | (c >> 2);
+        return 0xFF000000 | (scale(r) << 16) | (scale(g) << 8) | scale(b);
+    }
+}
+
 /**
  * A struct that defines the LCD and all
  * the registers associated with it
@@ -42,14 +176,23 @@ pub struct LCD {
     pub win_x: u8,
 
     // Other data
+    // The four shades that the DMG palettes map to
+    dmg_colors: [u32; 4],
     pub bg_colors: [u32; 4],
     pub sp1_colors: [u32; 4],
     pub sp2_colors: [u32; 4],
+    // CGB palettes
+    pub bg_cgb_palette: CgbPalette,
+    pub obj_cgb_palette: CgbPalette,
+    // Bit 0 set: sprites are prioritized by X coordinate like on
+    // DMG, otherwise by OAM index
+    opri: u8,
+    key0: u8,
 }
 
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum LCD_MODE {
     MODE_HBLANK = 0,
     MODE_VBLANK = 1,
@@ -118,27 +261,49 @@ pub const VBLANK_INT_MASK: u8 = 0x10;
 pub const HBLANK_INT_MASK: u8 = 0x08;
 const LYC_LY_MASK: u8 = 0x04;
 const PPU_MODE_MASK: u8 = 0x03;
+// Only the interrupt selection bits of STAT are writable,
+// the mode and LYC = LY bits are set by the PPU
+const STAT_WRITE_MASK: u8 = LYC_INT_MASK | OAM_INT_MASK | VBLANK_INT_MASK | HBLANK_INT_MASK;
 
 
-pub static mut LCD_CTX: LCD = LCD {
-    lcdc: 0x91,
-    lcds: 0,
-    scroll_x: 0,
-    scroll_y: 0,
-    ly: 0,
-    lyc: 0,
-    dma: 0,
-    bg_palette: 0xFC,
-    obj_palette: [0xFF; 2],
-    win_x: 0,
-    win_y: 0,
-    bg_colors: [DEFAULT_COLORS[0], DEFAULT_COLORS[1], DEFAULT_COLORS[2], DEFAULT_COLORS[3]],
-    sp1_colors: [DEFAULT_COLORS[0], DEFAULT_COLORS[1], DEFAULT_COLORS[2], DEFAULT_COLORS[3]],
-    sp2_colors: [DEFAULT_COLORS[0], DEFAULT_COLORS[1], DEFAULT_COLORS[2], DEFAULT_COLORS[3]],
-};
+pub static mut LCD_CTX: LCD = LCD::new();
 
 
 impl LCD {
+    const fn new() -> LCD {
+        LCD {
+            lcdc: 0x91,
+            lcds: 0,
+            scroll_x: 0,
+            scroll_y: 0,
+            ly: 0,
+            lyc: 0,
+            dma: 0,
+            bg_palette: 0xFC,
+            obj_palette: [0xFF; 2],
+            win_x: 0,
+            win_y: 0,
+            dmg_colors: DEFAULT_COLORS,
+            bg_colors: [DEFAULT_COLORS[0], DEFAULT_COLORS[1], DEFAULT_COLORS[2], DEFAULT_COLORS[3]],
+            sp1_colors: [DEFAULT_COLORS[0], DEFAULT_COLORS[1], DEFAULT_COLORS[2], DEFAULT_COLORS[3]],
+            sp2_colors: [DEFAULT_COLORS[0], DEFAULT_COLORS[1], DEFAULT_COLORS[2], DEFAULT_COLORS[3]],
+            bg_cgb_palette: CgbPalette::new(),
+            obj_cgb_palette: CgbPalette::new(),
+            opri: 0,
+            key0: 0,
+        }
+    }
+
+    /**
+     * Resets the registers, keeping the selected DMG colors
+     */
+    pub fn reset(&mut self) -> () {
+        let dmg_colors = self.dmg_colors;
+        *self = LCD::new();
+        self.set_dmg_colors(dmg_colors);
+        self.set_lcds_mode(LCD_MODE::MODE_OAM);
+    }
+
     pub fn init() -> () {
         log::info!("Initializing LCD...");
         unsafe { LCD_CTX.set_lcds_mode(LCD_MODE::MODE_OAM); }
@@ -148,7 +313,8 @@ impl LCD {
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
             0xFF40 => self.lcdc,
-            0xFF41 => self.lcds,
+            // Bit 7 is unused
+            0xFF41 => self.lcds | 0x80,
             0xFF42 => self.scroll_y,
             0xFF43 => self.scroll_x,
             0xFF44 => self.ly,
@@ -163,6 +329,32 @@ impl LCD {
         }
     }
 
+    /**
+     * Reads from one of the CGB palette registers
+     */
+    pub fn cgb_palette_read(&self, addr: u16) -> u8 {
+        match addr {
+            BCPS_ADDR => self.bg_cgb_palette.read_spec(),
+            BCPD_ADDR => self.bg_cgb_palette.read_data(),
+            OCPS_ADDR => self.obj_cgb_palette.read_spec(),
+            OCPD_ADDR => self.obj_cgb_palette.read_data(),
+            _ => panic!("Invalid CGB palette register read: {:#X}", addr),
+        }
+    }
+
+    /**
+     * Writes to one of the CGB palette registers
+     */
+    pub fn cgb_palette_write(&mut self, addr: u16, value: u8) -> () {
+        match addr {
+            BCPS_ADDR => self.bg_cgb_palette.write_spec(value),
+            BCPD_ADDR => self.bg_cgb_palette.write_data(value),
+            OCPS_ADDR => self.obj_cgb_palette.write_spec(value),
+            OCPD_ADDR => self.obj_cgb_palette.write_data(value),
+            _ => panic!("Invalid CGB palette register write: {:#X}", addr),
+        }
+    }
+
     fn update_palette(&mut self, palette_data: u8, palette: u8) -> () {
         let colors: *mut [u32; 4];
         match palette {
@@ -175,39 +367,65 @@ impl LCD {
             2 => {
                 colors = &mut self.sp2_colors;
             },
-            _ => {
-                log::warn!("Invalid palette number: {}", palette);
-                std::process::exit(1);
-            }
+            _ => fatal(EmuError::InvalidPalette(palette)),
         }
         unsafe {
-            (*colors)[0] = DEFAULT_COLORS[(palette_data & 0b11) as usize];
-            (*colors)[1] = DEFAULT_COLORS[((palette_data >> 2) & 0b11) as usize];
-            (*colors)[2] = DEFAULT_COLORS[((palette_data >> 4) & 0b11) as usize];
-            (*colors)[3] = DEFAULT_COLORS[((palette_data >> 6) & 0b11) as usize];
+            (*colors)[0] = self.dmg_colors[(palette_data & 0b11) as usize];
+            (*colors)[1] = self.dmg_colors[((palette_data >> 2) & 0b11) as usize];
+            (*colors)[2] = self.dmg_colors[((palette_data >> 4) & 0b11) as usize];
+            (*colors)[3] = self.dmg_colors[((palette_data >> 6) & 0b11) as usize];
         }
     }
 
+    /**
+     * Recomputes the BG and OBJ colors from the palette registers
+     */
+    fn update_palettes(&mut self) -> () {
+        self.update_palette(self.bg_palette, 0);
+        self.update_palette(self.obj_palette[0] & 0b11111100, 1);
+        self.update_palette(self.obj_palette[1] & 0b11111100, 2);
+    }
+
+    /**
+     * Replaces the four shades used by the DMG palettes
+     */
+    pub fn set_dmg_colors(&mut self, colors: [u32; 4]) -> () {
+        self.dmg_colors = colors;
+        self.update_palettes();
+    }
+
+    pub fn get_dmg_colors(&self) -> [u32; 4] {
+        return self.dmg_colors;
+    }
+
     pub fn write(&mut self, addr: u16, value: u8) -> () {
         match addr {
             0xFF40 => self.lcdc = value,
-            0xFF41 => self.lcds = value,
+            0xFF41 => self.lcds = (self.lcds & !STAT_WRITE_MASK) | (value & STAT_WRITE_MASK),
             0xFF42 => self.scroll_y = value,
             0xFF43 => self.scroll_x = value,
-            0xFF44 => self.ly = value,
-            0xFF45 => self.lyc = value,
+            // LY is read-only
+            0xFF44 => (),
+            // Writing LYC compares it with the current LY
+            0xFF45 => {
+                self.lyc = value;
+                self.update_lyc();
+            },
             DMA_ADDR => { 
                 self.dma = value;
                 unsafe { DMA_CTX.start(value) };
             }
             0xFF47 => { 
+                self.bg_palette = value;
                 self.update_palette(value, 0);
             },
             0xFF48 => {
+                self.obj_palette[0] = value;
                 // the lower two bits are ignored because color index 0 is transparent for OBJs
                 self.update_palette(value & 0b11111100, 1);
             },
             0xFF49 => {
+                self.obj_palette[1] = value;
                 // the lower two bits are ignored because color index 0 is transparent for OBJs
                 self.update_palette(value & 0b11111100, 2);
             },
@@ -269,10 +487,129 @@ impl LCD {
         self.lcds = (self.lcds & !LYC_LY_MASK) | ((value as u8) << 2);
     }
 
+    /**
+     * Compares LY with LYC and updates the LYC flag. The PPU requests
+     * the STAT interrupt on its next tick if the flag becomes set.
+     */
+    pub fn update_lyc(&mut self) -> () {
+        self.set_lcds_lyc(self.ly == self.lyc);
+    }
+
+    /**
+     * Sets LY, which always updates the LYC flag
+     */
+    pub fn set_ly(&mut self, ly: u8) -> () {
+        self.ly = ly;
+        self.update_lyc();
+    }
+
     /**
      * Returns the value of a LCD Status flag given its mask
      */
     pub fn get_lcds_flag(&self, mask: u8) -> bool {
         return (self.lcds & mask) != 0;
     }
-}
\ No newline at end of file
+
+    /**
+     * Reads the OPRI register (CGB only), bits 1 - 7 are unused
+     */
+    pub fn read_opri(&self) -> u8 {
+        return 0xFE | self.opri;
+    }
+
+    /**
+     * Writes the OPRI register (CGB only)
+     */
+    pub fn write_opri(&mut self, value: u8) -> () {
+        self.opri = value & 1;
+    }
+
+    /**
+     * Returns true if the sprite with the smaller X coordinate is
+     * drawn on top, as on DMG, instead of the one with the smaller
+     * OAM index
+     */
+    pub fn x_priority(&self) -> bool {
+        return (self.opri & 1) != 0;
+    }
+
+    pub fn read_key0(&self) -> u8 {
+        return self.key0;
+    }
+
+    /**
+     * Writes the KEY0 register (CGB only). The boot ROM selects the
+     * DMG compatibility mode for DMG games, in which sprites are
+     * prioritized by X coordinate.
+     */
+    pub fn write_key0(&mut self, value: u8) -> () {
+        self.key0 = value;
+        if (value & KEY0_DMG_COMPAT) != 0 {
+            self.opri = 1;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_bytes(&[
+            self.lcdc, self.lcds, self.scroll_y, self.scroll_x, self.ly, self.lyc,
+            self.dma, self.bg_palette, self.obj_palette[0], self.obj_palette[1],
+            self.win_y, self.win_x,
+        ]);
+        for palette in [&self.bg_cgb_palette, &self.obj_cgb_palette] {
+            writer.write_bytes(&palette.data);
+            writer.write_u8(palette.index);
+            writer.write_bool(palette.auto_increment);
+        }
+        writer.write_u8(self.opri);
+        writer.write_u8(self.key0);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let mut regs = [0u8; 12];
+        reader.read_bytes(&mut regs)?;
+        self.lcdc = regs[0];
+        self.lcds = regs[1];
+        self.scroll_y = regs[2];
+        self.scroll_x = regs[3];
+        self.ly = regs[4];
+        self.lyc = regs[5];
+        self.dma = regs[6];
+        self.bg_palette = regs[7];
+        self.obj_palette = [regs[8], regs[9]];
+        self.win_y = regs[10];
+        self.win_x = regs[11];
+        // The colors are derived from the registers so that the
+        // palette chosen by the user is kept
+        self.update_palettes();
+        for palette in [&mut self.bg_cgb_palette, &mut self.obj_cgb_palette] {
+            reader.read_bytes(&mut palette.data)?;
+            palette.index = reader.read_u8()? & 0x3F;
+            palette.auto_increment = reader.read_bool()?;
+        }
+        self.opri = reader.read_u8()? & 1;
+        self.key0 = reader.read_u8()?;
+        return Ok(());
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ly_and_the_stat_status_bits_are_read_only() {
+        let mut lcd = LCD::new();
+        lcd.write(0xFF45, 10);
+        lcd.set_ly(5);
+        lcd.set_lcds_mode(LCD_MODE::MODE_HBLANK);
+        lcd.write(0xFF44, 0xFF);
+        lcd.write(0xFF41, 0xFF);
+        assert_eq!(lcd.read(0xFF44), 5);
+        // Only the interrupt selection bits are set, bit 7 reads 1
+        assert_eq!(lcd.read(0xFF41), 0xF8);
+
+        lcd.set_ly(10);
+        lcd.set_lcds_mode(LCD_MODE::MODE_XFER);
+        lcd.write(0xFF41, 0x00);
+        assert_eq!(lcd.read(0xFF41), 0x80 | LYC_LY_MASK | LCD_MODE::MODE_XFER as u8);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::emulator::ppu::PPU_CTX;
+use crate::emulator::ram::RAM_CTX;
+use crate::emulator::Emulator;
+
+/**
+ * Dumps of the memories that are not fully visible through the
+ * address bus, e.g. all VRAM and WRAM banks of the CGB. Each dump
+ * is the raw content of the memory, starting at its first byte.
+ */
+
+/**
+ * The memories that can be dumped
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MemoryRegion {
+    // 0x8000 - 0x9FFF, both banks on the CGB
+    Vram,
+    // 0xFE00 - 0xFE9F
+    Oam,
+    // 0xC000 - 0xDFFF, all 8 banks on the CGB
+    Wram,
+    // 0xFF80 - 0xFFFE
+    Hram,
+}
+
+pub const MEMORY_REGIONS: [MemoryRegion; 4] = [
+    MemoryRegion::Vram, MemoryRegion::Oam, MemoryRegion::Wram, MemoryRegion::Hram
+];
+
+impl MemoryRegion {
+    /**
+     * Returns the name used in the file names and debugger commands
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryRegion::Vram => "vram",
+            MemoryRegion::Oam => "oam",
+            MemoryRegion::Wram => "wram",
+            MemoryRegion::Hram => "hram",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MemoryRegion> {
+        return MEMORY_REGIONS.iter().copied().find(|region| region.name() == name);
+    }
+
+    /**
+     * Returns a copy of the content of the memory. The banks that
+     * only exist on the CGB are left out in DMG mode.
+     */
+    pub fn read(&self) -> Vec<u8> {
+        let cgb = Emulator::is_cgb();
+        unsafe {
+            match self {
+                MemoryRegion::Vram => {
+                    let vram = PPU_CTX.vram();
+                    vram[..if cgb { vram.len() } else { vram.len() / 2 }].to_vec()
+                }
+                MemoryRegion::Oam => PPU_CTX.oam_bytes().to_vec(),
+                MemoryRegion::Wram => {
+                    let wram = RAM_CTX.wram();
+                    wram[..if cgb { wram.len() } else { wram.len() / 4 }].to_vec()
+                }
+                MemoryRegion::Hram => RAM_CTX.hram().to_vec(),
+            }
+        }
+    }
+}
+
+/**
+ * Writes the content of the memory to the given file. Must be called
+ * between two instructions, i.e., from the thread running the CPU.
+ */
+pub fn dump_memory(region: MemoryRegion, path: &str) -> std::io::Result<()> {
+    return std::fs::write(path, region.read());
+}
+
+/**
+ * Returns the file name of a dump of the current ROM, e.g.
+ * `tetris_vram_1234.bin` for a dump of the VRAM at frame 1234
+ */
+pub fn dump_path(region: MemoryRegion) -> String {
+    let rom_path = Emulator::state_slot_path();
+    let rom_name = std::path::Path::new(&rom_path).file_stem()
+        .map_or("dump".to_string(), |stem| stem.to_string_lossy().into_owned());
+    let frame = unsafe { PPU_CTX.curr_frame };
+    return format!("{}_{}_{}.bin", rom_name, region.name(), frame);
+}
+
+/**
+ * Dumps all memories next to the ROM and logs the result
+ */
+pub fn dump_all() -> () {
+    for region in MEMORY_REGIONS {
+        let path = dump_path(region);
+        match dump_memory(region, &path) {
+            Ok(()) => log::info!(target: "stdout", "Dumping {} to {}: SUCCESS", region.name(), path),
+            Err(err) => log::error!(target: "stdout", "Dumping {} to {}: {}", region.name(), path, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::address_bus::bus_write;
+    use crate::emulator::test_util;
+
+    #[test]
+    fn dumps_hold_the_content_of_the_memory() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        bus_write(0xC123, 0x5A);
+        bus_write(0xFF81, 0xA5);
+        let path = std::env::temp_dir().join(format!("gb-emu-dump-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        dump_memory(MemoryRegion::Wram, path).unwrap();
+        let wram = std::fs::read(path).unwrap();
+        // Only the 2 banks of the DMG
+        assert_eq!(wram.len(), 0x2000);
+        assert_eq!(wram[0x123], 0x5A);
+        dump_memory(MemoryRegion::Hram, path).unwrap();
+        assert_eq!(std::fs::read(path).unwrap()[1], 0xA5);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn regions_are_found_by_name() {
+        for region in MEMORY_REGIONS {
+            assert_eq!(MemoryRegion::from_name(region.name()), Some(region));
+        }
+        assert_eq!(MemoryRegion::from_name("rom"), None);
+    }
+}
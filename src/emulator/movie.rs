@@ -0,0 +1,361 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use crate::emulator::Emulator;
+use crate::emulator::cartridge::CARTRIDGE_CTX;
+use crate::emulator::gamepad::GAMEPAD_CTX;
+use crate::emulator::keymap::Button;
+use crate::emulator::savestate::StateError;
+
+/**
+ * Input movies, used to replay a session exactly, e.g. for tool-
+ * assisted play or to reproduce a bug. A movie holds the state of
+ * the machine when the recording started and the buttons held during
+ * each frame. While recording, the live input only reaches the game
+ * at frame boundaries, so that playing the movie back from the same
+ * state produces the same frames.
+ *
+ * The file is a flat little-endian binary stream: the magic header,
+ * the version, the header and global checksums of the ROM, the length
+ * of the initial save state followed by the state itself, then the
+ * number of frames followed by one button mask per frame.
+ */
+
+pub const MOVIE_MAGIC: &[u8; 8] = b"GBEMUMOV";
+// Must be bumped whenever the layout of the movie changes
+pub const MOVIE_VERSION: u32 = 1;
+
+/**
+ * Errors that can occur while saving or loading a movie
+ */
+#[derive(Debug)]
+pub enum MovieError {
+    // The movie file could not be read or written
+    Io(std::io::Error),
+    // The file does not start with the magic header
+    InvalidMagic,
+    // The movie was created by an incompatible version of the emulator
+    UnsupportedVersion(u32),
+    // The movie ended before all of its fields were read
+    Truncated,
+    // The movie was recorded with a different ROM
+    RomMismatch,
+    // The initial state of the movie could not be loaded
+    State(StateError),
+}
+
+impl std::fmt::Display for MovieError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MovieError::Io(err) => write!(f, "Unable to access movie: {}", err),
+            MovieError::InvalidMagic => write!(f, "Not a movie file"),
+            MovieError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported movie version {}, expected {}",
+                    version, MOVIE_VERSION),
+            MovieError::Truncated => write!(f, "Movie is truncated"),
+            MovieError::RomMismatch => write!(f, "Movie was recorded with a different ROM"),
+            MovieError::State(err) => write!(f, "Unable to load the movie state: {}", err),
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum MovieMode {
+    Idle,
+    Recording,
+    Playing,
+}
+
+pub struct Movie {
+    header_checksum: u8,
+    global_checksum: u16,
+    // Save state of the machine when the movie starts
+    initial_state: Vec<u8>,
+    // Buttons held during each frame, see Button::mask
+    frames: Vec<u8>,
+}
+
+/**
+ * The movie being recorded or played back by the emulator
+ */
+pub struct MovieSession {
+    mode: MovieMode,
+    movie: Movie,
+    // File the recording is written to when it stops
+    path: String,
+    // Next frame to play back
+    position: usize,
+}
+
+pub static mut MOVIE_CTX: MovieSession = MovieSession {
+    mode: MovieMode::Idle,
+    movie: Movie {
+        header_checksum: 0,
+        global_checksum: 0,
+        initial_state: Vec::new(),
+        frames: Vec::new(),
+    },
+    path: String::new(),
+    position: 0,
+};
+
+// Buttons currently held on the keyboard or controller while
+// recording. Written by the UI thread, applied by the CPU thread.
+static LIVE_BUTTONS: AtomicU8 = AtomicU8::new(0);
+
+/**
+ * Reads `len` bytes at the given position of a movie file
+ */
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], MovieError> {
+    if *pos + len > data.len() {
+        return Err(MovieError::Truncated);
+    }
+    let slice = &data[*pos..*pos + len];
+    *pos += len;
+    return Ok(slice);
+}
+
+fn take_u32(data: &[u8], pos: &mut usize) -> Result<u32, MovieError> {
+    let bytes = take(data, pos, 4)?;
+    return Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+}
+
+impl Movie {
+    /**
+     * Creates an empty movie starting from the current state
+     */
+    fn from_current_state() -> Movie {
+        let (header_checksum, global_checksum) = unsafe { CARTRIDGE_CTX.checksums() };
+        return Movie {
+            header_checksum: header_checksum,
+            global_checksum: global_checksum,
+            initial_state: Emulator::serialize_state(),
+            frames: Vec::new(),
+        };
+    }
+
+    /**
+     * Reads a movie file
+     */
+    pub fn from_file(path: &str) -> Result<Movie, MovieError> {
+        let data = std::fs::read(path).map_err(MovieError::Io)?;
+        return Movie::parse(&data);
+    }
+
+    /**
+     * Parses the content of a movie file
+     */
+    pub fn parse(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut pos = 0;
+        if take(data, &mut pos, MOVIE_MAGIC.len()).map_err(|_| MovieError::InvalidMagic)? != MOVIE_MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+        let version = take_u32(data, &mut pos)?;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let checksums = take(data, &mut pos, 3)?;
+        let header_checksum = checksums[0];
+        let global_checksum = u16::from_le_bytes([checksums[1], checksums[2]]);
+        let state_len = take_u32(data, &mut pos)? as usize;
+        let initial_state = take(data, &mut pos, state_len)?.to_vec();
+        let frame_count = take_u32(data, &mut pos)? as usize;
+        let frames = take(data, &mut pos, frame_count)?.to_vec();
+        return Ok(Movie {
+            header_checksum: header_checksum,
+            global_checksum: global_checksum,
+            initial_state: initial_state,
+            frames: frames,
+        });
+    }
+
+    /**
+     * Serializes the movie into the content of a movie file
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(23 + self.initial_state.len() + self.frames.len());
+        data.extend_from_slice(MOVIE_MAGIC);
+        data.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        data.push(self.header_checksum);
+        data.extend_from_slice(&self.global_checksum.to_le_bytes());
+        data.extend_from_slice(&(self.initial_state.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.initial_state);
+        data.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.frames);
+        return data;
+    }
+
+    /**
+     * Returns the buttons held during each frame
+     */
+    pub fn frames(&self) -> &[u8] {
+        return &self.frames;
+    }
+
+    /**
+     * Restores the state the movie starts from. Movies recorded
+     * with another ROM are rejected before anything is modified.
+     */
+    pub fn restore(&self) -> Result<(), MovieError> {
+        if unsafe { CARTRIDGE_CTX.checksums() } != (self.header_checksum, self.global_checksum) {
+            return Err(MovieError::RomMismatch);
+        }
+        Emulator::deserialize_state(&self.initial_state).map_err(MovieError::State)?;
+        return Ok(());
+    }
+}
+
+impl MovieSession {
+    /**
+     * Starts recording a movie from the current state. The movie
+     * is written to the given file when the recording stops.
+     */
+    pub fn start_recording(&mut self, path: &str) -> () {
+        self.movie = Movie::from_current_state();
+        self.path = path.to_string();
+        self.mode = MovieMode::Recording;
+        self.apply_live_input();
+        log::info!(target: "stdout", "Recording movie to {}", path);
+    }
+
+    /**
+     * Restores the initial state of the given movie and starts
+     * playing back its input. The live input is ignored until the
+     * last frame has been played.
+     */
+    pub fn start_playback(&mut self, movie: Movie) -> Result<(), MovieError> {
+        movie.restore()?;
+        self.movie = movie;
+        self.mode = MovieMode::Playing;
+        self.position = 0;
+        self.play_next_frame();
+        return Ok(());
+    }
+
+    /**
+     * Called by the PPU at the end of every frame. The input of
+     * the next frame is applied and, when recording, written down.
+     */
+    pub fn end_frame(&mut self) -> () {
+        match self.mode {
+            MovieMode::Recording => self.apply_live_input(),
+            MovieMode::Playing => self.play_next_frame(),
+            MovieMode::Idle => (),
+        }
+    }
+
+    /**
+     * Writes the movie being recorded to its file and stops
+     * recording or playing back
+     */
+    pub fn stop(&mut self) -> () {
+        if self.mode == MovieMode::Recording {
+            match std::fs::write(&self.path, self.movie.to_bytes()) {
+                Ok(()) => log::info!(target: "stdout", "Saving movie to {} ({} frames): SUCCESS",
+                    self.path, self.movie.frames.len()),
+                Err(err) => log::error!(target: "stdout",
+                    "Unable to save movie to {}: {}", self.path, err),
+            }
+        }
+        self.mode = MovieMode::Idle;
+    }
+
+    /**
+     * Applies the buttons currently held to the game pad and
+     * records them as the input of the next frame
+     */
+    fn apply_live_input(&mut self) -> () {
+        unsafe {
+            GAMEPAD_CTX.set_buttons(LIVE_BUTTONS.load(Ordering::Relaxed));
+            // Records the buttons once the SOCD have been resolved
+            self.movie.frames.push(GAMEPAD_CTX.buttons());
+        }
+    }
+
+    /**
+     * Applies the input of the next frame of the movie, or releases
+     * all the buttons and stops once the movie is over
+     */
+    fn play_next_frame(&mut self) -> () {
+        match self.movie.frames.get(self.position) {
+            Some(buttons) => unsafe { GAMEPAD_CTX.set_buttons(*buttons) },
+            None => {
+                unsafe { GAMEPAD_CTX.set_buttons(0); }
+                log::info!(target: "stdout", "Movie playback finished after {} frames",
+                    self.position);
+                self.mode = MovieMode::Idle;
+            }
+        }
+        self.position += 1;
+    }
+}
+
+/**
+ * Presses or releases a button from the keyboard or a controller.
+ * While recording, the button only reaches the game at the next
+ * frame boundary. During playback the live input is ignored.
+ */
+pub fn live_button(button: Button, pressed: bool) -> () {
+    match unsafe { MOVIE_CTX.mode } {
+        MovieMode::Idle => unsafe { GAMEPAD_CTX.set_button(button, pressed) },
+        MovieMode::Recording => {
+            if pressed {
+                LIVE_BUTTONS.fetch_or(button.mask(), Ordering::Relaxed);
+            } else {
+                LIVE_BUTTONS.fetch_and(!button.mask(), Ordering::Relaxed);
+            }
+        },
+        MovieMode::Playing => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GameBoy;
+    use crate::emulator::ppu;
+    use crate::emulator::test_util;
+
+    #[test]
+    fn played_back_movie_gives_the_recorded_frames() {
+        let _lock = test_util::lock();
+        let path = std::env::temp_dir().join(format!("gb-emu-movie-{}.gbm", std::process::id()));
+        let path = path.to_str().unwrap();
+        ppu::set_frame_limiter(false);
+        // loop: LD A, 0x20; LDH (P1), A; LDH A, (P1); LDH (BGP), A; JR loop
+        // The blank background takes the color of the directions held
+        test_util::boot(&[0x3E, 0x20, 0xE0, 0x00, 0xF0, 0x00, 0xE0, 0x47, 0x18, 0xF6]);
+        let mut gameboy = GameBoy { _private: () };
+        gameboy.run_frame();
+
+        let inputs = [Button::Right.mask(), 0, Button::Left.mask() | Button::A.mask(), 0, Button::Up.mask()];
+        let mut recorded = Vec::new();
+        unsafe { MOVIE_CTX.start_recording(path); }
+        for buttons in inputs {
+            for button in Button::ALL {
+                live_button(button, (buttons & button.mask()) != 0);
+            }
+            recorded.push(gameboy.run_frame().to_vec());
+        }
+        unsafe { MOVIE_CTX.stop(); }
+        // The input of the last frame boundary is recorded too
+        recorded.push(gameboy.run_frame().to_vec());
+
+        // Starts from another state, which the movie replaces
+        gameboy.run_frame();
+        let movie = Movie::from_file(path).unwrap();
+        unsafe { MOVIE_CTX.start_playback(movie).unwrap(); }
+        let played: Vec<Vec<u32>> = inputs.iter().map(|_| gameboy.run_frame().to_vec()).collect();
+        unsafe { MOVIE_CTX.stop(); }
+        let last = gameboy.play_movie(path).unwrap().to_vec();
+        let _ = std::fs::remove_file(path);
+        unsafe { GAMEPAD_CTX.set_buttons(0); }
+        LIVE_BUTTONS.store(0, Ordering::Relaxed);
+        ppu::set_frame_limiter(true);
+
+        assert!(played[..] == recorded[..inputs.len()]);
+        assert!(last == recorded[inputs.len()]);
+        // The input did reach the game
+        assert!(recorded[1] != recorded[3]);
+        assert!(recorded[5] != recorded[1]);
+    }
+}
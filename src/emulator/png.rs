@@ -0,0 +1,134 @@
+use std::io::Write;
+
+/**
+ * A minimal PNG encoder for dumping frames. The image data is
+ * stored in uncompressed deflate blocks, which keeps the encoder
+ * small at the cost of larger files.
+ * https://www.w3.org/TR/png/
+ * https://www.rfc-editor.org/rfc/rfc1950 (zlib)
+ * https://www.rfc-editor.org/rfc/rfc1951 (deflate)
+ */
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// Maximum size of a stored deflate block
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/**
+ * Computes the CRC-32 (ISO 3309) of the given data
+ */
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    return !crc;
+}
+
+/**
+ * Computes the Adler-32 checksum used by zlib
+ */
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return (b << 16) | a;
+}
+
+/**
+ * Wraps the given data into a zlib stream of stored deflate blocks
+ */
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // CMF (deflate, 32K window) and FLG (no dictionary, fastest)
+    let mut out = vec![0x78, 0x01];
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        // An empty final block
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        out.push(last as u8);
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    return out;
+}
+
+/**
+ * Writes a single PNG chunk
+ */
+fn write_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) -> () {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/**
+ * Encodes an image given as ARGB8888 pixels into a RGBA PNG
+ */
+pub fn encode_argb(width: u32, height: u32, pixels: &[u32]) -> Vec<u8> {
+    assert!(pixels.len() == (width * height) as usize);
+    // Each scanline is prefixed by its filter type (0 = None)
+    let mut raw: Vec<u8> = Vec::with_capacity(((width * 4 + 1) * height) as usize);
+    for row in pixels.chunks(width as usize) {
+        raw.push(0);
+        for pixel in row {
+            let [a, r, g, b] = pixel.to_be_bytes();
+            raw.extend_from_slice(&[r, g, b, a]);
+        }
+    }
+
+    let mut header: Vec<u8> = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, color type 6 (RGBA), deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut out: Vec<u8> = Vec::new();
+    out.extend_from_slice(&PNG_SIGNATURE);
+    write_chunk(&mut out, b"IHDR", &header);
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    return out;
+}
+
+/**
+ * Writes an image given as ARGB8888 pixels to a PNG file
+ */
+pub fn write_argb(path: &str, width: u32, height: u32, pixels: &[u32]) -> std::io::Result<()> {
+    let data = encode_argb(width, height, pixels);
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(&data)?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn argb_pixels_are_encoded_as_rgba() {
+        let png = encode_argb(2, 1, &[0xFF112233, 0x80445566]);
+        assert_eq!(png[0..8], PNG_SIGNATURE);
+        assert_eq!(png[8..16], [0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(png[16..29], [0, 0, 0, 2, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+        // A single stored block holding the filter type and the pixels
+        assert_eq!(png[37..41], *b"IDAT");
+        assert_eq!(png[41..48], [0x78, 0x01, 0x01, 0x09, 0x00, 0xF6, 0xFF]);
+        assert_eq!(png[48..57], [0x00, 0x11, 0x22, 0x33, 0xFF, 0x44, 0x55, 0x66, 0x80]);
+        assert_eq!(png[png.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+}
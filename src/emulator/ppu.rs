@@ -1,15 +1,23 @@
-use std::collections::LinkedList;
-use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use once_cell::sync::Lazy;
 use crate::emulator::cpu::interrupts::*;
 use crate::emulator::ui;
-use crate::emulator::address_bus::*;
 use crate::emulator::cartridge::CARTRIDGE_CTX;
+use crate::emulator::cheats::CHEATS_CTX;
+use crate::emulator::movie::MOVIE_CTX;
 use super::{lcd::*, cpu::interrupts::request_interrupt};
+use crate::emulator::savestate::*;
+use crate::emulator::png;
+use crate::emulator::Emulator;
+use crate::emulator::dma::HDMA_CTX;
+use crate::emulator::ram::{fill_power_on, InitRam};
 
 pub mod fifo;
 use fifo::*;
+pub mod scanline;
+pub use scanline::{set_renderer, Renderer};
 
 // Bit masks for accessing the OAM flags
 const PRIORITY_MASK: u8     = 0x80;
@@ -21,13 +29,139 @@ const CGB_PALETTE_MASK: u8  = 0x07;
 
 const LINES_PER_FRAME: u32  = 154;
 const TICKS_PER_LINE: u32   = 456;
+const OAM_TICKS: u32        = 80;
+// Length of mode 3 without any penalties
+const MIN_XFER_TICKS: u32   = 172;
+// Penalties that extend mode 3, and shorten HBlank by the same amount
+const WINDOW_PENALTY: u32   = 6;
+const SPRITE_PENALTY: u32   = 6;
 pub const Y_RES: u8             = 144;
 pub const X_RES: u8             = 160;
 
+// CGB only, VBK - VRAM bank
+pub const VBK_ADDR: u16 = 0xFF4F;
+const VRAM_BANK_SIZE: usize = 0x2000;
+
+// Bit masks for accessing the CGB background tile attributes
+const BG_PRIORITY_MASK: u8  = 0x80;
+const BG_Y_FLIP_MASK: u8    = 0x40;
+const BG_X_FLIP_MASK: u8    = 0x20;
+const BG_BANK_MASK: u8      = 0x08;
+const BG_PALETTE_MASK: u8   = 0x07;
+
 const TARGET_FRAME_TIME: u64 = 1000 / 60;
+#[allow(non_upper_case_globals)]
 static mut prev_frame_time: u64 = 0;
+#[allow(non_upper_case_globals)]
 static mut start_timer: u64 = 0;
+#[allow(non_upper_case_globals)]
 static mut frame_counter: u32 = 0;
+// Whether the frame rate is paced against the wall clock
+static mut FRAME_LIMITER: bool = true;
+// Whether the hardware limit of 10 sprites per line applies
+static mut SPRITE_LIMIT: bool = true;
+
+/**
+ * Enables or disables the limit of 10 sprites per line. Without it,
+ * the sprites that the hardware drops, usually causing flicker,
+ * are drawn as well.
+ */
+pub fn set_sprite_limit(enabled: bool) -> () {
+    unsafe { SPRITE_LIMIT = enabled; }
+}
+
+/**
+ * Returns the maximum number of sprites drawn on a line
+ */
+#[inline(always)]
+fn line_sprite_limit() -> usize {
+    return if unsafe { SPRITE_LIMIT } { MAX_LINE_SPRITES } else { OAM_ENTRIES };
+}
+
+// On DMG, the FIFO holds the palette and the color index of each
+// pixel instead of its color, so that a palette write only affects
+// the pixels pushed to the LCD afterwards. These entries have an
+// alpha of 0, unlike the ARGB colors of the CGB palettes.
+const DMG_PIXEL_BG: u32 = 0;
+const DMG_PIXEL_SP1: u32 = 1;
+const DMG_PIXEL_SP2: u32 = 2;
+
+/**
+ * Returns the FIFO entry of a DMG pixel
+ */
+#[inline(always)]
+fn dmg_pixel(palette: u32, index: u8) -> u32 {
+    return (palette << 2) | (index & 0x03) as u32;
+}
+
+/**
+ * Returns the color of a FIFO entry, using the current DMG
+ * palettes for DMG pixels
+ */
+#[inline(always)]
+fn resolve_pixel(data: u32) -> u32 {
+    if data >> 24 != 0 {
+        return data;
+    }
+    let index = (data & 0x03) as usize;
+    return unsafe {
+        match data >> 2 {
+            DMG_PIXEL_SP1 => LCD_CTX.sp1_colors[index],
+            DMG_PIXEL_SP2 => LCD_CTX.sp2_colors[index],
+            _ => LCD_CTX.bg_colors[index],
+        }
+    };
+}
+// Number of frames completed during the last second
+static FPS: AtomicU32 = AtomicU32::new(0);
+
+/**
+ * Enables or disables the frame limiter. Without it, frames are
+ * completed without reading the wall clock at all, so that the
+ * emulation only depends on its inputs. The FPS and the periodic
+ * battery save are then left to the caller.
+ */
+pub fn set_frame_limiter(enabled: bool) -> () {
+    unsafe { FRAME_LIMITER = enabled; }
+}
+
+/**
+ * Returns the number of frames rendered during the last second
+ */
+pub fn fps() -> u32 {
+    return FPS.load(Ordering::Relaxed);
+}
+
+/**
+ * Returns the emulation speed when rendering the given number of
+ * frames per second, in percent of the frame rate the frame limiter
+ * paces full speed at
+ */
+pub fn speed_percent(fps: u32) -> u32 {
+    return (fps as f64 * TARGET_FRAME_TIME as f64 / 10.0).round() as u32;
+}
+
+/**
+ * Returns how many milliseconds the frame limiter waits for, given
+ * the time since the previous frame. Runs as fast as possible in
+ * turbo mode and, unless the speed is given, in headless mode.
+ */
+fn frame_wait(frame_delay: u64) -> u64 {
+    let target_frame_time = (TARGET_FRAME_TIME as f64 / Emulator::speed()) as u64;
+    if frame_delay < target_frame_time && Emulator::is_paced() {
+        return target_frame_time - frame_delay;
+    }
+    return 0;
+}
+
+
+/**
+ * Writes the current content of the video buffer to a PNG file
+ */
+pub fn dump_framebuffer(path: &str) -> std::io::Result<()> {
+    let buffer = unsafe { &PPU_CTX.video_buffer };
+    return png::write_argb(path, X_RES as u32, Y_RES as u32, &buffer[..]);
+}
 
 
 // A struct representing a single Object Attribute Memory
@@ -67,6 +201,26 @@ impl OamEntry {
         return self.flags & mask;
     }
 
+    /**
+     * Returns the address of the low byte of the sprite row drawn on
+     * the given line. The rows of an 8x16 sprite span two tiles, the
+     * top one at the even tile index. With Y-flip, the whole 16 rows
+     * are mirrored, i.e., the top half shows the bottom tile flipped.
+     * https://gbdev.io/pandocs/OAM.html#byte-2--tile-index
+     */
+    pub fn tile_row_addr(&self, ly: u8, sprite_height: u8) -> u16 {
+        let mut row = (ly as u16 + 16).wrapping_sub(self.y as u16) % sprite_height as u16;
+        if self.get_flag(Y_FLIP_MASK) != 0 {
+            row = sprite_height as u16 - 1 - row;
+        }
+        let mut tile_index = self.tile;
+        if sprite_height == 16 {
+            // Removes the last bit
+            tile_index &= !1;
+        }
+        return 0x8000 + tile_index as u16 * 16 + row * 2;
+    }
+
     /**
      * Sets the value of the flag given its mask.
      */
@@ -76,6 +230,32 @@ impl OamEntry {
 }
 
 
+const FRAME_SIZE: usize = (X_RES as u32 * Y_RES as u32) as usize;
+
+/**
+ * A completed frame, published by the PPU at the start of VBlank
+ */
+pub struct Frame {
+    // Value of curr_frame when the frame was completed
+    pub number: u64,
+    pub pixels: Box<[u32; FRAME_SIZE]>,
+}
+
+// The last completed frame. This is the only part of the PPU
+// that may be accessed from the UI thread.
+pub static FRONT_BUFFER: Lazy<Mutex<Frame>> = Lazy::new(|| Mutex::new(Frame {
+    number: 0,
+    pixels: Box::new([0; FRAME_SIZE]),
+}));
+
+
+/**
+ * The PPU is owned by the CPU thread, which ticks it through
+ * Emulator::cycles(). Pixels are drawn into `video_buffer`, which
+ * is only accessed from the CPU thread. Once a frame is complete
+ * it is copied into FRONT_BUFFER under a lock, so that the UI
+ * thread never observes a half-drawn frame.
+ */
 pub struct PPU {
     pub curr_frame: u64,
     line_ticks: u32,
@@ -84,37 +264,101 @@ pub struct PPU {
     line_sprites: Vec<*mut OamEntry>,
     fetched_entry_count: u8,
     // Entries fetched during pipeline
-    fetched_entries: [*mut OamEntry; 3],
+    fetched_entries: [*mut OamEntry; OAM_ENTRIES],
+    // Internal line counter of the window, only incremented
+    // on lines where the window was rendered
     window_line: u8,
-
-    pub video_buffer: Box<[u32; (X_RES as u32 * Y_RES as u32) as usize]>,
+    // A window tile was fetched on the current line
+    window_rendered: bool,
+    // Length of mode 3 on the current line
+    xfer_ticks: u32,
+    // SCX % 8 latched at the start of mode 3. The tile column and SCY
+    // are read at each tile fetch, so that writes during the line
+    // only affect the tiles fetched afterwards.
+    fine_scroll_x: u8,
+    // State of the STAT interrupt line on the previous tick
+    stat_line: bool,
+    // State of LCDC bit 7 on the previous tick
+    lcd_on: bool,
+    // The LCD was just turned on and the current frame is not displayed
+    first_frame: bool,
+
+    pub video_buffer: Box<[u32; FRAME_SIZE]>,
     pub oam_ram: [OamEntry; 40],
-    vram: [u8; 0x2000],
+    // Bank 1 is only used on CGB
+    vram: [u8; VRAM_BANK_SIZE * 2],
+    vram_bank: u8,
 }
 
 
-pub static mut PPU_CTX: Lazy<PPU> = Lazy::new(|| PPU {
-    curr_frame: 0,
-    line_ticks: 0,
-    pixel_fifo: PixelFifo::new(),
-    line_sprites: Vec::new(),
-    fetched_entry_count: 0,
-    fetched_entries: [std::ptr::null_mut(); 3],
-    window_line: 0,
-    video_buffer: Box::new([0; (X_RES as u32 * Y_RES as u32) as usize]),
-    oam_ram: [OamEntry::new(); 40],
-    vram: [0; 0x2000],
-});
+pub static mut PPU_CTX: Lazy<PPU> = Lazy::new(PPU::new);
 
+/**
+ * Converts an address into an offset into a VRAM bank. Both
+ * 0x8000 - 0x9FFF and offsets into the bank are accepted, anything
+ * else mirrors every 0x2000 bytes.
+ */
+#[inline(always)]
+fn vram_offset(address: u16) -> usize {
+    return (address & (VRAM_BANK_SIZE as u16 - 1)) as usize;
+}
+
+/**
+ * Converts an address into an offset into the OAM RAM. Both
+ * 0xFE00 - 0xFE9F and offsets into the OAM, as used by DMA, are
+ * accepted. The OAM is 160 bytes long so anything else wraps around.
+ */
+#[inline(always)]
+fn oam_offset(address: u16) -> usize {
+    return (address & 0xFF) as usize % (std::mem::size_of::<OamEntry>() * OAM_ENTRIES);
+}
 
 impl PPU {
+    fn new() -> PPU {
+        PPU {
+        curr_frame: 0,
+        line_ticks: 0,
+        pixel_fifo: PixelFifo::new(),
+        line_sprites: Vec::new(),
+        fetched_entry_count: 0,
+        fetched_entries: [std::ptr::null_mut(); OAM_ENTRIES],
+        window_line: 0,
+        window_rendered: false,
+        xfer_ticks: MIN_XFER_TICKS,
+        fine_scroll_x: 0,
+        stat_line: false,
+        lcd_on: true,
+        first_frame: false,
+        video_buffer: Box::new([0; FRAME_SIZE]),
+        oam_ram: [OamEntry::new(); 40],
+        vram: [0; VRAM_BANK_SIZE * 2],
+        vram_bank: 0,
+        }
+    }
+
+    /**
+     * Clears VRAM, OAM and the video buffer. The frame counter
+     * keeps counting from its current value.
+     */
+    pub fn reset(&mut self) -> () {
+        let curr_frame = self.curr_frame;
+        *self = PPU::new();
+        self.curr_frame = curr_frame;
+    }
+
+    /**
+     * Fills the VRAM with its power-on content. The garbage pattern
+     * only applies to the WRAM, the VRAM is cleared by the boot ROM.
+     */
+    pub fn init_vram(&mut self, init: InitRam) -> () {
+        let init = if init == InitRam::Garbage { InitRam::Zero } else { init };
+        fill_power_on(init, &mut self.vram, 2);
+    }
+
     /**
      * Writes a byte to the OAM RAM
      */
-    pub fn oam_write(&mut self, mut address: u16, value: u8) -> () {
-        if address >= 0xFE00 {
-            address -= 0xFE00;
-        }
+    pub fn oam_write(&mut self, address: u16, value: u8) -> () {
         // Converts OAM RAM into a byte array
         let oam_bytes = unsafe {
             std::slice::from_raw_parts_mut(
@@ -122,73 +366,84 @@ impl PPU {
                 std::mem::size_of::<OamEntry>() * self.oam_ram.len()
             )
         };
-        if (address as usize) < oam_bytes.len() {
-            oam_bytes[address as usize] = value;
-        } else {
-            log::error!("Invalid write to OAM address {:04X}", address);
-            std::process::exit(-1);
-        }
+        oam_bytes[oam_offset(address)] = value;
     }
 
     /**
-     * Reads a byte from the OAM RAM
+     * Returns the OAM RAM as a byte array
      */
-    pub fn oam_read(&self, mut address: u16) -> u8 {
-        if address >= 0xFE00 {
-            address -= 0xFE00;
-        }
-        // Converts OAM RAM into a byte array
-        let oam_bytes = unsafe {
+    pub fn oam_bytes(&self) -> &[u8] {
+        return unsafe {
             std::slice::from_raw_parts(
                 self.oam_ram.as_ptr() as *const u8,
                 std::mem::size_of::<OamEntry>() * self.oam_ram.len()
             )
         };
-        if (address as usize) < oam_bytes.len() {
-            return oam_bytes[address as usize];
-        } else {
-            log::error!("Invalid read from OAM address {:04X}", address);
-            std::process::exit(-1);
-        }
+    }
+
+    /**
+     * Returns both VRAM banks
+     */
+    pub fn vram(&self) -> &[u8] {
+        return &self.vram;
+    }
+
+    /**
+     * Reads a byte from the OAM RAM
+     */
+    pub fn oam_read(&self, address: u16) -> u8 {
+        return self.oam_bytes()[oam_offset(address)];
     }
 
     /**
      * Writes a byte to the VRAM
      */
-    pub fn vram_write(&mut self, mut address: u16, value: u8) -> () {
-        address -= 0x8000;
-        if (address as usize) < self.vram.len() {
-            self.vram[address as usize] = value;
-        } else {
-            log::error!("Invalid write to VRAM address {:04X}", address);
-            std::process::exit(-1);
-        }
+    pub fn vram_write(&mut self, address: u16, value: u8) -> () {
+        self.vram[self.vram_bank as usize * VRAM_BANK_SIZE + vram_offset(address)] = value;
     }
 
     /**
      * Reads a byte from the VRAM
      */
-    pub fn vram_read(&self, mut address: u16) -> u8 {
-        address -= 0x8000;
-        if (address as usize) < self.vram.len() {
-            return self.vram[address as usize];
-        } else {
-            log::error!("Invalid read from VRAM address {:04X}", address);
-            std::process::exit(-1);
-        }
+    pub fn vram_read(&self, address: u16) -> u8 {
+        return self.vram_read_bank(self.vram_bank, address);
+    }
+
+    /**
+     * Reads a byte from the given VRAM bank regardless of the
+     * bank selected through VBK. Used by the pixel fetcher.
+     */
+    fn vram_read_bank(&self, bank: u8, address: u16) -> u8 {
+        return self.vram[(bank & 1) as usize * VRAM_BANK_SIZE + vram_offset(address)];
+    }
+
+    /**
+     * Reads the VBK register (CGB only)
+     */
+    pub fn get_vram_bank(&self) -> u8 {
+        return 0xFE | self.vram_bank;
+    }
+
+    /**
+     * Selects the VRAM bank accessed by the CPU (CGB only)
+     */
+    pub fn set_vram_bank(&mut self, value: u8) -> () {
+        self.vram_bank = value & 1;
     }
     /**********************************************************
      * Functions that implement different PPU modes / states
      **********************************************************/
 
     /**
-     * A helper function that fetches the color of a pixel
+     * A helper function that fetches the color of a pixel. The fetched
+     * entries are ordered by priority, the first one with an opaque
+     * pixel is drawn, unless it is behind the background.
      */
-    fn fetch_sprite_pixels(&mut self, mut bit: i32, mut color: u32, bg_color: u8) -> u32 {
+    fn fetch_sprite_pixels(&mut self, mut color: u32, bg_color: u8) -> u32 {
         // Iterates through all the fetched entries
         for i in 0..(self.fetched_entry_count) {
             let fetched_entry = unsafe { *self.fetched_entries[i as usize] };
-            let sp_x: i32 = ((fetched_entry.x as i32).wrapping_sub(8)).wrapping_add(unsafe { LCD_CTX.scroll_x % 8 } as i32);
+            let sp_x: i32 = ((fetched_entry.x as i32).wrapping_sub(8)).wrapping_add(self.fine_scroll_x as i32);
             
             if sp_x.wrapping_add(8) < self.pixel_fifo.fifo_x as i32 {
                 // If we have past the sprite, continue
@@ -202,7 +457,7 @@ impl PPU {
                 continue;
             }
 
-            bit = (7 - offset) as i32;
+            let mut bit = (7 - offset) as i32;
             if fetched_entry.get_flag(X_FLIP_MASK) != 0 {
                 bit = offset;
             }
@@ -223,16 +478,18 @@ impl PPU {
             if !bg_priority || bg_color == 0 {
                 let palette = fetched_entry.get_flag(DMG_PALETTE_MASK) != 0;
                 // println!("[DEBUG] ly: {}, palette: {}", unsafe { LCD_CTX.ly }, palette as u8);
-                if palette {
-                    color = unsafe { LCD_CTX.sp2_colors[val as usize] };
+                if Emulator::is_cgb() {
+                    let cgb_palette = fetched_entry.get_flag(CGB_PALETTE_MASK);
+                    color = unsafe { LCD_CTX.obj_cgb_palette.color(cgb_palette, val) };
+                } else if palette {
+                    color = dmg_pixel(DMG_PIXEL_SP2, val);
                 } else {
-                    color = unsafe { LCD_CTX.sp1_colors[val as usize] };
-                }
-
-                if val != 0 {
-                    break;
+                    color = dmg_pixel(DMG_PIXEL_SP1, val);
                 }
             }
+            // The sprites with a lower priority are hidden even if
+            // this one is behind the background
+            break;
         }
 
         return color;
@@ -251,22 +508,37 @@ impl PPU {
         // The FIFO is not full
         // Adds a new entry to the FIFO
         let x: i32 = self.pixel_fifo.fetch_x as i32 -
-            (8 - (unsafe { LCD_CTX.scroll_x } % 8)) as i32;
+            (8 - self.fine_scroll_x) as i32;
         
+        let attr = self.pixel_fifo.bgw_attr;
         for i in 0..8 {
+            // The bit of the tile data is used for sprites as well,
+            // the CGB horizontal flip only applies to the tile
             let bit: i32 = (7 - i) as i32;
-            let hi = (self.pixel_fifo.bgw_fetch_data[1] & (1 << bit) != 0) as u8;
-            let lo = ((self.pixel_fifo.bgw_fetch_data[2] & (1 << bit) != 0) as u8) << 1;
-            let mut color = unsafe { LCD_CTX.bg_colors[(hi | lo) as usize] };
-
-            // Checks if the background window display is enabled
-            if unsafe { !LCD_CTX.get_lcdc_flag(BGW_ENABLE_MASK) } {
-                color = unsafe { LCD_CTX.bg_colors[0] };
+            let tile_bit = if (attr & BG_X_FLIP_MASK) != 0 { i } else { bit };
+            let hi = (self.pixel_fifo.bgw_fetch_data[1] & (1 << tile_bit) != 0) as u8;
+            let lo = ((self.pixel_fifo.bgw_fetch_data[2] & (1 << tile_bit) != 0) as u8) << 1;
+            let mut color = dmg_pixel(DMG_PIXEL_BG, hi | lo);
+            let mut bg_color = hi | lo;
+
+            let mut bg_over_sprites = false;
+            if Emulator::is_cgb() {
+                color = unsafe { LCD_CTX.bg_cgb_palette.color(attr & BG_PALETTE_MASK, hi | lo) };
+                bg_over_sprites = (attr & BG_PRIORITY_MASK) != 0 && bg_color != 0;
+                // On CGB, LCDC bit 0 does not disable the background,
+                // but gives the sprites priority over it
+                if unsafe { !LCD_CTX.get_lcdc_flag(BGW_ENABLE_MASK) } {
+                    bg_color = 0;
+                    bg_over_sprites = false;
+                }
+            } else if unsafe { !LCD_CTX.get_lcdc_flag(BGW_ENABLE_MASK) } {
+                // Checks if the background window display is enabled
+                color = dmg_pixel(DMG_PIXEL_BG, 0);
             }
 
             // Checks if sprites are enabled
-            if unsafe { LCD_CTX.get_lcdc_flag(OBJ_ENABLE_MASK) } {
-                color = self.fetch_sprite_pixels(bit, color, hi | lo);
+            if unsafe { LCD_CTX.get_lcdc_flag(OBJ_ENABLE_MASK) } && !bg_over_sprites {
+                color = self.fetch_sprite_pixels(color, bg_color);
             }
             // println!("[DEBUG] ly: {}, color: {:08X}", unsafe { LCD_CTX.ly }, color);
             if x >= 0 {
@@ -287,7 +559,7 @@ impl PPU {
         for i in 0..self.line_sprites.len() {
             let entry = self.line_sprites[i];
                 let sp_x: i32 = unsafe { (((*entry).x as i32).wrapping_sub(8))
-                    .wrapping_add((LCD_CTX.scroll_x % 8) as i32) };
+                    .wrapping_add(self.fine_scroll_x as i32) };
             
             let fetch_x = self.pixel_fifo.fetch_x as i32;
             // The sprite is within the current fetch range
@@ -298,8 +570,7 @@ impl PPU {
                 self.fetched_entry_count = self.fetched_entry_count.wrapping_add(1);
             }
 
-            if self.fetched_entry_count >= 3 {
-                // Max 3 sprites per line
+            if self.fetched_entry_count as usize >= line_sprite_limit() {
                 break;
             }
         }
@@ -310,29 +581,23 @@ impl PPU {
      * A helper function that loads sprite data from memory
      */
     fn pipeline_load_sprite_data(&mut self, offset: u8) -> () {
-        let curr_y: i32 = unsafe { LCD_CTX.ly as i32 };
+        let ly = unsafe { LCD_CTX.ly };
         let sprite_height = unsafe { LCD_CTX.get_lcdc_obj_size() };
         for i in 0..self.fetched_entry_count {
             let entry = self.fetched_entries[i as usize];
-            let mut tile_y: u8 = (curr_y.wrapping_add(16) as i32)
-                .wrapping_sub(unsafe { (*entry).y }as i32).wrapping_mul(2) as u8;
-            if unsafe { (*entry).get_flag(Y_FLIP_MASK) } != 0 {
-                // If flipped upside down
-                tile_y =((sprite_height as i32 * 2) - 2).wrapping_sub(tile_y as i32) as u8;
-            }
-            let mut tile_index: u8 = unsafe { (*entry).tile };
-            if sprite_height == 16 {
-                // Removes the last bit
-                tile_index &= !1;
-            }
-            let addr = (0x8000 + (tile_index as u16 * 16) as u32 + tile_y as u32) + offset as u32;
+            let addr = unsafe { (*entry).tile_row_addr(ly, sprite_height) } + offset as u16;
             let index = ((i as i32) * 2 + offset as i32) as usize;
-            self.pixel_fifo.fetch_entry_data[index] =  bus_read(addr as u16);
+            // On CGB, the tile can be in either VRAM bank
+            let bank = if Emulator::is_cgb() && unsafe { (*entry).get_flag(BANK_MASK) } != 0 { 1 } else { 0 };
+            self.pixel_fifo.fetch_entry_data[index] = self.vram_read_bank(bank, addr);
         }
     }
 
     /**
-     * A helper function that loads a window tile from memory
+     * A helper function that loads a window tile from memory.
+     * The window starts at screen X = WX - 7, so with WX = 0 - 6 its
+     * left part is off-screen. Like the background, the window is
+     * fetched one tile (8 pixels) at a time.
      */
     fn pipeline_load_window_tile(&mut self) -> () {
         if !self.window_visible() {
@@ -344,25 +609,56 @@ impl PPU {
         let fetch_x: u16 =  self.pixel_fifo.fetch_x as u16;
         let ly =  unsafe { LCD_CTX.ly } as u16;
         let map_area = unsafe { LCD_CTX.get_lcdc_win_tile_map_area() };
-        if fetch_x.wrapping_add(7) >= win_x &&
-            fetch_x.wrapping_add(7) < win_x.wrapping_add(Y_RES as u16).wrapping_add(14) {
-            if ly >= win_y && ly < win_y.wrapping_add(X_RES as u16) {
-                let w_tile_y = self.window_line / 8;
-                let addr = map_area + (((fetch_x + 7 - win_x) as u16) / 8) +
-                    (w_tile_y as u16 * 32);
-                let data = bus_read(addr);
-                self.pixel_fifo.bgw_fetch_data[0] = data;
-
-                if unsafe { LCD_CTX.get_lcdc_bg_tile_data_area() } == 0x8800 {
-                    self.pixel_fifo.bgw_fetch_data[0] =
-                        self.pixel_fifo.bgw_fetch_data[0].wrapping_add(128);
-                }
+        // The fetched tile covers the screen from X = fetch_x
+        if fetch_x + 7 >= win_x && ly >= win_y {
+            let w_tile_x = (fetch_x + 7 - win_x) / 8;
+            let w_tile_y = self.window_line / 8;
+            let addr = map_area + (w_tile_x & 0x1F) + (w_tile_y as u16 * 32);
+            let data = self.vram_read_bank(0, addr);
+            self.pixel_fifo.bgw_fetch_data[0] = data;
+            self.pixel_fifo.bgw_attr = if Emulator::is_cgb() {
+                self.vram_read_bank(1, addr)
+            } else {
+                0
+            };
+            self.pixel_fifo.window_tile = true;
+            self.window_rendered = true;
+
+            if unsafe { LCD_CTX.get_lcdc_bg_tile_data_area() } == 0x8800 {
+                self.pixel_fifo.bgw_fetch_data[0] =
+                    self.pixel_fifo.bgw_fetch_data[0].wrapping_add(128);
             }
         }
-
     }
     
 
+    /**
+     * Returns the VRAM bank of the fetched background / window tile.
+     * Always 0 on DMG since the attributes are 0.
+     */
+    #[inline(always)]
+    fn bgw_tile_bank(&self) -> u8 {
+        return ((self.pixel_fifo.bgw_attr & BG_BANK_MASK) != 0) as u8;
+    }
+
+    /**
+     * Returns the offset of the current row in the fetched background /
+     * window tile, taking the CGB vertical flip attribute into account
+     */
+    #[inline(always)]
+    fn bgw_tile_y(&self) -> u8 {
+        // The row of a window tile depends on the window line counter
+        let tile_y = if self.pixel_fifo.window_tile {
+            (self.window_line % 8) * 2
+        } else {
+            self.pixel_fifo.tile_y
+        };
+        if (self.pixel_fifo.bgw_attr & BG_Y_FLIP_MASK) != 0 {
+            return 14 - tile_y;
+        }
+        return tile_y;
+    }
+
     /**
      * A helper function that fetches a tile based on the
      * current fetch state
@@ -371,14 +667,22 @@ impl PPU {
         match self.pixel_fifo.curr_state {
             FetchState::FS_TILE => {
                 self.fetched_entry_count = 0;
-                // Checks if the background window display is enabled
-                if unsafe { LCD_CTX.get_lcdc_flag(BGW_ENABLE_MASK) } {
+                self.pixel_fifo.window_tile = false;
+                // Checks if the background window display is enabled,
+                // which is always the case on CGB
+                if unsafe { LCD_CTX.get_lcdc_flag(BGW_ENABLE_MASK) } || Emulator::is_cgb() {
                     let map_area = unsafe { LCD_CTX.get_lcdc_bg_tile_map_area() };
                     let addr: u32 = map_area as u32 + 
                         (self.pixel_fifo.map_x as u32 / 8) + 
                         ((self.pixel_fifo.map_y as u32 / 8) * 32);
-                    let data = bus_read(addr as u16);
+                    let data = self.vram_read_bank(0, addr as u16);
                     self.pixel_fifo.bgw_fetch_data[0] = data;
+                    // The tile attributes are stored in VRAM bank 1 on CGB
+                    self.pixel_fifo.bgw_attr = if Emulator::is_cgb() {
+                        self.vram_read_bank(1, addr as u16)
+                    } else {
+                        0
+                    };
                     if unsafe { LCD_CTX.get_lcdc_bg_tile_data_area() } == 0x8800 {
                         self.pixel_fifo.bgw_fetch_data[0] = 
                             self.pixel_fifo.bgw_fetch_data[0].wrapping_add(128);
@@ -400,8 +704,8 @@ impl PPU {
                 let data_area = unsafe { LCD_CTX.get_lcdc_bg_tile_data_area() };
                 let addr: u32 = data_area as u32 +
                     (self.pixel_fifo.bgw_fetch_data[0] as u32 * 16) +
-                    (self.pixel_fifo.tile_y as u32);
-                let data = bus_read(addr as u16);
+                    (self.bgw_tile_y() as u32);
+                let data = self.vram_read_bank(self.bgw_tile_bank(), addr as u16);
                 self.pixel_fifo.bgw_fetch_data[1] = data;
 
                 self.pipeline_load_sprite_data(0);
@@ -413,8 +717,8 @@ impl PPU {
                 let data_area = unsafe { LCD_CTX.get_lcdc_bg_tile_data_area() };
                 let addr = data_area as u32 +
                     (self.pixel_fifo.bgw_fetch_data[0] as u32 * 16) +
-                    (self.pixel_fifo.tile_y as u32 + 1);
-                let data = bus_read(addr as u16);
+                    (self.bgw_tile_y() as u32 + 1);
+                let data = self.vram_read_bank(self.bgw_tile_bank(), addr as u16);
                 self.pixel_fifo.bgw_fetch_data[2] = data;
                 self.pipeline_load_sprite_data(1);
 
@@ -443,10 +747,10 @@ impl PPU {
         if self.pixel_fifo.get_size() > 8 {
             // The FIFO is full
             let data = self.pixel_fifo.pop();
-            if self.pixel_fifo.line_x >= unsafe { LCD_CTX.scroll_x % 8 } {
+            if self.pixel_fifo.line_x >= self.fine_scroll_x {
                 // Pushes the pixel to the video buffer
                 let offset: u32 = self.pixel_fifo.pushed_x as u32 + unsafe { LCD_CTX.ly as u32 * X_RES as u32};
-                self.video_buffer[offset as usize] = data;
+                self.video_buffer[offset as usize] = resolve_pixel(data);
                 self.pixel_fifo.pushed_x = self.pixel_fifo.pushed_x.wrapping_add(1);
             }
 
@@ -456,16 +760,16 @@ impl PPU {
 
     /**
      * A helper function that executes all procedures in the
-     * pixel processing pipeline
+     * pixel processing pipeline. The fetcher only advances
+     * every other tick.
      */
-    fn pipeline_process(&mut self) -> () {
+    fn pipeline_process(&mut self, fetch: bool) -> () {
         unsafe {
             self.pixel_fifo.map_y = LCD_CTX.ly.wrapping_add(LCD_CTX.scroll_y);
             self.pixel_fifo.map_x = self.pixel_fifo.fetch_x.wrapping_add(LCD_CTX.scroll_x);
             self.pixel_fifo.tile_y = (LCD_CTX.ly.wrapping_add(LCD_CTX.scroll_y) % 8) * 2;
         }
-        // If the line is an even number
-        if self.line_ticks & 1 == 0 {
+        if fetch {
             self.pipeline_fetch();
         }
         self.pipeline_push_pixel();
@@ -476,13 +780,55 @@ impl PPU {
      * A helper function that checks if the window is visible
      */
     #[inline(always)]
-    #[allow(unused_comparisons)]
     fn window_visible(&self) -> bool {
         let win_x = unsafe { LCD_CTX.win_x };
         let win_y = unsafe { LCD_CTX.win_y };
         return unsafe { LCD_CTX.get_lcdc_flag(WIN_ENABLE_MASK) } &&
-            win_x >= 0 && win_x <= 166 &&
-            win_y >= 0 && win_y < Y_RES;
+            win_x <= 166 && win_y < Y_RES;
+    }
+
+    /**
+     * Computes the length of mode 3 on the current line.
+     * On top of the 172 ticks needed to draw the line, the PPU
+     * discards SCX % 8 pixels of the first tile, stalls for 6 ticks
+     * when the window starts and fetches each sprite in 6 to 11 ticks.
+     * https://gbdev.io/pandocs/Rendering.html#mode-3-length
+     */
+    fn xfer_length(&self) -> u32 {
+        let scroll_x = unsafe { LCD_CTX.scroll_x } as u32;
+        let mut length = MIN_XFER_TICKS + self.fine_scroll_x as u32;
+
+        if self.window_visible() && unsafe { LCD_CTX.ly >= LCD_CTX.win_y } {
+            length += WINDOW_PENALTY;
+        }
+
+        // Sprites are not fetched while they are disabled on DMG
+        if unsafe { LCD_CTX.get_lcdc_flag(OBJ_ENABLE_MASK) } || Emulator::is_cgb() {
+            // Without the sprite limit, the timing stays that of the
+            // sprites that the hardware would draw
+            let mut sprites_x: Vec<u32> = self.line_sprites.iter()
+                .take(MAX_LINE_SPRITES)
+                .map(|entry| unsafe { (**entry).x } as u32)
+                .filter(|x| *x < X_RES as u32 + 8)
+                .collect();
+            sprites_x.sort();
+            // The background fetch is only delayed once per tile,
+            // by the time left until the fetch of the tile completes
+            let mut last_tile: Option<u32> = Option::None;
+            for x in sprites_x {
+                length += SPRITE_PENALTY;
+                if x == 0 {
+                    length += 5;
+                    continue;
+                }
+                let tile = (x + scroll_x) / 8;
+                if last_tile != Some(tile) {
+                    length += 5u32.saturating_sub((x + scroll_x) % 8);
+                    last_tile = Some(tile);
+                }
+            }
+        }
+        return length;
     }
 
     /**
@@ -492,25 +838,40 @@ impl PPU {
      */
     fn increment_ly(&mut self) -> () {
         unsafe {
-            let ly = LCD_CTX.ly;
-            if self.window_visible() && ly >= LCD_CTX.win_y && 
-                (ly as u16) < ((LCD_CTX.win_y as u16).wrapping_add(Y_RES as u16)) {
-                // If we are on the window
+            // The window line counter only advances on lines where the
+            // window was rendered, e.g., not while it is disabled or
+            // pushed off-screen by WX
+            if self.window_rendered {
                 self.window_line = self.window_line.wrapping_add(1);
-
-            }
-            LCD_CTX.ly = LCD_CTX.ly.wrapping_add(1);
-            if LCD_CTX.ly == LCD_CTX.lyc {
-                LCD_CTX.set_lcds_lyc(true);
-                if LCD_CTX.get_lcds_flag(LYC_INT_MASK) {
-                    request_interrupt(InterruptType::IT_LCD_STAT);
-                }
-            } else {
-                LCD_CTX.set_lcds_lyc(false);
+                self.window_rendered = false;
             }
+            LCD_CTX.set_ly(LCD_CTX.ly.wrapping_add(1));
         }
     }
 
+    /**
+     * Updates the STAT interrupt line, which is the OR of all
+     * enabled STAT sources. The interrupt is only requested on a
+     * rising edge of the line, so a source becoming active while
+     * another one already holds the line high does not request
+     * another interrupt ("STAT blocking").
+     */
+    fn update_stat_line(&mut self) -> () {
+        let mode = unsafe { LCD_CTX.get_lcds_mode() };
+        let line = unsafe {
+            // The LY = LYC comparison does not request an interrupt
+            // during the first frame after the LCD is turned on
+            (LCD_CTX.get_lcds_flag(LYC_INT_MASK) && LCD_CTX.get_lcds_lyc() && !self.first_frame) ||
+            (LCD_CTX.get_lcds_flag(HBLANK_INT_MASK) && mode == LCD_MODE::MODE_HBLANK) ||
+            (LCD_CTX.get_lcds_flag(VBLANK_INT_MASK) && mode == LCD_MODE::MODE_VBLANK) ||
+            (LCD_CTX.get_lcds_flag(OAM_INT_MASK) && mode == LCD_MODE::MODE_OAM)
+        };
+        if line && !self.stat_line {
+            request_interrupt(InterruptType::IT_LCD_STAT);
+        }
+        self.stat_line = line;
+    }
+
 
 
     /**
@@ -524,41 +885,8 @@ impl PPU {
                 // Resets the mode to VBlank
                 unsafe { LCD_CTX.set_lcds_mode(LCD_MODE::MODE_VBLANK); }
                 request_interrupt(InterruptType::IT_VBLANK);
-                // If the VBlank interrupt is enabled, request an interrupt
-                if unsafe { LCD_CTX.get_lcds_flag(VBLANK_INT_MASK) } {
-                    request_interrupt(InterruptType::IT_LCD_STAT);
-                }
-
-                // Increments the frame counter
-                self.curr_frame = self.curr_frame.wrapping_add(1);
-
-                // Aims to match the current frame rate
-                // with the target frame rate
-                let curr_time: u64 = ui::get_ticks();
-                let frame_delay = curr_time - unsafe { prev_frame_time };
-                if frame_delay < TARGET_FRAME_TIME {
-                    ui::delay((TARGET_FRAME_TIME - frame_delay) as u32);
-                }
-
-                // Computes the FPS
-                if curr_time - unsafe { start_timer } >= 1000 {
-                    // log::info!(target: "stdout", "FPS: {}", unsafe { frame_counter });
-                    println!("FPS: {}", unsafe { frame_counter });
-                    unsafe { 
-                        frame_counter = 0;
-                        start_timer = curr_time;
-                    }
-                    unsafe {
-                        if CARTRIDGE_CTX.need_save() {
-                            CARTRIDGE_CTX.save_battery();
-                        }
-                    }
-                }
-                unsafe {
-                    frame_counter = frame_counter.checked_add(1).unwrap();
-                    prev_frame_time = ui::get_ticks();
-                }
 
+                self.end_frame();
             } else {
                 // We are still in the middle of the line
                 // Resets the mode to OAM
@@ -569,6 +897,105 @@ impl PPU {
 
     }
 
+    /**
+     * Completes the current frame and publishes it
+     */
+    fn end_frame(&mut self) -> () {
+        // The first frame after the LCD is turned on is not displayed
+        if self.first_frame {
+            self.first_frame = false;
+        } else {
+            // Increments the frame counter
+            self.curr_frame = self.curr_frame.wrapping_add(1);
+            self.publish_frame();
+        }
+        // GameShark codes are applied once per frame
+        unsafe { CHEATS_CTX.apply_ram_writes(); }
+        // As is the input of a movie
+        unsafe { MOVIE_CTX.end_frame(); }
+        if unsafe { FRAME_LIMITER } {
+            self.limit_frame_rate();
+        }
+    }
+
+    /**
+     * Waits until the time of a frame has passed since the previous
+     * one, computes the FPS and saves the battery every second
+     */
+    fn limit_frame_rate(&self) -> () {
+        // Aims to match the current frame rate
+        // with the target frame rate
+        let curr_time: u64 = ui::get_ticks();
+        let wait = frame_wait(curr_time - unsafe { prev_frame_time });
+        if wait > 0 {
+            ui::delay(wait as u32);
+        }
+
+        // Computes the FPS
+        if curr_time - unsafe { start_timer } >= 1000 {
+            FPS.store(unsafe { frame_counter }, Ordering::Relaxed);
+            if Emulator::is_verbose() && !Emulator::is_headless() {
+                log::info!(target: "stdout", "FPS: {}", unsafe { frame_counter });
+            }
+            unsafe { 
+                frame_counter = 0;
+                start_timer = curr_time;
+            }
+            unsafe {
+                if CARTRIDGE_CTX.need_save() {
+                    CARTRIDGE_CTX.save_battery();
+                }
+            }
+        }
+        unsafe {
+            frame_counter = frame_counter.checked_add(1).unwrap();
+            prev_frame_time = ui::get_ticks();
+        }
+    }
+
+    /**
+     * Turns off the LCD. LY is reset to 0, the PPU stays in the
+     * HBlank mode and the screen turns white until it is turned on.
+     */
+    fn lcd_off(&mut self) -> () {
+        self.lcd_on = false;
+        self.line_ticks = 0;
+        self.window_line = 0;
+        self.window_rendered = false;
+        self.line_sprites.clear();
+        self.fetched_entry_count = 0;
+        self.pixel_fifo.clear();
+        self.stat_line = false;
+        unsafe {
+            LCD_CTX.set_ly(0);
+            LCD_CTX.set_lcds_mode(LCD_MODE::MODE_HBLANK);
+        }
+        let white = if Emulator::is_cgb() { 0xFFFFFFFF } else { unsafe { LCD_CTX.get_dmg_colors()[0] } };
+        self.video_buffer.fill(white);
+    }
+
+    /**
+     * Turns on the LCD, which starts from the top of a new frame
+     */
+    fn lcd_on(&mut self) -> () {
+        self.lcd_on = true;
+        self.first_frame = true;
+        self.line_ticks = 0;
+        unsafe {
+            LCD_CTX.update_lyc();
+            LCD_CTX.set_lcds_mode(LCD_MODE::MODE_OAM);
+        }
+    }
+
+    /**
+     * Copies the completed frame into the front buffer
+     */
+    fn publish_frame(&self) -> () {
+        let mut front = FRONT_BUFFER.lock().unwrap();
+        front.pixels.copy_from_slice(&self.video_buffer[..]);
+        front.number = self.curr_frame;
+    }
+
     /**
      * Performs operations under the VBlank mode
      */
@@ -581,8 +1008,9 @@ impl PPU {
             if unsafe { LCD_CTX.ly as u32} >= LINES_PER_FRAME {
                 unsafe { 
                     LCD_CTX.set_lcds_mode(LCD_MODE::MODE_OAM); 
-                    LCD_CTX.ly = 0;
+                    LCD_CTX.set_ly(0);
                     self.window_line = 0;
+                    self.window_rendered = false;
                 }
             }
 
@@ -598,13 +1026,10 @@ impl PPU {
         let sprite_height = unsafe { LCD_CTX.get_lcdc_obj_size() };
         for i in 0..self.oam_ram.len() {
             let entry: *mut OamEntry = &mut self.oam_ram[i];
-            if unsafe { (*entry).x == 0 } {
-                // If the sprite is not visible
-                continue;
-            }
-
-            if self.line_sprites.len() >= 10 {
-                // Max 10 sprites per line
+            if self.line_sprites.len() >= line_sprite_limit() {
+                // Max 10 sprites per line, the first 10 in OAM order
+                // are selected. Sprites that are off-screen horizontally
+                // count as well.
                 break;
             }
             if unsafe { (*entry).y as i32 <= curr_y.wrapping_add(16) } &&
@@ -616,12 +1041,18 @@ impl PPU {
                 self.line_sprites.push(entry);
             }
         }
-        // Sorts the sprites by their x coordinate
-        self.line_sprites.sort_by(|a, b| {
-            let a_x = (unsafe { *(*a) }).x;
-            let b_x = (unsafe { *(*b) }).x;
-            a_x.cmp(&b_x)
-        });
+        // On DMG, the sprite with the smaller x coordinate is drawn
+        // on top. The sort is stable, so sprites with the same x
+        // coordinate keep their OAM order, in which the lower index
+        // is drawn on top. On CGB, only the OAM order matters unless
+        // OPRI selects the DMG priority.
+        if !Emulator::is_cgb() || unsafe { LCD_CTX.x_priority() } {
+            self.line_sprites.sort_by(|a, b| {
+                let a_x = (unsafe { *(*a) }).x;
+                let b_x = (unsafe { *(*b) }).x;
+                a_x.cmp(&b_x)
+            });
+        }
     }
 
     /**
@@ -630,9 +1061,11 @@ impl PPU {
     fn mode_oam(&mut self) -> () {
         // OAM mode lasts for 80 ticks
         // After 80 ticks, the PPU switches to the XFER mode
-        if self.line_ticks >= 80 {
+        if self.line_ticks >= OAM_TICKS {
             unsafe { LCD_CTX.set_lcds_mode(LCD_MODE::MODE_XFER); }
             self.pixel_fifo.reset();
+            self.fine_scroll_x = unsafe { LCD_CTX.scroll_x } % 8;
+            self.xfer_ticks = self.xfer_length();
         }
 
         if self.line_ticks == 1 {
@@ -647,17 +1080,33 @@ impl PPU {
      * Performs operations under the XFER mode
      */
     fn mode_xfer(&mut self) -> () {
-        self.pipeline_process();
-        // XFER mode lasts for 172 ticks
-        // After 172 ticks, the PPU switches to the HBlank mode
-        if self.pixel_fifo.pushed_x >= X_RES {
+        if scanline::is_scanline() {
+            if self.line_ticks >= OAM_TICKS + self.xfer_ticks {
+                self.render_scanline();
+                unsafe { LCD_CTX.set_lcds_mode(LCD_MODE::MODE_HBLANK); }
+                unsafe { HDMA_CTX.hblank(); }
+            }
+            return;
+        }
+        if self.pixel_fifo.pushed_x < X_RES {
+            self.pipeline_process(self.line_ticks & 1 == 0);
+        }
+        // XFER mode lasts for 172 to 295 ticks, depending on the
+        // sprites, the window and SCX. The HBlank mode takes up the
+        // rest of the line, so that each line still lasts 456 ticks.
+        if self.line_ticks >= OAM_TICKS + self.xfer_ticks {
+            // The FIFO does not follow the timing of the hardware,
+            // the pixels that it has not pushed yet are drawn at once
+            let mut ticks = self.line_ticks;
+            while self.pixel_fifo.pushed_x < X_RES {
+                ticks = ticks.wrapping_add(1);
+                self.pipeline_process(ticks & 1 == 0);
+            }
             self.pixel_fifo.clear();
 
             unsafe { LCD_CTX.set_lcds_mode(LCD_MODE::MODE_HBLANK); }
-            // Checks if the HBlank interrupt is enabled
-            if unsafe { LCD_CTX.get_lcds_flag(HBLANK_INT_MASK) } {
-                request_interrupt(InterruptType::IT_LCD_STAT);
-            }
+            // A H-blank DMA copies one block per H-blank
+            unsafe { HDMA_CTX.hblank(); }
         }
     }
 
@@ -666,6 +1115,22 @@ impl PPU {
      * Performs a single PPU tick
      */
     pub fn tick(&mut self) -> () {
+        if unsafe { !LCD_CTX.get_lcdc_flag(LCD_ENABLE_MASK) } {
+            if self.lcd_on {
+                self.lcd_off();
+            }
+            // Keeps the frames coming at the usual rate while the
+            // LCD is off, so that the white screen is displayed
+            self.line_ticks = self.line_ticks.wrapping_add(1);
+            if self.line_ticks >= TICKS_PER_LINE * LINES_PER_FRAME {
+                self.line_ticks = 0;
+                self.end_frame();
+            }
+            return;
+        }
+        if !self.lcd_on {
+            self.lcd_on();
+        }
         self.line_ticks = self.line_ticks.wrapping_add(1);
         
         // During a frame, the Game Boy’s PPU cycles between four modes
@@ -684,5 +1149,528 @@ impl PPU {
                 self.mode_xfer();
             }
         }
+        self.update_stat_line();
+    }
+
+    /**
+     * Returns the index of the given OAM entry in the OAM RAM.
+     * Used to store the sprite pointers in a save state.
+     */
+    fn oam_index(&self, entry: *mut OamEntry) -> u8 {
+        if entry.is_null() {
+            return 0xFF;
+        }
+        return unsafe { entry.offset_from(self.oam_ram.as_ptr()) as u8 };
+    }
+
+    /**
+     * Converts an index written by oam_index() back into a pointer
+     */
+    fn oam_entry(&mut self, index: u8) -> Result<*mut OamEntry, StateError> {
+        if index == 0xFF {
+            return Ok(std::ptr::null_mut());
+        }
+        if index as usize >= self.oam_ram.len() {
+            return Err(StateError::Corrupted("OAM entry index"));
+        }
+        return Ok(&mut self.oam_ram[index as usize]);
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_u64(self.curr_frame);
+        writer.write_u32(self.line_ticks);
+        self.pixel_fifo.save_state(writer);
+        writer.write_u8(self.line_sprites.len() as u8);
+        for entry in self.line_sprites.iter() {
+            writer.write_u8(self.oam_index(*entry));
+        }
+        writer.write_u8(self.fetched_entry_count);
+        for entry in self.fetched_entries.iter() {
+            writer.write_u8(self.oam_index(*entry));
+        }
+        writer.write_u8(self.window_line);
+        writer.write_bool(self.window_rendered);
+        writer.write_u32(self.xfer_ticks);
+        writer.write_u8(self.fine_scroll_x);
+        writer.write_bool(self.stat_line);
+        writer.write_bool(self.lcd_on);
+        writer.write_bool(self.first_frame);
+        for pixel in self.video_buffer.iter() {
+            writer.write_u32(*pixel);
+        }
+        for i in 0..(self.oam_ram.len() * std::mem::size_of::<OamEntry>()) {
+            writer.write_u8(self.oam_read(i as u16));
+        }
+        writer.write_bytes(&self.vram);
+        writer.write_u8(self.vram_bank);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.curr_frame = reader.read_u64()?;
+        self.line_ticks = reader.read_u32()?;
+        self.pixel_fifo.load_state(reader)?;
+        self.line_sprites.clear();
+        let num_sprites = reader.read_u8()?;
+        for _ in 0..num_sprites {
+            let index = reader.read_u8()?;
+            let entry = self.oam_entry(index)?;
+            self.line_sprites.push(entry);
+        }
+        self.fetched_entry_count = reader.read_u8()?;
+        for i in 0..self.fetched_entries.len() {
+            let index = reader.read_u8()?;
+            self.fetched_entries[i] = self.oam_entry(index)?;
+        }
+        self.window_line = reader.read_u8()?;
+        self.window_rendered = reader.read_bool()?;
+        self.xfer_ticks = reader.read_u32()?;
+        if self.xfer_ticks < MIN_XFER_TICKS || OAM_TICKS + self.xfer_ticks >= TICKS_PER_LINE {
+            return Err(StateError::Corrupted("mode 3 length"));
+        }
+        self.fine_scroll_x = reader.read_u8()? & 0x07;
+        self.stat_line = reader.read_bool()?;
+        self.lcd_on = reader.read_bool()?;
+        self.first_frame = reader.read_bool()?;
+        for pixel in self.video_buffer.iter_mut() {
+            *pixel = reader.read_u32()?;
+        }
+        for i in 0..(self.oam_ram.len() * std::mem::size_of::<OamEntry>()) {
+            let value = reader.read_u8()?;
+            self.oam_write(i as u16, value);
+        }
+        reader.read_bytes(&mut self.vram)?;
+        self.vram_bank = reader.read_u8()? & 1;
+        return Ok(());
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::cpu::CPU_CTX;
+    use crate::emulator::test_util;
+    use crate::emulator::EMULATOR_CTX;
+
+    /**
+     * Resets the machine with the given LCDC and the identity
+     * palettes, so that color index i is drawn with the i-th shade
+     */
+    fn setup(lcdc: u8) -> () {
+        test_util::boot(&[]);
+        unsafe {
+            LCD_CTX.write(0xFF40, lcdc);
+            LCD_CTX.write(0xFF47, 0xE4);
+            LCD_CTX.write(0xFF48, 0xE4);
+            LCD_CTX.write(0xFF49, 0xE4);
+        }
+    }
+
+    /**
+     * Fills all the rows of the tile at the given index of the
+     * 0x8000 tile data area with the given color index
+     */
+    fn fill_tile(index: u8, color: u8) -> () {
+        for row in 0..8u16 {
+            let addr = 0x8000 + index as u16 * 16 + row * 2;
+            unsafe {
+                PPU_CTX.vram_write(addr, if color & 1 != 0 { 0xFF } else { 0x00 });
+                PPU_CTX.vram_write(addr + 1, if color & 2 != 0 { 0xFF } else { 0x00 });
+            }
+        }
+    }
+
+    /**
+     * Writes the OAM entry at the given index
+     */
+    fn set_sprite(index: u16, y: u8, x: u8, tile: u8, flags: u8) -> () {
+        for (i, value) in [y, x, tile, flags].iter().enumerate() {
+            unsafe { PPU_CTX.oam_write(index * 4 + i as u16, *value); }
+        }
+    }
+
+    /**
+     * Ticks the PPU until the given line has been drawn
+     */
+    fn render_line(ly: u8) -> () {
+        unsafe {
+            while LCD_CTX.ly != ly || LCD_CTX.get_lcds_mode() != LCD_MODE::MODE_HBLANK {
+                PPU_CTX.tick();
+            }
+        }
+    }
+
+    fn pixel(x: u8, ly: u8) -> u32 {
+        return unsafe { PPU_CTX.video_buffer[ly as usize * X_RES as usize + x as usize] };
+    }
+
+    #[test]
+    fn tall_sprite_rows_come_from_the_top_and_bottom_tiles() {
+        let mut entry = OamEntry::new();
+        // Lines 24 - 39, the odd tile index selects the same pair
+        entry.y = 40;
+        entry.tile = 0x05;
+        for (ly, addr, flipped_addr) in [
+            (24, 0x8040, 0x805E), (31, 0x804E, 0x8050),
+            (32, 0x8050, 0x804E), (39, 0x805E, 0x8040),
+        ] {
+            entry.set_flag(Y_FLIP_MASK, 0);
+            assert_eq!(entry.tile_row_addr(ly, 16), addr, "LY = {}", ly);
+            entry.set_flag(Y_FLIP_MASK, Y_FLIP_MASK);
+            assert_eq!(entry.tile_row_addr(ly, 16), flipped_addr, "LY = {} with Y-flip", ly);
+        }
+        // 8x8 sprites use the tile index as is
+        assert_eq!(entry.tile_row_addr(24, 8), 0x805E);
+        entry.set_flag(Y_FLIP_MASK, 0);
+        assert_eq!(entry.tile_row_addr(24, 8), 0x8050);
+    }
+
+    #[test]
+    fn tall_sprite_is_drawn_from_both_tiles() {
+        let _lock = test_util::lock();
+        let colors = unsafe { LCD_CTX.get_dmg_colors() };
+        for (flags, top, bottom) in [(0x00, 1, 2), (Y_FLIP_MASK, 2, 1)] {
+            // 8x16 sprites
+            setup(0x97);
+            fill_tile(4, 1);
+            fill_tile(5, 2);
+            set_sprite(0, 16, 8, 5, flags);
+            render_line(7);
+            assert_eq!(pixel(0, 0), colors[top]);
+            assert_eq!(pixel(0, 7), colors[top]);
+            render_line(15);
+            assert_eq!(pixel(0, 8), colors[bottom]);
+            assert_eq!(pixel(0, 15), colors[bottom]);
+            render_line(16);
+            assert_eq!(pixel(0, 16), colors[0]);
+        }
+    }
+
+    /**
+     * Draws 12 sprites side by side on the first lines and returns
+     * which of them are visible
+     */
+    fn visible_of_12_sprites(limit: bool) -> Vec<bool> {
+        set_sprite_limit(limit);
+        setup(0x93);
+        fill_tile(1, 3);
+        for i in 0..12 {
+            set_sprite(i, 16, 8 + i as u8 * 12, 1, 0);
+        }
+        render_line(0);
+        set_sprite_limit(true);
+        let dark = unsafe { LCD_CTX.get_dmg_colors()[3] };
+        return (0..12).map(|i| pixel(i * 12, 0) == dark).collect();
+    }
+
+    #[test]
+    fn only_10_sprites_per_line_without_the_no_sprite_limit_flag() {
+        let _lock = test_util::lock();
+        let limited = visible_of_12_sprites(true);
+        assert!(limited[..10].iter().all(|visible| *visible));
+        assert!(!limited[10] && !limited[11]);
+        assert!(visible_of_12_sprites(false).iter().all(|visible| *visible));
+    }
+
+    #[test]
+    fn lower_oam_index_wins_at_the_same_x() {
+        let _lock = test_util::lock();
+        let colors = unsafe { LCD_CTX.get_dmg_colors() };
+        for (first_color, second_color) in [(1, 2), (2, 1)] {
+            setup(0x93);
+            fill_tile(1, first_color);
+            fill_tile(2, second_color);
+            // Both sprites cover the pixels 0 - 7 of the lines 0 - 7
+            set_sprite(3, 16, 8, 1, 0);
+            set_sprite(5, 16, 8, 2, 0);
+            render_line(0);
+            assert_eq!(pixel(0, 0), colors[first_color as usize]);
+            assert_eq!(pixel(7, 0), colors[first_color as usize]);
+            assert_eq!(pixel(8, 0), colors[0]);
+        }
+    }
+
+    #[test]
+    fn opri_selects_the_priority_of_overlapping_sprites_on_cgb() {
+        let _lock = test_util::lock();
+        let mut rom = test_util::build_rom(0x00, 0x00, 0x00, &[]);
+        rom[0x143] = 0x80;
+        test_util::fix_checksums(&mut rom);
+        // The sprite 0 covers the pixels 8 - 15, the sprite 1 the
+        // pixels 4 - 11, so they overlap on the pixels 8 - 11
+        for (opri, front_x) in [(0, 12), (1, 4)] {
+            test_util::boot_rom(rom.clone());
+            unsafe {
+                LCD_CTX.write(0xFF40, 0x93);
+                // Colors 1 and 2 of the object palette 0 are red and green
+                LCD_CTX.cgb_palette_write(OCPS_ADDR, 0x80);
+                for byte in [0xFF, 0x7F, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x00] {
+                    LCD_CTX.cgb_palette_write(OCPD_ADDR, byte);
+                }
+                LCD_CTX.write_opri(opri);
+            }
+            fill_tile(1, 1);
+            fill_tile(2, 2);
+            set_sprite(0, 16, 16, 1, 0);
+            set_sprite(1, 16, 12, 2, 0);
+            render_line(0);
+            assert_ne!(pixel(4, 0), pixel(12, 0));
+            assert_eq!(pixel(8, 0), pixel(front_x, 0), "OPRI {}", opri);
+        }
+    }
+
+    #[test]
+    fn window_at_wx_7_starts_at_the_left_edge() {
+        let _lock = test_util::lock();
+        let colors = unsafe { LCD_CTX.get_dmg_colors() };
+        // The background uses the blank tile 0 of the map at 0x9800,
+        // the window the tile 1 of the map at 0x9C00
+        setup(0xF1);
+        fill_tile(1, 3);
+        for i in 0..0x400 {
+            unsafe { PPU_CTX.vram_write(0x9C00 + i, 1); }
+        }
+        unsafe {
+            LCD_CTX.write(0xFF4A, 0);
+            LCD_CTX.write(0xFF4B, 7);
+        }
+        render_line(0);
+        for x in 0..X_RES {
+            assert_eq!(pixel(x, 0), colors[3], "pixel {}", x);
+        }
+    }
+
+    /**
+     * Returns the number of ticks spent in the OAM, XFER and HBlank
+     * modes during the next line
+     */
+    fn mode_lengths() -> (u32, u32, u32) {
+        let (mut oam, mut xfer, mut hblank) = (0, 0, 0);
+        unsafe {
+            let ly = LCD_CTX.ly;
+            while LCD_CTX.ly == ly {
+                PPU_CTX.tick();
+            }
+            while LCD_CTX.ly == ly + 1 {
+                match LCD_CTX.get_lcds_mode() {
+                    LCD_MODE::MODE_OAM => oam += 1,
+                    LCD_MODE::MODE_XFER => xfer += 1,
+                    LCD_MODE::MODE_HBLANK => hblank += 1,
+                    LCD_MODE::MODE_VBLANK => panic!("VBlank during a visible line"),
+                }
+                PPU_CTX.tick();
+            }
+        }
+        return (oam, xfer, hblank);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn scroll_written_during_hblank_applies_from_the_next_line() {
+        let _lock = test_util::lock();
+        let colors = unsafe { LCD_CTX.get_dmg_colors() };
+        // The second column of the background map uses the dark tile 1
+        setup(0x91);
+        fill_tile(1, 3);
+        for row in 0..32 {
+            unsafe { PPU_CTX.vram_write(0x9801 + row * 32, 1); }
+        }
+        render_line(9);
+        unsafe { LCD_CTX.write(0xFF43, 4); }
+        render_line(10);
+        // The line already drawn is left as is
+        assert_eq!(pixel(4, 9), colors[0]);
+        assert_eq!(pixel(8, 9), colors[3]);
+        assert_eq!(pixel(3, 10), colors[0]);
+        assert_eq!(pixel(4, 10), colors[3]);
+        assert_eq!(pixel(12, 10), colors[0]);
+    }
+
+    /**
+     * Draws a frame of a static scene with the given renderer: a
+     * scrolled background of patterned tiles, the window at X = 80
+     * if enabled by LCDC and sprites in front of and behind the
+     * background
+     */
+    fn render_scene(renderer: Renderer, lcdc: u8, scx: u8) -> Vec<u32> {
+        set_renderer(renderer);
+        setup(lcdc);
+        for index in 0..5u16 {
+            for byte in 0..16u16 {
+                let value = (index * 16 + byte).wrapping_mul(37) as u8;
+                unsafe { PPU_CTX.vram_write(0x8000 + index * 16 + byte, value); }
+            }
+        }
+        for i in 0..0x400u16 {
+            unsafe {
+                PPU_CTX.vram_write(0x9800 + i, (i % 5) as u8);
+                PPU_CTX.vram_write(0x9C00 + i, ((i + 2) % 5) as u8);
+            }
+        }
+        set_sprite(0, 30, 20, 3, 0x00);
+        set_sprite(1, 34, 24, 4, 0x20);
+        set_sprite(2, 60, 100, 2, 0x80);
+        set_sprite(3, 90, 150, 1, 0x40);
+        unsafe {
+            LCD_CTX.write(0xFF42, 5);
+            LCD_CTX.write(0xFF43, scx);
+            LCD_CTX.write(0xFF4A, 40);
+            LCD_CTX.write(0xFF4B, 87);
+        }
+        render_line(143);
+        return unsafe { PPU_CTX.video_buffer.to_vec() };
+    }
+
+    #[test]
+    fn scanline_renderer_matches_the_fifo() {
+        let _lock = test_util::lock();
+        // The FIFO fetches the window in steps of 8 pixels from the
+        // fine scroll, so SCX stays a multiple of 8 with the window
+        for (lcdc, scx) in [(0xD3, 3), (0xF3, 8)] {
+            let fifo = render_scene(Renderer::Fifo, lcdc, scx);
+            let scanline = render_scene(Renderer::Scanline, lcdc, scx);
+            set_renderer(Renderer::Fifo);
+            for (i, (expected, actual)) in fifo.iter().zip(scanline.iter()).enumerate() {
+                assert_eq!(actual, expected, "pixel ({}, {}) with LCDC = {:02X}",
+                    i % X_RES as usize, i / X_RES as usize, lcdc);
+            }
+        }
+    }
+
+    #[test]
+    fn every_line_lasts_456_ticks() {
+        let _lock = test_util::lock();
+        setup(0x93);
+        let (oam, plain_xfer, hblank) = mode_lengths();
+        assert_eq!((oam, plain_xfer), (OAM_TICKS, MIN_XFER_TICKS));
+        assert_eq!(oam + plain_xfer + hblank, TICKS_PER_LINE);
+
+        // Two sprites on the lines 0 - 7, and the line shifted by SCX
+        setup(0x93);
+        set_sprite(0, 16, 20, 0, 0);
+        set_sprite(1, 16, 60, 0, 0);
+        unsafe { LCD_CTX.write(0xFF43, 3); }
+        let (oam, xfer, hblank) = mode_lengths();
+        assert_eq!(oam, OAM_TICKS);
+        assert!(xfer >= plain_xfer + 3 + 2 * SPRITE_PENALTY, "mode 3 lasts {} ticks", xfer);
+        assert_eq!(oam + xfer + hblank, TICKS_PER_LINE);
+    }
+
+    #[test]
+    fn overlapping_stat_sources_request_one_interrupt() {
+        let _lock = test_util::lock();
+        setup(0x91);
+        let mut requests = 0;
+        unsafe {
+            // The HBlank of line 0 raises the line, which the LY = LYC
+            // comparison then holds high until the end of line 1
+            LCD_CTX.write(0xFF41, LYC_INT_MASK | HBLANK_INT_MASK);
+            LCD_CTX.write(0xFF45, 1);
+            CPU_CTX.set_int_flags(0);
+            while LCD_CTX.ly < 2 {
+                PPU_CTX.tick();
+                if CPU_CTX.get_int_flags() & InterruptType::IT_LCD_STAT as u8 != 0 {
+                    requests += 1;
+                    CPU_CTX.set_int_flags(0);
+                }
+            }
+        }
+        assert_eq!(requests, 1);
+    }
+
+    #[test]
+    fn writing_a_matching_lyc_requests_the_stat_interrupt() {
+        let _lock = test_util::lock();
+        setup(0x91);
+        render_line(20);
+        unsafe {
+            LCD_CTX.write(0xFF41, LYC_INT_MASK);
+            LCD_CTX.write(0xFF45, 21);
+            PPU_CTX.tick();
+            CPU_CTX.set_int_flags(0);
+            assert!(!LCD_CTX.get_lcds_lyc());
+
+            LCD_CTX.write(0xFF45, 20);
+            assert!(LCD_CTX.get_lcds_lyc());
+            // Bit 2 of STAT
+            assert_eq!(LCD_CTX.read(0xFF41) & 0x04, 0x04);
+            PPU_CTX.tick();
+            assert_eq!(CPU_CTX.get_int_flags() & InterruptType::IT_LCD_STAT as u8,
+                InterruptType::IT_LCD_STAT as u8);
+
+            LCD_CTX.write(0xFF45, 21);
+            assert!(!LCD_CTX.get_lcds_lyc());
+        }
+    }
+
+    #[test]
+    fn turning_the_lcd_off_and_on_restarts_the_frame() {
+        let _lock = test_util::lock();
+        setup(0x91);
+        let white = unsafe { LCD_CTX.get_dmg_colors()[0] };
+        fill_tile(0, 3);
+        render_line(50);
+        unsafe {
+            LCD_CTX.write(0xFF40, 0x11);
+            PPU_CTX.tick();
+            assert_eq!(LCD_CTX.read(0xFF44), 0);
+            assert_eq!(LCD_CTX.get_lcds_mode(), LCD_MODE::MODE_HBLANK);
+            assert!(PPU_CTX.video_buffer.iter().all(|pixel| *pixel == white));
+            // LY stays at 0 while the LCD is off
+            for _ in 0..TICKS_PER_LINE * 3 {
+                PPU_CTX.tick();
+            }
+            assert_eq!(LCD_CTX.ly, 0);
+
+            LCD_CTX.write(0xFF41, LYC_INT_MASK);
+            LCD_CTX.write(0xFF45, 0);
+            CPU_CTX.set_int_flags(0);
+            LCD_CTX.write(0xFF40, 0x91);
+            PPU_CTX.tick();
+            assert_eq!(LCD_CTX.get_lcds_mode(), LCD_MODE::MODE_OAM);
+            assert_eq!(LCD_CTX.ly, 0);
+            // LY = LYC does not request the STAT interrupt yet
+            assert!(LCD_CTX.get_lcds_lyc());
+            assert_eq!(CPU_CTX.get_int_flags() & InterruptType::IT_LCD_STAT as u8, 0);
+            // The first frame after turning the LCD on is not displayed
+            let frame = PPU_CTX.curr_frame;
+            for _ in 0..TICKS_PER_LINE * LINES_PER_FRAME {
+                PPU_CTX.tick();
+            }
+            assert_eq!(PPU_CTX.curr_frame, frame);
+            for _ in 0..TICKS_PER_LINE * LINES_PER_FRAME {
+                PPU_CTX.tick();
+            }
+            assert_eq!(PPU_CTX.curr_frame, frame + 1);
+        }
+    }
+
+    #[test]
+    fn speed_is_relative_to_the_frame_limiter_target() {
+        // The limiter waits 16 ms per frame at full speed, i.e. 62.5 FPS
+        assert_eq!(speed_percent(62), 99);
+        assert_eq!(speed_percent(63), 101);
+        assert_eq!(speed_percent(125), 200);
+        assert_eq!(speed_percent(0), 0);
+    }
+
+    #[test]
+    fn headless_runs_are_only_paced_when_the_speed_is_given() {
+        let _lock = test_util::lock();
+        unsafe { EMULATOR_CTX.headless = true; }
+        assert_eq!(frame_wait(0), 0);
+        Emulator::set_speed(0.5);
+        assert_eq!(frame_wait(0), 32);
+        assert_eq!(frame_wait(10), 22);
+        assert_eq!(frame_wait(40), 0);
+        Emulator::set_speed(0.25);
+        assert_eq!(frame_wait(0), 64);
+        Emulator::set_turbo(true);
+        assert_eq!(frame_wait(0), 0);
+        Emulator::set_turbo(false);
+        unsafe {
+            EMULATOR_CTX.headless = false;
+            EMULATOR_CTX.speed = 1.0;
+            EMULATOR_CTX.speed_set = false;
+        }
+        // Windowed runs are always paced
+        assert_eq!(frame_wait(0), TARGET_FRAME_TIME);
+    }
+}
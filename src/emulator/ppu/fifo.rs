@@ -1,4 +1,5 @@
-use std::collections::LinkedList;
+use crate::emulator::savestate::*;
+use crate::emulator::error::{fatal, EmuError};
 
 /**
  * Implementation of everything related to the Pixel FIFO
@@ -20,16 +21,31 @@ pub enum FetchState {
     FS_PUSH,
 }
 
+// Every sprite on a line can overlap the same 8 pixels
+pub const MAX_LINE_SPRITES: usize = 10;
+// Without the sprite limit, every OAM entry can be on the same line
+pub const OAM_ENTRIES: usize = 40;
+
+// The fetcher only pushes 8 pixels when there are at most 8
+// pixels left, so the FIFO never holds more than 16 pixels
+const FIFO_CAPACITY: usize = 16;
 
 pub struct PixelFifo {
     pub curr_state: FetchState,
-    fifo: LinkedList<u32>,
+    // Ring buffer of pixels, `head` is the index of the oldest one
+    fifo: [u32; FIFO_CAPACITY],
+    head: usize,
+    len: usize,
     pub line_x: u8,
     pub pushed_x: u8,
     pub fetch_x: u8,
     pub bgw_fetch_data: [u8; 3],
+    // CGB attributes of the fetched background / window tile
+    pub bgw_attr: u8,
+    // The fetched tile belongs to the window instead of the background
+    pub window_tile: bool,
     // OAM data
-    pub fetch_entry_data: [u8; 6],
+    pub fetch_entry_data: [u8; OAM_ENTRIES * 2],
     pub map_y: u8,
     pub map_x: u8,
     pub tile_y: u8,
@@ -41,12 +57,16 @@ impl PixelFifo {
     pub fn new() -> PixelFifo {
         PixelFifo {
             curr_state: FetchState::FS_TILE,
-            fifo: LinkedList::new(),
+            fifo: [0; FIFO_CAPACITY],
+            head: 0,
+            len: 0,
             line_x: 0,
             pushed_x: 0,
             fetch_x: 0,
             bgw_fetch_data: [0; 3],
-            fetch_entry_data: [0; 6],
+            bgw_attr: 0,
+            window_tile: false,
+            fetch_entry_data: [0; OAM_ENTRIES * 2],
             map_y: 0,
             map_x: 0,
             tile_y: 0,
@@ -58,34 +78,39 @@ impl PixelFifo {
      * Pushes a pixel to the FIFO
      */
     pub fn push(&mut self, data: u32) -> () {
-        self.fifo.push_back(data);
+        if self.len == FIFO_CAPACITY {
+            fatal(EmuError::PixelFifo("push to a full FIFO"));
+        }
+        self.fifo[(self.head + self.len) % FIFO_CAPACITY] = data;
+        self.len += 1;
     }
 
     /**
      * Pops a pixel from the FIFO
      */
     pub fn pop(&mut self) -> u32 {
-        if self.fifo.len() == 0 {
-            log::error!("Attempted to pop from an empty Pixel FIFO");
-            std::process::exit(1);
+        if self.len == 0 {
+            fatal(EmuError::PixelFifo("pop from an empty FIFO"));
         }
-        return self.fifo.pop_front().unwrap();
+        let data = self.fifo[self.head];
+        self.head = (self.head + 1) % FIFO_CAPACITY;
+        self.len -= 1;
+        return data;
     }
 
     /**
-     * Returns the size of the FIFO.
-     * A wrapper around the LinkedList's len() method of
-     * the internal linked list.
+     * Returns the number of pixels in the FIFO.
      */
     pub fn get_size(&self) -> usize {
-        return self.fifo.len();
+        return self.len;
     }
 
     /**
      * Clears the FIFO.
      */
     pub fn clear(&mut self) -> () {
-        self.fifo.clear();
+        self.head = 0;
+        self.len = 0;
     }
 
     /**
@@ -100,4 +125,102 @@ impl PixelFifo {
         self.fifo_x = 0;
     }
 
-}
\ No newline at end of file
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_u8(self.curr_state as u8);
+        writer.write_u32(self.len as u32);
+        for i in 0..self.len {
+            writer.write_u32(self.fifo[(self.head + i) % FIFO_CAPACITY]);
+        }
+        writer.write_bytes(&[self.line_x, self.pushed_x, self.fetch_x]);
+        writer.write_bytes(&self.bgw_fetch_data);
+        writer.write_u8(self.bgw_attr);
+        writer.write_bool(self.window_tile);
+        writer.write_bytes(&self.fetch_entry_data);
+        writer.write_bytes(&[self.map_y, self.map_x, self.tile_y, self.fifo_x]);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.curr_state = match reader.read_u8()? {
+            0 => FetchState::FS_TILE,
+            1 => FetchState::FS_TILE_DATA_LOW,
+            2 => FetchState::FS_TILE_DATA_HIGH,
+            3 => FetchState::FS_IDLE,
+            4 => FetchState::FS_PUSH,
+            _ => return Err(StateError::Corrupted("pixel fetcher state")),
+        };
+        self.clear();
+        let len = reader.read_u32()?;
+        if len as usize > FIFO_CAPACITY {
+            return Err(StateError::Corrupted("pixel FIFO size"));
+        }
+        for _ in 0..len {
+            self.push(reader.read_u32()?);
+        }
+        self.line_x = reader.read_u8()?;
+        self.pushed_x = reader.read_u8()?;
+        self.fetch_x = reader.read_u8()?;
+        reader.read_bytes(&mut self.bgw_fetch_data)?;
+        self.bgw_attr = reader.read_u8()?;
+        self.window_tile = reader.read_bool()?;
+        reader.read_bytes(&mut self.fetch_entry_data)?;
+        self.map_y = reader.read_u8()?;
+        self.map_x = reader.read_u8()?;
+        self.tile_y = reader.read_u8()?;
+        self.fifo_x = reader.read_u8()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /*
+     * Counts the allocations made by the current thread, so that the
+     * tests running in parallel do not interfere with the count
+     */
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            return unsafe { System.alloc(layout) };
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) -> () {
+            unsafe { System.dealloc(ptr, layout) };
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn pixels_come_out_in_order_without_allocating() {
+        let mut fifo = PixelFifo::new();
+        let mut pushed: u32 = 0;
+        let mut popped: u32 = 0;
+        let before = ALLOCATIONS.with(|count| count.get());
+        // Pushes 8 pixels at a time when at most 8 are left, like the fetcher
+        while popped < 100_000 {
+            if fifo.get_size() <= 8 {
+                for _ in 0..8 {
+                    fifo.push(pushed);
+                    pushed += 1;
+                }
+            }
+            assert_eq!(fifo.pop(), popped);
+            popped += 1;
+        }
+        assert_eq!(ALLOCATIONS.with(|count| count.get()), before);
+        assert_eq!(fifo.get_size() as u32, pushed - popped);
+        fifo.clear();
+        assert_eq!(fifo.get_size(), 0);
+    }
+}
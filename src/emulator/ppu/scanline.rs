@@ -0,0 +1,154 @@
+use super::*;
+
+/**
+ * A faster alternative to the pixel FIFO that draws each line at
+ * once at the end of mode 3. Register writes made during mode 3
+ * therefore only take effect on the next line, which breaks some
+ * raster effects but not the timing of the PPU modes.
+ */
+
+/**
+ * Which pipeline draws the pixels of each line
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Renderer {
+    // Draws the pixels one dot at a time, accurate but slow
+    Fifo,
+    // Draws the whole line at the end of mode 3
+    Scanline,
+}
+
+static mut RENDERER: Renderer = Renderer::Fifo;
+
+/**
+ * Selects the pipeline that draws the pixels
+ */
+pub fn set_renderer(renderer: Renderer) -> () {
+    unsafe { RENDERER = renderer; }
+}
+
+/**
+ * Returns true if lines are drawn by the scanline renderer
+ */
+#[inline(always)]
+pub fn is_scanline() -> bool {
+    return unsafe { RENDERER } == Renderer::Scanline;
+}
+
+
+impl PPU {
+    /**
+     * Returns the color index of the given pixel of a tile and
+     * the attributes the tile was read with
+     */
+    fn scanline_tile_pixel(&self, map_addr: u16, tile_x: u8, tile_y: u8) -> (u8, u8) {
+        let mut tile_index = self.vram_read_bank(0, map_addr);
+        // The tile attributes are stored in VRAM bank 1 on CGB
+        let attr = if Emulator::is_cgb() { self.vram_read_bank(1, map_addr) } else { 0 };
+        let data_area = unsafe { LCD_CTX.get_lcdc_bg_tile_data_area() };
+        if data_area == 0x8800 {
+            tile_index = tile_index.wrapping_add(128);
+        }
+        let row = if (attr & BG_Y_FLIP_MASK) != 0 { 7 - tile_y } else { tile_y };
+        let bit = if (attr & BG_X_FLIP_MASK) != 0 { tile_x } else { 7 - tile_x };
+        let addr = data_area + tile_index as u16 * 16 + row as u16 * 2;
+        let bank = ((attr & BG_BANK_MASK) != 0) as u8;
+        let lo = (self.vram_read_bank(bank, addr) >> bit) & 1;
+        let hi = (self.vram_read_bank(bank, addr + 1) >> bit) & 1;
+        return (lo | (hi << 1), attr);
+    }
+
+    /**
+     * Returns the color index and the attributes of the background
+     * or window pixel at the given screen X coordinate
+     */
+    fn scanline_bgw_pixel(&mut self, x: u8, window: bool) -> (u8, u8) {
+        let ly = unsafe { LCD_CTX.ly };
+        let win_x = unsafe { LCD_CTX.win_x };
+        if window && x as u16 + 7 >= win_x as u16 {
+            self.window_rendered = true;
+            let w_x = (x as u16 + 7 - win_x as u16) as u8;
+            let map_area = unsafe { LCD_CTX.get_lcdc_win_tile_map_area() };
+            let addr = map_area + (w_x / 8) as u16 + (self.window_line / 8) as u16 * 32;
+            return self.scanline_tile_pixel(addr, w_x % 8, self.window_line % 8);
+        }
+        let map_x = x.wrapping_add(unsafe { LCD_CTX.scroll_x });
+        let map_y = ly.wrapping_add(unsafe { LCD_CTX.scroll_y });
+        let map_area = unsafe { LCD_CTX.get_lcdc_bg_tile_map_area() };
+        let addr = map_area + (map_x / 8) as u16 + (map_y / 8) as u16 * 32;
+        return self.scanline_tile_pixel(addr, map_x % 8, map_y % 8);
+    }
+
+    /**
+     * Returns the color of the sprite pixel at the given screen X
+     * coordinate, or the given background color if no opaque sprite
+     * pixel is in front of the background. The line sprites are
+     * ordered by priority like in fetch_sprite_pixels.
+     */
+    fn scanline_sprite_pixel(&self, x: u8, color: u32, bg_color: u8) -> u32 {
+        let ly = unsafe { LCD_CTX.ly };
+        let sprite_height = unsafe { LCD_CTX.get_lcdc_obj_size() };
+        for entry in self.line_sprites.iter() {
+            let entry = unsafe { **entry };
+            let offset = x as i32 + 8 - entry.x as i32;
+            if !(0..8).contains(&offset) {
+                continue;
+            }
+            let bit = if entry.get_flag(X_FLIP_MASK) != 0 { offset } else { 7 - offset };
+            let addr = entry.tile_row_addr(ly, sprite_height);
+            // On CGB, the tile can be in either VRAM bank
+            let bank = if Emulator::is_cgb() && entry.get_flag(BANK_MASK) != 0 { 1 } else { 0 };
+            let lo = (self.vram_read_bank(bank, addr) >> bit) & 1;
+            let hi = (self.vram_read_bank(bank, addr + 1) >> bit) & 1;
+            let val = lo | (hi << 1);
+            if val == 0 {
+                // Transparent pixel
+                continue;
+            }
+            if entry.get_flag(PRIORITY_MASK) != 0 && bg_color != 0 {
+                return color;
+            }
+            if Emulator::is_cgb() {
+                let cgb_palette = entry.get_flag(CGB_PALETTE_MASK);
+                return unsafe { LCD_CTX.obj_cgb_palette.color(cgb_palette, val) };
+            }
+            let palette = if entry.get_flag(DMG_PALETTE_MASK) != 0 { DMG_PIXEL_SP2 } else { DMG_PIXEL_SP1 };
+            return dmg_pixel(palette, val);
+        }
+        return color;
+    }
+
+    /**
+     * Draws the current line into the video buffer
+     */
+    pub(super) fn render_scanline(&mut self) -> () {
+        let ly = unsafe { LCD_CTX.ly };
+        let bgw_enabled = unsafe { LCD_CTX.get_lcdc_flag(BGW_ENABLE_MASK) };
+        let obj_enabled = unsafe { LCD_CTX.get_lcdc_flag(OBJ_ENABLE_MASK) };
+        let window = self.window_visible() && ly >= unsafe { LCD_CTX.win_y };
+        for x in 0..X_RES {
+            let mut color = dmg_pixel(DMG_PIXEL_BG, 0);
+            let mut bg_color = 0;
+            let mut bg_over_sprites = false;
+            // On CGB, LCDC bit 0 does not disable the background,
+            // but gives the sprites priority over it
+            if bgw_enabled || Emulator::is_cgb() {
+                let (index, attr) = self.scanline_bgw_pixel(x, window);
+                if Emulator::is_cgb() {
+                    color = unsafe { LCD_CTX.bg_cgb_palette.color(attr & BG_PALETTE_MASK, index) };
+                    bg_over_sprites = bgw_enabled && (attr & BG_PRIORITY_MASK) != 0 && index != 0;
+                } else {
+                    color = dmg_pixel(DMG_PIXEL_BG, index);
+                }
+                if bgw_enabled {
+                    bg_color = index;
+                }
+            }
+            if obj_enabled && !bg_over_sprites {
+                color = self.scanline_sprite_pixel(x, color, bg_color);
+            }
+            let offset = ly as usize * X_RES as usize + x as usize;
+            self.video_buffer[offset] = resolve_pixel(color);
+        }
+    }
+}
@@ -1,66 +1,258 @@
+use crate::emulator::savestate::*;
+
+// CGB only, SVBK - WRAM bank
+pub const SVBK_ADDR: u16 = 0xFF70;
+const WRAM_BANK_SIZE: usize = 0x1000;
+
+/**
+ * Content of the WRAM, HRAM and VRAM at power-on. The real
+ * hardware starts with whatever the RAM chips settle on, which
+ * games should not rely on.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InitRam {
+    // All bytes are 0
+    Zero,
+    // Bytes from a PRNG with the given seed
+    Random(u64),
+    // Alternating runs of 0x00 and 0xFF, close to the pattern found
+    // in the WRAM of DMG units
+    Garbage,
+}
+
+/**
+ * SplitMix64 generator, so that the same seed always gives the
+ * same RAM content
+ */
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        return z ^ (z >> 31);
+    }
+}
+
+/**
+ * Fills the buffer with its power-on content. The stream number
+ * makes the random content of each memory different for one seed.
+ */
+pub fn fill_power_on(init: InitRam, buffer: &mut [u8], stream: u64) -> () {
+    match init {
+        InitRam::Zero => buffer.fill(0),
+        InitRam::Random(seed) => {
+            let mut rng = SplitMix64 { state: seed ^ stream.wrapping_mul(0xD1B54A32D192ED03) };
+            for chunk in buffer.chunks_mut(8) {
+                let value = rng.next().to_le_bytes();
+                chunk.copy_from_slice(&value[..chunk.len()]);
+            }
+        }
+        InitRam::Garbage => {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = if i & 0x08 == 0 { 0x00 } else { 0xFF };
+            }
+        }
+    }
+}
+
 pub struct RAM {
-    // Work RAM (WRAM)
-    wram: [u8; 0x2000],
+    // Work RAM (WRAM), 8 banks of 4 KiB. Bank 0 is always mapped to
+    // 0xC000 - 0xCFFF, one of the banks 1 - 7 to 0xD000 - 0xDFFF
+    wram: [u8; WRAM_BANK_SIZE * 8],
+    wram_bank: u8,
     // High RAM (HRAM)
-    hram: [u8; 0x80]
+    hram: [u8; 0x80],
+    init: InitRam,
 }
 
 
 pub static mut RAM_CTX: RAM = RAM {
-    wram: [0; 0x2000],
-    hram: [0; 0x80]
+    wram: [0; WRAM_BANK_SIZE * 8],
+    wram_bank: 1,
+    hram: [0; 0x80],
+    init: InitRam::Zero,
 };
 
 impl RAM {
     /**
-     * Reads a byte from the WRAM
-     */    
-    pub fn wram_read(&self, mut address: u16) -> u8 {
-        address -= 0xC000;
-        if (address as usize) < self.wram.len() {
-            return self.wram[address as usize];
-        } else {
-            log::error!("Invalid read from RAM address {:04X}", address);
-            std::process::exit(-1);
-        }
+     * Sets the power-on content of the WRAM and HRAM, applied
+     * on the next reset
+     */
+    pub fn set_init(&mut self, init: InitRam) -> () {
+        self.init = init;
+    }
+
+    pub fn init(&self) -> InitRam {
+        return self.init;
+    }
+
+    /**
+     * Fills the WRAM and HRAM with their power-on content
+     */
+    pub fn reset(&mut self) -> () {
+        fill_power_on(self.init, &mut self.wram, 0);
+        self.wram_bank = 1;
+        fill_power_on(self.init, &mut self.hram, 1);
+    }
+
+    /**
+     * Reads a byte from the WRAM. The WRAM mirrors every 0x2000
+     * bytes, which also covers the echo RAM.
+     */
+    pub fn wram_read(&self, address: u16) -> u8 {
+        return self.wram[self.wram_offset(address)];
     }
 
     /**
      * Writes a byte to the WRAM
      */
-    pub fn wram_write(&mut self, mut address: u16, value: u8) -> () {
-        address -= 0xC000;
-        if (address as usize) < self.wram.len() {
-            self.wram[address as usize] = value;
-        } else {
-            log::error!("Invalid write to RAM address {:04X}", address);
-            std::process::exit(-1);
+    pub fn wram_write(&mut self, address: u16, value: u8) -> () {
+        let offset = self.wram_offset(address);
+        self.wram[offset] = value;
+    }
+
+    /**
+     * Converts an address into an offset into the WRAM given the
+     * selected bank
+     */
+    #[inline(always)]
+    fn wram_offset(&self, address: u16) -> usize {
+        let address = address.wrapping_sub(0xC000) & (WRAM_BANK_SIZE as u16 * 2 - 1);
+        if (address as usize) < WRAM_BANK_SIZE {
+            return address as usize;
         }
+        return self.wram_bank as usize * WRAM_BANK_SIZE + (address as usize - WRAM_BANK_SIZE);
+    }
+
+    /**
+     * Returns all WRAM banks
+     */
+    pub fn wram(&self) -> &[u8] {
+        return &self.wram;
+    }
+
+    pub fn hram(&self) -> &[u8] {
+        return &self.hram;
+    }
+
+    /**
+     * Reads the SVBK register (CGB only)
+     */
+    pub fn get_wram_bank(&self) -> u8 {
+        return 0xF8 | self.wram_bank;
+    }
+
+    /**
+     * Selects the WRAM bank mapped to 0xD000 - 0xDFFF (CGB only).
+     * Selecting bank 0 selects bank 1 instead.
+     */
+    pub fn set_wram_bank(&mut self, value: u8) -> () {
+        self.wram_bank = std::cmp::max(value & 0x07, 1);
     }
 
     /**
      * Reads a byte from the HRAM
      */
-    pub fn hram_read(&self, mut address: u16) -> u8 {
-        address -= 0xFF80;
-        if (address as usize) < self.hram.len() {
-            return self.hram[address as usize];
-        } else {
-            log::error!("Invalid read from RAM address {:04X}", address);
-            std::process::exit(-1);
-        }
+    pub fn hram_read(&self, address: u16) -> u8 {
+        return self.hram[self.hram_offset(address)];
     }
 
     /**
      * Writes a byte to the HRAM
      */
-    pub fn hram_write(&mut self, mut address: u16, value: u8) -> () {
-        address -= 0xFF80;
-        if (address as usize) < self.hram.len() {
-            self.hram[address as usize] = value;
-        } else {
-            log::error!("Invalid write to RAM address {:04X}", address);
-            std::process::exit(-1);
+    pub fn hram_write(&mut self, address: u16, value: u8) -> () {
+        let offset = self.hram_offset(address);
+        self.hram[offset] = value;
+    }
+
+    /**
+     * Converts an address into an offset into the HRAM. The HRAM
+     * is mapped to 0xFF80 - 0xFFFE and its array holds 0x80 bytes,
+     * addresses outside of it wrap around every 0x80 bytes.
+     */
+    #[inline(always)]
+    fn hram_offset(&self, address: u16) -> usize {
+        return address.wrapping_sub(0xFF80) as usize % self.hram.len();
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_bytes(&self.wram);
+        writer.write_u8(self.wram_bank);
+        writer.write_bytes(&self.hram);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes(&mut self.wram)?;
+        self.wram_bank = std::cmp::max(reader.read_u8()? & 0x07, 1);
+        reader.read_bytes(&mut self.hram)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_on(init: InitRam) -> RAM {
+        let mut ram = RAM {
+            wram: [0; WRAM_BANK_SIZE * 8],
+            wram_bank: 1,
+            hram: [0; 0x80],
+            init: init,
+        };
+        ram.reset();
+        return ram;
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_power_on_ram() {
+        let first = powered_on(InitRam::Random(1234));
+        let second = powered_on(InitRam::Random(1234));
+        assert_eq!(first.wram(), second.wram());
+        assert_eq!(first.hram(), second.hram());
+        assert_ne!(first.wram(), powered_on(InitRam::Random(1235)).wram());
+        // Each memory gets its own stream
+        assert_ne!(&first.wram()[..0x80], first.hram());
+    }
+
+    #[test]
+    fn zero_and_garbage_power_on_patterns() {
+        assert!(powered_on(InitRam::Zero).wram().iter().all(|byte| *byte == 0));
+        let garbage = powered_on(InitRam::Garbage);
+        assert_eq!(&garbage.wram()[..0x18], &[
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn wram_mirrors_every_0x2000_bytes() {
+        let mut ram = powered_on(InitRam::Zero);
+        ram.wram_write(0xC010, 1);
+        ram.wram_write(0xD010, 2);
+        // The echo RAM
+        assert_eq!(ram.wram_read(0xE010), 1);
+        assert_eq!(ram.wram_read(0xF010), 2);
+        assert_eq!(ram.wram_read(0xA010), 1);
+        ram.wram_write(0x0010, 3);
+        assert_eq!(ram.wram_read(0xC010), 3);
+    }
+
+    #[test]
+    fn hram_accepts_any_address() {
+        let mut ram = powered_on(InitRam::Zero);
+        for address in 0..=0xFFFF {
+            ram.hram_write(address, address as u8);
+            assert_eq!(ram.hram_read(address), address as u8);
         }
+        ram.hram_write(0xFF80, 5);
+        assert_eq!(ram.hram_read(0x0000), 5);
+        assert_eq!(ram.hram_read(0xFFFE), 0xFE);
     }
-}
\ No newline at end of file
+}
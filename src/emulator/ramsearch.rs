@@ -0,0 +1,157 @@
+use crate::emulator::address_bus::bus_peek;
+use crate::emulator::debugger::DEBUGGER_CTX;
+
+/**
+ * A search for the address of a value in RAM, used to find the
+ * addresses that cheat codes write to. A snapshot of WRAM and HRAM
+ * is taken when the search starts, each step then takes a new
+ * snapshot and only keeps the addresses whose value changed as
+ * requested since the previous one.
+ */
+
+// The memory searched, as seen through the address bus, i.e., the
+// WRAM bank currently mapped on the CGB
+const SEARCH_RANGES: [(u16, u16); 2] = [(0xC000, 0xDFFF), (0xFF80, 0xFFFE)];
+
+/**
+ * How the value of a candidate address must have changed since
+ * the previous snapshot for the address to be kept
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SearchPredicate {
+    Increased,
+    Decreased,
+    Unchanged,
+    Changed,
+    // The value now equals the given one
+    Equals(u8),
+}
+
+impl SearchPredicate {
+    /**
+     * Parses the name of a predicate used by the debugger:
+     * inc, dec, same, diff or eq <value>
+     */
+    pub fn parse(name: &str, value: Option<&str>) -> Option<SearchPredicate> {
+        match (name, value) {
+            ("inc", None) => Some(SearchPredicate::Increased),
+            ("dec", None) => Some(SearchPredicate::Decreased),
+            ("same", None) => Some(SearchPredicate::Unchanged),
+            ("diff", None) => Some(SearchPredicate::Changed),
+            ("eq", Some(value)) => {
+                let value = value.trim_start_matches('$').trim_start_matches("0x");
+                u8::from_str_radix(value, 16).ok().map(SearchPredicate::Equals)
+            },
+            _ => None,
+        }
+    }
+
+    fn matches(&self, old: u8, new: u8) -> bool {
+        match self {
+            SearchPredicate::Increased => new > old,
+            SearchPredicate::Decreased => new < old,
+            SearchPredicate::Unchanged => new == old,
+            SearchPredicate::Changed => new != old,
+            SearchPredicate::Equals(value) => new == *value,
+        }
+    }
+}
+
+
+pub struct RamSearch {
+    // Candidate addresses and their values in the last snapshot
+    candidates: Vec<(u16, u8)>,
+    started: bool,
+}
+
+impl RamSearch {
+    pub const fn new() -> RamSearch {
+        return RamSearch { candidates: Vec::new(), started: false };
+    }
+
+    /**
+     * Starts a new search, every searched address is a candidate
+     */
+    pub fn start(&mut self) -> () {
+        self.candidates = SEARCH_RANGES.iter()
+            .flat_map(|(start, end)| *start..=*end)
+            .map(|address| (address, bus_peek(address)))
+            .collect();
+        self.started = true;
+    }
+
+    /**
+     * Keeps the candidates whose value changed as requested since
+     * the previous step and returns them. Starts a new search if
+     * none is in progress, in which case only Equals filters
+     * anything out.
+     */
+    pub fn step(&mut self, predicate: SearchPredicate) -> &[(u16, u8)] {
+        if !self.started {
+            self.start();
+        }
+        self.candidates.retain_mut(|(address, value)| {
+            let new = bus_peek(*address);
+            let keep = predicate.matches(*value, new);
+            *value = new;
+            keep
+        });
+        return &self.candidates;
+    }
+
+    /**
+     * Returns the remaining candidates with their last seen values
+     */
+    pub fn candidates(&self) -> &[(u16, u8)] {
+        return &self.candidates;
+    }
+}
+
+/**
+ * Performs a step of the RAM search of the debugger session and
+ * returns the addresses that are still candidates. Must be called
+ * between two instructions, i.e., from the thread running the CPU.
+ */
+pub fn ram_search_step(predicate: SearchPredicate) -> Vec<u16> {
+    let search = unsafe { DEBUGGER_CTX.ram_search() };
+    return search.step(predicate).iter().map(|(address, _)| *address).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::address_bus::bus_write;
+    use crate::emulator::test_util;
+
+    #[test]
+    fn search_narrows_down_the_address_of_a_value() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        for (start, end) in SEARCH_RANGES {
+            for address in start..=end {
+                bus_write(address, 0);
+            }
+        }
+        unsafe { DEBUGGER_CTX.ram_search().start(); }
+        bus_write(0xC123, 1);
+        bus_write(0xFF90, 1);
+        assert_eq!(ram_search_step(SearchPredicate::Increased), vec![0xC123, 0xFF90]);
+        bus_write(0xC123, 2);
+        bus_write(0xFF90, 0);
+        assert_eq!(ram_search_step(SearchPredicate::Increased), vec![0xC123]);
+        assert_eq!(ram_search_step(SearchPredicate::Unchanged), vec![0xC123]);
+        bus_write(0xC123, 7);
+        assert_eq!(ram_search_step(SearchPredicate::Equals(7)), vec![0xC123]);
+        assert_eq!(ram_search_step(SearchPredicate::Equals(6)), Vec::<u16>::new());
+    }
+
+    #[test]
+    fn predicates_are_parsed_by_name() {
+        assert_eq!(SearchPredicate::parse("inc", None), Some(SearchPredicate::Increased));
+        assert_eq!(SearchPredicate::parse("diff", None), Some(SearchPredicate::Changed));
+        assert_eq!(SearchPredicate::parse("eq", Some("$1F")), Some(SearchPredicate::Equals(0x1F)));
+        assert_eq!(SearchPredicate::parse("eq", Some("0x1f")), Some(SearchPredicate::Equals(0x1F)));
+        assert_eq!(SearchPredicate::parse("eq", None), None);
+        assert_eq!(SearchPredicate::parse("inc", Some("1")), None);
+    }
+}
@@ -0,0 +1,248 @@
+/**
+ * Helpers for serializing the machine state into a save state.
+ * The format is a flat little-endian binary stream that starts
+ * with a magic header and a version number. Each component writes
+ * its fields in a fixed order and reads them back in the same order.
+ */
+
+pub const STATE_MAGIC: &[u8; 8] = b"GBEMUSAV";
+// Must be bumped whenever the layout of any component changes
+pub const STATE_VERSION: u32 = 20;
+
+/**
+ * Errors that can occur while saving or loading a save state
+ */
+#[derive(Debug)]
+pub enum StateError {
+    // The state file could not be read or written
+    Io(std::io::Error),
+    // The file does not start with the magic header
+    InvalidMagic,
+    // The state was created by an incompatible version of the emulator
+    UnsupportedVersion(u32),
+    // The state ended before all components were read
+    Truncated,
+    // The state was created for a different ROM
+    RomMismatch,
+    // The state contains a value that is out of range
+    Corrupted(&'static str),
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StateError::Io(err) => write!(f, "Unable to access save state: {}", err),
+            StateError::InvalidMagic => write!(f, "Not a save state file"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported save state version {}, expected {}",
+                    version, STATE_VERSION),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::RomMismatch => write!(f, "Save state was created for a different ROM"),
+            StateError::Corrupted(what) => write!(f, "Save state is corrupted: {}", what),
+        }
+    }
+}
+
+
+/**
+ * Appends values to the save state buffer
+ */
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /**
+     * Creates a new writer with the magic header and version
+     */
+    pub fn new() -> StateWriter {
+        let mut writer = StateWriter { data: Vec::new() };
+        writer.write_bytes(STATE_MAGIC);
+        writer.write_u32(STATE_VERSION);
+        return writer;
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> () {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) -> () {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) -> () {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> () {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) -> () {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> () {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /**
+     * Returns the serialized state
+     */
+    pub fn into_bytes(self) -> Vec<u8> {
+        return self.data;
+    }
+}
+
+
+/**
+ * Reads values back from a save state buffer
+ */
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /**
+     * Creates a new reader and validates the magic header and version
+     */
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        let mut reader = StateReader { data: data, pos: 0 };
+        let mut magic = [0u8; 8];
+        reader.read_bytes(&mut magic).map_err(|_| StateError::InvalidMagic)?;
+        if &magic != STATE_MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+        let version = reader.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        return Ok(reader);
+    }
+
+    /**
+     * Returns the next `len` bytes of the state
+     */
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.pos + len > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        return Ok(slice);
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        return Ok(self.read_u8()? != 0);
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        return Ok(u32::from_le_bytes(bytes));
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    /**
+     * Fills the given buffer with the next bytes of the state
+     */
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        buffer.copy_from_slice(self.take(buffer.len())?);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use crate::emulator::Emulator;
+    use crate::emulator::address_bus::bus_peek;
+    use crate::emulator::cpu::{CPU_CTX, Registers};
+    use crate::emulator::lcd::LCD_CTX;
+    use crate::emulator::ppu::PPU_CTX;
+    use crate::emulator::timer::TIMER_CTX;
+    use crate::emulator::test_util;
+
+    /**
+     * The parts of the machine compared after a state is loaded
+     */
+    #[derive(Debug, PartialEq)]
+    struct Snapshot {
+        registers: Registers,
+        ticks: u64,
+        ly: u8,
+        frame: u64,
+        timer_counter: u16,
+        wram: Vec<u8>,
+    }
+
+    fn snapshot() -> Snapshot {
+        unsafe {
+            return Snapshot {
+                registers: CPU_CTX.registers(),
+                ticks: CPU_CTX.ticks.load(Ordering::Relaxed),
+                ly: LCD_CTX.ly,
+                frame: PPU_CTX.curr_frame,
+                timer_counter: TIMER_CTX.internal_counter(),
+                wram: (0xC000..0xC200).map(bus_peek).collect(),
+            };
+        }
+    }
+
+    fn run(steps: usize) -> () {
+        for _ in 0..steps {
+            unsafe { CPU_CTX.step(); }
+        }
+    }
+
+    #[test]
+    fn state_round_trip_restores_the_machine() {
+        let _lock = test_util::lock();
+        // LD HL, $C000; loop: INC A; LD (HL+), A; JR loop
+        test_util::boot(&[0x21, 0x00, 0xC0, 0x3C, 0x22, 0x18, 0xFC]);
+        run(1000);
+        let saved = snapshot();
+        let state = Emulator::serialize_state();
+        run(1000);
+        assert_ne!(snapshot(), saved);
+        Emulator::deserialize_state(&state).unwrap();
+        assert_eq!(snapshot(), saved);
+    }
+
+    #[test]
+    fn state_with_wrong_magic_or_version_is_rejected() {
+        let _lock = test_util::lock();
+        test_util::boot(&[0x3C, 0x18, 0xFD]);
+        run(10);
+        let mut state = Emulator::serialize_state();
+        run(10);
+        let current = snapshot();
+
+        let mut wrong_magic = state.clone();
+        wrong_magic[0] = b'X';
+        assert!(matches!(Emulator::deserialize_state(&wrong_magic), Err(StateError::InvalidMagic)));
+        assert!(matches!(StateReader::new(b"GBEMU"), Err(StateError::InvalidMagic)));
+
+        state[8..12].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(Emulator::deserialize_state(&state),
+            Err(StateError::UnsupportedVersion(version)) if version == STATE_VERSION + 1));
+        // Nothing is restored from a rejected state
+        assert_eq!(snapshot(), current);
+    }
+}
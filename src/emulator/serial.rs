@@ -0,0 +1,361 @@
+use crate::emulator::cpu::interrupts::*;
+use crate::emulator::dbg::dbg_update;
+use crate::emulator::savestate::*;
+
+pub mod link;
+use link::*;
+
+/**
+ * Serial data transfer (link cable)
+ * https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+ */
+
+pub const SB_ADDR: u16 = 0xFF01;
+pub const SC_ADDR: u16 = 0xFF02;
+
+const TRANSFER_ENABLE_MASK: u8 = 0x80;
+const INTERNAL_CLOCK_MASK: u8 = 0x01;
+// The internal clock runs at 8192 Hz (16384 Hz in double speed mode),
+// a bit is shifted on each falling edge of bit 8 of the timer counter
+const CLOCK_BIT: u16 = 1 << 8;
+// M-cycles between two checks of the link for incoming transfers
+const CYCLES_PER_BIT: u32 = 128;
+// How long the master waits for the peer to answer a transfer
+const LINK_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+pub struct Serial {
+    // SB - Serial transfer data
+    sb: u8,
+    // SC - Serial transfer control
+    sc: u8,
+    // Number of bits shifted out in the current transfer
+    bits_shifted: u8,
+    // Value of the clock bit of the timer counter in the last M-cycle
+    clock_bit: bool,
+    // Link cable to another emulator, if connected
+    transport: Option<Box<dyn LinkTransport>>,
+    // M-cycles until the link is checked for incoming transfers
+    poll_cycles: u32,
+}
+
+pub static mut SERIAL_CTX: Serial = Serial {
+    sb: 0,
+    sc: 0,
+    bits_shifted: 0,
+    clock_bit: false,
+    transport: None,
+    poll_cycles: 0,
+};
+
+impl Serial {
+    /**
+     * Resets the serial registers, the link cable stays connected
+     */
+    pub fn reset(&mut self) -> () {
+        self.sb = 0;
+        self.sc = 0;
+        self.bits_shifted = 0;
+        self.clock_bit = false;
+        // Keeps polling the link, which stays connected
+        self.poll_cycles = CYCLES_PER_BIT;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            SB_ADDR => self.sb,
+            // Bits 1 - 6 are unused and read as 1
+            _ => self.sc | 0x7E,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) -> () {
+        match address {
+            SB_ADDR => self.sb = data,
+            _ => {
+                self.sc = data;
+                if self.is_transferring() {
+                    // Records the outgoing byte, test ROMs use
+                    // the serial port to print their results
+                    dbg_update(self.sb);
+                    self.bits_shifted = 0;
+                }
+            },
+        }
+    }
+
+    /**
+     * Connects the serial port to another emulator
+     */
+    pub fn connect(&mut self, transport: Box<dyn LinkTransport>) -> () {
+        self.transport = Some(transport);
+        self.poll_cycles = CYCLES_PER_BIT;
+    }
+
+    #[inline(always)]
+    fn is_transferring(&self) -> bool {
+        return (self.sc & TRANSFER_ENABLE_MASK) != 0;
+    }
+
+    #[inline(always)]
+    fn is_master(&self) -> bool {
+        return (self.sc & INTERNAL_CLOCK_MASK) != 0;
+    }
+
+    /**
+     * Finishes the current transfer with the byte received
+     */
+    fn complete_transfer(&mut self, data: u8) -> () {
+        self.sb = data;
+        self.sc &= !TRANSFER_ENABLE_MASK;
+        request_interrupt(InterruptType::IT_SERIAL);
+    }
+
+    /**
+     * Drops the link cable after an error, the serial port then
+     * behaves as if nothing was connected
+     */
+    fn disconnect(&mut self, err: std::io::Error) -> () {
+        log::warn!(target: "stdout", "Link cable disconnected: {}", err);
+        self.transport = None;
+    }
+
+    /**
+     * Sends the byte in SB to the peer as the master and returns
+     * the byte of the peer, or 0xFF if the peer does not answer.
+     * Blocks the emulation for up to LINK_TIMEOUT per byte while
+     * waiting for the answer.
+     */
+    fn exchange(&mut self) -> u8 {
+        let sb = self.sb;
+        let transport = match self.transport.as_mut() {
+            Some(transport) => transport,
+            None => return 0xFF,
+        };
+        if let Err(err) = transport.send(LINK_MASTER, sb) {
+            self.disconnect(err);
+            return 0xFF;
+        }
+        loop {
+            match transport.recv(LINK_TIMEOUT) {
+                Ok(Some((LINK_SLAVE, data))) => return data,
+                Ok(Some((_, _))) => {
+                    // Both sides are driving the clock, the peer's
+                    // transfer is answered as if nothing was connected
+                    if let Err(err) = transport.send(LINK_SLAVE, 0xFF) {
+                        self.disconnect(err);
+                        return 0xFF;
+                    }
+                },
+                Ok(None) => return 0xFF,
+                Err(err) => {
+                    self.disconnect(err);
+                    return 0xFF;
+                },
+            }
+        }
+    }
+
+    /**
+     * Answers a transfer started by the peer. If a transfer using
+     * the external clock is pending, it is completed.
+     */
+    fn poll_link(&mut self) -> () {
+        let message = match self.transport.as_mut() {
+            Some(transport) => transport.poll(),
+            None => return,
+        };
+        let data = match message {
+            Ok(Some((LINK_MASTER, data))) => data,
+            Ok(_) => return,
+            Err(err) => {
+                self.disconnect(err);
+                return;
+            },
+        };
+        let waiting = self.is_transferring() && !self.is_master();
+        let reply = if waiting { self.sb } else { 0xFF };
+        if let Err(err) = self.transport.as_mut().unwrap().send(LINK_SLAVE, reply) {
+            self.disconnect(err);
+            return;
+        }
+        if waiting {
+            self.complete_transfer(data);
+        }
+    }
+
+    /**
+     * Performs one serial tick (one M-cycle) given the internal
+     * counter of the timer. Transfers using the internal clock take
+     * 8 bits worth of cycles. Transfers using the external clock only
+     * make progress if a peer is connected.
+     */
+    pub fn tick(&mut self, counter: u16) -> () {
+        if self.transport.is_some() {
+            self.poll_cycles -= 1;
+            if self.poll_cycles == 0 {
+                self.poll_cycles = CYCLES_PER_BIT;
+                self.poll_link();
+            }
+        }
+
+        let clock_bit = (counter & CLOCK_BIT) != 0;
+        let falling_edge = self.clock_bit && !clock_bit;
+        self.clock_bit = clock_bit;
+        if !self.is_transferring() || !self.is_master() || !falling_edge {
+            return;
+        }
+        self.bits_shifted += 1;
+        if self.transport.is_none() {
+            // A disconnected cable always shifts in 1s
+            self.sb = (self.sb << 1) | 1;
+        }
+        if self.bits_shifted == 8 {
+            let data = if self.transport.is_some() { self.exchange() } else { self.sb };
+            self.complete_transfer(data);
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_bytes(&[self.sb, self.sc, self.bits_shifted]);
+        writer.write_bool(self.clock_bit);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.sb = reader.read_u8()?;
+        self.sc = reader.read_u8()?;
+        self.bits_shifted = reader.read_u8()?;
+        self.clock_bit = reader.read_bool()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use super::*;
+    use crate::emulator::cpu::CPU_CTX;
+    use crate::emulator::test_util;
+
+    /**
+     * A peer that never sends anything
+     */
+    struct SilentPeer;
+
+    impl LinkTransport for SilentPeer {
+        fn send(&mut self, _kind: u8, _data: u8) -> std::io::Result<()> {
+            return Ok(());
+        }
+
+        fn poll(&mut self) -> std::io::Result<Option<(u8, u8)>> {
+            return Ok(None);
+        }
+    }
+
+    /**
+     * A peer whose answers are held back until the test lets them
+     * through, so each end completes its transfer in a known order
+     */
+    struct GatedPeer {
+        inner: TcpTransport,
+        sent: Sender<()>,
+        release: Receiver<()>,
+    }
+
+    impl LinkTransport for GatedPeer {
+        fn send(&mut self, kind: u8, data: u8) -> std::io::Result<()> {
+            self.inner.send(kind, data)?;
+            self.sent.send(()).unwrap();
+            self.release.recv().unwrap();
+            return Ok(());
+        }
+
+        fn poll(&mut self) -> std::io::Result<Option<(u8, u8)>> {
+            return self.inner.poll();
+        }
+    }
+
+    fn serial_with(sb: u8, sc: u8) -> Serial {
+        return Serial {
+            sb: sb, sc: sc, bits_shifted: 0, clock_bit: false,
+            transport: None, poll_cycles: 0,
+        };
+    }
+
+    /**
+     * Ticks until the current transfer is completed, with a falling
+     * edge of the internal clock every other tick
+     */
+    fn run_transfer(serial: &mut Serial) -> () {
+        let mut i: u32 = 0;
+        while serial.is_transferring() {
+            serial.tick(if i % 2 == 0 { CLOCK_BIT } else { 0 });
+            i += 1;
+        }
+    }
+
+    fn take_serial_interrupt() -> bool {
+        unsafe {
+            let requested = CPU_CTX.int_flags & InterruptType::IT_SERIAL as u8 != 0;
+            CPU_CTX.int_flags &= !(InterruptType::IT_SERIAL as u8);
+            return requested;
+        }
+    }
+
+    #[test]
+    fn linked_serial_ports_swap_their_bytes() {
+        let _lock = test_util::lock();
+        let (master_end, slave_end) = TcpTransport::pair().unwrap();
+        let (sent_tx, sent_rx) = channel();
+        let (release_tx, release_rx) = channel();
+        let mut master = serial_with(0x12, 0x81);
+        master.connect(Box::new(master_end));
+        let mut slave = serial_with(0x34, 0x80);
+        slave.connect(Box::new(GatedPeer { inner: slave_end, sent: sent_tx, release: release_rx }));
+        take_serial_interrupt();
+
+        let slave = std::thread::spawn(move || {
+            run_transfer(&mut slave);
+            return slave;
+        });
+        let master = std::thread::spawn(move || {
+            run_transfer(&mut master);
+            return master;
+        });
+
+        // The slave has answered, the master completes its transfer
+        sent_rx.recv().unwrap();
+        let master = master.join().unwrap();
+        assert_eq!(master.sb, 0x34);
+        assert!(take_serial_interrupt());
+        // Then the slave completes its own
+        release_tx.send(()).unwrap();
+        let slave = slave.join().unwrap();
+        assert_eq!(slave.sb, 0x12);
+        assert_eq!(slave.read(SC_ADDR) & TRANSFER_ENABLE_MASK, 0);
+        assert!(take_serial_interrupt());
+    }
+
+    #[test]
+    fn master_reads_0xff_when_the_peer_does_not_answer() {
+        let _lock = test_util::lock();
+        let mut master = serial_with(0x12, 0x81);
+        master.connect(Box::new(SilentPeer));
+        take_serial_interrupt();
+        run_transfer(&mut master);
+        assert_eq!(master.sb, 0xFF);
+        assert!(take_serial_interrupt());
+        assert!(master.transport.is_some());
+    }
+
+    #[test]
+    fn reset_keeps_polling_the_connected_link() {
+        let mut serial = serial_with(0, 0);
+        serial.connect(Box::new(SilentPeer));
+        serial.tick(0);
+        serial.reset();
+        for _ in 0..CYCLES_PER_BIT * 2 {
+            serial.tick(0);
+        }
+        assert!(serial.transport.is_some());
+    }
+}
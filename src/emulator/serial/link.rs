@@ -0,0 +1,155 @@
+use std::io::{Read, Write, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+/**
+ * Transports used to connect the serial ports of two emulators.
+ * Every transfer is exchanged as a 2-byte message, the kind of
+ * the message followed by the transferred byte. The side that
+ * drives the clock sends LINK_MASTER, the other side answers
+ * with LINK_SLAVE and the content of its own SB register.
+ */
+
+pub const LINK_MASTER: u8 = 0x01;
+pub const LINK_SLAVE: u8 = 0x02;
+
+pub trait LinkTransport: Send {
+    /**
+     * Sends a message to the peer
+     */
+    fn send(&mut self, kind: u8, data: u8) -> std::io::Result<()>;
+
+    /**
+     * Returns the next message from the peer if one arrived,
+     * without blocking
+     */
+    fn poll(&mut self) -> std::io::Result<Option<(u8, u8)>>;
+
+    /**
+     * Waits at most `timeout` for the next message from the peer
+     */
+    fn recv(&mut self, timeout: Duration) -> std::io::Result<Option<(u8, u8)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(message) = self.poll()? {
+                return Ok(Some(message));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_micros(50));
+        }
+    }
+}
+
+
+/**
+ * Link cable over a TCP connection
+ */
+pub struct TcpTransport {
+    stream: TcpStream,
+    // Bytes received that do not form a full message yet
+    pending: Vec<u8>,
+}
+
+impl TcpTransport {
+    /**
+     * Waits for a peer to connect on the given port
+     */
+    pub fn listen(port: u16) -> std::io::Result<TcpTransport> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        log::info!(target: "stdout", "Waiting for link cable peer on port {}...", port);
+        let (stream, peer) = listener.accept()?;
+        log::info!(target: "stdout", "Link cable connected to {}", peer);
+        return TcpTransport::new(stream);
+    }
+
+    /**
+     * Connects to a peer listening on the given address (host:port)
+     */
+    pub fn connect(address: &str) -> std::io::Result<TcpTransport> {
+        let stream = TcpStream::connect(address)?;
+        log::info!(target: "stdout", "Link cable connected to {}", address);
+        return TcpTransport::new(stream);
+    }
+
+    /**
+     * Returns both ends of a link cable connected over the loopback
+     */
+    #[cfg(test)]
+    pub fn pair() -> std::io::Result<(TcpTransport, TcpTransport)> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let client = TcpStream::connect(listener.local_addr()?)?;
+        let (server, _) = listener.accept()?;
+        return Ok((TcpTransport::new(server)?, TcpTransport::new(client)?));
+    }
+
+    fn new(stream: TcpStream) -> std::io::Result<TcpTransport> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        return Ok(TcpTransport { stream: stream, pending: Vec::new() });
+    }
+}
+
+impl LinkTransport for TcpTransport {
+    fn send(&mut self, kind: u8, data: u8) -> std::io::Result<()> {
+        let message = [kind, data];
+        let mut written = 0;
+        while written < message.len() {
+            match self.stream.write(&message[written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => std::thread::yield_now(),
+                Err(err) => return Err(err),
+            }
+        }
+        return Ok(());
+    }
+
+    fn poll(&mut self) -> std::io::Result<Option<(u8, u8)>> {
+        let mut buffer = [0u8; 16];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => self.pending.extend_from_slice(&buffer[..n]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(err) => return Err(err),
+        }
+        if self.pending.len() < 2 {
+            return Ok(None);
+        }
+        let message = (self.pending[0], self.pending[1]);
+        self.pending.drain(..2);
+        return Ok(Some(message));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_arrive_in_order() {
+        let (mut master, mut slave) = TcpTransport::pair().unwrap();
+        master.send(LINK_MASTER, 0x12).unwrap();
+        master.send(LINK_MASTER, 0x34).unwrap();
+        let timeout = Duration::from_secs(1);
+        assert_eq!(slave.recv(timeout).unwrap(), Some((LINK_MASTER, 0x12)));
+        assert_eq!(slave.recv(timeout).unwrap(), Some((LINK_MASTER, 0x34)));
+        slave.send(LINK_SLAVE, 0x56).unwrap();
+        assert_eq!(master.recv(timeout).unwrap(), Some((LINK_SLAVE, 0x56)));
+    }
+
+    #[test]
+    fn recv_gives_up_after_the_timeout() {
+        let (mut master, _slave) = TcpTransport::pair().unwrap();
+        assert_eq!(master.poll().unwrap(), None);
+        assert_eq!(master.recv(Duration::from_millis(10)).unwrap(), None);
+    }
+
+    #[test]
+    fn a_closed_peer_is_an_error() {
+        let (mut master, slave) = TcpTransport::pair().unwrap();
+        drop(slave);
+        assert!(master.recv(Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+/**
+ * Helpers shared by the unit tests. The components live in global
+ * contexts, so the tests that use them take the lock first to avoid
+ * running concurrently with each other.
+ */
+
+use std::sync::{Mutex, MutexGuard};
+use crate::emulator::Emulator;
+use crate::emulator::address_bus;
+use crate::emulator::cpu::CPU_CTX;
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+// The logo at 0x0104 - 0x0133 that the boot ROM compares against
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// Address the test programs are placed at, right after the header
+pub const CODE_START: u16 = 0x150;
+
+/**
+ * Serializes the tests that use the global contexts. A test that
+ * panicked while holding the lock does not fail the other ones.
+ */
+pub fn lock() -> MutexGuard<'static, ()> {
+    return LOCK.lock().unwrap_or_else(|err| err.into_inner());
+}
+
+/**
+ * Builds a ROM of 32 KiB << rom_size with a valid header, the entry
+ * point jumping to CODE_START, where the given code is placed
+ */
+pub fn build_rom(cartridge_type: u8, rom_size: u8, ram_size: u8, code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000 << rom_size];
+    // NOP; JP CODE_START
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, CODE_START as u8, (CODE_START >> 8) as u8]);
+    rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x138].copy_from_slice(b"TEST");
+    rom[0x147] = cartridge_type;
+    rom[0x148] = rom_size;
+    rom[0x149] = ram_size;
+    rom[0x14A] = 0x01;
+    let start = CODE_START as usize;
+    rom[start..start + code.len()].copy_from_slice(code);
+    fix_checksums(&mut rom);
+    return rom;
+}
+
+/**
+ * Recomputes the header and global checksums after the ROM
+ * has been modified
+ */
+pub fn fix_checksums(rom: &mut [u8]) -> () {
+    let mut checksum: u8 = 0;
+    for byte in &rom[0x134..0x14D] {
+        checksum = checksum.wrapping_sub(*byte).wrapping_sub(1);
+    }
+    rom[0x14D] = checksum;
+    let mut global: u16 = 0;
+    for (i, byte) in rom.iter().enumerate() {
+        if i != 0x14E && i != 0x14F {
+            global = global.wrapping_add(*byte as u16);
+        }
+    }
+    rom[0x14E..0x150].copy_from_slice(&global.to_be_bytes());
+}
+
+/**
+ * Inserts a ROM only cartridge with the given code, resets the
+ * machine and starts executing at CODE_START
+ */
+pub fn boot(code: &[u8]) -> () {
+    boot_rom(build_rom(0x00, 0x00, 0x00, code));
+}
+
+/**
+ * Inserts the given ROM, resets the machine and starts executing
+ * at CODE_START
+ */
+pub fn boot_rom(rom: Vec<u8>) -> () {
+    address_bus::set_flat_memory(false);
+    Emulator::init_from_data(rom).expect("Unable to load the test ROM");
+    Emulator::reset();
+    unsafe {
+        let mut registers = CPU_CTX.registers();
+        registers.pc = CODE_START;
+        CPU_CTX.set_registers(registers);
+    }
+}
+
+/**
+ * Resets the CPU and replaces the memory map with a flat 64 KiB
+ * memory holding the given code at CODE_START
+ */
+pub fn flat_cpu(code: &[u8]) -> () {
+    address_bus::set_flat_memory(true);
+    unsafe {
+        CPU_CTX.reset();
+        let mut registers = CPU_CTX.registers();
+        registers.pc = CODE_START;
+        CPU_CTX.set_registers(registers);
+    }
+    for (i, byte) in code.iter().enumerate() {
+        address_bus::bus_write(CODE_START + i as u16, *byte);
+    }
+}
@@ -1,6 +1,6 @@
 use std::sync::atomic::{AtomicU16, Ordering};
-use crate::emulator::cpu::CPU;
-use crate::emulator::cpu::interrupts::*;
+use crate::emulator::savestate::*;
+use crate::emulator::error::{fatal, EmuError};
 
 pub const DIV_ADDR:  u16 = 0xFF04;
 pub const TIMA_ADDR: u16 = 0xFF05;
@@ -8,6 +8,8 @@ pub const TMA_ADDR:  u16 = 0xFF06;
 pub const TAC_ADDR:  u16 = 0xFF07;
 
 const DEFAULT_ORDER: Ordering = Ordering::Relaxed;
+// The TMA reload happens one M-cycle after TIMA overflows
+const RELOAD_DELAY: u8 = 4;
 
 
 /**
@@ -23,55 +25,98 @@ pub struct Timer {
     tma: u8,
     // Timer Control (TAC)
     tac: u8,
+    // Ticks left until TIMA is reloaded after an overflow,
+    // during which TIMA reads as 0
+    overflow_ticks: u8,
+    // Ticks left in the cycle in which TIMA is reloaded
+    reload_ticks: u8,
 }
 
-pub static mut TIMER_CTX: Timer = Timer {
-    div: AtomicU16::new(0xABCC),
-    tima: 0, tma: 0, tac: 0
-}; 
+pub static mut TIMER_CTX: Timer = Timer::new();
 
 impl Timer {
+    const fn new() -> Timer {
+        Timer {
+            div: AtomicU16::new(0xABCC),
+            tima: 0, tma: 0, tac: 0,
+            overflow_ticks: 0, reload_ticks: 0,
+        }
+    }
+
+    pub fn reset(&mut self) -> () {
+        *self = Timer::new();
+    }
+
     /**
      * Performs one timer tick. Returns true if the timer
      * interrupt should be requested.
      */
     pub fn tick(&mut self) -> bool {
+        let mut interrupt = false;
+        if self.reload_ticks > 0 {
+            self.reload_ticks -= 1;
+        }
+        if self.overflow_ticks > 0 {
+            self.overflow_ticks -= 1;
+            if self.overflow_ticks == 0 {
+                // TIMA is reloaded from TMA and the interrupt is
+                // requested one M-cycle after the overflow
+                self.tima = self.tma;
+                self.reload_ticks = RELOAD_DELAY;
+                interrupt = true;
+            }
+        }
+
         // Increments the DIV register
         let prev_div = self.div.load(DEFAULT_ORDER);
         self.div.fetch_add(1, DEFAULT_ORDER);
 
-        let mut timer_update: bool = false;
-        
-        match self.tac & 0b11 {
-            0x00 => {
-                timer_update = ((prev_div & (1 << 9)) != 0) && 
-                               ((self.div.load(DEFAULT_ORDER) & (1 << 9)) == 0);
-            },
-            0x01 => {
-                timer_update = ((prev_div & (1 << 3)) != 0) && 
-                               ((self.div.load(DEFAULT_ORDER) & (1 << 3)) == 0);
-            }
-            0x02 => {
-                timer_update = ((prev_div & (1 << 5)) != 0) && 
-                               ((self.div.load(DEFAULT_ORDER) & (1 << 5)) == 0);
-            }
-            0x03 => {
-                timer_update = ((prev_div & (1 << 7)) != 0) && 
-                               ((self.div.load(DEFAULT_ORDER) & (1 << 7)) == 0);
-            }
-            _ => (),
-        }
+        // TIMA is incremented on a falling edge of the DIV bit
+        // selected by TAC
+        let timer_update = ((prev_div & self.tac_bit()) != 0) &&
+                           ((self.div.load(DEFAULT_ORDER) & self.tac_bit()) == 0);
         // If the timer is enabled and the timer update flag is set
         if timer_update && self.is_enabled() {
-            self.tima = self.tima.wrapping_add(1);
-            if self.tima == 0xFF {
-                self.tima = self.tma;
-                return true;
-            }
+            self.increment_tima();
         }
-        return false;
+        return interrupt;
     }
     
+    /**
+     * Returns the mask of the DIV bit that clocks TIMA
+     * at the frequency selected by TAC
+     */
+    #[inline(always)]
+    fn tac_bit(&self) -> u16 {
+        match self.tac & 0b11 {
+            0x00 => 1 << 9,
+            0x01 => 1 << 3,
+            0x02 => 1 << 5,
+            _    => 1 << 7,
+        }
+    }
+
+    /**
+     * Increments TIMA and schedules the reload on an overflow
+     */
+    fn increment_tima(&mut self) -> () {
+        // TIMA overflows when it wraps from 0xFF to 0x00
+        if self.tima == 0xFF {
+            self.overflow_ticks = RELOAD_DELAY;
+        }
+        self.tima = self.tima.wrapping_add(1);
+    }
+
+    /**
+     * Returns the 16-bit counter whose upper byte is DIV. It is
+     * incremented every T-cycle (twice as fast in double speed mode)
+     * and its bits also clock the APU frame sequencer and the serial port.
+     */
+    #[inline(always)]
+    pub fn internal_counter(&self) -> u16 {
+        return self.div.load(DEFAULT_ORDER);
+    }
+
     #[inline(always)]
     pub fn is_enabled(&self) -> bool {
         return (self.tac & 0b100) != 0;
@@ -86,11 +131,9 @@ impl Timer {
             DIV_ADDR     => { return (self.div.load(DEFAULT_ORDER) >> 8) as u8; },
             TIMA_ADDR    => { return self.tima; },
             TMA_ADDR     => { return self.tma; },
-            TAC_ADDR     => { return self.tac; },
-            _ => {
-                log::error!("Invalid timer read address: {:04X}", address);
-                std::process::exit(-1);
-            }
+            // Bits 3 - 7 are unused
+            TAC_ADDR     => { return self.tac | 0xF8; },
+            _ => fatal(EmuError::InvalidAddress("timer", address)),
         }
     }
 
@@ -101,17 +144,145 @@ impl Timer {
     pub fn write(&mut self, address: u16, data: u8) -> () {
         match address {
             // Resets DIV
-            DIV_ADDR  => { self.div.store(0, DEFAULT_ORDER); },
+            DIV_ADDR  => {
+                // Resetting DIV while the selected bit is set is
+                // a falling edge, which increments TIMA
+                let prev_div = self.div.load(DEFAULT_ORDER);
+                self.div.store(0, DEFAULT_ORDER);
+                if (prev_div & self.tac_bit()) != 0 && self.is_enabled() {
+                    self.increment_tima();
+                }
+            },
             // TIMA
-            TIMA_ADDR => { self.tima = data; }
+            TIMA_ADDR => {
+                // A write in the cycle after the overflow cancels the
+                // reload and the interrupt, while a write in the cycle
+                // of the reload is ignored
+                if self.reload_ticks == 0 {
+                    self.tima = data;
+                    self.overflow_ticks = 0;
+                }
+            }
             // TMA
-            TMA_ADDR  => { self.tma = data; }
+            TMA_ADDR  => {
+                self.tma = data;
+                // TIMA is loaded with the new value during the reload
+                if self.reload_ticks > 0 {
+                    self.tima = data;
+                }
+            }
             // TAC
             TAC_ADDR  => { self.tac = data; }
-            _ => {
-                log::error!("Invalid timer write address: {:04X}", address);
-                std::process::exit(-1);
+            _ => fatal(EmuError::InvalidAddress("timer", address)),
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) -> () {
+        writer.write_u16(self.div.load(DEFAULT_ORDER));
+        writer.write_bytes(&[self.tima, self.tma, self.tac]);
+        writer.write_u8(self.overflow_ticks);
+        writer.write_u8(self.reload_ticks);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.div.store(reader.read_u16()?, DEFAULT_ORDER);
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tac = reader.read_u8()?;
+        self.overflow_ticks = reader.read_u8()?;
+        self.reload_ticks = reader.read_u8()?;
+        if self.overflow_ticks > RELOAD_DELAY || self.reload_ticks > RELOAD_DELAY {
+            return Err(StateError::Corrupted("timer reload delay"));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Returns a timer clocked every 16 ticks, ticked until TIMA
+     * has just overflowed
+     */
+    fn overflowed_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(DIV_ADDR, 0);
+        timer.write(TAC_ADDR, 0b101);
+        timer.write(TMA_ADDR, 0x42);
+        timer.write(TIMA_ADDR, 0xFF);
+        while timer.read(TIMA_ADDR) == 0xFF {
+            assert!(!timer.tick());
+        }
+        return timer;
+    }
+
+    /**
+     * Ticks the timer for one M-cycle and returns true if the
+     * interrupt was requested
+     */
+    fn m_cycle(timer: &mut Timer) -> bool {
+        let mut interrupt = false;
+        for _ in 0..4 {
+            interrupt |= timer.tick();
+        }
+        return interrupt;
+    }
+
+    #[test]
+    fn tima_is_reloaded_one_cycle_after_the_overflow() {
+        let mut timer = overflowed_timer();
+        assert_eq!(timer.read(TIMA_ADDR), 0x00);
+        assert!(m_cycle(&mut timer));
+        assert_eq!(timer.read(TIMA_ADDR), 0x42);
+    }
+
+    #[test]
+    fn tima_write_in_the_delay_cancels_the_reload() {
+        let mut timer = overflowed_timer();
+        timer.write(TIMA_ADDR, 0x10);
+        assert!(!m_cycle(&mut timer));
+        assert!(!m_cycle(&mut timer));
+        assert_eq!(timer.read(TIMA_ADDR), 0x10);
+    }
+
+    #[test]
+    fn tma_write_during_the_reload_is_taken() {
+        let mut timer = overflowed_timer();
+        assert!(m_cycle(&mut timer));
+        timer.write(TMA_ADDR, 0x55);
+        assert_eq!(timer.read(TIMA_ADDR), 0x55);
+    }
+
+    #[test]
+    fn tima_write_during_the_reload_is_ignored() {
+        let mut timer = overflowed_timer();
+        assert!(m_cycle(&mut timer));
+        timer.write(TIMA_ADDR, 0x10);
+        assert_eq!(timer.read(TIMA_ADDR), 0x42);
+        // The cycle after the reload accepts writes again
+        m_cycle(&mut timer);
+        timer.write(TIMA_ADDR, 0x10);
+        assert_eq!(timer.read(TIMA_ADDR), 0x10);
+    }
+
+    #[test]
+    fn div_write_with_the_selected_bit_set_increments_tima() {
+        for bit_set in [true, false] {
+            let mut timer = Timer::new();
+            timer.write(DIV_ADDR, 0);
+            timer.write(TAC_ADDR, 0b101);
+            timer.write(TIMA_ADDR, 0x20);
+            // Bit 3 of the counter is set after 8 ticks, clear after 16
+            let ticks = if bit_set { 8 } else { 16 + 4 };
+            for _ in 0..ticks {
+                timer.tick();
             }
+            assert_eq!(timer.internal_counter() & (1 << 3) != 0, bit_set);
+            let tima = timer.read(TIMA_ADDR);
+            timer.write(DIV_ADDR, 0);
+            assert_eq!(timer.read(TIMA_ADDR), tima + bit_set as u8);
         }
     }
-}
\ No newline at end of file
+}
@@ -7,12 +7,21 @@ use sdl2_sys::SDL_WindowEventID::*;
 
 use crate::emulator::address_bus::*;
 use crate::emulator::ppu::*;
-use crate::emulator::gamepad::*;
+use crate::emulator::apu::{APU_CTX, SAMPLE_RATE};
+use crate::emulator::{Emulator, CLOCK_MHZ};
+use crate::emulator::keymap::KEYMAP_CTX;
+use crate::emulator::movie;
+use crate::emulator::lcd::LCD_CTX;
+use crate::emulator::png;
+use crate::emulator::ppu;
+use crate::emulator::cartridge::CARTRIDGE_CTX;
 
+// Scale of the debug window
 const SCALE: i32 = 4;
-const WIDTH: i32 = X_RES as i32 * SCALE;
-const HEIGHT: i32 = Y_RES as i32 * SCALE;
-const FREQ: u32 = 60;
+// Size of the debug window, which fits the 16x24 grid of tiles with a
+// gap of one pixel between them. The tile map view is smaller.
+const DEBUG_WIDTH: i32 = 16 * (8 + 1) * SCALE;
+const DEBUG_HEIGHT: i32 = 24 * (8 + 1) * SCALE;
 
 const TILE_COLORS: [u32; 4] = [
     0xFFFFFFFF, // White
@@ -23,14 +32,63 @@ const TILE_COLORS: [u32; 4] = [
 
 // Jesus christ rust is a pain when
 // it comes to converting enums to ints
-const KEY_Z: i32 = SDLK_z as i32;
-const KEY_X: i32 = SDLK_x as i32;
-const KEY_RETURN: i32 = SDLK_RETURN as i32;
-const KEY_TAB: i32 = SDLK_TAB as i32;
-const KEY_UP: i32 = SDLK_UP as i32;
-const KEY_DOWN: i32 = SDLK_DOWN as i32;
-const KEY_LEFT: i32 = SDLK_LEFT as i32;
-const KEY_RIGHT: i32 = SDLK_RIGHT as i32;
+const KEY_F5: i32 = SDLK_F5 as i32;
+const KEY_F7: i32 = SDLK_F7 as i32;
+const KEY_F12: i32 = SDLK_F12 as i32;
+const KEY_F2: i32 = SDLK_F2 as i32;
+const KEY_PAUSE: i32 = SDLK_p as i32;
+// Resets the machine together with Ctrl
+const KEY_RESET: i32 = SDLK_r as i32;
+const KEY_F3: i32 = SDLK_F3 as i32;
+const KEY_F1: i32 = SDLK_F1 as i32;
+const KEY_F9: i32 = SDLK_F9 as i32;
+// Fast-forwards while held
+const KEY_TURBO: i32 = SDLK_SPACE as i32;
+const WINDOW_TITLE: &str = "Game Boy Emulator";
+
+/**
+ * How the LCD image is scaled to the size of the main window
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScalingMode {
+    // Largest integer multiple of the LCD size that fits, centered
+    Integer,
+    // Largest size that keeps the aspect ratio, letterboxed
+    Aspect,
+    // Fills the whole window
+    Stretch,
+}
+
+// Initial size of the main window in multiples of the LCD size
+#[allow(non_upper_case_globals)]
+static mut window_scale: i32 = 4;
+#[allow(non_upper_case_globals)]
+static mut scaling_mode: ScalingMode = ScalingMode::Integer;
+// Area of the main window that the LCD image is drawn to
+#[allow(non_upper_case_globals)]
+static mut main_viewport: SDL_Rect = SDL_Rect { x: 0, y: 0, w: 0, h: 0 };
+
+// Post-processing that mimics the LCD, off by default
+#[allow(non_upper_case_globals)]
+static mut lcd_filter: bool = false;
+// Weight of the previous frame when blending, 0 disables the ghosting
+#[allow(non_upper_case_globals)]
+static mut ghosting: f32 = 0.0;
+// The last frame shown with the filter applied
+#[allow(non_upper_case_globals)]
+static mut filtered_frame: Vec<u32> = Vec::new();
+
+// Shows the FPS, speed, title and panning over the LCD image, toggled with F1
+#[allow(non_upper_case_globals)]
+static mut show_overlay: bool = false;
+// The last frame shown with the overlay drawn on top
+#[allow(non_upper_case_globals)]
+static mut overlay_frame: Vec<u32> = Vec::new();
+const OVERLAY_TEXT_COLOR: u32 = 0xFFFFFFFF;
+const OVERLAY_BACK_COLOR: u32 = 0xFF000000;
+// Each glyph is 3x5 pixels and drawn in a 4x6 cell
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
 
 #[allow(non_upper_case_globals)]
 static mut main_window: *mut SDL_Window = std::ptr::null_mut();
@@ -38,8 +96,6 @@ static mut main_window: *mut SDL_Window = std::ptr::null_mut();
 static mut main_renderer: *mut SDL_Renderer = std::ptr::null_mut();
 #[allow(non_upper_case_globals)]
 static mut main_texture: *mut SDL_Texture = std::ptr::null_mut();
-#[allow(non_upper_case_globals)]
-static mut main_screen: *mut SDL_Surface = std::ptr::null_mut();
 
 #[allow(non_upper_case_globals)]
 static mut debug_window: *mut SDL_Window = std::ptr::null_mut();
@@ -50,6 +106,20 @@ static mut debug_texture: *mut SDL_Texture = std::ptr::null_mut();
 #[allow(non_upper_case_globals)]
 static mut debug_screen: *mut SDL_Surface = std::ptr::null_mut();
 
+// Shows the background tile map instead of the tiles
+// in the debug window, toggled with F2
+#[allow(non_upper_case_globals)]
+static mut debug_map_view: bool = false;
+// Each pixel of the tile map is drawn as a 2x2 square
+const MAP_SCALE: i32 = 2;
+const VIEWPORT_COLOR: u32 = 0xFFFF0000;
+
+#[allow(non_upper_case_globals)]
+static mut audio_device: SDL_AudioDeviceID = 0;
+// Maximum number of bytes allowed in the audio queue before
+// new samples are dropped, about 100ms of stereo f32 samples
+const MAX_QUEUED_AUDIO: u32 = (SAMPLE_RATE / 10) * 2 * 4;
+
 
 /**
  * Initializes the main window and debug window
@@ -57,26 +127,34 @@ static mut debug_screen: *mut SDL_Surface = std::ptr::null_mut();
 pub fn init() -> () {
     log::info!("Initializing UI...");
     unsafe {
-        SDL_Init(SDL_INIT_VIDEO);
+        // Controllers are opened when SDL reports them as added,
+        // which it also does for those connected at startup
+        SDL_Init(SDL_INIT_VIDEO | SDL_INIT_AUDIO | SDL_INIT_GAMECONTROLLER);
         // Creates the main window
-        SDL_CreateWindowAndRenderer(WIDTH, HEIGHT, 0, &mut main_window, &mut main_renderer);
-        main_screen = SDL_CreateRGBSurface(0, WIDTH, HEIGHT, 32,
-            0x00FF0000, 0x0000FF00, 0x000000FF, 0xFF000000);
+        let width = X_RES as i32 * window_scale;
+        let height = Y_RES as i32 * window_scale;
+        SDL_CreateWindowAndRenderer(width, height, SDL_WindowFlags::SDL_WINDOW_RESIZABLE as u32,
+            &mut main_window, &mut main_renderer);
+        update_viewport();
+        // The texture has the resolution of the LCD and is scaled up
+        // by the renderer with nearest neighbor filtering
+        SDL_SetHint(SDL_HINT_RENDER_SCALE_QUALITY.as_ptr() as *const i8,
+            b"nearest\0".as_ptr() as *const i8);
         main_texture = SDL_CreateTexture(main_renderer, SDL_PIXELFORMAT_ARGB8888 as u32,
-            SDL_TEXTUREACCESS_STREAMING as i32, WIDTH, HEIGHT);
+            SDL_TEXTUREACCESS_STREAMING as i32, X_RES as i32, Y_RES as i32);
 
         // Creates the debug window
-        SDL_CreateWindowAndRenderer(16 * 8 * SCALE, 32 * 8 * SCALE, 0, 
+        // The window, the surface and the texture have the same size,
+        // so that the debug view is not scaled
+        SDL_CreateWindowAndRenderer(DEBUG_WIDTH, DEBUG_HEIGHT, 0,
             &mut debug_window, &mut debug_renderer);
         
-        debug_screen = SDL_CreateRGBSurface(0, (16 * 8 * SCALE) + (16 * SCALE),
-            (32 * 8 * SCALE) + (64 * SCALE), 32,
+        debug_screen = SDL_CreateRGBSurface(0, DEBUG_WIDTH, DEBUG_HEIGHT, 32,
             0x00FF0000, 0x0000FF00, 0x000000FF, 0xFF000000);
         debug_texture = SDL_CreateTexture(debug_renderer,
                 SDL_PIXELFORMAT_ARGB8888 as u32,
                 SDL_TEXTUREACCESS_STREAMING as i32,
-                (16 * 8 * SCALE) + (16 * SCALE), 
-                (32 * 8 * SCALE) + (64 * SCALE));
+                DEBUG_WIDTH, DEBUG_HEIGHT);
         
         let mut x = 0;
         let mut y = 0;
@@ -84,57 +162,151 @@ pub fn init() -> () {
         // Sets the location of the debug window
         // relative to the main window
         SDL_GetWindowPosition(main_window, &mut x, &mut y);
-        SDL_SetWindowPosition(debug_window, x + WIDTH + 10, y);
+        SDL_SetWindowPosition(debug_window, x + width + 10, y);
+
+        init_audio();
     }
+    update_window_title();
     log::info!(target: "stdout", "Initialize UI: SUCCESS");
 
 }
 
 
+/**
+ * Draws a tile of the given tile data area, scaled up, with its
+ * top-left corner at (x, y) of the surface
+ */
 pub fn display_tile(surface: *mut SDL_Surface, start_loc: u16, tile_num: u16, x: i32, y: i32) -> () {
-    let mut rect: SDL_Rect = SDL_Rect {
-        x: 0,
-        y: 0,
-        w: 0,
-        h: 0
-    };
-    for tile_y in (0..16).step_by(2) {
-        let b1 = bus_read(start_loc + (tile_num * 16) + tile_y);
-        let b2 = bus_read(start_loc + (tile_num * 16) + tile_y + 1);
-        for bit in (0..8).rev() {
-            let hi = (((b1 & (1 << bit)) > 0) as u8) << 1;
-            let lo = ((b2 & (1 << bit)) > 0) as u8;
-            let color = hi | lo;
-
-            rect.x = (x + ((7 - bit) * SCALE)) as i32;
-            rect.y = (y + (tile_y as i32 / 2 * SCALE)) as i32;
-            rect.w = SCALE as i32;
-            rect.h = SCALE as i32;
-            // Draws the rectangle
-            unsafe {
-                SDL_FillRect(surface, &rect, TILE_COLORS[color as usize]);
+    for tile_y in 0..8u16 {
+        // The first byte of each row holds the low bits of the colors
+        let lo = bus_peek(start_loc + (tile_num * 16) + tile_y * 2);
+        let hi = bus_peek(start_loc + (tile_num * 16) + tile_y * 2 + 1);
+        for bit in 0..8u16 {
+            let color = (((hi >> (7 - bit)) & 1) << 1) | ((lo >> (7 - bit)) & 1);
+            for dy in 0..SCALE {
+                for dx in 0..SCALE {
+                    put_pixel(surface, x + bit as i32 * SCALE + dx, y + tile_y as i32 * SCALE + dy,
+                        TILE_COLORS[color as usize]);
+                }
             }
         }
     }
 }
 
 /**
- * A helper function that updates the debug window
+ * Fills a rectangle of the given surface with a color
  */
-fn update_debug_window() -> () {
-    // Fills the debug window with the color gray
-    let mut rect: SDL_Rect = SDL_Rect {
-        x: 0,
-        y: 0,
-        w: 0,
-        h: 0
-    };
+fn fill_rect(surface: *mut SDL_Surface, x: i32, y: i32, w: i32, h: i32, color: u32) -> () {
+    let rect = SDL_Rect { x: x, y: y, w: w, h: h };
+    unsafe { SDL_FillRect(surface, &rect, color); }
+}
+
+/**
+ * Sets a single pixel of a 32-bit surface
+ */
+#[inline(always)]
+fn put_pixel(surface: *mut SDL_Surface, x: i32, y: i32, color: u32) -> () {
     unsafe {
-        rect.w = (*debug_screen).w;
-        rect.h = (*debug_screen).h;
-        SDL_FillRect(debug_screen, &rect, 0xFF111111);
+        let pixels = (*surface).pixels as *mut u32;
+        let stride = (*surface).pitch / 4;
+        *pixels.offset((y * stride + x) as isize) = color;
     }
-    // Draws the tiles
+}
+
+/**
+ * Sets a single pixel of the debug surface
+ */
+#[inline(always)]
+fn put_debug_pixel(x: i32, y: i32, color: u32) -> () {
+    put_pixel(unsafe { debug_screen }, x, y, color);
+}
+
+/**
+ * Draws the 256x256 background tile map as it is composited by the
+ * PPU, with the 160x144 viewport at (SCX, SCY) outlined. Below the
+ * map, the current BG and OBJ palettes are shown as color swatches.
+ * CGB tile attributes are ignored.
+ */
+fn draw_tile_map() -> () {
+    let map_area = unsafe { LCD_CTX.get_lcdc_bg_tile_map_area() };
+    let data_area = unsafe { LCD_CTX.get_lcdc_bg_tile_data_area() };
+    let bg_colors = unsafe { LCD_CTX.bg_colors };
+    for map_y in 0..32u16 {
+        for map_x in 0..32u16 {
+            let tile_index = bus_peek(map_area + map_y * 32 + map_x);
+            // In the 0x8800 mode, the tile index is signed and relative to 0x9000
+            let tile_addr = if data_area == 0x8800 {
+                (0x9000 as i32 + (tile_index as i8 as i32) * 16) as u16
+            } else {
+                data_area + tile_index as u16 * 16
+            };
+            for tile_y in 0..8u16 {
+                let lo = bus_peek(tile_addr + tile_y * 2);
+                let hi = bus_peek(tile_addr + tile_y * 2 + 1);
+                for bit in 0..8u16 {
+                    let color = (((hi >> (7 - bit)) & 1) << 1) | ((lo >> (7 - bit)) & 1);
+                    let x = (map_x * 8 + bit) as i32 * MAP_SCALE;
+                    let y = (map_y * 8 + tile_y) as i32 * MAP_SCALE;
+                    for dy in 0..MAP_SCALE {
+                        for dx in 0..MAP_SCALE {
+                            put_debug_pixel(x + dx, y + dy, bg_colors[color as usize]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Outlines the viewport, which wraps around the edges of the map
+    let scroll_x = unsafe { LCD_CTX.scroll_x } as i32;
+    let scroll_y = unsafe { LCD_CTX.scroll_y } as i32;
+    let map_point = |x: i32, y: i32| {
+        let x = ((scroll_x + x) % 256) * MAP_SCALE;
+        let y = ((scroll_y + y) % 256) * MAP_SCALE;
+        for dy in 0..MAP_SCALE {
+            for dx in 0..MAP_SCALE {
+                put_debug_pixel(x + dx, y + dy, VIEWPORT_COLOR);
+            }
+        }
+    };
+    for x in 0..X_RES as i32 {
+        map_point(x, 0);
+        map_point(x, Y_RES as i32 - 1);
+    }
+    for y in 0..Y_RES as i32 {
+        map_point(0, y);
+        map_point(X_RES as i32 - 1, y);
+    }
+
+    // Draws the palettes, one row per palette
+    let swatch = 8 * SCALE;
+    let top = 256 * MAP_SCALE + swatch;
+    let mut palettes: Vec<[u32; 4]> = unsafe {
+        vec![LCD_CTX.bg_colors, LCD_CTX.sp1_colors, LCD_CTX.sp2_colors]
+    };
+    if Emulator::is_cgb() {
+        // 8 BG palettes followed by 8 OBJ palettes
+        palettes.clear();
+        for cgb_palette in unsafe { [LCD_CTX.bg_cgb_palette, LCD_CTX.obj_cgb_palette] } {
+            for palette in 0..8 {
+                palettes.push([0, 1, 2, 3].map(|color| cgb_palette.color(palette, color)));
+            }
+        }
+    }
+    for (row, colors) in palettes.iter().enumerate() {
+        for (i, color) in colors.iter().enumerate() {
+            // Two columns of palettes next to each other on CGB
+            let column = (row / 8) as i32 * 5 * swatch;
+            fill_rect(unsafe { debug_screen }, column + i as i32 * swatch,
+                top + (row % 8) as i32 * swatch, swatch - 2, swatch - 2, *color);
+        }
+    }
+}
+
+/**
+ * Draws the 384 tiles starting from 0x8000
+ */
+fn draw_tiles() -> () {
     let addr: u16 = 0x8000;
     let mut x_draw = 0;
     let mut y_draw = 0;
@@ -151,6 +323,21 @@ fn update_debug_window() -> () {
         y_draw += 8 * SCALE;
         x_draw = 0;
     }
+}
+
+/**
+ * A helper function that updates the debug window
+ */
+fn update_debug_window() -> () {
+    // Fills the debug window with the color gray
+    unsafe {
+        fill_rect(debug_screen, 0, 0, (*debug_screen).w, (*debug_screen).h, 0xFF111111);
+    }
+    if unsafe { debug_map_view } {
+        draw_tile_map();
+    } else {
+        draw_tiles();
+    }
     unsafe {
         SDL_UpdateTexture(debug_texture, std::ptr::null(), (*debug_screen).pixels, (*debug_screen).pitch);
         SDL_RenderClear(debug_renderer);
@@ -160,35 +347,257 @@ fn update_debug_window() -> () {
 }
 
 /**
- * A helper function that updates the main window
+ * Sets the initial size of the main window in multiples
+ * of the LCD size and how the image is scaled
  */
-fn update_main_window() -> () {
-    let mut rect: SDL_Rect = SDL_Rect {
-        x: 0,
-        y: 0,
-        w: 2048,
-        h: 2048
-    };
+pub fn set_scale(scale: u32, mode: ScalingMode) -> () {
+    unsafe {
+        window_scale = scale as i32;
+        scaling_mode = mode;
+    }
+}
 
-    let video_buffer = unsafe { PPU_CTX.video_buffer.clone() };
-    // Loops through each line and each pixel in the line
-    for line_num in 0..Y_RES {
-        for x in 0..X_RES {
-            rect.x = x as i32 * SCALE;
-            rect.y = line_num as i32 * SCALE;
-            rect.w = SCALE;
-            rect.h = SCALE;
-
-            let offset: u32 = (line_num as u32 * X_RES as u32) + x as u32;
-            unsafe {
-                SDL_FillRect(main_screen, &rect, video_buffer[offset as usize]);
+/**
+ * Enables the LCD filter, which blends each frame with the previous
+ * one by the given strength (0 - 1) and corrects the CGB colors
+ */
+pub fn set_lcd_filter(strength: f32) -> () {
+    unsafe {
+        lcd_filter = true;
+        ghosting = strength.clamp(0.0, 1.0);
+    }
+}
+
+/**
+ * Maps a CGB color to what it looks like on the CGB screen,
+ * which is less saturated and darker than an sRGB monitor
+ */
+#[inline(always)]
+fn correct_cgb_color(color: u32) -> u32 {
+    let r = (color >> 16) & 0xFF;
+    let g = (color >> 8) & 0xFF;
+    let b = color & 0xFF;
+    let r2 = (r * 13 + g * 2 + b) / 16;
+    let g2 = (g * 3 + b) / 4;
+    let b2 = (r * 3 + g * 2 + b * 11) / 16;
+    return 0xFF000000 | (r2 << 16) | (g2 << 8) | b2;
+}
+
+/**
+ * Blends two colors channel by channel, `weight` is the weight of `b`
+ */
+#[inline(always)]
+fn blend_colors(a: u32, b: u32, weight: f32) -> u32 {
+    let mut out: u32 = 0xFF000000;
+    for shift in [16, 8, 0] {
+        let ca = ((a >> shift) & 0xFF) as f32;
+        let cb = ((b >> shift) & 0xFF) as f32;
+        out |= ((ca + (cb - ca) * weight) as u32 & 0xFF) << shift;
+    }
+    return out;
+}
+
+/**
+ * Applies the LCD filter to a completed frame and returns the
+ * pixels to show. The ghosting blends with the last filtered
+ * frame, so old frames fade out gradually.
+ */
+fn apply_lcd_filter(pixels: &[u32]) -> &'static [u32] {
+    let frame = unsafe { &mut filtered_frame };
+    let first = frame.is_empty();
+    if first {
+        frame.resize(pixels.len(), 0);
+    }
+    let cgb = Emulator::is_cgb();
+    let weight = unsafe { ghosting };
+    for (out, pixel) in frame.iter_mut().zip(pixels.iter()) {
+        let color = if cgb { correct_cgb_color(*pixel) } else { *pixel };
+        *out = if first { color } else { blend_colors(color, *out, weight) };
+    }
+    return &frame[..];
+}
+
+/**
+ * Returns the rows of the 3x5 glyph of the given character, the
+ * lowest 3 bits of each row are the pixels from left to right.
+ * Lowercase letters are drawn as uppercase.
+ */
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        _   => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+/**
+ * Draws a line of text on a dark background into a frame
+ * of the size of the LCD
+ */
+fn draw_text(frame: &mut [u32], x: usize, y: usize, text: &str) -> () {
+    let width = text.chars().count() * (GLYPH_WIDTH + 1) + 1;
+    for row in y..(y + GLYPH_HEIGHT + 2).min(Y_RES as usize) {
+        for col in x..(x + width).min(X_RES as usize) {
+            frame[row * X_RES as usize + col] = OVERLAY_BACK_COLOR;
+        }
+    }
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + 1 + i * (GLYPH_WIDTH + 1);
+        for (dy, bits) in glyph(c).iter().enumerate() {
+            for dx in 0..GLYPH_WIDTH {
+                let (px, py) = (glyph_x + dx, y + 1 + dy);
+                if bits & (0b100 >> dx) != 0 && px < X_RES as usize && py < Y_RES as usize {
+                    frame[py * X_RES as usize + px] = OVERLAY_TEXT_COLOR;
+                }
             }
         }
     }
+}
+
+/**
+ * Draws the FPS, the emulation speed, the title of the game and
+ * the side each sound channel is panned to over the top-left
+ * corner of a frame
+ */
+fn draw_overlay(pixels: &[u32]) -> &'static [u32] {
+    let frame = unsafe { &mut overlay_frame };
+    frame.clear();
+    frame.extend_from_slice(pixels);
+    let fps = ppu::fps();
+    let speed = ppu::speed_percent(fps);
+    let line_height = GLYPH_HEIGHT + 2;
+    draw_text(frame, 0, 0, &format!("FPS {}", fps));
+    draw_text(frame, 0, line_height, &format!("SPD {}%", speed));
+    draw_text(frame, 0, line_height * 2, &unsafe { CARTRIDGE_CTX.title() });
+    let panning: Vec<String> = unsafe { APU_CTX.panning() }.iter().enumerate()
+        .map(|(i, (left, right))| format!("{}:{}{}", i + 1,
+            if *left { 'L' } else { '-' }, if *right { 'R' } else { '-' }))
+        .collect();
+    draw_text(frame, 0, line_height * 3, &format!("PAN {}", panning.join(" ")));
+    return &frame[..];
+}
+
+/**
+ * Returns the area of a w x h output that the LCD image is drawn to
+ * with the given scaling mode, centered in the output
+ */
+fn viewport(w: i32, h: i32, mode: ScalingMode) -> SDL_Rect {
+    let (lcd_w, lcd_h) = (X_RES as i32, Y_RES as i32);
+    let (view_w, view_h) = match mode {
+        ScalingMode::Integer => {
+            let scale = std::cmp::max(1, std::cmp::min(w / lcd_w, h / lcd_h));
+            (lcd_w * scale, lcd_h * scale)
+        },
+        ScalingMode::Aspect => {
+            let scale = f64::min(w as f64 / lcd_w as f64, h as f64 / lcd_h as f64);
+            ((lcd_w as f64 * scale) as i32, (lcd_h as f64 * scale) as i32)
+        },
+        ScalingMode::Stretch => (w, h),
+    };
+    return SDL_Rect { x: (w - view_w) / 2, y: (h - view_h) / 2, w: view_w, h: view_h };
+}
+
+/**
+ * Recomputes the area of the main window that the LCD image is
+ * drawn to, after the window was created or resized
+ */
+fn update_viewport() -> () {
+    let mut w: i32 = 0;
+    let mut h: i32 = 0;
+    unsafe {
+        // The output size is in pixels, which differs from the
+        // window size on high DPI displays
+        SDL_GetRendererOutputSize(main_renderer, &mut w, &mut h);
+        main_viewport = viewport(w, h, scaling_mode);
+    }
+}
+
+/**
+ * Writes the last completed frame to a 160x144 PNG file. Only the
+ * front buffer is read, so this can be called from any thread.
+ */
+pub fn save_screenshot(path: &str) -> std::io::Result<()> {
+    let pixels = FRONT_BUFFER.lock().unwrap().pixels.clone();
+    return png::write_argb(path, X_RES as u32, Y_RES as u32, &pixels[..]);
+}
+
+/**
+ * Returns the path of a new screenshot in the working directory,
+ * named after the ROM and the current time
+ */
+fn screenshot_path() -> String {
+    let rom_path = Emulator::state_slot_path();
+    let rom_name = std::path::Path::new(&rom_path).file_stem()
+        .map_or("screenshot".to_string(), |stem| stem.to_string_lossy().into_owned());
+    let millis = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis());
+    return format!("{}_{}.png", rom_name, millis);
+}
+
+/**
+ * A helper function that updates the main window
+ */
+fn update_main_window() -> () {
+    {
+        // Holds the lock only while the frame is uploaded
+        let frame = FRONT_BUFFER.lock().unwrap();
+        // The frame is already in ARGB8888
+        let pixels: &[u32] = if unsafe { lcd_filter } {
+            apply_lcd_filter(&frame.pixels[..])
+        } else {
+            &frame.pixels[..]
+        };
+        let pixels = if unsafe { show_overlay } { draw_overlay(pixels) } else { pixels };
+        unsafe {
+            SDL_UpdateTexture(main_texture, std::ptr::null(),
+                pixels.as_ptr() as *const std::ffi::c_void,
+                X_RES as i32 * std::mem::size_of::<u32>() as i32);
+        }
+    }
     unsafe {
-        SDL_UpdateTexture(main_texture, std::ptr::null(), (*main_screen).pixels, (*main_screen).pitch);
+        // The area around the viewport stays black
+        SDL_SetRenderDrawColor(main_renderer, 0, 0, 0, 0xFF);
         SDL_RenderClear(main_renderer);
-        SDL_RenderCopy(main_renderer, main_texture, std::ptr::null(), std::ptr::null());
+        SDL_RenderCopy(main_renderer, main_texture, std::ptr::null(), &main_viewport);
         SDL_RenderPresent(main_renderer);
     }
 
@@ -196,36 +605,101 @@ fn update_main_window() -> () {
 }
 
 
+/**
+ * Shows the current emulation speed in the title of the main window
+ */
+fn update_window_title() -> () {
+    let mut title = if Emulator::is_turbo() {
+        format!("{} [Turbo]", WINDOW_TITLE)
+    } else {
+        let speed = Emulator::speed();
+        format!("{} [{}x, {:.2} MHz]", WINDOW_TITLE, speed, speed * CLOCK_MHZ)
+    };
+    if Emulator::is_paused() {
+        title.push_str(" [Paused]");
+    }
+    let title = std::ffi::CString::new(title).unwrap();
+    unsafe { SDL_SetWindowTitle(main_window, title.as_ptr()); }
+}
+
+
 /**
  * A helper function that handles key events
  */
 fn handle_key_event(down: bool, key_code: i32) -> () {
     match key_code {
-        KEY_Z => {
-            unsafe { GAMEPAD_CTX.controller.b = down };
+        KEY_F5 => {
+            if down {
+                Emulator::request_save_state();
+            }
+            return;
         },
-        KEY_X => {
-            unsafe { GAMEPAD_CTX.controller.a = down };
+        KEY_F7 => {
+            if down {
+                Emulator::request_load_state();
+            }
+            return;
         },
-        KEY_RETURN => {
-            unsafe { GAMEPAD_CTX.controller.start = down };
+        KEY_F12 => {
+            if down {
+                let path = screenshot_path();
+                match save_screenshot(&path) {
+                    Ok(()) => log::info!(target: "stdout", "Saving screenshot {}: SUCCESS", path),
+                    Err(err) => log::error!(target: "stdout", "Saving screenshot {}: {}", path, err),
+                }
+            }
+            return;
         },
-        KEY_TAB => {
-            unsafe { GAMEPAD_CTX.controller.select = down };
+        KEY_F2 => {
+            if down {
+                unsafe { debug_map_view = !debug_map_view; }
+            }
+            return;
         },
-        KEY_UP => {
-            unsafe { GAMEPAD_CTX.controller.up = down };
+        KEY_F3 => {
+            if down {
+                Emulator::cycle_palette();
+            }
+            return;
         },
-        KEY_DOWN => {
-            unsafe { GAMEPAD_CTX.controller.down = down };
+        KEY_F1 => {
+            if down {
+                unsafe { show_overlay = !show_overlay; }
+            }
+            return;
         },
-        KEY_LEFT => {
-            unsafe { GAMEPAD_CTX.controller.left = down };
+        KEY_F9 => {
+            if down {
+                Emulator::request_memory_dump();
+            }
+            return;
         },
-        KEY_RIGHT => {
-            unsafe { GAMEPAD_CTX.controller.right = down };
+        KEY_RESET if unsafe { SDL_GetModState() as u32 } & (SDL_Keymod::KMOD_CTRL as u32) != 0 => {
+            if down {
+                Emulator::request_reset();
+            }
+            return;
         },
-        _ => {
+        KEY_PAUSE => {
+            if down {
+                Emulator::toggle_pause();
+                update_window_title();
+            }
+            return;
+        },
+        KEY_TURBO => {
+            if down != Emulator::is_turbo() {
+                Emulator::set_turbo(down);
+                update_window_title();
+            }
+            return;
+        },
+        _ => (),
+    }
+
+    match unsafe { KEYMAP_CTX.button(key_code) } {
+        Some(button) => movie::live_button(button, down),
+        Option::None => {
             log::warn!("Unsupported key code: {}", key_code);
         }
     }
@@ -233,8 +707,51 @@ fn handle_key_event(down: bool, key_code: i32) -> () {
 
 
 /**
- * UI loop, runs until the user closes the window. Handles events,
- * and updates the screen.
+ * A helper function that handles controller button events
+ */
+fn handle_pad_event(down: bool, pad_button: i32) -> () {
+    if let Some(button) = unsafe { KEYMAP_CTX.pad_button(pad_button) } {
+        movie::live_button(button, down);
+    }
+}
+
+/**
+ * Opens a controller that was plugged in
+ */
+fn open_controller(device_index: i32) -> () {
+    let controller = unsafe { SDL_GameControllerOpen(device_index) };
+    if controller.is_null() {
+        log::warn!(target: "stdout", "Unable to open controller {}", device_index);
+        return;
+    }
+    let name = unsafe { SDL_GameControllerName(controller) };
+    let name = if name.is_null() {
+        "Unknown controller".to_string()
+    } else {
+        unsafe { std::ffi::CStr::from_ptr(name) }.to_string_lossy().into_owned()
+    };
+    log::info!(target: "stdout", "Controller connected: {}", name);
+}
+
+/**
+ * Closes a controller that was unplugged. The buttons it held are
+ * released so that they do not stay pressed.
+ */
+fn close_controller(instance_id: i32) -> () {
+    let controller = unsafe { SDL_GameControllerFromInstanceID(instance_id) };
+    if !controller.is_null() {
+        unsafe { SDL_GameControllerClose(controller); }
+    }
+    for pad_button in 0..SDL_GameControllerButton::SDL_CONTROLLER_BUTTON_MAX as i32 {
+        handle_pad_event(false, pad_button);
+    }
+    log::info!(target: "stdout", "Controller disconnected");
+}
+
+
+/**
+ * UI loop, runs until the user closes the window or the CPU thread
+ * stops. Handles events, and updates the screen.
  */
 pub fn run() -> () {
     let mut prev_frame: u64 = 0;
@@ -242,26 +759,46 @@ pub fn run() -> () {
         type_: 0,
     };
     
-    loop {
+    while !Emulator::is_shutting_down() {
         // Event handling
         unsafe {
             while SDL_PollEvent(&mut event) > 0 {
                 if event.type_ == SDL_KEYDOWN as u32 {
-                    // Down arrow
-                    handle_key_event(true, event.key.keysym.sym);
+                    // Key repeats would toggle the pause, etc. again
+                    if event.key.repeat == 0 {
+                        handle_key_event(true, event.key.keysym.sym);
+                    }
                 } else if event.type_ == SDL_KEYUP as u32 {
                     // Up arrow
                     handle_key_event(false, event.key.keysym.sym);
-                } else if (event.type_ == SDL_WINDOWEVENT as u32) && 
-                   (event.window.event == SDL_WINDOWEVENT_CLOSE as u8) {
-                    std::process::exit(0);
+                } else if event.type_ == SDL_CONTROLLERBUTTONDOWN as u32 {
+                    handle_pad_event(true, event.cbutton.button as i32);
+                } else if event.type_ == SDL_CONTROLLERBUTTONUP as u32 {
+                    handle_pad_event(false, event.cbutton.button as i32);
+                } else if event.type_ == SDL_CONTROLLERDEVICEADDED as u32 {
+                    open_controller(event.cdevice.which);
+                } else if event.type_ == SDL_CONTROLLERDEVICEREMOVED as u32 {
+                    close_controller(event.cdevice.which);
+                } else if ((event.type_ == SDL_WINDOWEVENT as u32) &&
+                   (event.window.event == SDL_WINDOWEVENT_CLOSE as u8)) ||
+                   event.type_ == SDL_QUIT as u32 {
+                    // The battery is saved by the CPU thread once it stops
+                    return;
+                } else if (event.type_ == SDL_WINDOWEVENT as u32) &&
+                   // Sent for SDL_WINDOWEVENT_RESIZED as well as for
+                   // size changes made by the program
+                   (event.window.event == SDL_WINDOWEVENT_SIZE_CHANGED as u8) &&
+                   (event.window.windowID == SDL_GetWindowID(main_window)) {
+                    update_viewport();
+                    update_main_window();
                 }
             }
         }
-        if prev_frame != unsafe { PPU_CTX.curr_frame } {
+        let curr_frame = FRONT_BUFFER.lock().unwrap().number;
+        if prev_frame != curr_frame {
             update_main_window();
         }
-        prev_frame = unsafe { PPU_CTX.curr_frame };
+        prev_frame = curr_frame;
         // main.canvas.present();
         // debug_window.canvas.present();
         // std::thread::sleep(Duration::new(0, 1_000_000_000u32 / FREQ));
@@ -269,6 +806,54 @@ pub fn run() -> () {
 }
 
 
+/**
+ * Opens the default audio device in queue mode, i.e. without
+ * a callback. Samples are pushed with queue_audio().
+ */
+unsafe fn init_audio() -> () {
+    let desired = SDL_AudioSpec {
+        freq: SAMPLE_RATE as i32,
+        format: AUDIO_F32SYS as u16,
+        channels: 2,
+        silence: 0,
+        samples: 1024,
+        padding: 0,
+        size: 0,
+        callback: Option::None,
+        userdata: std::ptr::null_mut(),
+    };
+    let mut obtained: SDL_AudioSpec = std::mem::zeroed();
+    audio_device = SDL_OpenAudioDevice(std::ptr::null(), 0, &desired, &mut obtained, 0);
+    if audio_device == 0 {
+        log::warn!(target: "stdout", "Failed to open audio device, sound is disabled");
+        return;
+    }
+    SDL_PauseAudioDevice(audio_device, 0);
+}
+
+
+/**
+ * Queues interleaved stereo samples to the audio device.
+ * Samples are dropped if the device is not opened or if
+ * the queue is already full, to keep the latency low.
+ * The audio is muted when not running at normal speed
+ * since the samples would not match the playback rate.
+ */
+pub fn queue_audio(samples: &[f32]) -> () {
+    unsafe {
+        if audio_device == 0 || SDL_GetQueuedAudioSize(audio_device) > MAX_QUEUED_AUDIO {
+            return;
+        }
+        if Emulator::is_turbo() || Emulator::speed() != 1.0 {
+            SDL_ClearQueuedAudio(audio_device);
+            return;
+        }
+        SDL_QueueAudio(audio_device, samples.as_ptr() as *const std::ffi::c_void,
+            std::mem::size_of_val(samples) as u32);
+    }
+}
+
+
 /**
  * Returns the number of milliseconds since the SDL
  * library was initialized. A wrapper for SDL_GetTicks64.
@@ -283,4 +868,71 @@ pub fn get_ticks() -> u64 {
  */
 pub fn delay(ms: u32) -> () {
     unsafe { sdl2_sys::SDL_Delay(ms) };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::test_util;
+
+    fn rect(rect: SDL_Rect) -> (i32, i32, i32, i32) {
+        return (rect.x, rect.y, rect.w, rect.h);
+    }
+
+    #[test]
+    fn tile_view_draws_each_tile_in_its_cell() {
+        let _lock = test_util::lock();
+        test_util::boot(&[]);
+        // Tile 0, row 0: colors 1 and 3 in the first two columns
+        bus_write(0x8000, 0xC0);
+        bus_write(0x8001, 0x40);
+        // Tile 17, row 7: color 2 in the last column
+        bus_write(0x8000 + 17 * 16 + 14, 0x00);
+        bus_write(0x8000 + 17 * 16 + 15, 0x01);
+        let mut pixels = vec![0u32; (DEBUG_WIDTH * DEBUG_HEIGHT) as usize];
+        let mut surface: SDL_Surface = unsafe { std::mem::zeroed() };
+        surface.w = DEBUG_WIDTH;
+        surface.h = DEBUG_HEIGHT;
+        surface.pitch = DEBUG_WIDTH * 4;
+        surface.pixels = pixels.as_mut_ptr() as *mut std::ffi::c_void;
+        unsafe {
+            debug_screen = &mut surface;
+            draw_tiles();
+            debug_screen = std::ptr::null_mut();
+        }
+        let at = |x: i32, y: i32| pixels[(y * DEBUG_WIDTH + x) as usize];
+
+        assert_eq!(at(0, 0), TILE_COLORS[1]);
+        assert_eq!(at(SCALE - 1, SCALE - 1), TILE_COLORS[1]);
+        assert_eq!(at(SCALE, 0), TILE_COLORS[3]);
+        assert_eq!(at(2 * SCALE, 0), TILE_COLORS[0]);
+        // The tiles are one scaled pixel apart, tile 17 is the second of the second row
+        assert_eq!(at(8 * SCALE, 0), 0);
+        let cell = 9 * SCALE;
+        assert_eq!(at(cell + 7 * SCALE, cell + 7 * SCALE), TILE_COLORS[2]);
+        assert_eq!(at(cell + 8 * SCALE - 1, cell + 8 * SCALE - 1), TILE_COLORS[2]);
+        assert_eq!(at(cell + 6 * SCALE, cell + 7 * SCALE), TILE_COLORS[0]);
+    }
+
+    #[test]
+    fn viewport_scales_the_lcd_to_the_window() {
+        // 4x fits in 700 x 600, centered
+        assert_eq!(rect(viewport(700, 600, ScalingMode::Integer)), (30, 12, 640, 576));
+        // Never smaller than 1x
+        assert_eq!(rect(viewport(100, 100, ScalingMode::Integer)), (-30, -22, 160, 144));
+        // 3.75x keeps the aspect ratio, with bars on the sides
+        assert_eq!(rect(viewport(700, 540, ScalingMode::Aspect)), (50, 0, 600, 540));
+        assert_eq!(rect(viewport(700, 540, ScalingMode::Stretch)), (0, 0, 700, 540));
+    }
+
+    #[test]
+    fn lcd_filter_blends_and_corrects_the_colors() {
+        assert_eq!(blend_colors(0xFF000000, 0xFFFFFFFF, 0.0), 0xFF000000);
+        assert_eq!(blend_colors(0xFF000000, 0xFFFFFFFF, 0.5), 0xFF7F7F7F);
+        assert_eq!(blend_colors(0xFF204060, 0xFF204060, 0.3), 0xFF204060);
+        // Gray stays gray, pure colors bleed into the other channels
+        assert_eq!(correct_cgb_color(0xFFFFFFFF), 0xFFFFFFFF);
+        assert_eq!(correct_cgb_color(0xFF000000), 0xFF000000);
+        assert_eq!(correct_cgb_color(0xFFFF0000), 0xFFCF002F);
+    }
+}
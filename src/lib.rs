@@ -0,0 +1,220 @@
+// The code base spells out `return`, `-> ()` and `field: field`, and
+// keeps the state of the components in `static mut` contexts
+#![allow(clippy::needless_return, clippy::unused_unit, clippy::redundant_field_names, static_mut_refs)]
+// It also writes range checks, casts, borrows and late initializations
+// out explicitly and puts a blank line after its `/** */` block comments
+#![allow(clippy::manual_range_contains, clippy::unnecessary_cast, clippy::needless_borrow,
+    clippy::needless_late_init, clippy::if_same_then_else, clippy::len_zero, clippy::useless_format,
+    clippy::new_without_default, clippy::manual_c_str_literals, clippy::manual_is_multiple_of,
+    clippy::empty_line_after_doc_comments)]
+
+/**
+ * Library interface of the emulator for embedding it into other
+ * frontends. A `GameBoy` runs without opening any windows, the
+ * frontend is responsible for presenting the framebuffer and
+ * forwarding the input.
+ *
+ * A `GameBoy` does not own the state of the emulator yet: the
+ * components still live in the global contexts used by the binary
+ * and a `GameBoy` is only a handle to them, so only a single one
+ * can be created per process. Moving the state into the struct is
+ * left for a follow-up.
+ * Fatal emulation errors panic with an `EmuError` message, a handler
+ * can be installed with `emulator::error::set_fatal_handler`.
+ */
+pub mod emulator;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use emulator::Emulator;
+use emulator::cartridge::CartridgeError;
+use emulator::cpu::CPU_CTX;
+use emulator::gamepad::GAMEPAD_CTX;
+use emulator::ppu::{self, PPU_CTX};
+pub use emulator::keymap::Button;
+pub use emulator::error::EmuError;
+pub use emulator::movie::{Movie, MovieError};
+pub use emulator::ppu::{X_RES, Y_RES};
+
+// Number of T-cycles in a frame in normal speed mode
+const TICKS_PER_FRAME: u64 = 70224;
+
+static INSTANCE_CREATED: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Errors that can occur while creating a `GameBoy`
+ */
+#[derive(Debug)]
+pub enum GameBoyError {
+    // A GameBoy has already been created in this process
+    AlreadyCreated,
+    // The ROM could not be loaded
+    Cartridge(CartridgeError),
+}
+
+impl std::fmt::Display for GameBoyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GameBoyError::AlreadyCreated =>
+                write!(f, "Only one GameBoy can be created per process"),
+            GameBoyError::Cartridge(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+
+/**
+ * A handle to the emulated Game Boy, whose state is kept in the
+ * global contexts. It can be moved to another thread, but all calls
+ * must be made from the thread that owns it.
+ */
+pub struct GameBoy {
+    // Prevents the struct from being constructed outside of `new`
+    _private: (),
+}
+
+impl GameBoy {
+    /**
+     * Creates a new Game Boy with the given ROM inserted
+     */
+    pub fn new(rom_bytes: Vec<u8>) -> Result<GameBoy, GameBoyError> {
+        if INSTANCE_CREATED.swap(true, Ordering::SeqCst) {
+            return Err(GameBoyError::AlreadyCreated);
+        }
+        Emulator::init_from_data(rom_bytes).map_err(GameBoyError::Cartridge)?;
+        // Frames are paced by the frontend
+        ppu::set_frame_limiter(false);
+        return Ok(GameBoy { _private: () });
+    }
+
+    /**
+     * Executes a single instruction, or a single M-cycle if the
+     * CPU is halted
+     */
+    pub fn step_instruction(&mut self) -> () {
+        unsafe { CPU_CTX.step(); }
+    }
+
+    /**
+     * Runs until the PPU has finished the current frame and returns
+     * the framebuffer as 160x144 ARGB8888 pixels. If the LCD is off,
+     * returns after the time of a frame has passed.
+     */
+    pub fn run_frame(&mut self) -> &[u32] {
+        unsafe {
+            let start_frame = PPU_CTX.curr_frame;
+            let start_ticks = CPU_CTX.ticks.load(Ordering::Relaxed);
+            let frame_ticks = if CPU_CTX.is_double_speed() { TICKS_PER_FRAME * 2 } else { TICKS_PER_FRAME };
+            while PPU_CTX.curr_frame == start_frame &&
+                    CPU_CTX.ticks.load(Ordering::Relaxed) - start_ticks < frame_ticks {
+                CPU_CTX.step();
+            }
+            return &PPU_CTX.video_buffer[..];
+        }
+    }
+
+    /**
+     * Sets the state of every button from the given mask, see
+     * `Button::mask`, then runs a frame like `run_frame`. The input
+     * only changes at frame boundaries and the frame limiter only
+     * sleeps between frames without changing the state, so a
+     * sequence of masks always produces the same frames from the
+     * same state.
+     */
+    pub fn run_frame_with_input(&mut self, buttons: u8) -> &[u32] {
+        unsafe { GAMEPAD_CTX.set_buttons(buttons); }
+        return self.run_frame();
+    }
+
+    /**
+     * Restores the initial state of the given movie file and runs
+     * one frame per recorded input with `run_frame_with_input`.
+     * Returns the framebuffer of the last frame.
+     */
+    pub fn play_movie(&mut self, path: &str) -> Result<&[u32], MovieError> {
+        let movie = Movie::from_file(path)?;
+        movie.restore()?;
+        for buttons in movie.frames() {
+            self.run_frame_with_input(*buttons);
+        }
+        return Ok(unsafe { &PPU_CTX.video_buffer[..] });
+    }
+
+    /**
+     * Resets the machine as if it was power cycled,
+     * the ROM stays inserted
+     */
+    pub fn reset(&mut self) -> () {
+        Emulator::reset();
+    }
+
+    /**
+     * Presses or releases a button
+     */
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> () {
+        unsafe { GAMEPAD_CTX.set_button(button, pressed); }
+    }
+}
+
+impl Drop for GameBoy {
+    /**
+     * Writes the battery save if the cartridge RAM has unsaved changes
+     */
+    fn drop(&mut self) -> () {
+        Emulator::flush_battery();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::test_util;
+    use emulator::address_bus::bus_read;
+
+    // loop: LD A, 0x20; LDH (P1), A; LDH A, (P1); LDH (BGP), A; JR loop
+    // The blank background takes the color of the directions held
+    const INPUT_TO_PALETTE: [u8; 10] = [0x3E, 0x20, 0xE0, 0x00, 0xF0, 0x00, 0xE0, 0x47, 0x18, 0xF6];
+
+    /**
+     * Boots the test program and returns the framebuffers of the
+     * frames run with the given inputs
+     */
+    fn run_frames(inputs: &[u8]) -> Vec<Vec<u32>> {
+        test_util::boot(&INPUT_TO_PALETTE);
+        ppu::set_frame_limiter(false);
+        let mut gameboy = GameBoy { _private: () };
+        let frames = inputs.iter()
+            .map(|buttons| gameboy.run_frame_with_input(*buttons).to_vec())
+            .collect();
+        unsafe { GAMEPAD_CTX.set_buttons(0); }
+        ppu::set_frame_limiter(true);
+        return frames;
+    }
+
+    #[test]
+    fn frames_only_depend_on_the_input() {
+        let _lock = test_util::lock();
+        let inputs = [0, 0, Button::Right.mask(), Button::Left.mask() | Button::A.mask(), 0];
+        let first = run_frames(&inputs);
+        let second = run_frames(&inputs);
+        assert!(first == second);
+        // The input did reach the game
+        assert!(first[1] != first[3]);
+    }
+
+    #[test]
+    fn frames_keep_running_while_stopped() {
+        let _lock = test_util::lock();
+        // LD A, 0x10; LDH (P1), A; STOP; LD A, 0x1B; LDH (BGP), A; JR -2
+        test_util::boot(&[0x3E, 0x10, 0xE0, 0x00, 0x10, 0x00, 0x3E, 0x1B, 0xE0, 0x47, 0x18, 0xFE]);
+        ppu::set_frame_limiter(false);
+        let mut gameboy = GameBoy { _private: () };
+        gameboy.run_frame_with_input(0);
+        gameboy.run_frame_with_input(0);
+        assert_ne!(bus_read(0xFF47), 0x1B);
+        // Pressing A, a selected button, resumes the CPU
+        gameboy.run_frame_with_input(Button::A.mask());
+        assert_eq!(bus_read(0xFF47), 0x1B);
+        unsafe { GAMEPAD_CTX.set_buttons(0); }
+        ppu::set_frame_limiter(true);
+    }
+}
@@ -1,15 +1,50 @@
-use clap::{Command, Arg};
-use std::sync::Arc;
-use std::sync::Mutex;
+// The code base spells out `return`, `-> ()` and `field: field`, and
+// keeps the state of the components in `static mut` contexts
+#![allow(clippy::needless_return, clippy::unused_unit, clippy::redundant_field_names, static_mut_refs)]
+// It also writes range checks, casts, borrows and late initializations
+// out explicitly and puts a blank line after its `/** */` block comments
+#![allow(clippy::manual_range_contains, clippy::unnecessary_cast, clippy::needless_borrow,
+    clippy::needless_late_init, clippy::if_same_then_else, clippy::len_zero, clippy::useless_format,
+    clippy::new_without_default, clippy::manual_c_str_literals, clippy::manual_is_multiple_of,
+    clippy::empty_line_after_doc_comments)]
+
+use clap::{Command, Arg, ArgAction};
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
 use log4rs::encode::pattern::PatternEncoder;
 use log4rs::config::{Appender, Config, Root};
 
-mod emulator;
-use emulator::Emulator;
+use gb_emu::emulator::Emulator;
+
+
+/**
+ * Parses a `--log-target` value of the form `name=level`
+ */
+fn parse_log_target(spec: &str) -> Result<(String, LevelFilter), String> {
+    let (name, level) = spec.split_once('=')
+        .ok_or(format!("expected `name=level`, got '{}'", spec))?;
+    let level: LevelFilter = level.trim().parse()
+        .map_err(|_| format!("unknown log level '{}'", level.trim()))?;
+    return Ok((name.trim().to_string(), level));
+}
 
+/**
+ * Parses a `--disasm` range of the form `start:end`, both hexadecimal
+ */
+fn parse_address_range(spec: &str) -> Result<(u16, u16), String> {
+    let (start, end) = spec.split_once(':')
+        .ok_or(format!("expected `start:end`, got '{}'", spec))?;
+    let parse = |value: &str| {
+        let digits = value.trim().trim_start_matches('$').trim_start_matches("0x");
+        u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address '{}'", value.trim()))
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start > end {
+        return Err(format!("the start ${:04X} is after the end ${:04X}", start, end));
+    }
+    return Ok((start, end));
+}
 
 // Disable warnings for unused imports
 #[allow(unused_variables, unused_mut, unused_imports)]
@@ -40,6 +75,21 @@ fn main() {
                  .default_value("false")
                  .num_args(0)
                  .help("Enable logging"))
+        .arg(Arg::new("log_level")
+                 .long("log-level")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(["off", "error", "warn", "info", "debug", "trace"])
+                 .default_value("info")
+                 .help("Minimum level of the messages written to the log file and stdout"))
+        .arg(Arg::new("log_target")
+                 .long("log-target")
+                 .required(false)
+                 .num_args(1)
+                 .action(ArgAction::Append)
+                 .value_parser(parse_log_target)
+                 .help("Sets the level of a single log target, e.g. stdout=warn or \
+                        gb_emu::emulator::ppu=debug, can be given multiple times"))
         .arg(Arg::new("enable_tracing")
                  .short('t')
                  .long("trace")
@@ -47,6 +97,13 @@ fn main() {
                  .default_value("false")
                  .num_args(0)
                  .help("Enable instruction tracing. Logs executed instructions to trace.log"))
+        .arg(Arg::new("trace_format")
+                 .long("trace-format")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(["default", "doctor"])
+                 .default_value("default")
+                 .help("Format of the trace file, doctor matches the logs of Gameboy Doctor"))
         .arg(Arg::new("debug")
                  .short('d')
                  .long("debug")
@@ -54,13 +111,294 @@ fn main() {
                  .default_value("false")
                  .num_args(0)
                  .help("Enable debug mode. Logs CPU state after each instruction."))
+        .arg(Arg::new("debugger")
+                 .long("debugger")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Starts an interactive debugger on stdin with breakpoints and stepping"))
+        .arg(Arg::new("rom_info")
+                 .long("rom-info")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Prints the cartridge information and exits"))
+        .arg(Arg::new("disasm")
+                 .long("disasm")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(parse_address_range)
+                 .help("Prints the disassembly of the given range, e.g. 0100:014F, and exits"))
+        .arg(Arg::new("region")
+                 .long("region")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(["jp", "world"])
+                 .help("Overrides the destination code of the ROM (jp or world)"))
+        .arg(Arg::new("strict_header")
+                 .long("strict-header")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Refuses ROMs with a corrupted Nintendo logo or global checksum"))
+        .arg(Arg::new("io_trace")
+                 .long("io-trace")
+                 .required(false)
+                 .num_args(1)
+                 .help("Logs the I/O register accesses of the given comma-separated subsystems \
+                        (joypad, serial, timer, interrupts, sound, lcd, cgb, all) to the log file"))
+        .arg(Arg::new("verbose")
+                 .long("verbose")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Prints the FPS to stdout every second"))
+        .arg(Arg::new("open_bus")
+                 .long("open-bus")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Reads from unmapped memory return the last value on the bus instead of 0xFF"))
+        .arg(Arg::new("unusable_pattern")
+                 .long("unusable-pattern")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("On CGB, reads from 0xFEA0 - 0xFEFF return the high nibble of the \
+                        address twice, e.g. 0xFEB4 reads 0xBB, instead of 0xFF"))
+        .arg(Arg::new("regs")
+                 .long("regs")
+                 .required(false)
+                 .num_args(1)
+                 .help("Overrides the registers left by the boot ROM, e.g. A=11,F=80,SP=FFFE"))
+        .arg(Arg::new("save_dir")
+                 .long("save-dir")
+                 .required(false)
+                 .num_args(1)
+                 .help("Directory of the battery saves, next to the ROM by default"))
+        .arg(Arg::new("init_ram")
+                 .long("init-ram")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(["zero", "random", "garbage"])
+                 .default_value("zero")
+                 .help("Power-on content of the WRAM, HRAM and VRAM"))
+        .arg(Arg::new("seed")
+                 .long("seed")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(clap::value_parser!(u64))
+                 .help("Seed of the random power-on RAM content (--init-ram random)"))
+        .arg(Arg::new("ppu_lock")
+                 .long("ppu-lock")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Blocks CPU access to VRAM during mode 3 and to OAM during modes 2 and 3"))
+        .arg(Arg::new("record")
+                 .long("record")
+                 .required(false)
+                 .num_args(1)
+                 .conflicts_with("playback")
+                 .help("Records the input into the given movie file, which is written on exit"))
+        .arg(Arg::new("playback")
+                 .long("playback")
+                 .required(false)
+                 .num_args(1)
+                 .help("Plays back the input of the given movie file, the live input is ignored"))
+        .arg(Arg::new("socd")
+                 .long("socd")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(["allow", "block"])
+                 .default_value("allow")
+                 .help("Allows opposite directions to be held at the same time, or \
+                        makes the last pressed one win"))
+        .arg(Arg::new("no_sprite_limit")
+                 .long("no-sprite-limit")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Draws all the sprites of a line instead of the first 10, which removes flicker"))
+        .arg(Arg::new("dmg")
+                 .long("dmg")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Runs Game Boy Color games in DMG mode"))
+        .arg(Arg::new("headless")
+                 .long("headless")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Runs without opening any windows"))
+        .arg(Arg::new("frames")
+                 .long("frames")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(clap::value_parser!(u64))
+                 .help("Stops the emulator after the given number of frames"))
+        .arg(Arg::new("test_rom")
+                 .long("test-rom")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Runs a Blargg or Mooneye test ROM headless and exits with 0 \
+                        if it passed, 1 otherwise"))
+        .arg(Arg::new("max_cycles")
+                 .long("max-cycles")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(clap::value_parser!(u64))
+                 .help("Stops the emulator after the given number of CPU ticks and exits \
+                        with 0 if the serial output contains the pass text, 1 otherwise"))
+        .arg(Arg::new("pass_text")
+                 .long("pass-text")
+                 .required(false)
+                 .num_args(1)
+                 .default_value("Passed")
+                 .help("Serial output that marks a successful run with --max-cycles"))
+        .arg(Arg::new("dump_frame")
+                 .long("dump-frame")
+                 .required(false)
+                 .num_args(1)
+                 .requires("headless")
+                 .help("Writes the last frame to the given PNG file in headless mode"))
+        .arg(Arg::new("boot_rom")
+                 .long("boot")
+                 .required(false)
+                 .num_args(1)
+                 .help("Path to a DMG boot ROM to run before the cartridge"))
+        .arg(Arg::new("scale")
+                 .long("scale")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(clap::value_parser!(u32).range(1..=16))
+                 .default_value("4")
+                 .help("Initial size of the window in multiples of 160x144"))
+        .arg(Arg::new("renderer")
+                 .long("renderer")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(["fifo", "scanline"])
+                 .default_value("fifo")
+                 .help("Draws the pixels one dot at a time (accurate) or one line \
+                        at a time (faster, but breaks mid-line raster effects)"))
+        .arg(Arg::new("scaling")
+                 .long("scaling")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(["integer", "aspect", "stretch"])
+                 .default_value("integer")
+                 .help("How the image is scaled to the window: integer multiples, \
+                        any size with the aspect ratio kept, or stretched"))
+        .arg(Arg::new("lcd_filter")
+                 .long("lcd-filter")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Blends consecutive frames like the LCD and corrects the CGB colors"))
+        .arg(Arg::new("ghosting")
+                 .long("ghosting")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(clap::value_parser!(f32))
+                 .default_value("0.5")
+                 .help("Weight of the previous frame with --lcd-filter, from 0 to 1"))
+        .arg(Arg::new("palette")
+                 .long("palette")
+                 .required(false)
+                 .num_args(1)
+                 .help("Colors of the DMG output: gray, dmg-green, pocket, bgb or \
+                        four comma-separated hex colors from lightest to darkest"))
+        .arg(Arg::new("speed")
+                 .long("speed")
+                 .visible_alias("clock-scale")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(clap::value_parser!(f64))
+                 .help("Scales the emulated clock to the given multiple of the original \
+                        speed, from 0.25 to 8, e.g. 2 or 0.5. Also paces headless runs"))
+        .arg(Arg::new("serial_stdout")
+                 .long("serial-stdout")
+                 .required(false)
+                 .default_value("false")
+                 .num_args(0)
+                 .help("Prints the data sent through the serial port to stdout"))
+        .arg(Arg::new("link_listen")
+                 .long("link-listen")
+                 .required(false)
+                 .num_args(1)
+                 .value_parser(clap::value_parser!(u16))
+                 .conflicts_with("link_connect")
+                 .help("Waits for another emulator to connect the link cable on the given port"))
+        .arg(Arg::new("link_connect")
+                 .long("link-connect")
+                 .required(false)
+                 .num_args(1)
+                 .help("Connects the link cable to another emulator at host:port"))
+        .arg(Arg::new("keymap")
+                 .long("keymap")
+                 .required(false)
+                 .num_args(1)
+                 .help("Path to a key map file with one `button = keyname` or \
+                        `button = pad_<name>` per line"))
+        .arg(Arg::new("cheat")
+                 .long("cheat")
+                 .required(false)
+                 .num_args(1)
+                 .action(ArgAction::Append)
+                 .help("Enables a Game Genie (ABC-DEF-GHI) or GameShark (01VVLLHH) code, \
+                        can be given multiple times"))
         .get_matches();
 
     let rom_file = matches.get_one::<String>("rom_file").unwrap();
     let log_file = matches.get_one::<String>("log_file").unwrap();
     let disable_logging = matches.get_one::<bool>("disable_logging").unwrap();
+    let log_level: LevelFilter = matches.get_one::<String>("log_level").unwrap().parse().unwrap();
+    let log_targets: Vec<&(String, LevelFilter)> = matches.get_many::<(String, LevelFilter)>("log_target")
+        .unwrap_or_default().collect();
     let enable_tracing = matches.get_one::<bool>("enable_tracing").unwrap();
+    let trace_format = matches.get_one::<String>("trace_format").unwrap();
     let debug = matches.get_one::<bool>("debug").unwrap();
+    let debugger = matches.get_one::<bool>("debugger").unwrap();
+    let rom_info = matches.get_one::<bool>("rom_info").unwrap();
+    let disasm = matches.get_one::<(u16, u16)>("disasm");
+    let region = matches.get_one::<String>("region");
+    let keymap = matches.get_one::<String>("keymap");
+    let cheats: Vec<&String> = matches.get_many::<String>("cheat").unwrap_or_default().collect();
+    let serial_stdout = matches.get_one::<bool>("serial_stdout").unwrap();
+    let link_listen = matches.get_one::<u16>("link_listen");
+    let link_connect = matches.get_one::<String>("link_connect");
+    let test_rom = matches.get_one::<bool>("test_rom").unwrap();
+    let headless = matches.get_one::<bool>("headless").unwrap();
+    let dmg = matches.get_one::<bool>("dmg").unwrap();
+    let open_bus = matches.get_one::<bool>("open_bus").unwrap();
+    let unusable_pattern = matches.get_one::<bool>("unusable_pattern").unwrap();
+    let ppu_lock = matches.get_one::<bool>("ppu_lock").unwrap();
+    let no_sprite_limit = matches.get_one::<bool>("no_sprite_limit").unwrap();
+    let socd = matches.get_one::<String>("socd").unwrap();
+    let record = matches.get_one::<String>("record");
+    let playback = matches.get_one::<String>("playback");
+    let verbose = matches.get_one::<bool>("verbose").unwrap();
+    let init_ram = matches.get_one::<String>("init_ram").unwrap();
+    let save_dir = matches.get_one::<String>("save_dir");
+    let regs = matches.get_one::<String>("regs");
+    let seed = matches.get_one::<u64>("seed");
+    let io_trace = matches.get_one::<String>("io_trace");
+    let strict_header = matches.get_one::<bool>("strict_header").unwrap();
+    let frames = matches.get_one::<u64>("frames");
+    let max_cycles = matches.get_one::<u64>("max_cycles");
+    let pass_text = matches.get_one::<String>("pass_text").unwrap();
+    let speed = matches.get_one::<f64>("speed");
+    let palette = matches.get_one::<String>("palette");
+    let dump_frame = matches.get_one::<String>("dump_frame");
+    let boot_rom = matches.get_one::<String>("boot_rom");
+    let scale = matches.get_one::<u32>("scale").unwrap();
+    let scaling = matches.get_one::<String>("scaling").unwrap();
+    let renderer = matches.get_one::<String>("renderer").unwrap();
+    let lcd_filter = matches.get_one::<bool>("lcd_filter").unwrap();
+    let ghosting = matches.get_one::<f32>("ghosting").unwrap();
 
     // Initialize the logger with the given log file
     // Implementation from:
@@ -82,19 +420,37 @@ fn main() {
             .build("trace.log")
             .unwrap();
         
-        let config: Config = Config::builder()
+        // The last --log-target for a name wins
+        let target_level = |name: &str, default: LevelFilter| -> LevelFilter {
+            return log_targets.iter().rev()
+                .find(|(target, _)| target == name)
+                .map_or(default, |(_, level)| *level);
+        };
+        let mut builder = Config::builder()
             .appender(Appender::builder().build("stdout", Box::new(stdout)))
             .appender(Appender::builder().build("log_file", Box::new(log_file_appender)))
             .appender(Appender::builder().build("trace_file", Box::new(trace_file_appender)))
             .logger(log4rs::config::Logger::builder()
                     .appender("stdout")
-                    .build("stdout", LevelFilter::Info))
+                    .build("stdout", target_level("stdout", log_level)))
             .logger(log4rs::config::Logger::builder()
                     .appender("trace_file")
-                    .build("trace_file", LevelFilter::Trace))
+                    .build("trace_file", target_level("trace_file", LevelFilter::Trace)));
+        // Any other target, e.g. a module path, only writes to the log file
+        let mut names: Vec<&str> = Vec::new();
+        for (target, _) in log_targets.iter() {
+            if target != "stdout" && target != "trace_file" && !names.contains(&target.as_str()) {
+                names.push(target.as_str());
+            }
+        }
+        for name in names {
+            builder = builder.logger(log4rs::config::Logger::builder()
+                .build(name, target_level(name, log_level)));
+        }
+        let config: Config = builder
             .build(Root::builder()
                     .appender("log_file")
-                    .build(LevelFilter::Info))
+                    .build(log_level))
             .unwrap();
 
         log4rs::init_config(config).unwrap();
@@ -106,8 +462,101 @@ fn main() {
             log::warn!("This will slow down the emulator and produce a large log file.");
         }
     }
+    if let Some(region) = region {
+        Emulator::set_region(region == "jp");
+    }
+    if let Some(keymap) = keymap {
+        Emulator::set_keymap(keymap);
+    }
+    Emulator::set_strict_header(*strict_header);
+    if *rom_info {
+        // Only prints the cartridge information
+        Emulator::print_rom_info(&rom_file);
+        return;
+    }
+    if let Some((start, end)) = disasm {
+        Emulator::print_disassembly(&rom_file, *start, *end);
+        return;
+    }
+    Emulator::set_trace_format(trace_format == "doctor");
+    Emulator::set_force_dmg(*dmg);
+    Emulator::set_open_bus(*open_bus);
+    Emulator::set_unusable_pattern(*unusable_pattern);
+    Emulator::set_ppu_lock(*ppu_lock);
+    Emulator::set_no_sprite_limit(*no_sprite_limit);
+    Emulator::set_socd(socd);
+    Emulator::set_verbose(*verbose);
+    Emulator::set_init_ram(init_ram, seed.copied());
+    Emulator::exit_on_fatal_error();
+    for code in cheats {
+        Emulator::add_cheat(code);
+    }
+    if let Some(io_trace) = io_trace {
+        Emulator::set_io_trace(io_trace);
+    }
+    Emulator::set_serial_stdout(*serial_stdout);
+    Emulator::set_debugger(*debugger);
+    if let Some(frames) = frames {
+        Emulator::set_frame_limit(*frames);
+    }
+    if let Some(max_cycles) = max_cycles {
+        Emulator::set_cycle_limit(*max_cycles);
+    }
+    if let Some(speed) = speed {
+        Emulator::set_speed(*speed);
+    }
+    Emulator::set_scale(*scale, scaling);
+    Emulator::set_renderer(renderer);
+    if *lcd_filter {
+        Emulator::set_lcd_filter(*ghosting);
+    }
+    if let Some(palette) = palette {
+        Emulator::set_palette(palette);
+    }
+    if let Some(boot_rom) = boot_rom {
+        Emulator::set_boot_rom(boot_rom);
+    }
+    if let Some(save_dir) = save_dir {
+        Emulator::set_save_dir(save_dir);
+    }
+    if let Some(regs) = regs {
+        Emulator::set_register_overrides(regs);
+    }
     // Initialize the emulator
     Emulator::init(&rom_file, *enable_tracing);
+    Emulator::set_link(link_listen.copied(), link_connect.map(|s| s.as_str()));
+    if let Some(record) = record {
+        Emulator::record_movie(record);
+    }
+    if let Some(playback) = playback {
+        Emulator::play_movie(playback);
+    }
     // Starts the emulator
-    Emulator::run(*debug);
+    if *test_rom {
+        Emulator::set_test_rom(true);
+        Emulator::run_headless(*debug, dump_frame.map(|s| s.as_str()));
+        std::process::exit(Emulator::test_exit_code());
+    }
+    if *headless {
+        Emulator::run_headless(*debug, dump_frame.map(|s| s.as_str()));
+    } else {
+        Emulator::run(*debug);
+    }
+    if max_cycles.is_some() {
+        std::process::exit(Emulator::serial_exit_code(pass_text));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_targets_are_parsed_as_name_and_level() {
+        assert_eq!(parse_log_target("stdout=warn"), Ok(("stdout".to_string(), LevelFilter::Warn)));
+        assert_eq!(parse_log_target(" trace_file = OFF "),
+            Ok(("trace_file".to_string(), LevelFilter::Off)));
+        assert!(parse_log_target("stdout").is_err());
+        assert!(parse_log_target("stdout=loud").is_err());
+    }
 }